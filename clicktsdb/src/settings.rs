@@ -3,6 +3,7 @@ use std::{io, path::PathBuf};
 use anyhow::{anyhow, Result};
 use config::{Config, Environment, File};
 use serde::Deserialize;
+use services::influxdb::InfluxDbSettings;
 use storage::StorageSettings;
 use structopt::StructOpt;
 
@@ -23,6 +24,9 @@ pub struct WebSettings {
 pub struct PrometheusSettings {
     pub read: bool,
     pub write: bool,
+    /// Whether remote read results carry the metadata of their metric families.
+    #[serde(default)]
+    pub read_metadata: bool,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -30,6 +34,8 @@ pub struct Settings {
     pub web: WebSettings,
     pub storage: StorageSettings,
     pub prometheus: PrometheusSettings,
+    #[serde(default)]
+    pub influxdb: InfluxDbSettings,
 }
 
 impl Settings {
@@ -11,8 +11,9 @@ use serde_json::{json, Value};
 use anyhow::{Context, Result};
 use axum::{http::StatusCode, routing::get, Json, Router};
 // use serde::{Deserialize, Serialize};
-use services::{prometheus::prometheus_router, influxdb::influxdb_router};
+use services::{prometheus::prometheus_router, influxdb::influxdb_router, otlp::otlp_router};
 use storage::StorageFactory;
+use tokio::signal;
 
 use crate::settings::Settings;
 
@@ -22,11 +23,13 @@ pub async fn serve(settings: Settings) -> Result<()> {
 
     let app = Router::new()
         .route("/", get(welcome))
-        .merge(influxdb_router(storage.clone()))
+        .merge(influxdb_router(storage.clone(), settings.influxdb.clone()))
+        .merge(otlp_router(storage.clone()))
         .merge(prometheus_router(
-            storage,
+            storage.clone(),
             settings.prometheus.read,
             settings.prometheus.write,
+            settings.prometheus.read_metadata,
         ));
 
     let addr = format!("{}:{}", settings.web.host, settings.web.port);
@@ -36,8 +39,41 @@ pub async fn serve(settings: Settings) -> Result<()> {
         .await
         .context(format!("Failed to bind to address: `{}`.", addr))?;
     axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
         .await
-        .context("Failed to start the web server.")
+        .context("Failed to start the web server.")?;
+
+    // In-flight requests are done, flush what they wrote.
+    storage
+        .shutdown()
+        .await
+        .context("Failed to shut down the storage.")
+}
+
+/// Resolves on SIGTERM or Ctrl+C.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Failed to install the Ctrl+C handler.");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install the SIGTERM handler.")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    println!("Shutting down gracefully.");
 }
 
 async fn welcome() -> (StatusCode, Json<Value>) {
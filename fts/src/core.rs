@@ -1,12 +1,11 @@
 // use std::collections::HashMap;
 
 use std::{
-    collections::{BTreeSet, BinaryHeap, VecDeque},
+    collections::{BTreeSet, HashSet},
     fs,
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
     thread::{self, JoinHandle},
-    time::Duration,
 };
 
 use bytes::Bytes;
@@ -15,12 +14,31 @@ use crossbeam::channel::{Receiver, Sender};
 use crate::{
     error::{FstResult, FtsError},
     query::Query,
-    segment::{CommitReplySender, Segment, SegmentFinalizer, SegmentReader, WritableSegment},
+    segment::{
+        CommitReplySender, Deletion, Segment, SegmentFinalizer, SegmentMerger, SegmentReader,
+        WritableSegment, MERGING_SEGMENT_SUFFIX,
+    },
 };
 // use crate::pos Postings;
 
 pub type DocId = u64;
 
+/// Separates the field name from the value in the term of a field.
+/// It sorts before any other character, so the terms of a field are
+/// contiguous in the term dictionary.
+pub(crate) const FIELD_SEPARATOR: char = '\0';
+
+/// Version of the on disk index format, stored in the index directory.
+/// Indexes without a version file predate field terms: their labels are
+/// indexed as `name:value` terms and cannot be searched by field until
+/// they are rewritten by `Index::migrate`.
+pub const INDEX_FORMAT_VERSION: u32 = 2;
+const LEGACY_INDEX_FORMAT_VERSION: u32 = 1;
+const INDEX_VERSION_FILE_NAME: &str = "index.version";
+/// Suffixes of the directories an index is migrated through.
+const MIGRATING_DIRECTORY_SUFFIX: &str = ".migrating";
+const LEGACY_DIRECTORY_SUFFIX: &str = ".legacy";
+
 pub struct Document {
     pub id: DocId,
     pub content: Bytes,
@@ -36,6 +54,28 @@ impl Document {
             terms: terms.iter().map(|s| s.to_string()).collect(),
         }
     }
+
+    /// Creates a document indexed by `(field, value)` pairs,
+    /// to be searched with `Query::Field` & `Query::Exists`.
+    pub fn with_fields<F: AsRef<str>, V: AsRef<str>>(
+        id: DocId,
+        content: &str,
+        fields: &[(F, V)],
+    ) -> Self {
+        Self {
+            id,
+            content: Bytes::copy_from_slice(content.as_bytes()),
+            terms: fields
+                .iter()
+                .map(|(field, value)| field_term(field.as_ref(), value.as_ref()))
+                .collect(),
+        }
+    }
+}
+
+/// Returns the term indexing the value of a field.
+pub(crate) fn field_term(field: &str, value: &str) -> String {
+    format!("{}{}{}", field, FIELD_SEPARATOR, value)
 }
 
 pub enum SegmentComponent {
@@ -43,6 +83,7 @@ pub enum SegmentComponent {
     TermDictionary,
     PostingList,
     Metadata,
+    DeleteBitmap,
 }
 
 impl SegmentComponent {
@@ -52,18 +93,19 @@ impl SegmentComponent {
             SegmentComponent::TermDictionary => "terms.dict",
             SegmentComponent::PostingList => "postings.list",
             SegmentComponent::Metadata => "meta.data",
+            SegmentComponent::DeleteBitmap => "deletes.bitmap",
         }
     }
 }
 
 pub fn segment_component_file(
-    index_dir: &PathBuf,
+    index_dir: &Path,
     segment_id: &str,
     segment_component: SegmentComponent,
 ) -> PathBuf {
     index_dir
         .join(segment_id)
-        .join(format!("{}", segment_component.file_name()))
+        .join(segment_component.file_name())
 }
 
 /// An mmaped file backed storage for documents.
@@ -83,19 +125,25 @@ pub fn segment_component_file(
 #[derive(Debug)]
 pub struct Config {
     pub directory: PathBuf,
+    /// Number of segments of the same size tier merged together.
+    /// Segment merging is disabled when lower than 2.
+    pub merge_factor: usize,
+    /// Segments with more documents than this are never merged.
+    pub merge_max_docs: usize,
 }
 
 impl Config {
     pub fn new(dir: &Path) -> Self {
         Self {
             directory: dir.to_path_buf(),
+            merge_factor: 10,
+            merge_max_docs: 1_000_000,
         }
     }
 }
 
 pub struct Index {
     config: Arc<Config>,
-    current_segment: WritableSegment,
     segments: Arc<RwLock<Vec<Arc<Segment>>>>,
     handle: IndexerHandle,
 }
@@ -103,33 +151,11 @@ pub struct Index {
 impl Index {
     pub fn open(config: Config) -> FstResult<Self> {
         let directory = config.directory.clone();
-        let mut segments = Vec::new();
         if !directory.as_path().exists() {
             fs::create_dir(&directory)?;
         }
-
-        let paths = fs::read_dir(&directory)?;
-        for path in paths {
-            match path {
-                Ok(entry) if entry.metadata()?.is_dir() => {
-                    // Skip all segment with invalid ulid id.
-                    let segment_id = entry.file_name();
-                    let segment_id_str = segment_id.to_str().unwrap();
-                    if ulid::Ulid::from_string(segment_id_str).is_err() {
-                        println!("Ignoring segment with invalid id `{}`.", segment_id_str);
-                        continue;
-                    };
-
-                    let segment = Segment::open(&directory, segment_id_str)?;
-                    segments.push(Arc::new(segment));
-                }
-                _ => continue,
-            }
-        }
-
-        // Sort segments in ascending order of creation,
-        // knowing that segment id is ulid ordered
-        segments.sort_by_key(|segment| segment.get_id().to_string());
+        check_format_version(&directory)?;
+        let segments = open_segments(&directory)?;
 
         let config = Arc::new(config);
         let segments = Arc::new(RwLock::new(segments));
@@ -144,7 +170,6 @@ impl Index {
 
         Ok(Self {
             config,
-            current_segment: Segment::create(),
             segments,
             handle: IndexerHandle {
                 join_handle: task_join_handle,
@@ -153,6 +178,69 @@ impl Index {
         })
     }
 
+    /// Rewrites an index of the legacy format in the current one, `document`
+    /// rebuilds each stored document, with its terms, from its content.
+    /// Indexes in any other format are left as is.
+    ///
+    /// The documents are indexed in a new directory that replaces the
+    /// legacy one once committed, an interrupted migration starts over.
+    pub fn migrate<F>(config: &Config, document: F) -> FstResult<()>
+    where
+        F: Fn(DocId, Bytes) -> FstResult<Document>,
+    {
+        let directory = config.directory.as_path();
+        let legacy_directory = sibling_directory(directory, LEGACY_DIRECTORY_SUFFIX)?;
+        if legacy_directory.exists() {
+            if directory.exists() {
+                fs::remove_dir_all(&legacy_directory)?;
+            } else {
+                fs::rename(&legacy_directory, directory)?;
+            }
+        }
+        if !directory.exists()
+            || read_format_version(directory)? != Some(LEGACY_INDEX_FORMAT_VERSION)
+        {
+            return Ok(());
+        }
+
+        let reader = IndexReader {
+            segment_readers: open_segments(directory)?
+                .into_iter()
+                .map(SegmentReader::new)
+                .collect(),
+        };
+        let mut documents = Vec::new();
+        for id in reader.query(Query::All)? {
+            documents.push(document(id, reader.fetch_doc(id)?)?);
+        }
+        println!(
+            "Migrating the {} documents of the legacy index `{}`.",
+            documents.len(),
+            directory.display()
+        );
+
+        let migrating_directory = sibling_directory(directory, MIGRATING_DIRECTORY_SUFFIX)?;
+        if migrating_directory.exists() {
+            fs::remove_dir_all(&migrating_directory)?;
+        }
+        let index = Index::open(Config {
+            directory: migrating_directory.clone(),
+            merge_factor: config.merge_factor,
+            merge_max_docs: config.merge_max_docs,
+        })?;
+        index.writer().insert_docs(documents);
+        index.close(true)?;
+
+        fs::rename(directory, &legacy_directory)?;
+        fs::rename(&migrating_directory, directory)?;
+        fs::remove_dir_all(&legacy_directory)?;
+        Ok(())
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
     pub fn writer(&self) -> IndexWriter {
         IndexWriter {
             operation_sender: self.handle.ops_sender.clone(),
@@ -164,7 +252,7 @@ impl Index {
         let segment_readers = segments_lock
             .iter()
             .cloned()
-            .map(|segment| SegmentReader::new(segment))
+            .map(SegmentReader::new)
             .collect();
         IndexReader { segment_readers }
     }
@@ -178,11 +266,114 @@ impl Index {
     }
 }
 
+/// Opens the segments of the index in `directory`, in order of creation.
+fn open_segments(directory: &Path) -> FstResult<Vec<Arc<Segment>>> {
+    let mut segments = Vec::new();
+    let paths = fs::read_dir(directory)?;
+    for path in paths {
+        match path {
+            Ok(entry) if entry.metadata()?.is_dir() => {
+                // Skip all segment with invalid ulid id.
+                let segment_id = entry.file_name();
+                let segment_id_str = segment_id.to_str().unwrap();
+                // Remove the leftovers of an interrupted merge.
+                if segment_id_str.ends_with(MERGING_SEGMENT_SUFFIX) {
+                    fs::remove_dir_all(entry.path())?;
+                    continue;
+                }
+                if ulid::Ulid::from_string(segment_id_str).is_err() {
+                    println!("Ignoring segment with invalid id `{}`.", segment_id_str);
+                    continue;
+                };
+
+                let segment = Segment::open(directory, segment_id_str)?;
+                segments.push(Arc::new(segment));
+            }
+            _ => continue,
+        }
+    }
+
+    // Sort segments in ascending order of creation,
+    // knowing that segment id is ulid ordered
+    segments.sort_by_key(|segment| segment.get_id().to_string());
+
+    // Drop the segments that were merged but not yet removed before a crash.
+    let merged_segment_ids: HashSet<String> = segments
+        .iter()
+        .flat_map(|segment| segment.merged_from().to_vec())
+        .collect();
+    for segment in segments.iter() {
+        if merged_segment_ids.contains(segment.get_id()) {
+            fs::remove_dir_all(directory.join(segment.get_id()))?;
+        }
+    }
+    segments.retain(|segment| !merged_segment_ids.contains(segment.get_id()));
+    Ok(segments)
+}
+
+/// Fails on indexes written in another format, a new or empty
+/// index gets the current format version.
+fn check_format_version(directory: &Path) -> FstResult<()> {
+    match read_format_version(directory)? {
+        None => {
+            let version_file_name = directory.join(INDEX_VERSION_FILE_NAME);
+            fs::write(version_file_name, INDEX_FORMAT_VERSION.to_string())?;
+            Ok(())
+        }
+        Some(INDEX_FORMAT_VERSION) => Ok(()),
+        Some(found) => Err(FtsError::IncompatibleFormat {
+            found,
+            expected: INDEX_FORMAT_VERSION,
+        }),
+    }
+}
+
+/// Returns the format version of the index in `directory`,
+/// `None` for a new or empty index without a version yet.
+fn read_format_version(directory: &Path) -> FstResult<Option<u32>> {
+    let version_file_name = directory.join(INDEX_VERSION_FILE_NAME);
+    if version_file_name.exists() {
+        let content = fs::read_to_string(&version_file_name)?;
+        let version = content.trim().parse::<u32>().map_err(|_| {
+            FtsError::Other(format!(
+                "invalid index format version `{}`.",
+                content.trim()
+            ))
+        })?;
+        return Ok(Some(version));
+    }
+
+    let has_segments = fs::read_dir(directory)?.any(|entry| {
+        entry.is_ok_and(|entry| {
+            entry.path().is_dir()
+                && entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| ulid::Ulid::from_string(name).is_ok())
+        })
+    });
+    Ok(has_segments.then_some(LEGACY_INDEX_FORMAT_VERSION))
+}
+
+/// Returns the path of a directory next to `directory`, named after it.
+fn sibling_directory(directory: &Path, suffix: &str) -> FstResult<PathBuf> {
+    let name = directory.file_name().ok_or_else(|| {
+        FtsError::Other(format!(
+            "invalid index directory `{}`.",
+            directory.display()
+        ))
+    })?;
+    let mut sibling_name = name.to_os_string();
+    sibling_name.push(suffix);
+    Ok(directory.with_file_name(sibling_name))
+}
+
 struct IndexerHandle {
     join_handle: JoinHandle<FstResult<()>>,
     ops_sender: Sender<IndexingOp>,
 }
 
+#[derive(Clone)]
 pub struct IndexWriter {
     operation_sender: Sender<IndexingOp>,
 }
@@ -200,13 +391,36 @@ impl IndexWriter {
             .unwrap();
     }
 
+    /// Deletes a document from the index. The deletion applies to the
+    /// documents inserted before it, committed or not. Committed documents
+    /// are removed from search eventually, use `commit(true)` to wait for it.
+    pub fn delete_doc(&self, id: DocId) {
+        self.operation_sender
+            .send(IndexingOp::Delete(Deletion::Docs(vec![id])))
+            .unwrap();
+    }
+
+    pub fn delete_docs(&self, ids: Vec<DocId>) {
+        self.operation_sender
+            .send(IndexingOp::Delete(Deletion::Docs(ids)))
+            .unwrap();
+    }
+
+    /// Deletes all the documents matching a query.
+    /// This commits the current in-memory segment first.
+    pub fn delete_by_query(&self, query: Query) {
+        self.operation_sender
+            .send(IndexingOp::Delete(Deletion::Query(query)))
+            .unwrap();
+    }
+
     /// Commits the current in-memory segment & persist to disk.
     /// The `wait` param denotes whether you want wait for the commit
     /// operation to complete or you don't care.
     /// - Waiting (true): means committed docs will be available in
-    /// search immediately after this function returns.
+    ///   search immediately after this function returns.
     /// - No Waiting (false): means committed docs will be available in
-    /// search eventually. This is useful for setups favoring high ingestion.
+    ///   search eventually. This is useful for setups favoring high ingestion.
     pub fn commit(&self, wait: bool) -> FstResult<()> {
         if !wait {
             self.operation_sender
@@ -226,6 +440,7 @@ impl IndexWriter {
 enum IndexingOp {
     Insert(Vec<Document>),
     Commit(Option<CommitReplySender>),
+    Delete(Deletion),
     Shutdown(bool),
 }
 
@@ -236,11 +451,12 @@ fn indexing_task(
 ) -> FstResult<()> {
     let mut current_segment: Option<WritableSegment> = None;
     // starts a workers
-    let segment_finalizer = SegmentFinalizer::start(&config.directory, segments);
+    let segment_merger = SegmentMerger::start(config.clone(), segments.clone());
+    let segment_finalizer = SegmentFinalizer::start(&config.directory, segments, &segment_merger);
     loop {
         let command = document_receiver
             .recv()
-            .map_err(|err| FtsError::Other("failed to receive command.".to_string()))?;
+            .map_err(|_err| FtsError::Other("failed to receive command.".to_string()))?;
         match command {
             IndexingOp::Insert(documents) => {
                 if current_segment.is_none() {
@@ -249,9 +465,24 @@ fn indexing_task(
                 current_segment.as_mut().unwrap().insert(documents);
             }
             IndexingOp::Commit(commit_reply_sender_opt) => {
-                if let Some(writable_segment) = current_segment.replace(Segment::create()) {
-                    segment_finalizer.finalize(writable_segment, commit_reply_sender_opt);
+                let writable_segment = current_segment.take().unwrap_or_else(Segment::create);
+                segment_finalizer.finalize(writable_segment, commit_reply_sender_opt);
+            }
+            IndexingOp::Delete(deletion) => {
+                match &deletion {
+                    Deletion::Docs(doc_ids) => {
+                        if let Some(writable_segment) = current_segment.as_mut() {
+                            writable_segment.delete(doc_ids);
+                        }
+                    }
+                    // Queries can only be evaluated on committed segments.
+                    Deletion::Query(_) => {
+                        if let Some(writable_segment) = current_segment.take() {
+                            segment_finalizer.finalize(writable_segment, None);
+                        }
+                    }
                 }
+                segment_finalizer.delete(deletion);
             }
             IndexingOp::Shutdown(commit) => {
                 println!("process shutdown with commit={commit}");
@@ -263,6 +494,7 @@ fn indexing_task(
                     }
                 }
                 segment_finalizer.stop()?;
+                segment_merger.stop()?;
                 break;
             }
         }
@@ -276,6 +508,11 @@ pub struct IndexReader {
 }
 
 impl IndexReader {
+    /// Returns the number of segments this reader searches.
+    pub fn num_segments(&self) -> usize {
+        self.segment_readers.len()
+    }
+
     /// Returns all terms matching this range query.
     pub fn terms_range(
         &self,
@@ -308,6 +545,32 @@ impl IndexReader {
         Ok(terms_set.into_iter().collect())
     }
 
+    /// Returns the values of a field matching this query, e.g.
+    /// `field_values("job", Query::StartsWith("api"))`.
+    pub fn field_values(&self, field: &str, query: Query) -> FstResult<Vec<String>> {
+        let prefix_len = field_term(field, "").len();
+        let terms = self.terms(Query::Field(field.to_string(), Box::new(query)))?;
+        Ok(terms
+            .into_iter()
+            .map(|term| term[prefix_len..].to_string())
+            .collect())
+    }
+
+    /// Returns the names of the fields of the documents indexed with
+    /// `Document::with_fields`, sorted.
+    pub fn fields(&self) -> FstResult<Vec<String>> {
+        //TODO: Skip over the values of each field.
+        let mut fields_set = BTreeSet::new();
+        for term in self.terms(Query::All)? {
+            if let Some((field, _)) = term.split_once(FIELD_SEPARATOR) {
+                if !fields_set.contains(field) {
+                    fields_set.insert(field.to_string());
+                }
+            }
+        }
+        Ok(fields_set.into_iter().collect())
+    }
+
     /// Returns the doc ids matching this query.
     pub fn query(&self, query: Query) -> FstResult<Vec<u64>> {
         let mut terms_set = BTreeSet::new();
@@ -0,0 +1,81 @@
+use std::{fs, path::Path};
+
+use crate::{error::FstResult, segment_component_file, SegmentComponent};
+
+const U64_NUM_BITS: usize = 64;
+
+/// A persisted bitmap of the deleted documents of a segment.
+/// Bit `n` is set when the document with ordinal `n`, i.e. the
+/// position of its id in the sorted doc ids of the segment, is deleted.
+///
+/// Deletion Bitmap Format: little endian u64 words
+/// ┌────────┬─────┬────────┐
+/// │ word 0 │ ... │ word N │
+/// └────────┴─────┴────────┘
+///
+#[derive(Debug)]
+pub(crate) struct DeleteBitmap {
+    words: Vec<u64>,
+    num_deleted: usize,
+}
+
+impl DeleteBitmap {
+    pub fn new(num_docs: usize) -> Self {
+        Self {
+            words: vec![0; num_docs.div_ceil(U64_NUM_BITS)],
+            num_deleted: 0,
+        }
+    }
+
+    pub fn open(index_directory: &Path, segment_id: &str, num_docs: usize) -> FstResult<Self> {
+        let bitmap_file_name =
+            segment_component_file(index_directory, segment_id, SegmentComponent::DeleteBitmap);
+        if !bitmap_file_name.exists() {
+            return Ok(Self::new(num_docs));
+        }
+
+        let bytes = fs::read(bitmap_file_name)?;
+        let words: Vec<u64> = bytes
+            .chunks_exact(U64_NUM_BITS / 8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        let num_deleted = words.iter().map(|word| word.count_ones() as usize).sum();
+        Ok(Self { words, num_deleted })
+    }
+
+    /// Writes the bitmap next to the other segment components.
+    /// The file is replaced atomically to survive crashes.
+    pub fn persist(&self, index_directory: &Path, segment_id: &str) -> FstResult<()> {
+        let bitmap_file_name =
+            segment_component_file(index_directory, segment_id, SegmentComponent::DeleteBitmap);
+        let tmp_bitmap_file_name = bitmap_file_name.with_extension("tmp");
+        let bytes: Vec<u8> = self
+            .words
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        fs::write(&tmp_bitmap_file_name, bytes)?;
+        fs::rename(tmp_bitmap_file_name, bitmap_file_name)?;
+        Ok(())
+    }
+
+    /// Marks a document as deleted, returns false if it already was.
+    pub fn insert(&mut self, ordinal: usize) -> bool {
+        let (word, bit) = (ordinal / U64_NUM_BITS, ordinal % U64_NUM_BITS);
+        if self.words[word] & (1 << bit) != 0 {
+            return false;
+        }
+        self.words[word] |= 1 << bit;
+        self.num_deleted += 1;
+        true
+    }
+
+    pub fn contains(&self, ordinal: usize) -> bool {
+        let (word, bit) = (ordinal / U64_NUM_BITS, ordinal % U64_NUM_BITS);
+        self.words[word] & (1 << bit) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_deleted == 0
+    }
+}
@@ -3,17 +3,14 @@
 use std::{
     fs::OpenOptions,
     io::{self, Seek, Write},
-    path::PathBuf,
+    path::Path,
 };
 
 use bytes::Bytes;
 use hashbrown::HashMap;
 use memmap2::Mmap;
 
-use crate::{
-    error::FstResult, segment_component_file, DocId,
-    SegmentComponent,
-};
+use crate::{error::FstResult, segment_component_file, DocId, SegmentComponent};
 
 const ZSTD_LEVEL: i32 = 2;
 const U64_NUM_BYTE: usize = 8;
@@ -29,12 +26,14 @@ pub struct DocStoreInfo {
 #[derive(Debug)]
 pub struct DocStore {
     index: HashMap<DocId, DocStoreInfo>,
+    /// Sorted ids of the stored documents.
+    doc_ids: Vec<DocId>,
     store: Mmap,
 }
 
 impl DocStore {
     pub fn new(
-        index_directory: &PathBuf,
+        index_directory: &Path,
         segment_id: &str,
         documents: HashMap<DocId, Bytes>,
     ) -> FstResult<Self> {
@@ -44,6 +43,7 @@ impl DocStore {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(store_file_name)?;
 
         let mut store_file_writer = io::BufWriter::new(&store_file);
@@ -56,7 +56,7 @@ impl DocStore {
             index.insert(
                 doc_id,
                 DocStoreInfo {
-                    offset: offset,
+                    offset,
                     length: compressed_doc_content.len(),
                 },
             );
@@ -64,20 +64,23 @@ impl DocStore {
             store_file_writer.write_all(&doc_id.to_le_bytes())?;
             store_file_writer.write_all(&(compressed_doc_content.len() as u64).to_le_bytes())?;
             store_file_writer.write_all(&compressed_doc_content)?;
-            offset += (2*U64_NUM_BYTE) + compressed_doc_content.len();
+            offset += (2 * U64_NUM_BYTE) + compressed_doc_content.len();
         }
         store_file_writer.flush()?;
 
         let store = unsafe { Mmap::map(&store_file)? };
-        Ok(Self { index, store })
+        let doc_ids = sorted_doc_ids(&index);
+        Ok(Self {
+            index,
+            doc_ids,
+            store,
+        })
     }
 
-    pub fn open(index_directory: &PathBuf, segment_id: &str) -> FstResult<Self> {
+    pub fn open(index_directory: &Path, segment_id: &str) -> FstResult<Self> {
         let store_file_name =
             segment_component_file(index_directory, segment_id, SegmentComponent::DocStore);
-        let store_file = OpenOptions::new()
-            .read(true)
-            .open(store_file_name)?;
+        let store_file = OpenOptions::new().read(true).open(store_file_name)?;
         let mut store_file_reader = io::BufReader::new(&store_file);
         let mut index = HashMap::new();
         let mut offset = 0u64;
@@ -90,28 +93,52 @@ impl DocStore {
             store_file_reader.seek(io::SeekFrom::Start(offset))?;
             let doc_id = store_file_reader.read_u64::<LittleEndian>()?;
             let doc_length = store_file_reader.read_u64::<LittleEndian>()?;
-            
-            index.insert(doc_id, DocStoreInfo{
-                offset: offset as usize,
-                length: doc_length as usize,
-            });
+
+            index.insert(
+                doc_id,
+                DocStoreInfo {
+                    offset: offset as usize,
+                    length: doc_length as usize,
+                },
+            );
             offset = offset + (2 * U64_NUM_BYTE as u64) + doc_length;
         }
 
         let store = unsafe { Mmap::map(&store_file)? };
+        let doc_ids = sorted_doc_ids(&index);
         Ok(Self {
             index,
+            doc_ids,
             store,
         })
     }
 
+    pub fn num_docs(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns the sorted ids of all documents in the store.
+    pub fn doc_ids(&self) -> &[DocId] {
+        &self.doc_ids
+    }
+
+    /// Returns the position of a document in the sorted doc ids.
+    pub fn ordinal(&self, id: DocId) -> Option<usize> {
+        self.doc_ids.binary_search(&id).ok()
+    }
+
     pub fn fetch_doc(&self, id: DocId) -> FstResult<Option<Bytes>> {
         let Some(info) = self.index.get(&id) else {
             return Ok(None);
         };
         let offset = info.offset + (2 * U64_NUM_BYTE);
-        let doc_content =
-            zstd::stream::decode_all(&self.store[offset..offset + info.length])?;
+        let doc_content = zstd::stream::decode_all(&self.store[offset..offset + info.length])?;
         Ok(Some(Bytes::from(doc_content)))
     }
 }
+
+fn sorted_doc_ids(index: &HashMap<DocId, DocStoreInfo>) -> Vec<DocId> {
+    let mut doc_ids: Vec<DocId> = index.keys().copied().collect();
+    doc_ids.sort_unstable();
+    doc_ids
+}
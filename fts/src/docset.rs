@@ -0,0 +1,224 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use crate::DocId;
+
+/// A sorted stream of doc ids.
+/// Queries are evaluated by composing doc sets so that posting
+/// lists are consumed block by block instead of being fully loaded.
+pub(crate) trait DocSet {
+    /// Returns the next doc id.
+    fn next(&mut self) -> Option<DocId>;
+
+    /// Returns the next doc id greater than or equal to `target`,
+    /// skipping all the doc ids in between.
+    fn seek(&mut self, target: DocId) -> Option<DocId> {
+        while let Some(doc_id) = self.next() {
+            if doc_id >= target {
+                return Some(doc_id);
+            }
+        }
+        None
+    }
+
+    fn collect_vec(mut self) -> Vec<DocId>
+    where
+        Self: Sized,
+    {
+        let mut doc_ids = vec![];
+        while let Some(doc_id) = self.next() {
+            doc_ids.push(doc_id);
+        }
+        doc_ids
+    }
+}
+
+impl<D: DocSet + ?Sized> DocSet for Box<D> {
+    fn next(&mut self) -> Option<DocId> {
+        (**self).next()
+    }
+
+    fn seek(&mut self, target: DocId) -> Option<DocId> {
+        (**self).seek(target)
+    }
+}
+
+/// A doc set over an in-memory sorted list of doc ids.
+pub(crate) struct VecDocSet {
+    doc_ids: Vec<DocId>,
+    position: usize,
+}
+
+impl VecDocSet {
+    pub fn new(doc_ids: Vec<DocId>) -> Self {
+        Self {
+            doc_ids,
+            position: 0,
+        }
+    }
+}
+
+impl DocSet for VecDocSet {
+    fn next(&mut self) -> Option<DocId> {
+        let doc_id = self.doc_ids.get(self.position).copied()?;
+        self.position += 1;
+        Some(doc_id)
+    }
+
+    fn seek(&mut self, target: DocId) -> Option<DocId> {
+        let remaining = &self.doc_ids[self.position..];
+        self.position += remaining.partition_point(|doc_id| *doc_id < target);
+        self.next()
+    }
+}
+
+/// Doc ids present in all the doc sets (AND).
+pub(crate) struct IntersectionDocSet<L, R> {
+    left: L,
+    right: R,
+}
+
+impl<L: DocSet, R: DocSet> IntersectionDocSet<L, R> {
+    pub fn new(left: L, right: R) -> Self {
+        Self { left, right }
+    }
+
+    /// Leapfrogs both doc sets from the left candidate until they agree.
+    fn align(&mut self, mut candidate: DocId) -> Option<DocId> {
+        loop {
+            let right_doc_id = self.right.seek(candidate)?;
+            if right_doc_id == candidate {
+                return Some(candidate);
+            }
+            candidate = self.left.seek(right_doc_id)?;
+            if candidate == right_doc_id {
+                return Some(candidate);
+            }
+        }
+    }
+}
+
+impl<L: DocSet, R: DocSet> DocSet for IntersectionDocSet<L, R> {
+    fn next(&mut self) -> Option<DocId> {
+        let candidate = self.left.next()?;
+        self.align(candidate)
+    }
+
+    fn seek(&mut self, target: DocId) -> Option<DocId> {
+        let candidate = self.left.seek(target)?;
+        self.align(candidate)
+    }
+}
+
+/// Doc ids present in any of the doc sets (OR).
+pub(crate) struct UnionDocSet<D> {
+    doc_sets: Vec<D>,
+    /// Head doc id of each doc set, smallest first.
+    heads: BinaryHeap<Reverse<(DocId, usize)>>,
+    started: bool,
+}
+
+impl<D: DocSet> UnionDocSet<D> {
+    pub fn new(doc_sets: Vec<D>) -> Self {
+        Self {
+            heads: BinaryHeap::with_capacity(doc_sets.len()),
+            doc_sets,
+            started: false,
+        }
+    }
+
+    fn start(&mut self) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+        for (index, doc_set) in self.doc_sets.iter_mut().enumerate() {
+            if let Some(doc_id) = doc_set.next() {
+                self.heads.push(Reverse((doc_id, index)));
+            }
+        }
+    }
+}
+
+impl<D: DocSet> DocSet for UnionDocSet<D> {
+    fn next(&mut self) -> Option<DocId> {
+        self.start();
+        let Reverse((doc_id, _)) = *self.heads.peek()?;
+        // Advance all the doc sets positioned on this doc id.
+        while let Some(Reverse((head_doc_id, index))) = self.heads.peek().copied() {
+            if head_doc_id != doc_id {
+                break;
+            }
+            self.heads.pop();
+            if let Some(next_doc_id) = self.doc_sets[index].next() {
+                self.heads.push(Reverse((next_doc_id, index)));
+            }
+        }
+        Some(doc_id)
+    }
+
+    fn seek(&mut self, target: DocId) -> Option<DocId> {
+        self.start();
+        while let Some(Reverse((head_doc_id, index))) = self.heads.peek().copied() {
+            if head_doc_id >= target {
+                break;
+            }
+            self.heads.pop();
+            if let Some(next_doc_id) = self.doc_sets[index].seek(target) {
+                self.heads.push(Reverse((next_doc_id, index)));
+            }
+        }
+        self.next()
+    }
+}
+
+/// Doc ids of the left doc set that are not in the right one (AND NOT).
+pub(crate) struct DifferenceDocSet<L, R> {
+    left: L,
+    right: R,
+    right_head: Option<DocId>,
+    right_exhausted: bool,
+}
+
+impl<L: DocSet, R: DocSet> DifferenceDocSet<L, R> {
+    pub fn new(left: L, right: R) -> Self {
+        Self {
+            left,
+            right,
+            right_head: None,
+            right_exhausted: false,
+        }
+    }
+
+    fn is_excluded(&mut self, doc_id: DocId) -> bool {
+        if self.right_exhausted {
+            return false;
+        }
+        if self
+            .right_head
+            .is_none_or(|right_doc_id| right_doc_id < doc_id)
+        {
+            self.right_head = self.right.seek(doc_id);
+            self.right_exhausted = self.right_head.is_none();
+        }
+        self.right_head == Some(doc_id)
+    }
+}
+
+impl<L: DocSet, R: DocSet> DocSet for DifferenceDocSet<L, R> {
+    fn next(&mut self) -> Option<DocId> {
+        loop {
+            let doc_id = self.left.next()?;
+            if !self.is_excluded(doc_id) {
+                return Some(doc_id);
+            }
+        }
+    }
+
+    fn seek(&mut self, target: DocId) -> Option<DocId> {
+        let mut doc_id = self.left.seek(target)?;
+        while self.is_excluded(doc_id) {
+            doc_id = self.left.next()?;
+        }
+        Some(doc_id)
+    }
+}
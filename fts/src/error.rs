@@ -1,4 +1,4 @@
-use std::{error, io};
+use std::io;
 
 use thiserror::Error;
 
@@ -21,6 +21,9 @@ pub enum FtsError {
     #[error("Query not supported.")]
     QueryNotSupported,
 
+    /// The index was written by an incompatible version of the index format.
+    #[error("Index format version {found} is not supported (expected {expected}), migrate it with `Index::migrate` or rebuild it")]
+    IncompatibleFormat { found: u32, expected: u32 },
     #[error("IndexReader error")]
     IndexReader,
     #[error("Document not found")]
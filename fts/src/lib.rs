@@ -1,5 +1,7 @@
 pub mod core;
+mod deletes;
 pub mod doc_store;
+mod docset;
 pub mod error;
 pub mod matcher;
 pub mod postings;
@@ -9,15 +11,16 @@ pub mod segment;
 pub use core::*;
 pub use error::{FstResult, FtsError};
 
-use std::vec;
-
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::{fs, thread, time::Duration};
 
+    use bytes::Bytes;
     use tempdir::TempDir;
 
-    use crate::{error::FstResult, query::Query, Config, Document, Index};
+    use crate::{
+        error::FstResult, query::Query, Config, Document, FtsError, Index, INDEX_FORMAT_VERSION,
+    };
 
     #[test]
     fn usage() -> FstResult<()> {
@@ -79,6 +82,48 @@ mod tests {
             assert_eq!(&terms, &["bar", "baz", "biz"]);
         }
 
+        {
+            // label matcher style queries
+            let writer = index.writer();
+            writer.insert_doc(Document::new(10, "a", &["job:a", "env:prod"]));
+            writer.insert_doc(Document::new(11, "ab", &["job:ab", "env:dev"]));
+            writer.insert_doc(Document::new(12, "abc", &["job:abc", "env:prod"]));
+            writer.commit(true)?;
+
+            let reader = index.reader();
+            let doc_ids = reader.query(Query::Regex("job:(?:a|ab)".to_string()))?;
+            assert_eq!(&doc_ids, &[10, 11]);
+
+            let doc_ids = reader.query(Query::And(
+                Box::new(Query::StartsWith("job:".to_string())),
+                Box::new(Query::Equal("env:prod".to_string())),
+            ))?;
+            assert_eq!(&doc_ids, &[10, 12]);
+
+            let doc_ids = reader.query(Query::Or(
+                Box::new(Query::Equal("job:ab".to_string())),
+                Box::new(Query::Regex("job:ab.*".to_string())),
+            ))?;
+            assert_eq!(&doc_ids, &[11, 12]);
+        }
+
+        {
+            // document level negation
+            let reader = index.reader();
+            let doc_ids = reader.query(Query::NotEqual("env:prod".to_string()))?;
+            assert_eq!(&doc_ids, &[1, 2, 3, 11]);
+
+            let doc_ids =
+                reader.query(Query::Not(Box::new(Query::StartsWith("job:".to_string()))))?;
+            assert_eq!(&doc_ids, &[1, 2, 3]);
+
+            let doc_ids = reader.query(Query::AndNot(
+                Box::new(Query::StartsWith("job:".to_string())),
+                Box::new(Query::Regex("job:a.+".to_string())),
+            ))?;
+            assert_eq!(&doc_ids, &[10]);
+        }
+
         // { // search
         //     let reader = index.reader();
         //     let doc_ids = reader.query("foo AND bar")?;
@@ -94,4 +139,357 @@ mod tests {
         index.close(false).unwrap();
         Ok(())
     }
+
+    #[test]
+    fn incompatible_format_version() -> FstResult<()> {
+        let tmp_dir = TempDir::new("./data").unwrap();
+        let index = Index::open(Config::new(tmp_dir.path()))?;
+        index.close(false)?;
+        let index = Index::open(Config::new(tmp_dir.path()))?;
+        index.close(false)?;
+
+        // Indexes written before the format was versioned are rejected.
+        let legacy_dir = TempDir::new("./data").unwrap();
+        fs::create_dir(legacy_dir.path().join(ulid::Ulid::new().to_string()))?;
+        assert!(matches!(
+            Index::open(Config::new(legacy_dir.path())),
+            Err(FtsError::IncompatibleFormat {
+                found: 1,
+                expected: INDEX_FORMAT_VERSION
+            })
+        ));
+
+        fs::write(tmp_dir.path().join("index.version"), "3")?;
+        assert!(matches!(
+            Index::open(Config::new(tmp_dir.path())),
+            Err(FtsError::IncompatibleFormat { found: 3, .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_legacy_index() -> FstResult<()> {
+        // A legacy index: `name:value` terms and no version file.
+        let tmp_dir = TempDir::new("./data").unwrap();
+        let index = Index::open(Config::new(tmp_dir.path()))?;
+        let legacy_doc = |id, content: &str| {
+            let terms: Vec<&str> = content.split(',').collect();
+            Document::new(id, content, &terms)
+        };
+        let writer = index.writer();
+        writer.insert_doc(legacy_doc(1, "job:api,env:prod"));
+        writer.insert_doc(legacy_doc(2, "job:db,env:prod"));
+        writer.commit(true)?;
+        writer.insert_doc(legacy_doc(3, "job:web,env:dev"));
+        writer.delete_doc(2);
+        index.close(true)?;
+        fs::remove_file(tmp_dir.path().join("index.version"))?;
+        assert!(matches!(
+            Index::open(Config::new(tmp_dir.path())),
+            Err(FtsError::IncompatibleFormat { found: 1, .. })
+        ));
+
+        // The documents are indexed again, by the fields rebuilt from their content.
+        let config = Config::new(tmp_dir.path());
+        let document = |id, content: Bytes| {
+            let content = std::str::from_utf8(&content).unwrap();
+            let fields: Vec<(&str, &str)> = content
+                .split(',')
+                .filter_map(|term| term.split_once(':'))
+                .collect();
+            Ok(Document::with_fields(id, content, &fields))
+        };
+        Index::migrate(&config, document)?;
+        let index = Index::open(Config::new(tmp_dir.path()))?;
+        let reader = index.reader();
+        assert_eq!(reader.query(Query::All)?, &[1, 3]);
+        assert_eq!(reader.fetch_doc(3)?.as_ref(), b"job:web,env:dev");
+        assert_eq!(reader.fields()?, &["env", "job"]);
+        assert_eq!(reader.field_values("job", Query::All)?, &["api", "web"]);
+        assert_eq!(
+            reader.query(Query::Field(
+                "env".to_string(),
+                Box::new(Query::Equal("prod".to_string()))
+            ))?,
+            &[1]
+        );
+        index.close(false)?;
+
+        // Migrating an index in the current format does nothing.
+        Index::migrate(&config, |_, _| panic!("the index is already migrated"))?;
+        let name = tmp_dir.path().file_name().unwrap().to_str().unwrap();
+        for suffix in [".migrating", ".legacy"] {
+            let sibling = tmp_dir.path().with_file_name(format!("{}{}", name, suffix));
+            assert!(!sibling.exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn merge_segments() -> FstResult<()> {
+        let tmp_dir = TempDir::new("./data").unwrap();
+        let mut config = Config::new(tmp_dir.path());
+        config.merge_factor = 2;
+        let index = Index::open(config)?;
+
+        // Merges run in the background.
+        let wait_for_merges = || {
+            let mut reader = index.reader();
+            for _ in 0..100 {
+                if reader.num_segments() == 1 {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+                reader = index.reader();
+            }
+            assert_eq!(reader.num_segments(), 1);
+            reader
+        };
+
+        let writer = index.writer();
+        writer.insert_doc(Document::new(1, "old", &["a"]));
+        writer.commit(true)?;
+        writer.insert_doc(Document::new(2, "two", &["b"]));
+        writer.commit(true)?;
+        writer.insert_doc(Document::new(3, "three", &["c"]));
+        writer.insert_doc(Document::new(4, "deleted", &["d"]));
+        writer.commit(true)?;
+        wait_for_merges();
+
+        // Deletions of the merged segment are dropped by the next merge.
+        writer.delete_doc(4);
+        writer.insert_doc(Document::new(1, "new", &["a2"]));
+        writer.insert_doc(Document::new(5, "five", &["e"]));
+        writer.insert_doc(Document::new(6, "six", &["f"]));
+        writer.insert_doc(Document::new(7, "seven", &["g"]));
+        writer.commit(true)?;
+
+        let reader = wait_for_merges();
+        assert_eq!(reader.query(Query::All)?, &[1, 2, 3, 5, 6, 7]);
+        assert!(reader.query(Query::Equal("a".to_string()))?.is_empty());
+        assert_eq!(reader.query(Query::Equal("a2".to_string()))?, &[1]);
+        assert_eq!(reader.fetch_doc(1)?.as_ref(), b"new");
+        index.close(false)?;
+
+        // Merged segments and deleted docs are gone from disk.
+        let num_segment_dirs = fs::read_dir(tmp_dir.path())?
+            .filter(|entry| entry.as_ref().unwrap().path().is_dir())
+            .count();
+        assert_eq!(num_segment_dirs, 1);
+        let index = Index::open(Config::new(tmp_dir.path()))?;
+        assert_eq!(
+            index.reader().terms(Query::All)?,
+            &["a2", "b", "c", "e", "f", "g"]
+        );
+        index.close(false)?;
+        Ok(())
+    }
+
+    #[test]
+    fn merge_segments_with_reinserted_doc() -> FstResult<()> {
+        let tmp_dir = TempDir::new("./data").unwrap();
+        let mut config = Config::new(tmp_dir.path());
+        config.merge_factor = 2;
+        let index = Index::open(config)?;
+
+        let writer = index.writer();
+        writer.insert_doc(Document::new(1, "one", &["a"]));
+        writer.commit(true)?;
+        writer.delete_doc(1);
+        writer.commit(true)?;
+        writer.insert_doc(Document::new(1, "one again", &["a2"]));
+        writer.commit(true)?;
+
+        let mut reader = index.reader();
+        for _ in 0..100 {
+            if reader.num_segments() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+            reader = index.reader();
+        }
+        assert_eq!(reader.num_segments(), 1);
+
+        // The delete that predates the re-insert is not carried over.
+        assert_eq!(reader.query(Query::All)?, &[1]);
+        assert_eq!(reader.fetch_doc(1)?.as_ref(), b"one again");
+        assert_eq!(reader.terms(Query::All)?, &["a2"]);
+        index.close(false)?;
+        Ok(())
+    }
+
+    #[test]
+    fn delete_docs() -> FstResult<()> {
+        let tmp_dir = TempDir::new("./data").unwrap();
+        let index = Index::open(Config::new(tmp_dir.path()))?;
+
+        let writer = index.writer();
+        writer.insert_doc(Document::new(1, "one", &["job:api", "env:prod"]));
+        writer.insert_doc(Document::new(2, "two", &["job:db", "env:prod"]));
+        writer.insert_doc(Document::new(3, "three", &["job:web", "env:dev"]));
+        writer.commit(true)?;
+        writer.insert_doc(Document::new(4, "four", &["job:cache", "env:dev"]));
+
+        // Committed and pending documents can be deleted.
+        writer.delete_doc(1);
+        writer.delete_doc(4);
+        writer.commit(true)?;
+
+        let reader = index.reader();
+        assert_eq!(reader.query(Query::All)?, &[2, 3]);
+        assert!(matches!(reader.fetch_doc(1), Err(FtsError::DocNotFound)));
+        assert_eq!(
+            reader.terms(Query::StartsWith("job:".to_string()))?,
+            &["job:db", "job:web"]
+        );
+
+        writer.delete_by_query(Query::Equal("env:dev".to_string()));
+        writer.commit(true)?;
+        let reader = index.reader();
+        assert_eq!(reader.query(Query::All)?, &[2]);
+        assert_eq!(reader.terms_range(None, None)?, &["env:prod", "job:db"]);
+        index.close(false)?;
+
+        // Deletions are persisted.
+        let index = Index::open(Config::new(tmp_dir.path()))?;
+        let reader = index.reader();
+        assert_eq!(reader.query(Query::All)?, &[2]);
+
+        // A deleted document can be inserted again.
+        let writer = index.writer();
+        writer.insert_doc(Document::new(1, "one again", &["job:api"]));
+        writer.commit(true)?;
+        let reader = index.reader();
+        assert_eq!(reader.query(Query::All)?, &[1, 2]);
+        assert_eq!(reader.fetch_doc(1)?.as_ref(), b"one again");
+        index.close(false)?;
+        Ok(())
+    }
+
+    #[test]
+    fn large_posting_lists() -> FstResult<()> {
+        let tmp_dir = TempDir::new("./data").unwrap();
+        let index = Index::open(Config::new(tmp_dir.path()))?;
+
+        // Posting lists spanning several blocks, with small & large gaps.
+        let doc_id = |i: u64| match i {
+            999 => u64::MAX,
+            i => i * i,
+        };
+        let writer = index.writer();
+        for i in 0..1000u64 {
+            let terms = [format!("mod3:{}", i % 3), format!("mod5:{}", i % 5)];
+            writer.insert_doc(Document::new(doc_id(i), "", &terms));
+        }
+        writer.commit(true)?;
+        index.close(false)?;
+
+        let index = Index::open(Config::new(tmp_dir.path()))?;
+        let reader = index.reader();
+        let expected = |predicate: fn(u64) -> bool| {
+            (0..1000u64)
+                .filter(|i| predicate(*i))
+                .map(doc_id)
+                .collect::<Vec<_>>()
+        };
+        let equal = |term: &str| Box::new(Query::Equal(term.to_string()));
+
+        assert_eq!(reader.query(*equal("mod3:0"))?, expected(|i| i % 3 == 0));
+        assert_eq!(
+            reader.query(Query::And(equal("mod3:0"), equal("mod5:0")))?,
+            expected(|i| i % 15 == 0)
+        );
+        assert_eq!(
+            reader.query(Query::Or(equal("mod3:1"), equal("mod5:4")))?,
+            expected(|i| i % 3 == 1 || i % 5 == 4)
+        );
+        assert_eq!(
+            reader.query(Query::AndNot(equal("mod5:0"), equal("mod3:0")))?,
+            expected(|i| i % 5 == 0 && i % 3 != 0)
+        );
+        assert_eq!(
+            reader.query(Query::And(
+                Box::new(Query::Not(equal("mod3:2"))),
+                Box::new(Query::Regex("mod5:[34]".to_string()))
+            ))?,
+            expected(|i| i % 3 != 2 && i % 5 >= 3)
+        );
+        index.close(false)?;
+        Ok(())
+    }
+
+    #[test]
+    fn fields() -> FstResult<()> {
+        let tmp_dir = TempDir::new("./data").unwrap();
+        let index = Index::open(Config::new(tmp_dir.path()))?;
+
+        let writer = index.writer();
+        writer.insert_doc(Document::with_fields(
+            1,
+            "",
+            &[("job", "api"), ("env", "prod")],
+        ));
+        writer.insert_doc(Document::with_fields(
+            2,
+            "",
+            &[("job", "db"), ("env", "dev")],
+        ));
+        writer.insert_doc(Document::with_fields(3, "", &[("job", "env:prod")]));
+        writer.insert_doc(Document::new(4, "", &["job"]));
+        writer.commit(true)?;
+
+        let reader = index.reader();
+        let field = |name: &str, query: Query| Query::Field(name.to_string(), Box::new(query));
+        assert_eq!(
+            reader.query(field("job", Query::Regex("api|db".to_string())))?,
+            &[1, 2]
+        );
+        // Matchers do not cross the field boundary.
+        assert_eq!(
+            reader.query(field("env", Query::Regex(".*prod".to_string())))?,
+            &[1]
+        );
+        assert_eq!(
+            reader.query(field("job", Query::StartsWith("env".to_string())))?,
+            &[3]
+        );
+        assert_eq!(
+            reader.query(field("job", Query::Fuzzy("ab".to_string(), 1)))?,
+            &[2]
+        );
+        assert_eq!(reader.query(Query::Exists("env".to_string()))?, &[1, 2]);
+        assert_eq!(
+            reader.query(Query::Not(Box::new(Query::Exists("job".to_string()))))?,
+            &[4]
+        );
+        assert_eq!(
+            reader.query(field("job", Query::NotEqual("api".to_string())))?,
+            &[2, 3, 4]
+        );
+        assert_eq!(
+            reader.query(field(
+                "job",
+                Query::Or(
+                    Box::new(Query::Equal("api".to_string())),
+                    Box::new(Query::Equal("db".to_string()))
+                )
+            ))?,
+            &[1, 2]
+        );
+
+        assert_eq!(
+            reader.field_values("job", Query::All)?,
+            &["api", "db", "env:prod"]
+        );
+        assert_eq!(
+            reader.field_values("job", Query::NotEqual("db".to_string()))?,
+            &["api", "env:prod"]
+        );
+        assert_eq!(reader.field_values("env", Query::All)?, &["dev", "prod"]);
+        assert!(reader.field_values("missing", Query::All)?.is_empty());
+        // Plain terms are not fields.
+        assert_eq!(reader.fields()?, &["env", "job"]);
+        index.close(false)?;
+        Ok(())
+    }
 }
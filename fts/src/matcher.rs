@@ -3,6 +3,8 @@
 pub(crate) struct Matcher<'a> {
     pub term_matcher: TermMatcher<'a>,
     pub complement: bool,
+    /// Restricts the matching to the values of this field.
+    pub field: Option<&'a str>,
 }
 
 impl<'a> Matcher<'a> {
@@ -10,6 +12,7 @@ impl<'a> Matcher<'a> {
         Self {
             term_matcher: TermMatcher::All,
             complement,
+            field: None,
         }
     }
 
@@ -17,6 +20,7 @@ impl<'a> Matcher<'a> {
         Self {
             term_matcher: TermMatcher::Equal(term),
             complement,
+            field: None,
         }
     }
 
@@ -24,6 +28,7 @@ impl<'a> Matcher<'a> {
         Self {
             term_matcher: TermMatcher::StartsWith(term),
             complement,
+            field: None,
         }
     }
 
@@ -31,6 +36,7 @@ impl<'a> Matcher<'a> {
         Self {
             term_matcher: TermMatcher::Fuzzy(term, distance),
             complement,
+            field: None,
         }
     }
 
@@ -38,6 +44,14 @@ impl<'a> Matcher<'a> {
         Self {
             term_matcher: TermMatcher::Regex(pattern),
             complement,
+            field: None,
+        }
+    }
+
+    pub fn with_field(self, field: &'a str) -> Self {
+        Self {
+            field: Some(field),
+            ..self
         }
     }
 }
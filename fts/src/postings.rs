@@ -1,7 +1,7 @@
 use std::{
     fs::{File, OpenOptions},
     io::{self, Write},
-    path::PathBuf,
+    path::Path,
 };
 
 use hashbrown::HashMap;
@@ -9,16 +9,28 @@ use memmap2::Mmap;
 
 use fst::{
     automaton::{AlwaysMatch, Levenshtein, Str},
-    Automaton, IntoStreamer, Map, MapBuilder,
+    map::OpBuilder,
+    Automaton, IntoStreamer, Map, MapBuilder, Streamer,
 };
 
 use crate::{
+    docset::{DocSet, VecDocSet},
     error::{FstResult, FtsError},
+    field_term,
     matcher::{Matcher, TermMatcher},
     segment_component_file, DocId, SegmentComponent,
 };
 
 const U64_NUM_BYTE: usize = 8;
+const U32_NUM_BYTE: usize = 4;
+
+/// Marks a posting list file using the block format, files
+/// without it use the legacy bincode format.
+const BLOCK_FORMAT_MAGIC: &[u8; 8] = b"FTSPBLK1";
+/// Number of doc ids per bit-packed block.
+const BLOCK_SIZE: usize = 128;
+/// Size of a skip entry: last doc id of the block & block offset.
+const SKIP_ENTRY_NUM_BYTE: usize = U64_NUM_BYTE + U32_NUM_BYTE;
 
 /// A postings or inverted index to map term -> sorted_vec<doc_id>.
 /// It has two stored components of files (.term, .post)
@@ -30,28 +42,106 @@ pub(crate) struct Postings {
     // file_name: PathBuf,
     term_dictionary: Map<Mmap>,
 
-    /// Posting List Format: sorted list of doc_id
+    /// Posting List Format: sorted list of doc_id, split in blocks of
+    /// `BLOCK_SIZE` delta-encoded & bit-packed doc ids. The skip entries
+    /// hold the last doc id of each block and its offset from the first block,
+    /// so that a block can be reached without decoding the previous ones.
+    /// ┌──────────┬────────────┬──────────────────┬─────┬──────────────────┬─────────┬─────┬─────────┐
+    /// │ num_docs │ num_blocks │ last_doc, offset │ ... │ last_doc, offset │ block 0 │ ... │ block N │
+    /// └──────────┴────────────┴──────────────────┴─────┴──────────────────┴─────────┴─────┴─────────┘
+    /// Block Format: deltas from the previous doc id (or previous block last doc id)
+    /// ┌───────────┬───────────────────────────────┐
+    /// │ bit_width │ bit-packed deltas (LSB first) │
+    /// └───────────┴───────────────────────────────┘
+    ///
+    posting_list: Mmap,
+
+    /// Legacy Posting List Format: bincode serialized list of doc_id
     /// ┌────────────┬─────┬────┬────┬────┬─────────┐
     /// │# data-size │ ... │ .. │ .. │ .. │  item N │
     /// └────────────┴─────┴────┴────┴────┴─────────┘
     ///
-    posting_list: Mmap,
+    legacy_format: bool,
 }
 
 impl Postings {
     pub fn new(
-        index_directory: &PathBuf,
+        index_directory: &Path,
         segment_id: &str,
         mut term_dictionary: HashMap<String, Vec<DocId>>,
     ) -> FstResult<Self> {
+        // sort the terms as fst only accepts in-order insertion
+        let mut terms: Vec<(&[u8], &[DocId])> = term_dictionary
+            .iter_mut()
+            .map(|(term, list)| {
+                list.sort();
+                (term.as_bytes(), list.as_slice())
+            })
+            .collect();
+        terms.sort_unstable_by_key(|(term, _)| *term);
+
+        Self::write(index_directory, segment_id, |postings_builder| {
+            for (term, list) in terms {
+                postings_builder.insert(term, list)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Merges the postings of several segments into a new one.
+    /// The term dictionaries are unioned and the posting lists of
+    /// a term are merged, keeping only the doc ids accepted by `keep`
+    /// which receives the position of the source and the doc id.
+    pub fn merge<F>(
+        index_directory: &Path,
+        segment_id: &str,
+        sources: &[&Postings],
+        keep: F,
+    ) -> FstResult<Self>
+    where
+        F: Fn(usize, DocId) -> bool,
+    {
+        let mut op_builder = OpBuilder::new();
+        for source in sources {
+            op_builder = op_builder.add(&source.term_dictionary);
+        }
+
+        Self::write(index_directory, segment_id, |postings_builder| {
+            let mut union_stream = op_builder.union();
+            while let Some((term, indexed_values)) = union_stream.next() {
+                let mut doc_ids = vec![];
+                for indexed_value in indexed_values {
+                    let source = sources[indexed_value.index];
+                    let mut doc_set = source.doc_set(indexed_value.value as usize)?;
+                    while let Some(doc_id) = doc_set.next() {
+                        if keep(indexed_value.index, doc_id) {
+                            doc_ids.push(doc_id);
+                        }
+                    }
+                }
+                if doc_ids.is_empty() {
+                    continue;
+                }
+                doc_ids.sort_unstable();
+                doc_ids.dedup();
+                postings_builder.insert(term, &doc_ids)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write<F>(index_directory: &Path, segment_id: &str, fill: F) -> FstResult<Self>
+    where
+        F: FnOnce(&mut PostingsBuilder<io::BufWriter<&File>>) -> FstResult<()>,
+    {
         let posting_list_file_name =
             segment_component_file(index_directory, segment_id, SegmentComponent::PostingList);
         let posting_list_file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(posting_list_file_name)?;
-        let mut posting_list_writer = io::BufWriter::new(&posting_list_file);
 
         let term_dictionary_file_name = segment_component_file(
             index_directory,
@@ -62,31 +152,20 @@ impl Postings {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(term_dictionary_file_name)?;
-        let term_dictionary_writer = io::BufWriter::new(&term_dictionary_file);
-        // Create the fst builder to insert new term->posting_offset pairs.
-        let mut term_dictionary_builder = MapBuilder::new(term_dictionary_writer)?;
-        // sort the terms as fst only accepts in-order insertion
-        let mut terms: Vec<(&[u8], &[DocId])> = term_dictionary
-            .iter_mut()
-            .map(|(term, list)| {
-                list.sort();
-                (term.as_bytes(), list.as_slice())
-            })
-            .collect();
-        terms.sort_unstable_by_key(|(term, _)| *term);
 
-        let mut offset = 0;
-        for (term, list) in terms {
-            term_dictionary_builder.insert(term, offset)?;
-            let posting_list_bytes = bincode::serialize(&list)?;
-            let posting_list_bytes_size = posting_list_bytes.len() as u64;
-            posting_list_writer.write_all(&posting_list_bytes_size.to_le_bytes())?;
-            posting_list_writer.write_all(&posting_list_bytes)?;
-            offset += (U64_NUM_BYTE + posting_list_bytes.len()) as u64;
-        }
-        posting_list_writer.flush()?;
-        term_dictionary_builder.finish()?;
+        let mut posting_list_writer = io::BufWriter::new(&posting_list_file);
+        posting_list_writer.write_all(BLOCK_FORMAT_MAGIC)?;
+        let mut postings_builder = PostingsBuilder {
+            // Create the fst builder to insert new term->posting_offset pairs.
+            term_dictionary_builder: MapBuilder::new(io::BufWriter::new(&term_dictionary_file))?,
+            posting_list_writer,
+            offset: BLOCK_FORMAT_MAGIC.len() as u64,
+            buffer: vec![],
+        };
+        fill(&mut postings_builder)?;
+        postings_builder.finish()?;
 
         let term_dictionary_mmap_file = unsafe { Mmap::map(&term_dictionary_file)? };
         let term_dictionary = Map::new(term_dictionary_mmap_file)?;
@@ -94,10 +173,11 @@ impl Postings {
         Ok(Postings {
             term_dictionary,
             posting_list: posting_list_mmap_file,
+            legacy_format: false,
         })
     }
 
-    pub fn open(index_directory: &PathBuf, segment_id: &str) -> FstResult<Self> {
+    pub fn open(index_directory: &Path, segment_id: &str) -> FstResult<Self> {
         let posting_list_file_name =
             segment_component_file(index_directory, segment_id, SegmentComponent::PostingList);
         let posting_list = unsafe { Mmap::map(&File::open(posting_list_file_name)?)? };
@@ -111,9 +191,11 @@ impl Postings {
             unsafe { Mmap::map(&File::open(term_dictionary_file_name)?)? };
         let term_dictionary = Map::new(term_dictionary_mmap_file)?;
 
+        let legacy_format = !posting_list.starts_with(BLOCK_FORMAT_MAGIC);
         Ok(Postings {
             term_dictionary,
             posting_list,
+            legacy_format,
         })
     }
 
@@ -128,26 +210,37 @@ impl Postings {
     }
 
     pub fn search(&self, matcher: Matcher) -> FstResult<Vec<(String, u64)>> {
-        let complement = matcher.complement;
+        let (field, complement) = (matcher.field, matcher.complement);
         match matcher.term_matcher {
-            TermMatcher::All => self.search_automaton(AlwaysMatch, complement),
-            TermMatcher::Equal(term) => self.search_automaton(Str::new(term), complement),
+            TermMatcher::All => self.search_automaton(AlwaysMatch, field, complement),
+            TermMatcher::Equal(term) => self.search_automaton(Str::new(term), field, complement),
             TermMatcher::StartsWith(term) => {
-                self.search_automaton(Str::new(term).starts_with(), complement)
+                self.search_automaton(Str::new(term).starts_with(), field, complement)
             }
             TermMatcher::Fuzzy(term, dist) => {
-                self.search_automaton(Levenshtein::new(term, dist)?, complement)
+                self.search_automaton(Levenshtein::new(term, dist)?, field, complement)
             }
             TermMatcher::Regex(pattern) => {
+                // Leftmost-longest semantics so that alternations keep
+                // matching past their first branch, e.g `a|ab` on `ab`.
                 let dfa = regex_automata::dense::Builder::new()
                     .anchored(true)
+                    .longest_match(true)
                     .build(pattern)?;
-                self.search_automaton(dfa, complement)
+                self.search_automaton(dfa, field, complement)
             }
         }
     }
 
-    pub fn postings(&self, offset: usize) -> FstResult<Vec<DocId>> {
+    /// Returns an iterator over the posting list at `offset`,
+    /// decoding a single block at a time.
+    pub fn doc_set(&self, offset: usize) -> FstResult<Box<dyn DocSet + '_>> {
+        if !self.legacy_format {
+            return Ok(Box::new(PostingsIterator::new(
+                &self.posting_list[offset..],
+            )));
+        }
+
         let mut data_size_bytes = [0u8; U64_NUM_BYTE];
         data_size_bytes.clone_from_slice(&self.posting_list[offset..offset + U64_NUM_BYTE]);
         let data_size = u64::from_le_bytes(data_size_bytes) as usize;
@@ -155,23 +248,25 @@ impl Postings {
         let posting_list_bytes =
             &self.posting_list[offset + U64_NUM_BYTE..offset + U64_NUM_BYTE + data_size];
         let posting_list = bincode::deserialize::<Vec<u64>>(posting_list_bytes)?;
-        Ok(posting_list)
+        Ok(Box::new(VecDocSet::new(posting_list)))
     }
 
     fn search_automaton<A: Automaton>(
         &self,
         aut: A,
+        field: Option<&str>,
         complement: bool,
     ) -> FstResult<Vec<(String, u64)>> {
-        if complement {
-            return self
-                .term_dictionary
-                .search(aut.complement())
-                .into_stream()
-                .into_str_vec()
-                .map_err(FtsError::from);
+        // The complement of a field scoped matcher stays within the field.
+        match (field, complement) {
+            (Some(field), true) => self.stream_terms(FieldAutomaton::new(field, aut.complement())),
+            (Some(field), false) => self.stream_terms(FieldAutomaton::new(field, aut)),
+            (None, true) => self.stream_terms(aut.complement()),
+            (None, false) => self.stream_terms(aut),
         }
+    }
 
+    fn stream_terms<A: Automaton>(&self, aut: A) -> FstResult<Vec<(String, u64)>> {
         self.term_dictionary
             .search(aut)
             .into_stream()
@@ -179,3 +274,275 @@ impl Postings {
             .map_err(FtsError::from)
     }
 }
+
+/// Matches the terms of a field, running the inner automaton on their value.
+struct FieldAutomaton<A> {
+    prefix: Vec<u8>,
+    inner: A,
+}
+
+impl<A: Automaton> FieldAutomaton<A> {
+    fn new(field: &str, inner: A) -> Self {
+        Self {
+            prefix: field_term(field, "").into_bytes(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum FieldState<S> {
+    /// Number of matched bytes of the field prefix.
+    Prefix(usize),
+    Value(S),
+    Dead,
+}
+
+impl<A: Automaton> Automaton for FieldAutomaton<A> {
+    type State = FieldState<A::State>;
+
+    fn start(&self) -> Self::State {
+        FieldState::Prefix(0)
+    }
+
+    fn is_match(&self, state: &Self::State) -> bool {
+        match state {
+            FieldState::Value(inner_state) => self.inner.is_match(inner_state),
+            _ => false,
+        }
+    }
+
+    fn can_match(&self, state: &Self::State) -> bool {
+        match state {
+            FieldState::Prefix(_) => true,
+            FieldState::Value(inner_state) => self.inner.can_match(inner_state),
+            FieldState::Dead => false,
+        }
+    }
+
+    fn will_always_match(&self, state: &Self::State) -> bool {
+        match state {
+            FieldState::Value(inner_state) => self.inner.will_always_match(inner_state),
+            _ => false,
+        }
+    }
+
+    fn accept(&self, state: &Self::State, byte: u8) -> Self::State {
+        match state {
+            FieldState::Prefix(len) if self.prefix[*len] != byte => FieldState::Dead,
+            FieldState::Prefix(len) if *len + 1 == self.prefix.len() => {
+                FieldState::Value(self.inner.start())
+            }
+            FieldState::Prefix(len) => FieldState::Prefix(len + 1),
+            FieldState::Value(inner_state) => {
+                FieldState::Value(self.inner.accept(inner_state, byte))
+            }
+            FieldState::Dead => FieldState::Dead,
+        }
+    }
+}
+
+/// Writes terms and their posting lists, terms must be inserted in order.
+pub(crate) struct PostingsBuilder<W: Write> {
+    term_dictionary_builder: MapBuilder<W>,
+    posting_list_writer: W,
+    offset: u64,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> PostingsBuilder<W> {
+    /// Inserts a term with its sorted & deduplicated doc ids.
+    pub fn insert(&mut self, term: &[u8], doc_ids: &[DocId]) -> FstResult<()> {
+        self.term_dictionary_builder.insert(term, self.offset)?;
+        self.buffer.clear();
+        encode_posting_list(doc_ids, &mut self.buffer);
+        self.posting_list_writer.write_all(&self.buffer)?;
+        self.offset += self.buffer.len() as u64;
+        Ok(())
+    }
+
+    fn finish(mut self) -> FstResult<()> {
+        self.posting_list_writer.flush()?;
+        self.term_dictionary_builder.finish()?;
+        Ok(())
+    }
+}
+
+/// Encodes sorted doc ids in the block format.
+fn encode_posting_list(doc_ids: &[DocId], output: &mut Vec<u8>) {
+    let num_blocks = doc_ids.len().div_ceil(BLOCK_SIZE);
+    output.extend_from_slice(&(doc_ids.len() as u32).to_le_bytes());
+    output.extend_from_slice(&(num_blocks as u32).to_le_bytes());
+
+    let mut blocks = vec![];
+    let mut previous_doc_id = 0;
+    let mut deltas = Vec::with_capacity(BLOCK_SIZE);
+    for block in doc_ids.chunks(BLOCK_SIZE) {
+        output.extend_from_slice(&block[block.len() - 1].to_le_bytes());
+        output.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+
+        deltas.clear();
+        for doc_id in block {
+            deltas.push(doc_id - previous_doc_id);
+            previous_doc_id = *doc_id;
+        }
+        let bit_width = deltas
+            .iter()
+            .map(|delta| u64::BITS - delta.leading_zeros())
+            .max()
+            .unwrap_or(0) as u8;
+        blocks.push(bit_width);
+        bit_pack(&deltas, bit_width, &mut blocks);
+    }
+    output.extend_from_slice(&blocks);
+}
+
+fn bit_pack(values: &[u64], bit_width: u8, output: &mut Vec<u8>) {
+    let mut accumulator = 0u128;
+    let mut num_bits = 0u32;
+    for value in values {
+        accumulator |= (*value as u128) << num_bits;
+        num_bits += bit_width as u32;
+        while num_bits >= 8 {
+            output.push(accumulator as u8);
+            accumulator >>= 8;
+            num_bits -= 8;
+        }
+    }
+    if num_bits > 0 {
+        output.push(accumulator as u8);
+    }
+}
+
+fn bit_unpack(data: &[u8], bit_width: u8, output: &mut [u64]) {
+    let mask = if bit_width == 64 {
+        u64::MAX
+    } else {
+        (1u64 << bit_width) - 1
+    };
+    let mut bytes = data.iter();
+    let mut accumulator = 0u128;
+    let mut num_bits = 0u32;
+    for value in output.iter_mut() {
+        while num_bits < bit_width as u32 {
+            accumulator |= (*bytes.next().unwrap() as u128) << num_bits;
+            num_bits += 8;
+        }
+        *value = (accumulator as u64) & mask;
+        accumulator >>= bit_width;
+        num_bits -= bit_width as u32;
+    }
+}
+
+/// Iterates a block formatted posting list, decoding one block at a time
+/// and using the skip entries to jump over blocks when seeking.
+struct PostingsIterator<'a> {
+    num_docs: usize,
+    num_blocks: usize,
+    skip_entries: &'a [u8],
+    blocks: &'a [u8],
+    /// Index of the next block to decode.
+    next_block_index: usize,
+    block: [DocId; BLOCK_SIZE],
+    block_len: usize,
+    position: usize,
+}
+
+impl<'a> PostingsIterator<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        let num_docs = read_u32(data, 0) as usize;
+        let num_blocks = read_u32(data, U32_NUM_BYTE) as usize;
+        let skip_entries_end = 2 * U32_NUM_BYTE + num_blocks * SKIP_ENTRY_NUM_BYTE;
+        Self {
+            num_docs,
+            num_blocks,
+            skip_entries: &data[2 * U32_NUM_BYTE..skip_entries_end],
+            blocks: &data[skip_entries_end..],
+            next_block_index: 0,
+            block: [0; BLOCK_SIZE],
+            block_len: 0,
+            position: 0,
+        }
+    }
+
+    fn last_doc_id(&self, block_index: usize) -> DocId {
+        let entry_offset = block_index * SKIP_ENTRY_NUM_BYTE;
+        u64::from_le_bytes(
+            self.skip_entries[entry_offset..entry_offset + U64_NUM_BYTE]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    fn block_offset(&self, block_index: usize) -> usize {
+        read_u32(
+            self.skip_entries,
+            block_index * SKIP_ENTRY_NUM_BYTE + U64_NUM_BYTE,
+        ) as usize
+    }
+
+    /// Decodes the next block, returns false when there are no more blocks.
+    fn load_next_block(&mut self) -> bool {
+        let block_index = self.next_block_index;
+        if block_index >= self.num_blocks {
+            return false;
+        }
+
+        let offset = self.block_offset(block_index);
+        let bit_width = self.blocks[offset];
+        self.block_len = BLOCK_SIZE.min(self.num_docs - block_index * BLOCK_SIZE);
+        bit_unpack(
+            &self.blocks[offset + 1..],
+            bit_width,
+            &mut self.block[..self.block_len],
+        );
+
+        let mut doc_id = match block_index {
+            0 => 0,
+            _ => self.last_doc_id(block_index - 1),
+        };
+        for value in self.block[..self.block_len].iter_mut() {
+            doc_id += *value;
+            *value = doc_id;
+        }
+        self.next_block_index += 1;
+        self.position = 0;
+        true
+    }
+}
+
+impl<'a> DocSet for PostingsIterator<'a> {
+    fn next(&mut self) -> Option<DocId> {
+        if self.position == self.block_len && !self.load_next_block() {
+            return None;
+        }
+        let doc_id = self.block[self.position];
+        self.position += 1;
+        Some(doc_id)
+    }
+
+    fn seek(&mut self, target: DocId) -> Option<DocId> {
+        // Skip the blocks ending before the target without decoding them.
+        let current_block_done = self.position == self.block_len
+            || (self.block_len > 0 && self.block[self.block_len - 1] < target);
+        if current_block_done {
+            while self.next_block_index < self.num_blocks
+                && self.last_doc_id(self.next_block_index) < target
+            {
+                self.next_block_index += 1;
+            }
+            if !self.load_next_block() {
+                self.position = self.block_len;
+                return None;
+            }
+        }
+
+        let remaining = &self.block[self.position..self.block_len];
+        self.position += remaining.partition_point(|doc_id| *doc_id < target);
+        self.next()
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + U32_NUM_BYTE].try_into().unwrap())
+}
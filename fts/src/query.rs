@@ -3,7 +3,13 @@ use crate::{
     matcher::Matcher,
 };
 
-#[derive(Debug)]
+/// A query over the terms of the index.
+///
+/// Negated variants (`NotEqual`, `NotRegex`, ...) are evaluated at the
+/// document level when searching, i.e `NotEqual(t)` is the same as
+/// `Not(Equal(t))`. When listing terms they select the terms that
+/// do not match instead.
+#[derive(Debug, Clone, PartialEq)]
 pub enum Query {
     All,
     Equal(String),
@@ -16,23 +22,53 @@ pub enum Query {
     NotRegex(String),
     Or(Box<Query>, Box<Query>),
     And(Box<Query>, Box<Query>),
+    /// Documents matching the left query but not the right one.
+    AndNot(Box<Query>, Box<Query>),
+    /// Documents not matching the query.
+    Not(Box<Query>),
+    /// Scopes the query to the values of a field, e.g
+    /// `Field("job", Regex("api|db"))` only matches the values of `job`.
+    Field(String, Box<Query>),
+    /// Documents having any value for the field.
+    Exists(String),
 }
 
 impl Query {
-    pub(crate) fn matcher(&self) -> FstResult<Matcher> {
+    pub(crate) fn matcher(&self) -> FstResult<Matcher<'_>> {
         match self {
             Query::All => Ok(Matcher::all(false)),
-            Query::Equal(term) => Ok(Matcher::equal(&term, false)),
-            Query::NotEqual(term) => Ok(Matcher::equal(&term, true)),
+            Query::Equal(term) => Ok(Matcher::equal(term, false)),
+            Query::NotEqual(term) => Ok(Matcher::equal(term, true)),
             Query::StartsWith(term) => Ok(Matcher::starts_with(term, false)),
             Query::NotStartsWith(term) => Ok(Matcher::starts_with(term, true)),
             Query::Fuzzy(term, distance) => Ok(Matcher::fuzzy(term, *distance, false)),
             Query::NotFuzzy(term, distance) => Ok(Matcher::fuzzy(term, *distance, true)),
             Query::Regex(pattern) => Ok(Matcher::regex(pattern, false)),
             Query::NotRegex(pattern) => Ok(Matcher::regex(pattern, true)),
+            Query::Exists(field) => Ok(Matcher::all(false).with_field(field)),
+            Query::Field(field, query) => {
+                let matcher = query.matcher()?;
+                if matcher.field.is_some() {
+                    return Err(FtsError::QueryNotSupported);
+                }
+                Ok(matcher.with_field(field))
+            }
             _ => Err(FtsError::QueryNotSupported),
         }
     }
+
+    /// Pushes a field scope down to the term queries of `query`,
+    /// so that compound queries can be evaluated on a single field.
+    pub(crate) fn scoped_to_field(field: &str, query: &Query) -> Query {
+        let scope = |query: &Query| Box::new(Self::scoped_to_field(field, query));
+        match query {
+            Query::Or(left, right) => Query::Or(scope(left), scope(right)),
+            Query::And(left, right) => Query::And(scope(left), scope(right)),
+            Query::AndNot(left, right) => Query::AndNot(scope(left), scope(right)),
+            Query::Not(query) => Query::Not(scope(query)),
+            query => Query::Field(field.to_string(), Box::new(query.clone())),
+        }
+    }
 }
 
 // TODO: add query builder
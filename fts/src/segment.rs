@@ -1,6 +1,6 @@
 use std::{
     fs,
-    path::PathBuf,
+    path::Path,
     sync::{Arc, RwLock},
     thread::{self, JoinHandle},
 };
@@ -11,9 +11,18 @@ use hashbrown::{HashMap, HashSet};
 use ulid::Ulid;
 
 use crate::{
-    doc_store::DocStore, error::FstResult, postings::Postings, query::Query, DocId, Document,
+    deletes::DeleteBitmap,
+    doc_store::DocStore,
+    docset::{DifferenceDocSet, DocSet, IntersectionDocSet, UnionDocSet, VecDocSet},
+    error::{FstResult, FtsError},
+    postings::Postings,
+    query::Query,
+    segment_component_file, Config, DocId, Document, SegmentComponent,
 };
 
+/// Suffix of the temporary directory a merged segment is written to.
+pub(crate) const MERGING_SEGMENT_SUFFIX: &str = ".merging";
+
 pub struct SegmentInfo {
     pub id: String,
     pub num_docs: usize,
@@ -47,10 +56,28 @@ impl WritableSegment {
         }
     }
 
+    /// Removes documents that are not committed yet.
+    pub fn delete(&mut self, doc_ids: &[DocId]) {
+        let mut deleted_doc_ids = HashSet::new();
+        for doc_id in doc_ids {
+            if let Some(content) = self.documents.remove(doc_id) {
+                self.num_bytes -= content.len();
+                deleted_doc_ids.insert(*doc_id);
+            }
+        }
+        if deleted_doc_ids.is_empty() {
+            return;
+        }
+        self.terms.retain(|_, posting_list| {
+            posting_list.retain(|doc_id| !deleted_doc_ids.contains(doc_id));
+            !posting_list.is_empty()
+        });
+    }
+
     /// This operation can be expensive. We might need to run
     /// this in another thread to avoid blocking document
     /// ingestion.
-    pub fn into_segment(self, index_directory: &PathBuf) -> FstResult<Segment> {
+    pub fn into_segment(self, index_directory: &Path) -> FstResult<Segment> {
         //TODO: sort and remove duplicate in posting_list `self.terms`
         let segment_directory = index_directory.join(&self.id);
         if !segment_directory.exists() {
@@ -58,10 +85,13 @@ impl WritableSegment {
         }
         let postings = Postings::new(index_directory, &self.id, self.terms)?;
         let store = DocStore::new(index_directory, &self.id, self.documents)?;
+        let deletes = RwLock::new(DeleteBitmap::new(store.num_docs()));
         Ok(Segment {
             id: self.id,
             postings,
             store,
+            deletes,
+            merged_from: vec![],
         })
     }
 
@@ -69,6 +99,7 @@ impl WritableSegment {
         self.documents.len()
     }
 
+    #[allow(dead_code)]
     pub fn info(&self) -> SegmentInfo {
         SegmentInfo {
             id: self.id.clone(),
@@ -84,6 +115,10 @@ pub(crate) struct Segment {
     id: String,
     postings: Postings,
     store: DocStore,
+    /// Deleted documents of this segment.
+    deletes: RwLock<DeleteBitmap>,
+    /// Ids of the segments this segment replaces, if it is the result of a merge.
+    merged_from: Vec<String>,
 }
 
 impl Segment {
@@ -96,19 +131,141 @@ impl Segment {
         }
     }
 
-    pub fn open(index_directory: &PathBuf, segment_id: &str) -> FstResult<Self> {
+    pub fn open(index_directory: &Path, segment_id: &str) -> FstResult<Self> {
         let postings = Postings::open(index_directory, segment_id)?;
         let store = DocStore::open(index_directory, segment_id)?;
+        let deletes = DeleteBitmap::open(index_directory, segment_id, store.num_docs())?;
+        let merged_from = read_merged_from(index_directory, segment_id)?;
         Ok(Self {
             id: segment_id.to_string(),
             postings,
             store,
+            deletes: RwLock::new(deletes),
+            merged_from,
         })
     }
 
+    /// Merges segments, ordered from oldest to newest, into a new segment.
+    /// When a document exists in several segments, only its newest version
+    /// is kept and deleted documents are purged. The merged segment is written in a temporary directory that
+    /// is renamed once complete, so a crash never leaves a partial segment.
+    pub fn merge(
+        index_directory: &Path,
+        segment_id: &str,
+        segments: &[Arc<Segment>],
+    ) -> FstResult<Self> {
+        // Position of the segment holding the newest version of each doc.
+        let mut doc_owners: HashMap<DocId, usize> = HashMap::new();
+        for (position, segment) in segments.iter().enumerate() {
+            for doc_id in segment.live_doc_ids() {
+                doc_owners.insert(doc_id, position);
+            }
+        }
+
+        let tmp_segment_id = format!("{}{}", segment_id, MERGING_SEGMENT_SUFFIX);
+        let tmp_segment_directory = index_directory.join(&tmp_segment_id);
+        if tmp_segment_directory.exists() {
+            fs::remove_dir_all(&tmp_segment_directory)?;
+        }
+        fs::create_dir(&tmp_segment_directory)?;
+
+        let sources: Vec<&Postings> = segments.iter().map(|segment| &segment.postings).collect();
+        Postings::merge(
+            index_directory,
+            &tmp_segment_id,
+            &sources,
+            |position, doc_id| doc_owners.get(&doc_id) == Some(&position),
+        )?;
+
+        let mut documents = HashMap::with_capacity(doc_owners.len());
+        for (doc_id, position) in doc_owners {
+            if let Some(content) = segments[position].store.fetch_doc(doc_id)? {
+                documents.insert(doc_id, content);
+            }
+        }
+        DocStore::new(index_directory, &tmp_segment_id, documents)?;
+
+        let merged_from: Vec<String> = segments.iter().map(|s| s.id.clone()).collect();
+        let metadata_file_name =
+            segment_component_file(index_directory, &tmp_segment_id, SegmentComponent::Metadata);
+        fs::write(metadata_file_name, merged_from.join("\n"))?;
+
+        fs::rename(tmp_segment_directory, index_directory.join(segment_id))?;
+        Segment::open(index_directory, segment_id)
+    }
+
     pub fn get_id(&self) -> &str {
         &self.id
     }
+
+    pub fn num_docs(&self) -> usize {
+        self.store.num_docs()
+    }
+
+    /// Marks documents of this segment as deleted and persists the deletions.
+    pub fn delete(&self, index_directory: &Path, doc_ids: &[DocId]) -> FstResult<()> {
+        let mut deletes = self.deletes.write().unwrap();
+        let mut modified = false;
+        for doc_id in doc_ids {
+            if let Some(ordinal) = self.store.ordinal(*doc_id) {
+                modified |= deletes.insert(ordinal);
+            }
+        }
+        if modified {
+            deletes.persist(index_directory, &self.id)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_deleted(&self, doc_id: DocId) -> bool {
+        let deletes = self.deletes.read().unwrap();
+        self.store
+            .ordinal(doc_id)
+            .is_some_and(|ordinal| deletes.contains(ordinal))
+    }
+
+    fn has_deletes(&self) -> bool {
+        !self.deletes.read().unwrap().is_empty()
+    }
+
+    /// Returns the sorted ids of the documents that are not deleted.
+    pub fn live_doc_ids(&self) -> Vec<DocId> {
+        let deletes = self.deletes.read().unwrap();
+        self.store
+            .doc_ids()
+            .iter()
+            .enumerate()
+            .filter(|(ordinal, _)| !deletes.contains(*ordinal))
+            .map(|(_, doc_id)| *doc_id)
+            .collect()
+    }
+
+    /// Returns the sorted ids of the deleted documents.
+    pub fn deleted_doc_ids(&self) -> Vec<DocId> {
+        let deletes = self.deletes.read().unwrap();
+        self.store
+            .doc_ids()
+            .iter()
+            .enumerate()
+            .filter(|(ordinal, _)| deletes.contains(*ordinal))
+            .map(|(_, doc_id)| *doc_id)
+            .collect()
+    }
+
+    /// Ids of the segments this segment was merged from.
+    pub fn merged_from(&self) -> &[String] {
+        &self.merged_from
+    }
+}
+
+fn read_merged_from(index_directory: &Path, segment_id: &str) -> FstResult<Vec<String>> {
+    let metadata_file_name =
+        segment_component_file(index_directory, segment_id, SegmentComponent::Metadata);
+    if !metadata_file_name.exists() {
+        return Ok(vec![]);
+    }
+    let content = fs::read_to_string(metadata_file_name)?;
+    Ok(content.lines().map(|line| line.to_string()).collect())
 }
 
 #[derive(Debug, Clone)]
@@ -123,37 +280,64 @@ impl SegmentReader {
 
     /// List all the terms matching within a term range.
     pub fn list_terms_in_range(&self, from: &str, to: &str) -> FstResult<Vec<String>> {
-        self.segment
-            .postings
-            .range(from, to)
-            .map(|postings_info| postings_info.into_iter().map(|(term, _)| term).collect())
+        let posting_info = self.segment.postings.range(from, to)?;
+        self.live_terms(posting_info)
     }
 
     /// Search and returns matching doc_ids within a term range.
     pub fn search_in_range(&self, from: &str, to: &str) -> FstResult<Vec<DocId>> {
         let posting_info = self.segment.postings.range(from, to)?;
-        fetch_doc_ids(&self.segment.postings, posting_info)
+        let doc_set = fetch_doc_set(&self.segment.postings, posting_info)?;
+        Ok(self.remove_deleted(doc_set.collect_vec()))
     }
 
     /// List all the terms matching a query.
     pub fn list_terms(&self, query: &Query) -> FstResult<Vec<String>> {
         let matcher = query.matcher()?;
-        self.segment
-            .postings
-            .search(matcher)
-            .map(|postings_info| postings_info.into_iter().map(|(term, _)| term).collect())
+        let posting_info = self.segment.postings.search(matcher)?;
+        self.live_terms(posting_info)
     }
 
     /// Search and returns doc_ids matching a query.
     pub fn search(&self, query: &Query) -> FstResult<Vec<DocId>> {
-        evaluate_query(&self.segment.postings, query)
+        let doc_set = evaluate_query(&self.segment, query)?;
+        Ok(self.remove_deleted(doc_set.collect_vec()))
     }
 
     /// Get the content of a document with the provided DocId.
     pub fn fetch_doc(&self, id: DocId) -> FstResult<Option<Bytes>> {
+        if self.segment.is_deleted(id) {
+            return Ok(None);
+        }
         self.segment.store.fetch_doc(id)
     }
 
+    /// Keeps the terms that still have a document that is not deleted.
+    fn live_terms(&self, posting_info: Vec<(String, u64)>) -> FstResult<Vec<String>> {
+        if !self.segment.has_deletes() {
+            return Ok(posting_info.into_iter().map(|(term, _)| term).collect());
+        }
+
+        let mut terms = vec![];
+        for (term, offset) in posting_info {
+            let mut doc_set = self.segment.postings.doc_set(offset as usize)?;
+            while let Some(doc_id) = doc_set.next() {
+                if !self.segment.is_deleted(doc_id) {
+                    terms.push(term);
+                    break;
+                }
+            }
+        }
+        Ok(terms)
+    }
+
+    fn remove_deleted(&self, mut doc_ids: Vec<DocId>) -> Vec<DocId> {
+        if self.segment.has_deletes() {
+            doc_ids.retain(|doc_id| !self.segment.is_deleted(*doc_id));
+        }
+        doc_ids
+    }
+
     // fn fetch_doc_ids(&self, posting_info: Vec<(String, u64)>) -> FstResult<Vec<DocId>> {
     //     let mut doc_ids = HashSet::new();
     //     for (_, offset) in posting_info {
@@ -168,31 +352,68 @@ impl SegmentReader {
 
 pub(crate) type CommitReplySender = oneshot::Sender<FstResult<()>>;
 
+/// Documents to delete from the committed segments.
+pub(crate) enum Deletion {
+    Docs(Vec<DocId>),
+    Query(Query),
+}
+
+enum FinalizerOp {
+    Finalize(WritableSegment, Option<CommitReplySender>),
+    Delete(Deletion),
+}
+
+/// Persists committed segments and applies deletions to them.
+/// Both go through the same queue so that a deletion only applies
+/// to documents committed before it.
 pub(crate) struct SegmentFinalizer {
-    segment_sender: Sender<(WritableSegment, Option<CommitReplySender>)>,
+    op_sender: Sender<FinalizerOp>,
     join_handle: JoinHandle<FstResult<()>>,
 }
 
 impl SegmentFinalizer {
-    pub fn start(index_directory: &PathBuf, segments: Arc<RwLock<Vec<Arc<Segment>>>>) -> Self {
-        let (segment_sender, segment_receiver) =
-            crossbeam::channel::bounded::<(WritableSegment, Option<CommitReplySender>)>(10);
-        let moved_index_directory = index_directory.clone();
+    pub fn start(
+        index_directory: &Path,
+        segments: Arc<RwLock<Vec<Arc<Segment>>>>,
+        segment_merger: &SegmentMerger,
+    ) -> Self {
+        let merge_notifier = segment_merger.notifier();
+        let (op_sender, op_receiver) = crossbeam::channel::bounded::<FinalizerOp>(10);
+        let moved_index_directory = index_directory.to_path_buf();
         let join_handle = thread::spawn(move || {
-            for (writable_segment, reply_sender_opt) in segment_receiver.iter() {
-                let segment = writable_segment.into_segment(&moved_index_directory)?;
-                let mut segment_lock = segments.write().unwrap();
-                segment_lock.push(Arc::new(segment));
-                drop(segment_lock);
-                if let Some(reply_sender) = reply_sender_opt {
-                    reply_sender.send(Ok(())).unwrap();
+            for op in op_receiver.iter() {
+                match op {
+                    FinalizerOp::Finalize(writable_segment, reply_sender_opt) => {
+                        // do not bother finalizing empty segment.
+                        if writable_segment.num_docs() > 0 {
+                            let segment = writable_segment.into_segment(&moved_index_directory)?;
+                            let mut segment_lock = segments.write().unwrap();
+                            segment_lock.push(Arc::new(segment));
+                            drop(segment_lock);
+                            // A merge check is already pending when the channel is full.
+                            let _ = merge_notifier.try_send(());
+                        }
+                        if let Some(reply_sender) = reply_sender_opt {
+                            reply_sender.send(Ok(())).unwrap();
+                        }
+                    }
+                    FinalizerOp::Delete(deletion) => {
+                        // Holding the lock orders deletions with merges.
+                        let segments_lock = segments.read().unwrap();
+                        let result = segments_lock.iter().try_for_each(|segment| {
+                            apply_deletion(&moved_index_directory, segment, &deletion)
+                        });
+                        if let Err(err) = result {
+                            println!("Fts deletion error {:?}", err);
+                        }
+                    }
                 }
             }
             Ok(())
         });
 
         Self {
-            segment_sender,
+            op_sender,
             join_handle,
         }
     }
@@ -202,92 +423,231 @@ impl SegmentFinalizer {
         segment: WritableSegment,
         commit_reply_sender_opt: Option<CommitReplySender>,
     ) {
-        // do not bother finalizing empty segment.
-        if segment.num_docs() > 0 {
-            self.segment_sender
-                .send((segment, commit_reply_sender_opt))
-                .unwrap();
-        }
+        self.op_sender
+            .send(FinalizerOp::Finalize(segment, commit_reply_sender_opt))
+            .unwrap();
+    }
+
+    pub fn delete(&self, deletion: Deletion) {
+        self.op_sender.send(FinalizerOp::Delete(deletion)).unwrap();
     }
 
     pub fn stop(self) -> FstResult<()> {
-        drop(self.segment_sender);
+        drop(self.op_sender);
         self.join_handle.join().unwrap()
     }
 }
 
-fn evaluate_query(postings: &Postings, query: &Query) -> FstResult<Vec<DocId>> {
-    match query {
-        Query::Or(left, right) => {
-            let mut left_doc_ids = evaluate_query(postings, left)?.into_iter().peekable();
-            let mut right_doc_ids = evaluate_query(postings, right)?.into_iter().peekable();
-
-            // perform union (OR)
-            let mut result = Vec::with_capacity(left_doc_ids.len() + right_doc_ids.len());
-            loop {
-                let (Some(left_v), Some(right_v)) = (left_doc_ids.peek(), right_doc_ids.peek())
-                else {
-                    break;
-                };
+fn apply_deletion(index_directory: &Path, segment: &Segment, deletion: &Deletion) -> FstResult<()> {
+    match deletion {
+        Deletion::Docs(doc_ids) => segment.delete(index_directory, doc_ids),
+        Deletion::Query(query) => {
+            let doc_ids = evaluate_query(segment, query)?.collect_vec();
+            segment.delete(index_directory, &doc_ids)
+        }
+    }
+}
 
-                if left_v < right_v {
-                    result.push(left_doc_ids.next().unwrap());
-                } else {
-                    result.push(right_doc_ids.next().unwrap());
+/// Merges small segments into larger ones in the background.
+///
+/// Segments are grouped in size tiers of `merge_factor` (a segment with
+/// `n` docs is in tier `log(n) / log(merge_factor)`). Whenever `merge_factor`
+/// adjacent segments belong to the same tier, they are merged into a single
+/// segment of the next tier. Only adjacent segments are merged so that the
+/// segment order, which decides what version of a doc is the newest, is kept.
+pub(crate) struct SegmentMerger {
+    notify_sender: Sender<()>,
+    join_handle: JoinHandle<FstResult<()>>,
+}
+
+impl SegmentMerger {
+    pub fn start(config: Arc<Config>, segments: Arc<RwLock<Vec<Arc<Segment>>>>) -> Self {
+        let (notify_sender, notify_receiver) = crossbeam::channel::bounded::<()>(1);
+        let join_handle = thread::spawn(move || {
+            for _ in notify_receiver.iter() {
+                if config.merge_factor < 2 {
+                    continue;
+                }
+                while let Some(candidates) = find_merge_candidates(&config, &segments) {
+                    if let Err(err) = merge_segments(&config.directory, &segments, candidates) {
+                        println!("Fts segment merge error {:?}", err);
+                        break;
+                    }
                 }
             }
+            Ok(())
+        });
 
-            while let Some(left_v) = left_doc_ids.next() {
-                result.push(left_v);
-            }
+        Self {
+            notify_sender,
+            join_handle,
+        }
+    }
 
-            while let Some(right_v) = right_doc_ids.next() {
-                result.push(right_v);
-            }
+    fn notifier(&self) -> Sender<()> {
+        self.notify_sender.clone()
+    }
 
-            Ok(result)
+    pub fn stop(self) -> FstResult<()> {
+        drop(self.notify_sender);
+        self.join_handle.join().unwrap()
+    }
+}
+
+fn segment_tier(num_docs: usize, merge_factor: usize) -> usize {
+    let mut tier = 0;
+    let mut num_docs = num_docs;
+    while num_docs >= merge_factor {
+        num_docs /= merge_factor;
+        tier += 1;
+    }
+    tier
+}
+
+/// Returns the oldest run of `merge_factor` adjacent segments of the same tier.
+fn find_merge_candidates(
+    config: &Config,
+    segments: &RwLock<Vec<Arc<Segment>>>,
+) -> Option<Vec<Arc<Segment>>> {
+    let segments_lock = segments.read().unwrap();
+    segments_lock
+        .windows(config.merge_factor)
+        .find(|window| {
+            let tier = segment_tier(window[0].num_docs(), config.merge_factor);
+            window.iter().all(|segment| {
+                segment.num_docs() <= config.merge_max_docs
+                    && segment_tier(segment.num_docs(), config.merge_factor) == tier
+            })
+        })
+        .map(|window| window.to_vec())
+}
+
+fn merge_segments(
+    index_directory: &Path,
+    segments: &RwLock<Vec<Arc<Segment>>>,
+    candidates: Vec<Arc<Segment>>,
+) -> FstResult<()> {
+    // The merged segment takes the place of the newest merged segment.
+    let last_id = candidates[candidates.len() - 1].get_id();
+    let segment_id = Ulid::from_string(last_id)
+        .ok()
+        .and_then(|ulid| ulid.increment())
+        .ok_or_else(|| {
+            FtsError::Other(format!(
+                "cannot derive merged segment id from `{}`.",
+                last_id
+            ))
+        })?
+        .to_string();
+
+    // Deletions made before the merge are already purged from the merged
+    // segment. Holding the write lock keeps pending deletions from being
+    // half applied to the candidates while taking the snapshot.
+    let deleted_before_merge: Vec<HashSet<DocId>> = {
+        let _segments_lock = segments.write().unwrap();
+        candidates
+            .iter()
+            .map(|segment| segment.deleted_doc_ids().into_iter().collect())
+            .collect()
+    };
+    let merged_segment = Segment::merge(index_directory, &segment_id, &candidates)?;
+
+    // Only the merger removes segments, the candidates are still adjacent.
+    let mut segments_lock = segments.write().unwrap();
+    // Only deletions applied while merging are carried over to the merged
+    // segment, older ones could hit a doc re-inserted in a newer candidate.
+    for (segment, deleted_before) in candidates.iter().zip(deleted_before_merge) {
+        let deleted_while_merging: Vec<DocId> = segment
+            .deleted_doc_ids()
+            .into_iter()
+            .filter(|doc_id| !deleted_before.contains(doc_id))
+            .collect();
+        merged_segment.delete(index_directory, &deleted_while_merging)?;
+    }
+    let position = segments_lock
+        .iter()
+        .position(|segment| segment.get_id() == candidates[0].get_id())
+        .ok_or_else(|| FtsError::Other("merged segments are gone.".to_string()))?;
+    segments_lock.splice(
+        position..position + candidates.len(),
+        [Arc::new(merged_segment)],
+    );
+    drop(segments_lock);
+
+    // Readers still holding the old segments keep their mmaps alive.
+    for segment in candidates {
+        fs::remove_dir_all(index_directory.join(segment.get_id()))?;
+    }
+    Ok(())
+}
+
+/// Evaluates a query into a sorted doc set, composing the posting list
+/// iterators so that they are consumed block by block.
+fn evaluate_query<'a>(segment: &'a Segment, query: &Query) -> FstResult<Box<dyn DocSet + 'a>> {
+    match query {
+        Query::All => Ok(Box::new(all_docs(segment))),
+        Query::Or(left, right) => {
+            let left_doc_set = evaluate_query(segment, left)?;
+            let right_doc_set = evaluate_query(segment, right)?;
+            Ok(Box::new(UnionDocSet::new(vec![
+                left_doc_set,
+                right_doc_set,
+            ])))
         }
         Query::And(left, right) => {
-            let mut left_doc_ids = evaluate_query(postings, left)?.into_iter().peekable();
-            let mut right_doc_ids = evaluate_query(postings, right)?.into_iter().peekable();
-
-            // perform intersection (AND)
-            let mut result = vec![];
-            loop {
-                let (Some(left_v), Some(right_v)) = (left_doc_ids.peek(), right_doc_ids.peek())
-                else {
-                    break;
-                };
-
-                if left_v < right_v {
-                    left_doc_ids.next();
-                } else if left_v > right_v {
-                    right_doc_ids.next();
-                } else {
-                    if left_v == right_v {
-                        result.push(*left_v);
-                    }
-                    left_doc_ids.next();
-                    right_doc_ids.next();
-                }
-            }
-
-            Ok(result)
+            let left_doc_set = evaluate_query(segment, left)?;
+            let right_doc_set = evaluate_query(segment, right)?;
+            Ok(Box::new(IntersectionDocSet::new(
+                left_doc_set,
+                right_doc_set,
+            )))
+        }
+        Query::AndNot(left, right) => {
+            let left_doc_set = evaluate_query(segment, left)?;
+            let right_doc_set = evaluate_query(segment, right)?;
+            Ok(Box::new(DifferenceDocSet::new(left_doc_set, right_doc_set)))
+        }
+        Query::Not(query) => {
+            let doc_set = evaluate_query(segment, query)?;
+            Ok(Box::new(DifferenceDocSet::new(all_docs(segment), doc_set)))
+        }
+        Query::Field(field, query)
+            if matches!(
+                **query,
+                Query::Or(..) | Query::And(..) | Query::AndNot(..) | Query::Not(..)
+            ) =>
+        {
+            evaluate_query(segment, &Query::scoped_to_field(field, query))
         }
         query => {
-            let posting_info = postings.search(query.matcher()?)?;
-            fetch_doc_ids(postings, posting_info)
+            let mut matcher = query.matcher()?;
+            // Negated term queries are evaluated at the document level:
+            // a document matches when none of its terms match.
+            let negate = matcher.complement;
+            matcher.complement = false;
+
+            let posting_info = segment.postings.search(matcher)?;
+            let doc_set = fetch_doc_set(&segment.postings, posting_info)?;
+            if negate {
+                return Ok(Box::new(DifferenceDocSet::new(all_docs(segment), doc_set)));
+            }
+            Ok(doc_set)
         }
     }
 }
 
-fn fetch_doc_ids(postings: &Postings, posting_info: Vec<(String, u64)>) -> FstResult<Vec<DocId>> {
-    let mut doc_ids = HashSet::new();
-    for (_, offset) in posting_info {
-        let ids = postings.postings(offset as usize)?;
-        for id in ids {
-            doc_ids.insert(id);
-        }
-    }
-    Ok(doc_ids.into_iter().collect())
+fn all_docs(segment: &Segment) -> VecDocSet {
+    VecDocSet::new(segment.store.doc_ids().to_vec())
+}
+
+/// Returns the union of the posting lists of the matched terms.
+fn fetch_doc_set<'a>(
+    postings: &'a Postings,
+    posting_info: Vec<(String, u64)>,
+) -> FstResult<Box<dyn DocSet + 'a>> {
+    let doc_sets = posting_info
+        .into_iter()
+        .map(|(_, offset)| postings.doc_set(offset as usize))
+        .collect::<FstResult<Vec<_>>>()?;
+    Ok(Box::new(UnionDocSet::new(doc_sets)))
 }
@@ -5,8 +5,49 @@ fn main() -> Result<()> {
         &[
             "src/prometheus/remote/prompb/types.proto",
             "src/prometheus/remote/prompb/remote.proto",
+            "src/prometheus/remote/prompb/io/prometheus/write/v2/types.proto",
         ],
         &["src/prometheus/remote/prompb/"],
     )?;
+    // OTLP/JSON is the protobuf JSON mapping of the messages, with camelCase fields,
+    // flattened oneofs, integer enums, 64 bits integers as strings and hex ids. Oneofs
+    // are matched by suffix, a `.opentelemetry` prefix would match their variants too.
+    let otlp_int = r#"#[serde(deserialize_with = "crate::otlp::types::json_int")]"#;
+    prost_build::Config::new()
+        .message_attribute(
+            ".opentelemetry",
+            r#"#[derive(serde::Deserialize)] #[serde(rename_all = "camelCase", default)]"#,
+        )
+        .enum_attribute(
+            ".opentelemetry",
+            r#"#[derive(serde::Deserialize)] #[serde(rename_all = "camelCase")]"#,
+        )
+        .field_attribute("Metric.data", "#[serde(flatten)]")
+        .field_attribute("NumberDataPoint.value", "#[serde(flatten)]")
+        .field_attribute("Exemplar.value", "#[serde(flatten)]")
+        .field_attribute("AnyValue.value", "#[serde(flatten)]")
+        .field_attribute(".opentelemetry.proto.common.v1.AnyValue.value.bytes_value", "#[serde(skip)]")
+        .field_attribute("start_time_unix_nano", otlp_int)
+        .field_attribute("time_unix_nano", otlp_int)
+        .field_attribute("count", otlp_int)
+        .field_attribute("zero_count", otlp_int)
+        .field_attribute("as_int", otlp_int)
+        .field_attribute("int_value", otlp_int)
+        .field_attribute(
+            "bucket_counts",
+            r#"#[serde(deserialize_with = "crate::otlp::types::json_ints")]"#,
+        )
+        .field_attribute(
+            "span_id",
+            r#"#[serde(deserialize_with = "crate::otlp::types::json_hex")]"#,
+        )
+        .field_attribute(
+            "trace_id",
+            r#"#[serde(deserialize_with = "crate::otlp::types::json_hex")]"#,
+        )
+        .compile_protos(
+            &["src/otlp/proto/opentelemetry/proto/collector/metrics/v1/metrics_service.proto"],
+            &["src/otlp/proto/"],
+        )?;
     Ok(())
 }
@@ -0,0 +1,99 @@
+use std::io::Read;
+
+use axum::{
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use flate2::read::MultiGzDecoder;
+use serde_json::Value as JsonValue;
+
+/// How long clients should wait before retrying overloaded or unavailable requests.
+const RETRY_AFTER_SECONDS: u32 = 5;
+
+/// The largest size of a decompressed request body.
+pub(crate) const MAX_DECODED_BODY_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Why a request body cannot be decoded.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ContentError {
+    /// The body is invalid or of an unsupported encoding.
+    Invalid(String),
+    /// The decompressed body is larger than allowed.
+    TooLarge(String),
+}
+
+/// Builds the JSON response of an error, asking clients to retry later
+/// when the server is overloaded or unavailable.
+pub(crate) fn error_response(status_code: StatusCode, body: JsonValue) -> Response {
+    retry_later((status_code, Json(body)).into_response())
+}
+
+/// Asks clients to retry a response later if the server is overloaded or unavailable.
+pub(crate) fn retry_later(mut response: Response) -> Response {
+    if matches!(
+        response.status(),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECONDS));
+    }
+    response
+}
+
+/// Decodes a request body of `content_encoding`, gzip or identity.
+pub(crate) fn decode_content(
+    content_encoding: Option<&str>,
+    body: Vec<u8>,
+) -> Result<Vec<u8>, ContentError> {
+    match content_encoding {
+        None | Some("identity") => Ok(body),
+        Some("gzip") => decode_gzip(&body, MAX_DECODED_BODY_BYTES),
+        Some(encoding) => Err(ContentError::Invalid(format!(
+            "unsupported content encoding `{}`",
+            encoding
+        ))),
+    }
+}
+
+/// Decompresses a gzip body, failing once it exceeds `limit` bytes rather
+/// than decompressing it whole.
+fn decode_gzip(body: &[u8], limit: u64) -> Result<Vec<u8>, ContentError> {
+    let mut decoded = vec![];
+    MultiGzDecoder::new(body)
+        .take(limit + 1)
+        .read_to_end(&mut decoded)
+        .map_err(|err| ContentError::Invalid(format!("invalid gzip body: {}", err)))?;
+    if decoded.len() as u64 > limit {
+        return Err(ContentError::TooLarge(format!(
+            "decompressed body larger than {} bytes",
+            limit
+        )));
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    #[test]
+    fn decode_gzip_with_limit() {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&[0; 1024]).unwrap();
+        let body = encoder.finish().unwrap();
+        assert_eq!(decode_gzip(&body, 1024).unwrap().len(), 1024);
+        assert!(matches!(decode_gzip(&body, 1023), Err(ContentError::TooLarge(_))));
+        assert!(matches!(decode_gzip(&[1, 2, 3], 1024), Err(ContentError::Invalid(_))));
+        assert_eq!(decode_content(Some("identity"), vec![1]), Ok(vec![1]));
+        assert!(matches!(
+            decode_content(Some("br"), vec![]),
+            Err(ContentError::Invalid(_))
+        ));
+    }
+}
@@ -1,17 +1,30 @@
-use std::{sync::Arc, fmt::Display, collections::HashMap};
+use std::{
+    sync::Arc, fmt::Display, collections::HashMap, str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use axum::{response::IntoResponse, http::StatusCode};
-use influxdb_line_protocol::{parse_lines, ParsedLine, FieldValue};
+use influxdb_line_protocol::{parse_lines, split_lines, ParsedLine, FieldValue};
 use serde::{Serialize, Deserialize};
-use storage::{Label, TimeSeries, SERIES_NAME_LABEL, Sample, Storage, TimeSeriesInfo};
+use serde_json::json;
+use storage::{Label, TimeSeries, SERIES_NAME_LABEL, Sample, Storage, StorageError, TimeSeriesInfo};
 use thiserror::Error;
 
+use crate::http::{decode_content, error_response, ContentError};
+use super::settings::{InfluxDbSettings, MissingTimestamp, StringFields};
+
 pub type InfluxDbResult<T> = Result<T, InfluxDbError>;
 
 #[derive(Error, Debug)]
 pub enum InfluxDbError {
     Storage(#[from] storage::StorageError),
-    LineProtocol(#[from] influxdb_line_protocol::Error), 
+    /// Lines of a write were rejected, the other ones were written if `partial`.
+    InvalidLines { lines: Vec<LineError>, partial: bool },
+    /// The request is invalid, e.g. an unknown precision or a body that is not gzip.
+    BadRequest(String),
+    /// The decompressed body of a write is too large.
+    PayloadTooLarge(String),
+    #[allow(dead_code)]
     Other(String),
 }
 
@@ -19,22 +32,52 @@ impl Display for InfluxDbError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Storage(err) => f.write_fmt(format_args!("StorageError {}", err)),
-            Self::LineProtocol(err) => f.write_fmt(format_args!("LineProtocol {}", err)),
+            Self::InvalidLines { lines, partial } => {
+                let written = if *partial { "partial write" } else { "nothing written" };
+                f.write_fmt(format_args!("{}: {} lines rejected", written, lines.len()))?;
+                for (index, error) in lines.iter().enumerate() {
+                    let separator = if index == 0 { ": " } else { "; " };
+                    f.write_fmt(format_args!("{}line {}: {}", separator, error.line, error.message))?;
+                }
+                Ok(())
+            }
+            Self::BadRequest(err) => f.write_fmt(format_args!("BadRequest {}", err)),
+            Self::PayloadTooLarge(err) => f.write_fmt(format_args!("PayloadTooLarge {}", err)),
             Self::Other(err) => f.write_fmt(format_args!("Other {}", err)),
         }
     }
 }
 
 impl IntoResponse for InfluxDbError {
+    /// Clients retry 5xx and 429 responses, and drop the lines of other ones.
     fn into_response(self) -> axum::response::Response {
-        let error_message = match self {
-            InfluxDbError::Storage(err) => format!("Internal server error: {}", err),
-            InfluxDbError::LineProtocol(err) => format!("Internal server error: {}", err),
-            InfluxDbError::Other(err) => {
-                format!("Internal server error: {}", err)
+        let (status_code, code) = match &self {
+            InfluxDbError::InvalidLines { .. }
+            | InfluxDbError::BadRequest(_)
+            | InfluxDbError::Storage(StorageError::OutOfBounds(_)) => {
+                (StatusCode::BAD_REQUEST, "invalid")
+            }
+            InfluxDbError::PayloadTooLarge(_) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "request too large")
+            }
+            InfluxDbError::Storage(StorageError::Overloaded(_)) => {
+                (StatusCode::TOO_MANY_REQUESTS, "too many requests")
+            }
+            InfluxDbError::Storage(StorageError::Unavailable(_)) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+            }
+            InfluxDbError::Storage(_) | InfluxDbError::Other(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal error")
             }
         };
-        (StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response()
+        let mut body = json!({
+            "code": code,
+            "message": self.to_string(),
+        });
+        if let InfluxDbError::InvalidLines { lines, .. } = &self {
+            body["errors"] = json!(lines);
+        }
+        error_response(status_code, body)
     }
 }
 
@@ -43,47 +86,226 @@ pub struct WriteRequest {
     timeseries: Vec<TimeSeries>,
 }
 
+impl WriteRequest {
+    #[cfg(test)]
+    pub fn into_timeseries(self) -> Vec<TimeSeries> {
+        self.timeseries
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct InfluxDbStorage {
     storage: Arc<Storage>,
+    settings: InfluxDbSettings,
 }
 
 impl InfluxDbStorage {
-    pub fn new(storage: Arc<Storage>) -> Self {
-        InfluxDbStorage { storage }
+    pub fn new(storage: Arc<Storage>, settings: InfluxDbSettings) -> Self {
+        InfluxDbStorage { storage, settings }
+    }
+
+    /// Returns the label of the writes to `bucket`.
+    pub fn bucket_label(&self, bucket: Option<String>) -> Option<Label> {
+        match bucket {
+            Some(bucket) if !self.settings.bucket_label.is_empty() => Some(Label {
+                name: self.settings.bucket_label.clone(),
+                value: bucket,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the timestamp (in ms) of the lines of a write received
+    /// at `arrival_time` without one, `None` to skip them.
+    pub fn missing_timestamp(&self, arrival_time: SystemTime) -> Option<i64> {
+        match self.settings.missing_timestamp {
+            MissingTimestamp::Arrival => Some(
+                arrival_time
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_millis() as i64),
+            ),
+            MissingTimestamp::Skip => None,
+        }
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    pub fn settings(&self) -> &InfluxDbSettings {
+        &self.settings
     }
 
-    /// Write samples to remote storage.
-    pub async fn write(&self, request: WriteRequest) -> Result<(), InfluxDbError> {
-        self.storage.write(request.timeseries).await?;
-        Ok(())
+    /// Returns what the string fields of the lines become.
+    pub fn string_fields(&self) -> StringFields {
+        self.settings.string_fields
+    }
+
+    /// Write samples to remote storage. With `invalid_lines`, they are only written
+    /// in partial write mode, and the invalid lines are reported in both cases.
+    pub async fn write(
+        &self,
+        request: WriteRequest,
+        invalid_lines: Vec<LineError>,
+    ) -> Result<(), InfluxDbError> {
+        let partial = self.settings.partial_writes;
+        if (invalid_lines.is_empty() || partial) && !request.timeseries.is_empty() {
+            self.storage.write(request.timeseries).await?;
+        }
+        if invalid_lines.is_empty() {
+            Ok(())
+        } else {
+            Err(InfluxDbError::InvalidLines { lines: invalid_lines, partial })
+        }
     }
 } 
 
-pub fn decode_influx_lines_request(body: String) -> InfluxDbResult<WriteRequest> {
+/// The unit of the line protocol timestamps of a write.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Precision {
+    #[default]
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    Minutes,
+    Hours,
+}
+
+impl Precision {
+    /// Converts a timestamp of this precision into storage milliseconds.
+    pub fn to_millis(self, timestamp: i64) -> i64 {
+        match self {
+            Precision::Nanoseconds => timestamp.div_euclid(1_000_000),
+            Precision::Microseconds => timestamp.div_euclid(1_000),
+            Precision::Milliseconds => timestamp,
+            Precision::Seconds => timestamp.saturating_mul(1_000),
+            Precision::Minutes => timestamp.saturating_mul(60_000),
+            Precision::Hours => timestamp.saturating_mul(3_600_000),
+        }
+    }
+
+    /// Converts a timestamp in storage milliseconds into this precision.
+    pub fn convert_millis(self, timestamp: i64) -> i64 {
+        match self {
+            Precision::Nanoseconds => timestamp.saturating_mul(1_000_000),
+            Precision::Microseconds => timestamp.saturating_mul(1_000),
+            Precision::Milliseconds => timestamp,
+            Precision::Seconds => timestamp.div_euclid(1_000),
+            Precision::Minutes => timestamp.div_euclid(60_000),
+            Precision::Hours => timestamp.div_euclid(3_600_000),
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = InfluxDbError;
+
+    /// Parses the `precision` of a v2 write (`ns`, `us`, `ms`, `s`) or of
+    /// a v1 one, which also accepts `n`, `u`, `m` and `h`.
+    fn from_str(precision: &str) -> Result<Self, Self::Err> {
+        match precision {
+            "ns" | "n" => Ok(Precision::Nanoseconds),
+            "us" | "u" | "µ" => Ok(Precision::Microseconds),
+            "ms" => Ok(Precision::Milliseconds),
+            "s" => Ok(Precision::Seconds),
+            "m" => Ok(Precision::Minutes),
+            "h" => Ok(Precision::Hours),
+            _ => Err(InfluxDbError::BadRequest(format!("invalid precision `{}`", precision))),
+        }
+    }
+}
+
+/// Decodes the body of a write according to its `Content-Encoding`, `gzip` or none.
+pub fn decode_body(content_encoding: Option<&str>, body: Vec<u8>) -> InfluxDbResult<String> {
+    let body = decode_content(content_encoding, body).map_err(|err| match err {
+        ContentError::Invalid(err) => InfluxDbError::BadRequest(err),
+        ContentError::TooLarge(err) => InfluxDbError::PayloadTooLarge(err),
+    })?;
+    String::from_utf8(body)
+        .map_err(|err| InfluxDbError::BadRequest(format!("invalid UTF-8 body: {}", err)))
+}
+
+/// A line of a write that was not ingested, numbered from 1.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LineError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Decodes line protocol into series named `<measurement>_<field>`, labeled with the
+/// tags of the line and `bucket_label`, if any. Timestamps of `precision` are converted
+/// to milliseconds, lines without one are at `missing_timestamp`, or skipped if none.
+///
+/// Invalid lines are left out of the request and reported along with it.
+pub fn decode_influx_lines_request(
+    body: String,
+    precision: Precision,
+    bucket_label: Option<Label>,
+    missing_timestamp: Option<i64>,
+    string_fields: StringFields,
+) -> (WriteRequest, Vec<LineError>) {
     let mut timeseries_map: HashMap<u64, TimeSeries> = HashMap::new();
-    let mut parsed_lines = parse_lines(&body);
-    while let Some(line_result) = parsed_lines.next() {
+    let mut errors = vec![];
+    for (index, line) in split_lines(&body).enumerate() {
+        let Some(line_result) = parse_lines(line).next() else {
+            continue;
+        };
         let ParsedLine {
             series,
             field_set,
             timestamp,
-        } = line_result?;
+        } = match line_result {
+            Ok(parsed_line) => parsed_line,
+            Err(err) => {
+                errors.push(LineError{ line: index + 1, message: err.to_string() });
+                continue;
+            }
+        };
 
-        let Some(timestamp) = timestamp  else {
-            continue;
+        let timestamp = match timestamp {
+            Some(timestamp) => precision.to_millis(timestamp),
+            None => match missing_timestamp {
+                Some(timestamp) => timestamp,
+                None => continue,
+            },
         };
 
-        let tags = series.tag_set.map_or(vec![], |tags| {
+        let mut tags = series.tag_set.map_or(vec![], |tags| {
             tags.into_iter()
                 .map(|(name, value)| Label { name: name.to_string(), value: value.to_string() })
                 .collect::<Vec<_>>()
         });
+        tags.extend(bucket_label.clone());
+
+        let mut values = vec![];
+        let mut invalid_field = None;
         for (field_name, field_value) in field_set {
-            let Some(value) = convert_value(field_value) else {
-                continue;
-            };
+            match (field_value, string_fields) {
+                (FieldValue::String(value), StringFields::Label) => {
+                    if tags.iter().any(|tag| tag.name == field_name.as_str()) {
+                        invalid_field = Some(format!("string field `{}` is also a tag", field_name));
+                        break;
+                    }
+                    tags.push(Label { name: field_name.to_string(), value: value.to_string() });
+                }
+                (FieldValue::String(_), StringFields::Drop) => {}
+                (field_value, _) => {
+                    if let Some(value) = convert_value(field_value) {
+                        values.push((field_name, value));
+                    }
+                }
+            }
+        }
+        if invalid_field.is_none() && values.is_empty() {
+            invalid_field = Some("no numeric or boolean field".to_string());
+        }
+        if let Some(message) = invalid_field {
+            errors.push(LineError{ line: index + 1, message });
+            continue;
+        }
 
+        for (field_name, value) in values {
             let mut labels = tags.clone();
             labels.push(Label{ 
                 name: SERIES_NAME_LABEL.to_string(), 
@@ -99,21 +321,81 @@ pub fn decode_influx_lines_request(body: String) -> InfluxDbResult<WriteRequest>
         }
     }
 
-    let timeseries = timeseries_map
-        .into_iter()
-        .map(|(_, ts)| ts)
-        .collect();
-    Ok(WriteRequest{timeseries})
+    let timeseries = timeseries_map.into_values().collect();
+    (WriteRequest{timeseries}, errors)
 }
 
+/// Returns the sample value of a numeric or boolean field, as 0 or 1.
 fn convert_value(value: FieldValue) -> Option<f64> {
     match value {
         FieldValue::I64(v) => Some(v as f64),
         FieldValue::U64(v) => Some(v as f64),
         FieldValue::F64(v) => Some(v),
         FieldValue::String(_) => None,
-        FieldValue::Boolean(_) => None,
+        FieldValue::Boolean(v) => Some(if v { 1.0 } else { 0.0 }),
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    #[test]
+    fn decode_with_precision_and_bucket() {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(b"cpu,host=a usage=0.5 1700000000\ncpu,host=b usage=1\n").unwrap();
+        let body = decode_body(Some("gzip"), encoder.finish().unwrap()).unwrap();
+        assert!(decode_body(Some("br"), vec![]).is_err());
+
+        let bucket = Label { name: "bucket".to_string(), value: "telegraf".to_string() };
+        let precision: Precision = "s".parse().unwrap();
+        let (request, _) =
+            decode_influx_lines_request(body.clone(), precision, Some(bucket), None, StringFields::Drop);
+        assert_eq!(request.timeseries.len(), 1);
+        let (labels, samples, _) = request.timeseries[0].clone().into_parts();
+        let labels: Vec<(&str, &str)> = labels.iter()
+            .map(|label| (label.name.as_str(), label.value.as_str()))
+            .collect();
+        assert!(labels.contains(&("bucket", "telegraf")));
+        assert!(labels.contains(&(SERIES_NAME_LABEL, "cpu_usage")));
+        assert_eq!(samples[0].timestamp, 1_700_000_000_000);
+
+        let (request, _) = decode_influx_lines_request(body, precision, None, Some(42), StringFields::Drop);
+        let mut timestamps: Vec<i64> = request.timeseries.into_iter()
+            .map(|series| series.into_parts().1[0].timestamp)
+            .collect();
+        timestamps.sort();
+        assert_eq!(timestamps, &[42, 1_700_000_000_000]);
 
+        assert_eq!(Precision::default().to_millis(1_700_000_000_123_456_789), 1_700_000_000_123);
+        assert!("d".parse::<Precision>().is_err());
+    }
+
+    #[test]
+    fn decode_invalid_lines_and_fields() {
+        let body = "cpu,host=a up=true,state=\"ok\" 1\ncpu,host=a up=\n\n# comment\ncpu state=\"ok\" 2\ncpu,state=a state=\"ok\",up=f 3\n";
+        let decode = |string_fields| {
+            decode_influx_lines_request(body.to_string(), Precision::Milliseconds, None, None, string_fields)
+        };
+
+        let (request, errors) = decode(StringFields::Drop);
+        assert_eq!(request.timeseries.len(), 2);
+        let lines: Vec<usize> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, &[2, 5]);
+
+        let (request, errors) = decode(StringFields::Label);
+        assert_eq!(request.timeseries.len(), 1);
+        let (labels, samples, _) = request.timeseries[0].clone().into_parts();
+        assert!(labels.contains(&Label { name: "state".to_string(), value: "ok".to_string() }));
+        assert_eq!(samples[0].value, 1.0);
+        let lines: Vec<usize> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, &[2, 5, 6]);
+
+        let message = InfluxDbError::InvalidLines { lines: errors, partial: true }.to_string();
+        assert!(message.starts_with("partial write: 3 lines rejected: line 2: "), "{}", message);
+    }
+}
@@ -0,0 +1,643 @@
+use std::{iter::Peekable, str::Chars};
+
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// A statement of the supported InfluxQL subset.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Select(SelectStatement),
+    ShowMeasurements {
+        limit: Option<usize>,
+    },
+    ShowTagKeys {
+        measurement: Option<String>,
+    },
+    ShowTagValues {
+        measurement: Option<String>,
+        key: String,
+    },
+    ShowFieldKeys {
+        measurement: Option<String>,
+    },
+}
+
+/// `SELECT <fields> FROM <measurement> [WHERE ...] [GROUP BY ...] [fill(...)] [LIMIT n]`,
+/// where the conditions are tag comparisons and time bounds joined by `AND`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectStatement {
+    pub fields: Vec<SelectField>,
+    pub measurement: String,
+    pub conditions: Vec<TagCondition>,
+    /// The inclusive lower time bound (in ms).
+    pub start: Option<i64>,
+    /// The exclusive upper time bound (in ms).
+    pub end: Option<i64>,
+    /// The `GROUP BY time(...)` interval (in ms).
+    pub interval: Option<i64>,
+    pub group_by_tags: Vec<String>,
+    pub fill: Fill,
+    pub limit: Option<usize>,
+}
+
+/// A field, or an aggregate of it, named `alias` or else after the function or field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectField {
+    pub function: Option<Aggregate>,
+    pub field: String,
+    pub alias: Option<String>,
+}
+
+impl SelectField {
+    pub fn column_name(&self) -> &str {
+        match (&self.alias, &self.function) {
+            (Some(alias), _) => alias,
+            (None, Some(function)) => function.name(),
+            (None, None) => &self.field,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Count,
+    First,
+    Last,
+    Max,
+    Mean,
+    Median,
+    Min,
+    Spread,
+    Sum,
+}
+
+impl Aggregate {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "count" => Some(Aggregate::Count),
+            "first" => Some(Aggregate::First),
+            "last" => Some(Aggregate::Last),
+            "max" => Some(Aggregate::Max),
+            "mean" => Some(Aggregate::Mean),
+            "median" => Some(Aggregate::Median),
+            "min" => Some(Aggregate::Min),
+            "spread" => Some(Aggregate::Spread),
+            "sum" => Some(Aggregate::Sum),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aggregate::Count => "count",
+            Aggregate::First => "first",
+            Aggregate::Last => "last",
+            Aggregate::Max => "max",
+            Aggregate::Mean => "mean",
+            Aggregate::Median => "median",
+            Aggregate::Min => "min",
+            Aggregate::Spread => "spread",
+            Aggregate::Sum => "sum",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagCondition {
+    pub tag: String,
+    pub op: TagOp,
+    /// The value, or the unanchored regex for `=~` and `!~`.
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagOp {
+    Eq,
+    Neq,
+    Re,
+    Nre,
+}
+
+/// The values of the `GROUP BY time(...)` intervals without samples.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Fill {
+    #[default]
+    Null,
+    None,
+    Previous,
+    Value(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    String(String),
+    Regex(String),
+    Number(String),
+    /// A number and its unit, e.g. `5m`.
+    Duration(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 16] = [
+    "!=", "<>", "=~", "!~", "<=", ">=", "=", "<", ">", "(", ")", ",", ";", ".", "+", "-",
+];
+
+/// Parses the `;` separated statements of `query`, `now()` being `now` (in ms).
+pub fn parse_statements(query: &str, now: i64) -> Result<Vec<Statement>, String> {
+    let tokens = tokenize(query)?;
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        now,
+    };
+    let mut statements = vec![];
+    loop {
+        while parser.accept_symbol(";") {}
+        if parser.peek().is_none() {
+            break;
+        }
+        statements.push(parser.statement()?);
+        if parser.peek().is_some() && !parser.accept_symbol(";") {
+            return Err(format!("unexpected {}", parser.describe_next()));
+        }
+    }
+    if statements.is_empty() {
+        return Err("empty query".to_string());
+    }
+    Ok(statements)
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = query.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let value = read_quoted(&mut chars, c)?;
+            tokens.push(if c == '"' {
+                Token::Ident(value)
+            } else {
+                Token::String(value)
+            });
+        } else if c == '/' && matches!(tokens.last(), Some(Token::Symbol("=~" | "!~"))) {
+            chars.next();
+            tokens.push(Token::Regex(read_quoted(&mut chars, '/')?));
+        } else if c.is_ascii_digit() {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                number.push(c);
+                chars.next();
+            }
+            let mut unit = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_alphabetic()) {
+                unit.push(c);
+                chars.next();
+            }
+            tokens.push(if unit.is_empty() {
+                Token::Number(number)
+            } else {
+                Token::Duration(number + &unit)
+            });
+        } else if c.is_alphabetic() || c == '_' {
+            let mut ident = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                ident.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Ident(ident));
+        } else {
+            let rest: String = chars.clone().take(2).collect();
+            let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) else {
+                return Err(format!("unexpected `{}`", c));
+            };
+            for _ in 0..symbol.len() {
+                chars.next();
+            }
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Reads up to the closing `quote`, unescaping `\<quote>` and `\\`.
+fn read_quoted(chars: &mut Peekable<Chars>, quote: char) -> Result<String, String> {
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) if escaped == quote || (escaped == '\\' && quote != '/') => {
+                    value.push(escaped)
+                }
+                Some(escaped) => {
+                    value.push('\\');
+                    value.push(escaped);
+                }
+                None => break,
+            },
+            c if c == quote => return Ok(value),
+            c => value.push(c),
+        }
+    }
+    Err(format!("unterminated {}", quote))
+}
+
+/// Parses a duration literal, e.g. `90s` or `1h`, into ms.
+fn parse_duration(duration: &str) -> Result<i64, String> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (value, unit) = duration.split_at(split);
+    let value: i64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration `{}`", duration))?;
+    let nanos = match unit {
+        "ns" => 1,
+        "u" | "µ" => 1_000,
+        "ms" => 1_000_000,
+        "s" => 1_000_000_000,
+        "m" => 60 * 1_000_000_000,
+        "h" => 3_600 * 1_000_000_000,
+        "d" => 86_400 * 1_000_000_000,
+        "w" => 604_800 * 1_000_000_000,
+        _ => return Err(format!("invalid duration `{}`", duration)),
+    };
+    Ok(value.saturating_mul(nanos).div_euclid(1_000_000))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    now: i64,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn describe_next(&self) -> String {
+        match self.peek() {
+            Some(Token::Ident(ident)) => format!("`{}`", ident),
+            Some(Token::String(value)) => format!("'{}'", value),
+            Some(Token::Regex(regex)) => format!("/{}/", regex),
+            Some(Token::Number(number) | Token::Duration(number)) => format!("`{}`", number),
+            Some(Token::Symbol(symbol)) => format!("`{}`", symbol),
+            None => "end of query".to_string(),
+        }
+    }
+
+    fn accept_symbol(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Symbol(next)) if *next == symbol) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        if self.accept_symbol(symbol) {
+            return Ok(());
+        }
+        Err(format!(
+            "expected `{}`, found {}",
+            symbol,
+            self.describe_next()
+        ))
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case(keyword))
+    }
+
+    fn accept_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek_keyword(keyword) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        if self.accept_keyword(keyword) {
+            return Ok(());
+        }
+        Err(format!(
+            "expected {}, found {}",
+            keyword,
+            self.describe_next()
+        ))
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Ident(ident)) => {
+                let ident = ident.clone();
+                self.position += 1;
+                Ok(ident)
+            }
+            _ => Err(format!(
+                "expected identifier, found {}",
+                self.describe_next()
+            )),
+        }
+    }
+
+    /// Parses a measurement, the last part of `"db"."rp"."measurement"`.
+    fn measurement(&mut self) -> Result<String, String> {
+        let mut measurement = self.ident()?;
+        while self.accept_symbol(".") {
+            measurement = self.ident()?;
+        }
+        Ok(measurement)
+    }
+
+    fn limit(&mut self) -> Result<Option<usize>, String> {
+        if !self.accept_keyword("LIMIT") {
+            return Ok(None);
+        }
+        match self.next() {
+            Some(Token::Number(number)) => number
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("invalid limit `{}`", number)),
+            _ => Err("expected a limit".to_string()),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement, String> {
+        if self.accept_keyword("SELECT") {
+            return self.select().map(Statement::Select);
+        }
+        if !self.accept_keyword("SHOW") {
+            return Err(format!(
+                "expected SELECT or SHOW, found {}",
+                self.describe_next()
+            ));
+        }
+        if self.accept_keyword("MEASUREMENTS") {
+            let limit = self.limit()?;
+            return Ok(Statement::ShowMeasurements { limit });
+        }
+        if self.accept_keyword("FIELD") {
+            self.expect_keyword("KEYS")?;
+            let measurement = self.from()?;
+            return Ok(Statement::ShowFieldKeys { measurement });
+        }
+        self.expect_keyword("TAG")?;
+        if self.accept_keyword("KEYS") {
+            let measurement = self.from()?;
+            return Ok(Statement::ShowTagKeys { measurement });
+        }
+        self.expect_keyword("VALUES")?;
+        let measurement = self.from()?;
+        self.expect_keyword("WITH")?;
+        self.expect_keyword("KEY")?;
+        self.expect_symbol("=")?;
+        let key = self.ident()?;
+        Ok(Statement::ShowTagValues { measurement, key })
+    }
+
+    fn from(&mut self) -> Result<Option<String>, String> {
+        if self.accept_keyword("FROM") {
+            return self.measurement().map(Some);
+        }
+        Ok(None)
+    }
+
+    fn select(&mut self) -> Result<SelectStatement, String> {
+        let mut fields = vec![self.select_field()?];
+        while self.accept_symbol(",") {
+            fields.push(self.select_field()?);
+        }
+        self.expect_keyword("FROM")?;
+        let measurement = self.measurement()?;
+        let mut statement = SelectStatement {
+            fields,
+            measurement,
+            conditions: vec![],
+            start: None,
+            end: None,
+            interval: None,
+            group_by_tags: vec![],
+            fill: Fill::default(),
+            limit: None,
+        };
+        if self.accept_keyword("WHERE") {
+            self.condition(&mut statement)?;
+            while self.accept_keyword("AND") {
+                self.condition(&mut statement)?;
+            }
+            if self.peek_keyword("OR") {
+                return Err("OR conditions are not supported".to_string());
+            }
+        }
+        if self.accept_keyword("GROUP") {
+            self.expect_keyword("BY")?;
+            loop {
+                if self.accept_keyword("time") {
+                    self.expect_symbol("(")?;
+                    let interval = match self.next() {
+                        Some(Token::Duration(duration)) => parse_duration(&duration)?,
+                        _ => return Err("expected a GROUP BY time interval".to_string()),
+                    };
+                    if interval <= 0 {
+                        return Err("GROUP BY time interval must be at least 1ms".to_string());
+                    }
+                    statement.interval = Some(interval);
+                    self.expect_symbol(")")?;
+                } else {
+                    statement.group_by_tags.push(self.ident()?);
+                }
+                if !self.accept_symbol(",") {
+                    break;
+                }
+            }
+        }
+        if self.accept_keyword("fill") {
+            self.expect_symbol("(")?;
+            let negative = self.accept_symbol("-");
+            statement.fill = match self.next() {
+                Some(Token::Ident(fill)) if fill.eq_ignore_ascii_case("null") => Fill::Null,
+                Some(Token::Ident(fill)) if fill.eq_ignore_ascii_case("none") => Fill::None,
+                Some(Token::Ident(fill)) if fill.eq_ignore_ascii_case("previous") => Fill::Previous,
+                Some(Token::Number(number)) => {
+                    let value: f64 = number
+                        .parse()
+                        .map_err(|_| format!("invalid fill value `{}`", number))?;
+                    Fill::Value(if negative { -value } else { value })
+                }
+                _ => return Err("expected null, none, previous or a number to fill".to_string()),
+            };
+            self.expect_symbol(")")?;
+        }
+        statement.limit = self.limit()?;
+
+        let is_aggregate = statement.fields[0].function.is_some();
+        if statement
+            .fields
+            .iter()
+            .any(|field| field.function.is_some() != is_aggregate)
+        {
+            return Err("mixing aggregate and non-aggregate queries is not supported".to_string());
+        }
+        if !is_aggregate && statement.interval.is_some() {
+            return Err("GROUP BY requires at least one aggregate function".to_string());
+        }
+        Ok(statement)
+    }
+
+    fn select_field(&mut self) -> Result<SelectField, String> {
+        let name = self.ident()?;
+        let field = if self.accept_symbol("(") {
+            let function = Aggregate::parse(&name)
+                .ok_or_else(|| format!("unsupported function `{}`", name))?;
+            let field = self.ident()?;
+            self.expect_symbol(")")?;
+            SelectField {
+                function: Some(function),
+                field,
+                alias: None,
+            }
+        } else {
+            SelectField {
+                function: None,
+                field: name,
+                alias: None,
+            }
+        };
+        if self.accept_keyword("AS") {
+            let alias = self.ident()?;
+            return Ok(SelectField {
+                alias: Some(alias),
+                ..field
+            });
+        }
+        Ok(field)
+    }
+
+    fn condition(&mut self, statement: &mut SelectStatement) -> Result<(), String> {
+        if self.accept_symbol("(") {
+            self.condition(statement)?;
+            return self.expect_symbol(")");
+        }
+        let tag = self.ident()?;
+        let op = match self.next() {
+            Some(Token::Symbol(op)) => op,
+            _ => return Err(format!("expected a comparison after `{}`", tag)),
+        };
+        if tag.eq_ignore_ascii_case("time") {
+            let time = self.time()?;
+            match op {
+                ">" => statement.start = Some(time.saturating_add(1)),
+                ">=" => statement.start = Some(time),
+                "<" => statement.end = Some(time),
+                "<=" => statement.end = Some(time.saturating_add(1)),
+                "=" => {
+                    statement.start = Some(time);
+                    statement.end = Some(time.saturating_add(1));
+                }
+                _ => return Err(format!("invalid time comparison `{}`", op)),
+            }
+            return Ok(());
+        }
+        let (op, value) = match (op, self.next()) {
+            ("=", Some(Token::String(value))) => (TagOp::Eq, value),
+            ("!=" | "<>", Some(Token::String(value))) => (TagOp::Neq, value),
+            ("=~", Some(Token::Regex(regex))) => (TagOp::Re, regex),
+            ("!~", Some(Token::Regex(regex))) => (TagOp::Nre, regex),
+            _ => {
+                return Err(format!(
+                    "unsupported condition on `{}`, only tag comparisons to strings or regexes are",
+                    tag
+                ))
+            }
+        };
+        statement.conditions.push(TagCondition { tag, op, value });
+        Ok(())
+    }
+
+    /// Parses `now() [+|- duration]`, a RFC3339 date, a nanosecond
+    /// timestamp or a duration since the epoch into ms.
+    fn time(&mut self) -> Result<i64, String> {
+        match self.next() {
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("now") => {
+                self.expect_symbol("(")?;
+                self.expect_symbol(")")?;
+                let sign = if self.accept_symbol("-") {
+                    -1
+                } else if self.accept_symbol("+") {
+                    1
+                } else {
+                    return Ok(self.now);
+                };
+                match self.next() {
+                    Some(Token::Duration(duration)) => {
+                        Ok(self.now.saturating_add(sign * parse_duration(&duration)?))
+                    }
+                    _ => Err("expected a duration after now()".to_string()),
+                }
+            }
+            Some(Token::String(date)) => OffsetDateTime::parse(&date, &Rfc3339)
+                .map(|date_time| (date_time.unix_timestamp_nanos() / 1_000_000) as i64)
+                .map_err(|_| format!("invalid time '{}'", date)),
+            Some(Token::Number(number)) => number
+                .parse::<i64>()
+                .map(|nanos| nanos.div_euclid(1_000_000))
+                .map_err(|_| format!("invalid time `{}`", number)),
+            Some(Token::Duration(duration)) => parse_duration(&duration),
+            _ => Err("expected a time".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_select_and_show() {
+        let now = 10 * 3_600_000;
+        let statements = parse_statements(
+            r#"SELECT mean("usage") AS avg, max(usage) FROM "telegraf"."autogen".cpu
+            WHERE host = 'a' AND region =~ /^eu/ AND time > now() - 1h AND time <= '1970-01-01T10:00:00Z'
+            GROUP BY time(5m), host fill(none) LIMIT 10; SHOW TAG VALUES FROM cpu WITH KEY = "host""#,
+            now,
+        )
+        .unwrap();
+        let Statement::Select(select) = &statements[0] else {
+            panic!("not a select: {:?}", statements[0]);
+        };
+        assert_eq!(select.measurement, "cpu");
+        let columns: Vec<&str> = select.fields.iter().map(SelectField::column_name).collect();
+        assert_eq!(columns, &["avg", "max"]);
+        assert_eq!(select.fields[0].function, Some(Aggregate::Mean));
+        assert_eq!(select.conditions[1].op, TagOp::Re);
+        assert_eq!(select.conditions[1].value, "^eu");
+        assert_eq!(select.start, Some(now - 3_600_000 + 1));
+        assert_eq!(select.end, Some(now + 1));
+        assert_eq!(select.interval, Some(300_000));
+        assert_eq!(select.group_by_tags, &["host"]);
+        assert_eq!(select.fill, Fill::None);
+        assert_eq!(select.limit, Some(10));
+        assert_eq!(
+            statements[1],
+            Statement::ShowTagValues {
+                measurement: Some("cpu".to_string()),
+                key: "host".to_string()
+            }
+        );
+
+        assert!(parse_statements("SELECT usage FROM cpu GROUP BY time(1m)", now).is_err());
+        assert!(parse_statements("SELECT mean(usage), usage FROM cpu", now).is_err());
+        assert!(parse_statements("SELECT usage FROM cpu WHERE a = 'b' OR c = 'd'", now).is_err());
+        assert!(parse_statements("DROP MEASUREMENT cpu", now).is_err());
+    }
+}
@@ -1,23 +1,164 @@
 mod core;
+mod influxql;
+mod query;
+mod settings;
 
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use axum::{extract::State, Router, routing::post};
+use axum::{
+    body::Bytes,
+    extract::{Form, Query, State},
+    http::{header::CONTENT_ENCODING, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
 use storage::Storage;
 
-use self::core::{InfluxDbResult, InfluxDbStorage, decode_influx_lines_request};
+use self::core::{
+    decode_body, decode_influx_lines_request, InfluxDbError, InfluxDbResult, InfluxDbStorage,
+    Precision,
+};
+use self::query::Executor;
+pub use self::settings::{InfluxDbSettings, MissingTimestamp, StringFields};
+
+/// The version reported to clients, which only check that the server answers.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The query parameters of the v1 and v2 write endpoints, `org`,
+/// `rp` and the v1 credentials are accepted but ignored.
+#[derive(Debug, Deserialize)]
+struct WriteParams {
+    precision: Option<String>,
+    /// The v2 bucket.
+    bucket: Option<String>,
+    /// The v1 database.
+    db: Option<String>,
+}
 
 async fn write_handler_service(
     State(storage): State<InfluxDbStorage>,
-    body: String,
-) -> InfluxDbResult<()> {
-    let write_request = decode_influx_lines_request(body)?;
-    storage.write(write_request).await
+    Query(params): Query<WriteParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> InfluxDbResult<StatusCode> {
+    let missing_timestamp = storage.missing_timestamp(SystemTime::now());
+    let precision = match params.precision.as_deref() {
+        Some(precision) => precision.parse()?,
+        None => Precision::default(),
+    };
+    let content_encoding = headers
+        .get(CONTENT_ENCODING)
+        .map(|value| value.to_str())
+        .transpose()
+        .map_err(|err| InfluxDbError::BadRequest(format!("invalid content encoding: {}", err)))?;
+    let body = decode_body(content_encoding, body.to_vec())?;
+    let bucket_label = storage.bucket_label(params.bucket.or(params.db));
+    let (write_request, invalid_lines) = decode_influx_lines_request(
+        body,
+        precision,
+        bucket_label,
+        missing_timestamp,
+        storage.string_fields(),
+    );
+    storage.write(write_request, invalid_lines).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The parameters of an InfluxQL query, in the URL or a form body.
+#[derive(Debug, Default, Deserialize)]
+struct QueryParams {
+    db: Option<String>,
+    q: Option<String>,
+    epoch: Option<String>,
+}
+
+/// Executes the InfluxQL statements of `q`, each result holding its
+/// series or error, times being RFC3339 dates unless `epoch` is given.
+async fn query_handler_service(
+    State(storage): State<InfluxDbStorage>,
+    Query(params): Query<QueryParams>,
+    form: Option<Form<QueryParams>>,
+) -> (StatusCode, Json<Value>) {
+    let form = form.map(|Form(form)| form).unwrap_or_default();
+    let bad_request = |error: String| (StatusCode::BAD_REQUEST, Json(json!({ "error": error })));
+    let Some(q) = form.q.or(params.q) else {
+        return bad_request("missing required parameter \"q\"".to_string());
+    };
+    let epoch = match form.epoch.or(params.epoch) {
+        Some(epoch) => match epoch.parse::<Precision>() {
+            Ok(precision) => Some(precision),
+            Err(_) => return bad_request(format!("invalid epoch `{}`", epoch)),
+        },
+        None => None,
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    let statements = match influxql::parse_statements(&q, now) {
+        Ok(statements) => statements,
+        Err(err) => return bad_request(format!("error parsing query: {}", err)),
+    };
+
+    let executor = Executor {
+        storage: storage.storage(),
+        bucket_label: &storage.settings().bucket_label,
+        db: form.db.or(params.db),
+        epoch,
+        now,
+    };
+    let mut results = vec![];
+    for (statement_id, statement) in statements.into_iter().enumerate() {
+        let result = match executor.execute(statement).await {
+            Ok(series) if series.is_empty() => json!({ "statement_id": statement_id }),
+            Ok(series) => json!({ "statement_id": statement_id, "series": series }),
+            Err(err) => json!({ "statement_id": statement_id, "error": err }),
+        };
+        results.push(result);
+    }
+    (StatusCode::OK, Json(json!({ "results": results })))
+}
+
+async fn ping_handler_service() -> impl IntoResponse {
+    (
+        StatusCode::NO_CONTENT,
+        [("X-Influxdb-Version", VERSION), ("X-Influxdb-Build", "OSS")],
+    )
+}
+
+async fn health_handler_service() -> Json<Value> {
+    Json(json!({
+        "name": "influxdb",
+        "message": "ready for queries and writes",
+        "status": "pass",
+        "checks": [],
+        "version": VERSION,
+    }))
 }
 
-pub fn influxdb_router(storage: Arc<Storage>) -> Router {
-    let ctx = InfluxDbStorage::new(storage);
+/// Serves the InfluxDB v1 and v2 write endpoints, as `/influxdb` did before, along
+/// with `/ping` and `/health` so that Telegraf and the Influx clients accept the server,
+/// and the v1 `/query` endpoint for a subset of InfluxQL.
+pub fn influxdb_router(storage: Arc<Storage>, settings: InfluxDbSettings) -> Router {
+    let ctx = InfluxDbStorage::new(storage, settings);
     Router::new()
         .route("/influxdb", post(write_handler_service))
+        .route("/write", post(write_handler_service))
+        .route("/api/v2/write", post(write_handler_service))
+        .route(
+            "/ping",
+            get(ping_handler_service).head(ping_handler_service),
+        )
+        .route("/health", get(health_handler_service))
+        .route(
+            "/query",
+            get(query_handler_service).post(query_handler_service),
+        )
         .with_state(ctx)
 }
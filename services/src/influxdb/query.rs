@@ -0,0 +1,557 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use fts::query::Query as NativeQuery;
+use serde_json::{json, Value as JsonValue};
+use storage::{Label, Sample, Storage, SERIES_NAME_LABEL};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use super::{
+    core::Precision,
+    influxql::{Aggregate, Fill, SelectStatement, Statement, TagOp},
+};
+use crate::prometheus::remote::types::{
+    convert_prom_query_to_native_query, label_matcher::Type, LabelMatcher, Query,
+};
+
+/// The most `GROUP BY time(...)` intervals a series of a result can have.
+const MAX_INTERVALS: i64 = 100_000;
+
+/// Executes InfluxQL statements on the series written through line protocol,
+/// named `<measurement>_<field>`. Without a `FROM` measurement, names are split
+/// at their first `_`, measurements named with one can only be queried by name.
+pub struct Executor<'a> {
+    pub storage: &'a Storage,
+    /// The label the bucket, or v1 database, of a write is stored as, none if empty.
+    pub bucket_label: &'a str,
+    /// The database of the query, all of them if none.
+    pub db: Option<String>,
+    /// How times are returned, RFC3339 dates if none.
+    pub epoch: Option<Precision>,
+    /// The time of `now()` (in ms).
+    pub now: i64,
+}
+
+impl Executor<'_> {
+    /// Returns the series of the result of `statement`.
+    pub async fn execute(&self, statement: Statement) -> Result<Vec<JsonValue>, String> {
+        match statement {
+            Statement::Select(select) => self.select(select).await,
+            Statement::ShowMeasurements { limit } => {
+                let mut measurements = BTreeSet::new();
+                for name in self.names(None).await? {
+                    if let Some((measurement, _)) = split_name(&name, None) {
+                        measurements.insert(measurement.to_string());
+                    }
+                }
+                let values: Vec<JsonValue> = measurements
+                    .into_iter()
+                    .take(limit.unwrap_or(usize::MAX))
+                    .map(|measurement| json!([measurement]))
+                    .collect();
+                Ok(series_result("measurements", &["name"], values))
+            }
+            Statement::ShowFieldKeys { measurement } => {
+                let mut fields: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+                for name in self.names(measurement.as_deref()).await? {
+                    if let Some((measurement, field)) = split_name(&name, measurement.as_deref()) {
+                        fields
+                            .entry(measurement.to_string())
+                            .or_default()
+                            .insert(field.to_string());
+                    }
+                }
+                Ok(fields
+                    .into_iter()
+                    .flat_map(|(measurement, fields)| {
+                        let values = fields
+                            .into_iter()
+                            .map(|field| json!([field, "float"]))
+                            .collect();
+                        series_result(&measurement, &["fieldKey", "fieldType"], values)
+                    })
+                    .collect())
+            }
+            Statement::ShowTagKeys { measurement } => {
+                let tags = self
+                    .measurement_tags(measurement.as_deref(), |label| Some(vec![label.name]))
+                    .await?;
+                Ok(tags
+                    .into_iter()
+                    .flat_map(|(measurement, values)| {
+                        series_result(
+                            &measurement,
+                            &["tagKey"],
+                            values.into_iter().map(|row| json!(row)).collect(),
+                        )
+                    })
+                    .collect())
+            }
+            Statement::ShowTagValues { measurement, key } => {
+                let tags = self
+                    .measurement_tags(measurement.as_deref(), |label| {
+                        (label.name == key).then(|| vec![label.name, label.value])
+                    })
+                    .await?;
+                Ok(tags
+                    .into_iter()
+                    .flat_map(|(measurement, values)| {
+                        series_result(
+                            &measurement,
+                            &["key", "value"],
+                            values.into_iter().map(|row| json!(row)).collect(),
+                        )
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// Returns the names of the series of `measurement`, or of all of them.
+    async fn names(&self, measurement: Option<&str>) -> Result<Vec<String>, String> {
+        let query = self.query(measurement_matcher(measurement))?;
+        self.storage
+            .label_values(SERIES_NAME_LABEL, Some(query))
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    /// Returns the distinct `row`s of the tags of the series of each measurement.
+    async fn measurement_tags(
+        &self,
+        measurement: Option<&str>,
+        row: impl Fn(Label) -> Option<Vec<String>>,
+    ) -> Result<BTreeMap<String, BTreeSet<Vec<String>>>, String> {
+        let query = self.query(measurement_matcher(measurement))?;
+        let series = self
+            .storage
+            .series(query)
+            .await
+            .map_err(|err| err.to_string())?;
+        let mut tags: BTreeMap<String, BTreeSet<Vec<String>>> = BTreeMap::new();
+        for labels in series {
+            let Some(name) = labels.iter().find(|label| label.name == SERIES_NAME_LABEL) else {
+                continue;
+            };
+            let Some((series_measurement, _)) = split_name(&name.value, measurement) else {
+                continue;
+            };
+            let rows = tags.entry(series_measurement.to_string()).or_default();
+            for label in labels.iter() {
+                if label.name == SERIES_NAME_LABEL || label.name == self.bucket_label {
+                    continue;
+                }
+                if let Some(row) = row(label.clone()) {
+                    rows.insert(row);
+                }
+            }
+        }
+        tags.retain(|_, rows| !rows.is_empty());
+        Ok(tags)
+    }
+
+    async fn select(&self, select: SelectStatement) -> Result<Vec<JsonValue>, String> {
+        let start = select.start.unwrap_or(i64::MIN);
+        // As in InfluxDB, intervals end now unless the query says otherwise.
+        let end = match (select.end, select.interval) {
+            (Some(end), _) => end,
+            (None, Some(_)) => self.now.saturating_add(1),
+            (None, None) => i64::MAX,
+        };
+
+        // The samples of each field, by the values of the GROUP BY tags.
+        let mut groups: BTreeMap<Vec<String>, Vec<Vec<Sample>>> = BTreeMap::new();
+        for (index, field) in select.fields.iter().enumerate() {
+            let mut matchers = vec![matcher(
+                Type::Eq,
+                SERIES_NAME_LABEL,
+                format!("{}_{}", select.measurement, field.field),
+            )];
+            for condition in &select.conditions {
+                matchers.push(match condition.op {
+                    TagOp::Eq => matcher(Type::Eq, &condition.tag, condition.value.clone()),
+                    TagOp::Neq => matcher(Type::Neq, &condition.tag, condition.value.clone()),
+                    TagOp::Re => {
+                        matcher(Type::Re, &condition.tag, anchored_regex(&condition.value))
+                    }
+                    TagOp::Nre => {
+                        matcher(Type::Nre, &condition.tag, anchored_regex(&condition.value))
+                    }
+                });
+            }
+            let query = self.query(matchers)?;
+            let series = self
+                .storage
+                .read(query, start, end)
+                .await
+                .map_err(|err| err.to_string())?;
+            for series in series {
+                let (labels, samples, _) = series.into_parts();
+                let key = select
+                    .group_by_tags
+                    .iter()
+                    .map(|tag| {
+                        labels
+                            .iter()
+                            .find(|label| label.name == *tag)
+                            .map_or(String::new(), |label| label.value.clone())
+                    })
+                    .collect();
+                groups
+                    .entry(key)
+                    .or_insert_with(|| vec![vec![]; select.fields.len()])[index]
+                    .extend(samples);
+            }
+        }
+
+        let mut columns = vec!["time"];
+        columns.extend(select.fields.iter().map(|field| field.column_name()));
+        let mut results = vec![];
+        for (key, samples) in groups {
+            if samples.iter().all(Vec::is_empty) {
+                continue;
+            }
+            let mut rows = match select.interval {
+                Some(interval) => interval_rows(&select, &samples, start, end, interval)?,
+                None if select.fields[0].function.is_some() => {
+                    let values = select
+                        .fields
+                        .iter()
+                        .zip(&samples)
+                        .map(|(field, samples)| aggregate(field.function.unwrap(), samples))
+                        .collect();
+                    vec![(select.start.unwrap_or(0), values)]
+                }
+                None => raw_rows(samples),
+            };
+            rows.truncate(select.limit.unwrap_or(usize::MAX));
+            if rows.is_empty() {
+                continue;
+            }
+            let values = rows
+                .into_iter()
+                .map(|(timestamp, values)| {
+                    let mut row = vec![self.time(timestamp)];
+                    row.extend(values.into_iter().map(|value| {
+                        value
+                            .and_then(serde_json::Number::from_f64)
+                            .map_or(JsonValue::Null, JsonValue::Number)
+                    }));
+                    JsonValue::Array(row)
+                })
+                .collect();
+            let mut result = json!({
+                "name": select.measurement,
+                "columns": columns,
+                "values": JsonValue::Array(values),
+            });
+            if !select.group_by_tags.is_empty() {
+                let tags: serde_json::Map<String, JsonValue> = select
+                    .group_by_tags
+                    .iter()
+                    .cloned()
+                    .zip(key.into_iter().map(JsonValue::String))
+                    .collect();
+                result["tags"] = JsonValue::Object(tags);
+            }
+            results.push(result);
+        }
+        Ok(results)
+    }
+
+    /// Converts label matchers, along with the one of the database
+    /// if any, into a native query.
+    fn query(&self, mut matchers: Vec<LabelMatcher>) -> Result<NativeQuery, String> {
+        if let Some(db) = self.db.as_ref().filter(|_| !self.bucket_label.is_empty()) {
+            matchers.push(matcher(Type::Eq, self.bucket_label, db.clone()));
+        }
+        convert_prom_query_to_native_query(Query {
+            start_timestamp_ms: 0,
+            end_timestamp_ms: 0,
+            matchers,
+            hints: None,
+        })
+        .map_err(|err| err.to_string())
+    }
+
+    fn time(&self, timestamp: i64) -> JsonValue {
+        match self.epoch {
+            Some(precision) => json!(precision.convert_millis(timestamp)),
+            None => OffsetDateTime::from_unix_timestamp_nanos(timestamp as i128 * 1_000_000)
+                .ok()
+                .and_then(|date_time| date_time.format(&Rfc3339).ok())
+                .map_or(JsonValue::Null, JsonValue::String),
+        }
+    }
+}
+
+type Row = (i64, Vec<Option<f64>>);
+
+/// Returns a row by `GROUP BY time(interval)` interval of `[start, end)`, from the first
+/// one with samples if `start` is unbounded, without samples ones being filled.
+fn interval_rows(
+    select: &SelectStatement,
+    samples: &[Vec<Sample>],
+    start: i64,
+    end: i64,
+    interval: i64,
+) -> Result<Vec<Row>, String> {
+    let mut intervals: BTreeMap<i64, Vec<Vec<Sample>>> = BTreeMap::new();
+    for (index, samples) in samples.iter().enumerate() {
+        for sample in samples {
+            let interval_start = sample.timestamp.div_euclid(interval) * interval;
+            intervals
+                .entry(interval_start)
+                .or_insert_with(|| vec![vec![]; select.fields.len()])[index]
+                .push(sample.clone());
+        }
+    }
+    let values = |samples: &[Vec<Sample>]| -> Vec<Option<f64>> {
+        select
+            .fields
+            .iter()
+            .zip(samples)
+            .map(|(field, samples)| aggregate(field.function.unwrap(), samples))
+            .collect()
+    };
+    if select.fill == Fill::None {
+        return Ok(intervals
+            .iter()
+            .map(|(timestamp, samples)| (*timestamp, values(samples)))
+            .collect());
+    }
+
+    let Some(first) = intervals.keys().next() else {
+        return Ok(vec![]);
+    };
+    let first = if start == i64::MIN {
+        *first
+    } else {
+        start.div_euclid(interval) * interval
+    };
+    let last = (end - 1).div_euclid(interval) * interval;
+    if (last - first) / interval >= MAX_INTERVALS {
+        return Err(format!(
+            "too many GROUP BY time intervals, at most {} are allowed",
+            MAX_INTERVALS
+        ));
+    }
+    let mut rows = vec![];
+    let mut previous = vec![None; select.fields.len()];
+    let mut timestamp = first;
+    while timestamp <= last {
+        let mut row = match intervals.get(&timestamp) {
+            Some(samples) => values(samples),
+            None => vec![None; select.fields.len()],
+        };
+        for (value, previous) in row.iter_mut().zip(previous.iter_mut()) {
+            match (select.fill, &value) {
+                (Fill::Previous, None) => *value = *previous,
+                (Fill::Value(fill), None) => *value = Some(fill),
+                _ => {}
+            }
+            *previous = *value;
+        }
+        rows.push((timestamp, row));
+        timestamp += interval;
+    }
+    Ok(rows)
+}
+
+/// Returns a row by timestamp of the samples of the fields.
+fn raw_rows(samples: Vec<Vec<Sample>>) -> Vec<Row> {
+    let mut rows: BTreeMap<i64, Vec<Option<f64>>> = BTreeMap::new();
+    let num_fields = samples.len();
+    for (index, samples) in samples.into_iter().enumerate() {
+        for sample in samples {
+            rows.entry(sample.timestamp)
+                .or_insert_with(|| vec![None; num_fields])[index] = Some(sample.value);
+        }
+    }
+    rows.into_iter().collect()
+}
+
+fn aggregate(function: Aggregate, samples: &[Sample]) -> Option<f64> {
+    if samples.is_empty() {
+        return None;
+    }
+    let values = samples.iter().map(|sample| sample.value);
+    let value = match function {
+        Aggregate::Count => samples.len() as f64,
+        Aggregate::First => samples.iter().min_by_key(|sample| sample.timestamp)?.value,
+        Aggregate::Last => samples.iter().max_by_key(|sample| sample.timestamp)?.value,
+        Aggregate::Max => values.fold(f64::NEG_INFINITY, f64::max),
+        Aggregate::Min => values.fold(f64::INFINITY, f64::min),
+        Aggregate::Mean => values.sum::<f64>() / samples.len() as f64,
+        Aggregate::Median => {
+            let mut values: Vec<f64> = values.collect();
+            values.sort_by(f64::total_cmp);
+            let middle = values.len() / 2;
+            if values.len().is_multiple_of(2) {
+                (values[middle - 1] + values[middle]) / 2.0
+            } else {
+                values[middle]
+            }
+        }
+        Aggregate::Spread => {
+            let (min, max) = values
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| {
+                    (min.min(value), max.max(value))
+                });
+            max - min
+        }
+        Aggregate::Sum => values.sum(),
+    };
+    Some(value)
+}
+
+/// Splits a series name into its measurement and field, at the first
+/// `_` unless the measurement is known.
+fn split_name<'a>(name: &'a str, measurement: Option<&'a str>) -> Option<(&'a str, &'a str)> {
+    match measurement {
+        Some(measurement) => name
+            .strip_prefix(measurement)
+            .and_then(|field| field.strip_prefix('_'))
+            .filter(|field| !field.is_empty())
+            .map(|field| (measurement, field)),
+        None => name.split_once('_'),
+    }
+}
+
+fn measurement_matcher(measurement: Option<&str>) -> Vec<LabelMatcher> {
+    measurement
+        .map(|measurement| {
+            let regex = format!("{}_.+", regex::escape(measurement));
+            matcher(Type::Re, SERIES_NAME_LABEL, regex)
+        })
+        .into_iter()
+        .collect()
+}
+
+fn matcher(r#type: Type, name: &str, value: String) -> LabelMatcher {
+    LabelMatcher {
+        r#type: r#type as i32,
+        name: name.to_string(),
+        value,
+    }
+}
+
+/// Converts an InfluxQL regex, which matches anywhere in a value
+/// unless anchored, into a fully anchored Prometheus one.
+fn anchored_regex(regex: &str) -> String {
+    let (prefix, regex) = match regex.strip_prefix('^') {
+        Some(regex) => ("", regex),
+        None => (".*", regex),
+    };
+    let (suffix, regex) = match regex
+        .strip_suffix('$')
+        .filter(|regex| !regex.ends_with('\\'))
+    {
+        Some(regex) => ("", regex),
+        None => (".*", regex),
+    };
+    format!("{}(?:{}){}", prefix, regex, suffix)
+}
+
+fn series_result(name: &str, columns: &[&str], values: Vec<JsonValue>) -> Vec<JsonValue> {
+    if values.is_empty() {
+        return vec![];
+    }
+    vec![json!({
+        "name": name,
+        "columns": columns,
+        "values": values,
+    })]
+}
+
+#[cfg(test)]
+mod tests {
+    use storage::{StorageFactory, StorageSettings, WalFsyncPolicy};
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::influxdb::{
+        core::decode_influx_lines_request, influxql::parse_statements, settings::StringFields,
+    };
+
+    #[tokio::test]
+    async fn execute_select_and_show() {
+        let directory = TempDir::new("influxql").unwrap();
+        let settings = StorageSettings::Native {
+            path: directory.path().to_str().unwrap().to_string(),
+            block_duration: 120,
+            wal_fsync_policy: WalFsyncPolicy::Never,
+            max_exemplars: 0,
+        };
+        let storage = StorageFactory::open(&settings).unwrap();
+        let lines = "cpu,host=a usage=1 0\ncpu,host=a usage=3 30\ncpu,host=b usage=5 90\ncpu,host=a idle=2 0\n";
+        let bucket = Label {
+            name: "bucket".to_string(),
+            value: "telegraf".to_string(),
+        };
+        let (request, _) = decode_influx_lines_request(
+            lines.to_string(),
+            Precision::Seconds,
+            Some(bucket),
+            None,
+            StringFields::Drop,
+        );
+        storage.write(request.into_timeseries()).await.unwrap();
+
+        let executor = Executor {
+            storage: &storage,
+            bucket_label: "bucket",
+            db: Some("telegraf".to_string()),
+            epoch: Some(Precision::Seconds),
+            now: 120_000,
+        };
+        let execute = |query: &str| {
+            let statement = parse_statements(query, executor.now).unwrap().remove(0);
+            executor.execute(statement)
+        };
+
+        let series =
+            execute("SELECT mean(usage), count(usage) FROM cpu WHERE time >= 0s GROUP BY time(1m)")
+                .await
+                .unwrap();
+        assert_eq!(series[0]["columns"], json!(["time", "mean", "count"]));
+        assert_eq!(
+            series[0]["values"],
+            json!([[0, 2.0, 2.0], [60, 5.0, 1.0], [120, null, null]])
+        );
+
+        let series = execute("SELECT last(usage) FROM cpu WHERE host =~ /b/ GROUP BY host")
+            .await
+            .unwrap();
+        assert_eq!(
+            series,
+            vec![json!({
+                "name": "cpu",
+                "tags": {"host": "b"},
+                "columns": ["time", "last"],
+                "values": [[0, 5.0]],
+            })]
+        );
+
+        let series = execute("SELECT usage FROM cpu WHERE host = 'a' LIMIT 1")
+            .await
+            .unwrap();
+        assert_eq!(series[0]["values"], json!([[0, 1.0]]));
+
+        let series = execute("SHOW MEASUREMENTS").await.unwrap();
+        assert_eq!(series[0]["values"], json!([["cpu"]]));
+        let series = execute("SHOW FIELD KEYS FROM cpu").await.unwrap();
+        assert_eq!(
+            series[0]["values"],
+            json!([["idle", "float"], ["usage", "float"]])
+        );
+        let series = execute("SHOW TAG VALUES WITH KEY = host").await.unwrap();
+        assert_eq!(series[0]["values"], json!([["host", "a"], ["host", "b"]]));
+
+        let executor = Executor {
+            db: Some("other".to_string()),
+            ..executor
+        };
+        let statement = parse_statements("SHOW TAG KEYS", 0).unwrap().remove(0);
+        assert!(executor.execute(statement).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,61 @@
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InfluxDbSettings {
+    /// The label the bucket, or v1 database, of a write is stored as, none if empty.
+    #[serde(default = "default_bucket_label")]
+    pub bucket_label: String,
+
+    /// What to do with the lines without a timestamp.
+    #[serde(default)]
+    pub missing_timestamp: MissingTimestamp,
+
+    /// Whether the valid lines of a write with invalid ones are written.
+    #[serde(default = "default_partial_writes")]
+    pub partial_writes: bool,
+
+    /// What the string fields of the lines become.
+    #[serde(default)]
+    pub string_fields: StringFields,
+}
+
+impl Default for InfluxDbSettings {
+    fn default() -> Self {
+        InfluxDbSettings {
+            bucket_label: default_bucket_label(),
+            missing_timestamp: MissingTimestamp::default(),
+            partial_writes: default_partial_writes(),
+            string_fields: StringFields::default(),
+        }
+    }
+}
+
+/// The timestamp of the lines written without one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingTimestamp {
+    /// The time the server received the write at, as InfluxDB does.
+    #[default]
+    Arrival,
+    /// None, the lines are dropped.
+    Skip,
+}
+
+/// What the string fields of the lines become, samples only hold numbers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StringFields {
+    /// Nothing, they are dropped.
+    #[default]
+    Drop,
+    /// Labels of the series of the other fields of their line.
+    Label,
+}
+
+fn default_partial_writes() -> bool {
+    true
+}
+
+fn default_bucket_label() -> String {
+    "bucket".to_string()
+}
@@ -1,2 +1,4 @@
+mod http;
 pub mod influxdb;
 pub mod prometheus;
+pub mod otlp;
@@ -0,0 +1,917 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use storage::{
+    BucketSpan, CounterResetHint, Exemplar as NativeExemplar, HistogramSample, Label,
+    MetricMetadata, MetricType, Sample, TimeSeries, TimeSeriesInfo, SERIES_NAME_LABEL,
+};
+
+use super::types::{
+    any_value, exemplar, metric, number_data_point, AggregationTemporality, AnyValue, Buckets,
+    DataPointFlags, Exemplar, ExponentialHistogramDataPoint, HistogramDataPoint, KeyValue, Metric,
+    NumberDataPoint, SummaryDataPoint,
+};
+use crate::prometheus::engine::STALE_NAN;
+
+/// The smallest and largest schemas of native histograms, finer exponential
+/// histograms are downscaled and coarser ones rejected.
+const MIN_SCHEMA: i32 = -4;
+const MAX_SCHEMA: i32 = 8;
+
+/// The Prometheus words of the UCUM units of OTLP metrics.
+const UNITS: [(&str, &str); 26] = [
+    ("d", "days"),
+    ("h", "hours"),
+    ("min", "minutes"),
+    ("s", "seconds"),
+    ("ms", "milliseconds"),
+    ("us", "microseconds"),
+    ("ns", "nanoseconds"),
+    ("By", "bytes"),
+    ("KiBy", "kibibytes"),
+    ("MiBy", "mebibytes"),
+    ("GiBy", "gibibytes"),
+    ("TiBy", "tebibytes"),
+    ("KBy", "kilobytes"),
+    ("MBy", "megabytes"),
+    ("GBy", "gigabytes"),
+    ("TBy", "terabytes"),
+    ("m", "meters"),
+    ("V", "volts"),
+    ("A", "amperes"),
+    ("J", "joules"),
+    ("W", "watts"),
+    ("g", "grams"),
+    ("Cel", "celsius"),
+    ("Hz", "hertz"),
+    ("%", "percent"),
+    ("1", ""),
+];
+
+/// The Prometheus words of the time units a unit can be per, e.g. `By/s`.
+const PER_UNITS: [(&str, &str); 7] = [
+    ("s", "second"),
+    ("m", "minute"),
+    ("h", "hour"),
+    ("d", "day"),
+    ("w", "week"),
+    ("mo", "month"),
+    ("y", "year"),
+];
+
+/// How long the total of an accumulated series is kept without new deltas, a
+/// series accumulated again afterwards restarts from 0 as after a counter reset.
+const TOTALS_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// Converts the metrics of OTLP exports into series. Delta sums and histograms
+/// are accumulated into counters, from 0 when a series is first seen. The
+/// deltas of an export are added to the totals as it is converted, so that
+/// concurrent exports of a series never write a lower total, and are rolled
+/// back if it is not written.
+#[derive(Debug, Default)]
+pub(crate) struct MetricsConverter {
+    /// The running total of each accumulated series, by series id.
+    totals: HashMap<u64, Total>,
+    /// When totals without recent deltas were last evicted.
+    evicted_at: Option<Instant>,
+}
+
+/// The running total of an accumulated series.
+#[derive(Debug, Clone, Copy)]
+struct Total {
+    value: f64,
+    updated_at: Instant,
+}
+
+/// The series and metadata of an export, and the data points rejected from it.
+#[derive(Debug, Default)]
+pub(crate) struct Conversion {
+    pub series: Vec<TimeSeries>,
+    pub metadata: Vec<MetricMetadata>,
+    pub rejected_data_points: i64,
+    pub errors: BTreeSet<String>,
+    /// The sum of the deltas of each accumulated series, see `MetricsConverter::rollback`.
+    pub deltas: HashMap<u64, f64>,
+}
+
+/// The series of a conversion by id, in the order they were first seen,
+/// and the deltas accumulated into them.
+#[derive(Debug, Default)]
+struct SeriesSet {
+    ids: HashMap<u64, usize>,
+    series: Vec<TimeSeries>,
+    deltas: HashMap<u64, f64>,
+}
+
+impl SeriesSet {
+    fn get_mut(&mut self, labels: BTreeMap<String, String>) -> (u64, &mut TimeSeries) {
+        let labels = labels
+            .into_iter()
+            .map(|(name, value)| Label { name, value })
+            .collect();
+        let info = TimeSeriesInfo::new(labels);
+        let index = *self.ids.entry(info.id).or_insert_with(|| {
+            self.series.push(TimeSeries::new(info.labels, vec![]));
+            self.series.len() - 1
+        });
+        (info.id, &mut self.series[index])
+    }
+
+    /// Pushes a sample to the series of `labels`, accumulated on top of
+    /// `totals` if `delta`.
+    fn push_sample(
+        &mut self,
+        labels: BTreeMap<String, String>,
+        timestamp: i64,
+        value: f64,
+        delta: bool,
+        totals: &HashMap<u64, Total>,
+    ) -> &mut TimeSeries {
+        let (id, _) = self.get_mut(labels);
+        let value = if delta && value.to_bits() != STALE_NAN {
+            let series_delta = self.deltas.entry(id).or_insert(0.0);
+            *series_delta += value;
+            totals.get(&id).map_or(0.0, |total| total.value) + *series_delta
+        } else {
+            value
+        };
+        let series = &mut self.series[self.ids[&id]];
+        series.push(Sample { timestamp, value });
+        series
+    }
+}
+
+impl MetricsConverter {
+    /// Converts the metrics of an export, adding its deltas to the totals.
+    /// Once per `TOTALS_RETENTION`, forgets the totals without deltas for as long.
+    pub fn convert(
+        &mut self,
+        resource_metrics: Vec<super::types::opentelemetry::proto::metrics::v1::ResourceMetrics>,
+        now: Instant,
+    ) -> Conversion {
+        let mut conversion = Conversion::default();
+        let mut series = SeriesSet::default();
+        let mut metadata = BTreeMap::new();
+        for resource_metrics in resource_metrics {
+            let resource_attributes = resource_metrics
+                .resource
+                .map(|resource| resource.attributes)
+                .unwrap_or_default();
+            let mut resource_labels = attribute_labels(&resource_attributes, "");
+            let attribute = |key: &str| {
+                resource_attributes
+                    .iter()
+                    .find(|attribute| attribute.key == key)
+                    .and_then(|attribute| attribute.value.as_ref())
+                    .map(any_value_to_string)
+            };
+            if let Some(service_name) = attribute("service.name") {
+                let job = match attribute("service.namespace") {
+                    Some(namespace) => format!("{}/{}", namespace, service_name),
+                    None => service_name,
+                };
+                resource_labels.insert("job".to_string(), job);
+            }
+            if let Some(instance) = attribute("service.instance.id") {
+                resource_labels.insert("instance".to_string(), instance);
+            }
+
+            for scope_metrics in resource_metrics.scope_metrics {
+                let mut scope_labels = resource_labels.clone();
+                if let Some(scope) = scope_metrics.scope {
+                    scope_labels.extend(attribute_labels(&scope.attributes, "otel_scope_"));
+                    if !scope.name.is_empty() {
+                        scope_labels.insert("otel_scope_name".to_string(), scope.name);
+                    }
+                    if !scope.version.is_empty() {
+                        scope_labels.insert("otel_scope_version".to_string(), scope.version);
+                    }
+                }
+                for metric in scope_metrics.metrics {
+                    let Some(family) =
+                        self.convert_metric(metric, &scope_labels, &mut series, &mut conversion)
+                    else {
+                        continue;
+                    };
+                    metadata.insert(family.metric_family_name.clone(), family);
+                }
+            }
+        }
+        conversion.series = series.series;
+        conversion.deltas = series.deltas;
+        conversion.metadata = metadata.into_values().collect();
+
+        for (id, delta) in conversion.deltas.iter() {
+            let total = self.totals.entry(*id).or_insert(Total {
+                value: 0.0,
+                updated_at: now,
+            });
+            total.value += delta;
+            total.updated_at = now;
+        }
+        self.evict_totals(now);
+        conversion
+    }
+
+    /// Removes the deltas of a conversion which could not be written from the
+    /// totals, the client retries the export with them. A series converted
+    /// again in the meantime was written with them, until the export is
+    /// retried its next totals are lower.
+    pub fn rollback(&mut self, deltas: HashMap<u64, f64>) {
+        for (id, delta) in deltas {
+            if let Some(total) = self.totals.get_mut(&id) {
+                total.value -= delta;
+            }
+        }
+    }
+
+    fn evict_totals(&mut self, now: Instant) {
+        let evicted_at = *self.evicted_at.get_or_insert(now);
+        if now.duration_since(evicted_at) >= TOTALS_RETENTION {
+            self.totals
+                .retain(|_, total| now.duration_since(total.updated_at) < TOTALS_RETENTION);
+            self.evicted_at = Some(now);
+        }
+    }
+
+    /// Converts the data points of `metric`, returning the metadata of its family.
+    fn convert_metric(
+        &self,
+        metric: Metric,
+        scope_labels: &BTreeMap<String, String>,
+        series: &mut SeriesSet,
+        conversion: &mut Conversion,
+    ) -> Option<MetricMetadata> {
+        let Some(data) = metric.data else {
+            conversion
+                .errors
+                .insert(format!("metric `{}` has no data", metric.name));
+            return None;
+        };
+        let metric_type = match &data {
+            metric::Data::Gauge(_) => MetricType::Gauge,
+            metric::Data::Sum(sum) if sum.is_monotonic => MetricType::Counter,
+            metric::Data::Sum(_) => MetricType::Gauge,
+            metric::Data::Histogram(_) | metric::Data::ExponentialHistogram(_) => {
+                MetricType::Histogram
+            }
+            metric::Data::Summary(_) => MetricType::Summary,
+        };
+        let (name, unit) = metric_name(&metric.name, &metric.unit, metric_type);
+        let reject = |conversion: &mut Conversion, error: String| {
+            conversion.rejected_data_points += 1;
+            conversion
+                .errors
+                .insert(format!("metric `{}`: {}", metric.name, error));
+        };
+        let point_labels = |attributes: &[KeyValue], name: &str| {
+            let mut labels = scope_labels.clone();
+            labels.extend(attribute_labels(attributes, ""));
+            labels.insert(SERIES_NAME_LABEL.to_string(), name.to_string());
+            labels
+        };
+
+        match data {
+            metric::Data::Gauge(gauge) => {
+                for point in gauge.data_points {
+                    let labels = point_labels(&point.attributes, &name);
+                    if let Err(err) = self.convert_number(point, labels, false, series) {
+                        reject(conversion, err);
+                    }
+                }
+            }
+            metric::Data::Sum(sum) => {
+                let delta = sum.aggregation_temporality == AggregationTemporality::Delta as i32;
+                for point in sum.data_points {
+                    let labels = point_labels(&point.attributes, &name);
+                    if let Err(err) = self.convert_number(point, labels, delta, series) {
+                        reject(conversion, err);
+                    }
+                }
+            }
+            metric::Data::Histogram(histogram) => {
+                let delta =
+                    histogram.aggregation_temporality == AggregationTemporality::Delta as i32;
+                for point in histogram.data_points {
+                    let labels = point_labels(&point.attributes, &name);
+                    if let Err(err) = self.convert_histogram(point, labels, delta, series) {
+                        reject(conversion, err);
+                    }
+                }
+            }
+            metric::Data::ExponentialHistogram(histogram) => {
+                let delta =
+                    histogram.aggregation_temporality == AggregationTemporality::Delta as i32;
+                for point in histogram.data_points {
+                    if delta {
+                        reject(
+                            conversion,
+                            "delta exponential histograms are not supported".to_string(),
+                        );
+                        continue;
+                    }
+                    let labels = point_labels(&point.attributes, &name);
+                    if let Err(err) = convert_exponential_histogram(point, labels, series) {
+                        reject(conversion, err);
+                    }
+                }
+            }
+            metric::Data::Summary(summary) => {
+                for point in summary.data_points {
+                    let labels = point_labels(&point.attributes, &name);
+                    if let Err(err) = convert_summary(point, labels, series) {
+                        reject(conversion, err);
+                    }
+                }
+            }
+        }
+        Some(MetricMetadata {
+            metric_family_name: name,
+            metric_type,
+            help: metric.description,
+            unit,
+        })
+    }
+
+    fn convert_number(
+        &self,
+        point: NumberDataPoint,
+        labels: BTreeMap<String, String>,
+        delta: bool,
+        series: &mut SeriesSet,
+    ) -> Result<(), String> {
+        let timestamp = timestamp(point.time_unix_nano)?;
+        let value = if no_recorded_value(point.flags) {
+            f64::from_bits(STALE_NAN)
+        } else {
+            match point.value {
+                Some(number_data_point::Value::AsDouble(value)) => value,
+                Some(number_data_point::Value::AsInt(value)) => value as f64,
+                None => return Err("data point without a value".to_string()),
+            }
+        };
+        let series = series.push_sample(labels, timestamp, value, delta, &self.totals);
+        for exemplar in point.exemplars {
+            series.push_exemplar(convert_exemplar(exemplar)?);
+        }
+        Ok(())
+    }
+
+    /// Converts an explicit bucket histogram into classic
+    /// `_bucket`, `_count` and `_sum` series.
+    fn convert_histogram(
+        &self,
+        point: HistogramDataPoint,
+        labels: BTreeMap<String, String>,
+        delta: bool,
+        series: &mut SeriesSet,
+    ) -> Result<(), String> {
+        let timestamp = timestamp(point.time_unix_nano)?;
+        if !point.bucket_counts.is_empty()
+            && point.bucket_counts.len() != point.explicit_bounds.len() + 1
+        {
+            return Err(format!(
+                "`{}` bucket counts for `{}` bounds",
+                point.bucket_counts.len(),
+                point.explicit_bounds.len()
+            ));
+        }
+        let stale = no_recorded_value(point.flags);
+        let name = labels[SERIES_NAME_LABEL].clone();
+        let mut push = |suffix: &str,
+                        le: Option<String>,
+                        value: f64,
+                        exemplars: Vec<NativeExemplar>| {
+            let mut labels = labels.clone();
+            labels.insert(SERIES_NAME_LABEL.to_string(), format!("{}{}", name, suffix));
+            if let Some(le) = le {
+                labels.insert("le".to_string(), le);
+            }
+            let value = if stale { f64::from_bits(STALE_NAN) } else { value };
+            series
+                .push_sample(labels, timestamp, value, delta, &self.totals)
+                .extend_exemplars(exemplars);
+        };
+
+        let mut exemplars: Vec<Vec<NativeExemplar>> = vec![vec![]; point.bucket_counts.len()];
+        for exemplar in point.exemplars {
+            let exemplar = convert_exemplar(exemplar)?;
+            let bucket = point
+                .explicit_bounds
+                .iter()
+                .position(|bound| exemplar.value <= *bound)
+                .unwrap_or(point.explicit_bounds.len());
+            if let Some(bucket_exemplars) = exemplars.get_mut(bucket) {
+                bucket_exemplars.push(exemplar);
+            }
+        }
+        let mut cumulative_count = 0;
+        for (index, (count, exemplars)) in point.bucket_counts.iter().zip(exemplars).enumerate() {
+            cumulative_count += count;
+            let le = match point.explicit_bounds.get(index) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            push("_bucket", Some(le), cumulative_count as f64, exemplars);
+        }
+        push("_count", None, point.count as f64, vec![]);
+        if let Some(sum) = point.sum {
+            push("_sum", None, sum, vec![]);
+        }
+        Ok(())
+    }
+}
+
+/// Converts an exponential histogram into a native histogram, bucket `i` of scale `s`
+/// being bucket `i + 1` of schema `s` as it spans `(2^(2^-s * i), 2^(2^-s * (i + 1))]`.
+fn convert_exponential_histogram(
+    point: ExponentialHistogramDataPoint,
+    labels: BTreeMap<String, String>,
+    series: &mut SeriesSet,
+) -> Result<(), String> {
+    let timestamp = timestamp(point.time_unix_nano)?;
+    if point.scale < MIN_SCHEMA {
+        return Err(format!(
+            "scale `{}` below the smallest schema `{}`",
+            point.scale, MIN_SCHEMA
+        ));
+    }
+    let downscale = (point.scale - MAX_SCHEMA).max(0) as u32;
+    let buckets = |buckets: Option<Buckets>| match buckets {
+        Some(buckets) if !buckets.bucket_counts.is_empty() => {
+            let (offset, counts) = downscale_buckets(&buckets, downscale);
+            let spans = vec![BucketSpan {
+                offset: offset + 1,
+                length: counts.len() as u32,
+            }];
+            (spans, counts)
+        }
+        _ => (vec![], vec![]),
+    };
+    let (positive_spans, positive_counts) = buckets(point.positive);
+    let (negative_spans, negative_counts) = buckets(point.negative);
+    let histogram = if no_recorded_value(point.flags) {
+        HistogramSample {
+            timestamp,
+            counter_reset_hint: CounterResetHint::Unknown,
+            schema: point.scale - downscale as i32,
+            zero_threshold: 0.0,
+            zero_count: 0.0,
+            count: 0.0,
+            sum: f64::from_bits(STALE_NAN),
+            positive_spans: vec![],
+            positive_counts: vec![],
+            negative_spans: vec![],
+            negative_counts: vec![],
+        }
+    } else {
+        HistogramSample {
+            timestamp,
+            counter_reset_hint: CounterResetHint::Unknown,
+            schema: point.scale - downscale as i32,
+            zero_threshold: point.zero_threshold,
+            zero_count: point.zero_count as f64,
+            count: point.count as f64,
+            sum: point.sum.unwrap_or_default(),
+            positive_spans,
+            positive_counts,
+            negative_spans,
+            negative_counts,
+        }
+    };
+    let (_, series) = series.get_mut(labels);
+    series.push_histogram(histogram);
+    for exemplar in point.exemplars {
+        series.push_exemplar(convert_exemplar(exemplar)?);
+    }
+    Ok(())
+}
+
+/// Merges the buckets of a scale into the ones of the scale `downscale` lower,
+/// returning the index of the first bucket and the counts.
+fn downscale_buckets(buckets: &Buckets, downscale: u32) -> (i32, Vec<f64>) {
+    let offset = buckets.offset >> downscale;
+    let mut counts: Vec<f64> = vec![];
+    for (index, count) in buckets.bucket_counts.iter().enumerate() {
+        let merged_index = ((buckets.offset + index as i32) >> downscale) - offset;
+        let merged_index = merged_index as usize;
+        if counts.len() <= merged_index {
+            counts.resize(merged_index + 1, 0.0);
+        }
+        counts[merged_index] += *count as f64;
+    }
+    (offset, counts)
+}
+
+/// Converts a summary into `quantile` series and `_count` and `_sum` series.
+fn convert_summary(
+    point: SummaryDataPoint,
+    labels: BTreeMap<String, String>,
+    series: &mut SeriesSet,
+) -> Result<(), String> {
+    let timestamp = timestamp(point.time_unix_nano)?;
+    let stale = no_recorded_value(point.flags);
+    let name = labels[SERIES_NAME_LABEL].clone();
+    let mut push = |name: String, quantile: Option<f64>, value: f64| {
+        let mut labels = labels.clone();
+        labels.insert(SERIES_NAME_LABEL.to_string(), name);
+        if let Some(quantile) = quantile {
+            labels.insert("quantile".to_string(), quantile.to_string());
+        }
+        let value = if stale {
+            f64::from_bits(STALE_NAN)
+        } else {
+            value
+        };
+        series.get_mut(labels).1.push(Sample { timestamp, value });
+    };
+    for quantile in point.quantile_values {
+        push(name.clone(), Some(quantile.quantile), quantile.value);
+    }
+    push(format!("{}_count", name), None, point.count as f64);
+    push(format!("{}_sum", name), None, point.sum);
+    Ok(())
+}
+
+fn convert_exemplar(exemplar: Exemplar) -> Result<NativeExemplar, String> {
+    let value = match exemplar.value {
+        Some(exemplar::Value::AsDouble(value)) => value,
+        Some(exemplar::Value::AsInt(value)) => value as f64,
+        None => return Err("exemplar without a value".to_string()),
+    };
+    let mut labels = attribute_labels(&exemplar.filtered_attributes, "");
+    if !exemplar.trace_id.is_empty() {
+        labels.insert("trace_id".to_string(), hex(&exemplar.trace_id));
+    }
+    if !exemplar.span_id.is_empty() {
+        labels.insert("span_id".to_string(), hex(&exemplar.span_id));
+    }
+    Ok(NativeExemplar {
+        labels: labels
+            .into_iter()
+            .map(|(name, value)| Label { name, value })
+            .collect(),
+        value,
+        timestamp: timestamp(exemplar.time_unix_nano)?,
+    })
+}
+
+fn timestamp(time_unix_nano: u64) -> Result<i64, String> {
+    if time_unix_nano == 0 {
+        return Err("data point without a timestamp".to_string());
+    }
+    Ok((time_unix_nano / 1_000_000) as i64)
+}
+
+fn no_recorded_value(flags: u32) -> bool {
+    flags & DataPointFlags::NoRecordedValueMask as u32 != 0
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Returns the attributes as labels named `<prefix><key>`, made valid label names.
+fn attribute_labels(attributes: &[KeyValue], prefix: &str) -> BTreeMap<String, String> {
+    attributes
+        .iter()
+        .filter(|attribute| !attribute.key.is_empty())
+        .map(|attribute| {
+            let value = attribute
+                .value
+                .as_ref()
+                .map(any_value_to_string)
+                .unwrap_or_default();
+            (label_name(&format!("{}{}", prefix, attribute.key)), value)
+        })
+        .collect()
+}
+
+fn any_value_to_string(value: &AnyValue) -> String {
+    match &value.value {
+        Some(any_value::Value::StringValue(value)) => value.clone(),
+        Some(any_value::Value::BoolValue(value)) => value.to_string(),
+        Some(any_value::Value::IntValue(value)) => value.to_string(),
+        Some(any_value::Value::DoubleValue(value)) => value.to_string(),
+        Some(any_value::Value::ArrayValue(array)) => {
+            let values: Vec<String> = array.values.iter().map(any_value_to_string).collect();
+            format!("[{}]", values.join(","))
+        }
+        Some(any_value::Value::KvlistValue(list)) => {
+            let values: Vec<String> = list
+                .values
+                .iter()
+                .map(|kv| {
+                    let value = kv
+                        .value
+                        .as_ref()
+                        .map(any_value_to_string)
+                        .unwrap_or_default();
+                    format!("{}:{}", kv.key, value)
+                })
+                .collect();
+            format!("{{{}}}", values.join(","))
+        }
+        Some(any_value::Value::BytesValue(bytes)) => hex(bytes),
+        None => String::new(),
+    }
+}
+
+/// Makes `name` a valid label name, invalid characters becoming `_`.
+fn label_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        return format!("key_{}", name);
+    }
+    name
+}
+
+/// Returns the Prometheus name of a metric, with its unit and `_total` suffixes
+/// as in the Prometheus OTLP receiver, and the unit of its metadata.
+fn metric_name(name: &str, unit: &str, metric_type: MetricType) -> (String, String) {
+    let mut words: Vec<String> = name
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != ':')
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    // Annotations such as `{requests}` are left out of units.
+    let mut unit = unit.to_string();
+    while let (Some(start), Some(end)) = (unit.find('{'), unit.find('}')) {
+        if end < start {
+            break;
+        }
+        unit.replace_range(start..=end, "");
+    }
+    let (main_unit, per_unit) = match unit.split_once('/') {
+        Some((main_unit, per_unit)) => (main_unit.trim(), per_unit.trim()),
+        None => (unit.trim(), ""),
+    };
+    let lookup = |units: &[(&str, &'static str)], unit: &str| {
+        units
+            .iter()
+            .find(|(ucum, _)| *ucum == unit)
+            .map_or_else(|| label_name(unit), |(_, word)| word.to_string())
+    };
+    let main_word = lookup(&UNITS, main_unit);
+    let per_word = lookup(&PER_UNITS, per_unit);
+    let is_word = |word: &str, words: &[String]| words.iter().any(|other| other == word);
+    if !main_word.is_empty() && !is_word(&main_word, &words) {
+        words.push(main_word.clone());
+    }
+    if !per_word.is_empty() && !is_word(&per_word, &words) {
+        words.push("per".to_string());
+        words.push(per_word.clone());
+    }
+    match metric_type {
+        MetricType::Counter => {
+            words.retain(|word| word != "total");
+            words.push("total".to_string());
+        }
+        MetricType::Gauge if main_unit == "1" && !is_word("ratio", &words) => {
+            words.push("ratio".to_string());
+        }
+        _ => {}
+    }
+
+    let mut name = words.join("_");
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    let unit = match (main_word.is_empty(), per_word.is_empty()) {
+        (false, false) => format!("{}_per_{}", main_word, per_word),
+        (false, true) => main_word,
+        _ => String::new(),
+    };
+    (name, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::super::types::ExportMetricsServiceRequest;
+    use super::*;
+
+    fn labels(series: &TimeSeries) -> BTreeMap<&str, &str> {
+        series
+            .get_labels()
+            .iter()
+            .map(|label| (label.name.as_str(), label.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn convert_json_export() {
+        let export = |value: i64| {
+            let request: ExportMetricsServiceRequest = serde_json::from_value(json!({
+                "resourceMetrics": [{
+                    "resource": {"attributes": [
+                        {"key": "service.name", "value": {"stringValue": "checkout"}},
+                        {"key": "service.instance.id", "value": {"stringValue": "pod-1"}},
+                        {"key": "host.cores", "value": {"intValue": "8"}},
+                    ]},
+                    "scopeMetrics": [{
+                        "scope": {"name": "otel.sdk", "version": "1.0"},
+                        "metrics": [
+                            {
+                                "name": "http.requests",
+                                "unit": "{request}",
+                                "description": "The requests.",
+                                "sum": {
+                                    "aggregationTemporality": 1,
+                                    "isMonotonic": true,
+                                    "dataPoints": [
+                                        {"timeUnixNano": "1700000000000000000", "asInt": value.to_string()},
+                                        {"asInt": "1"},
+                                    ],
+                                },
+                            },
+                            {
+                                "name": "http.duration",
+                                "unit": "ms",
+                                "histogram": {
+                                    "aggregationTemporality": 2,
+                                    "dataPoints": [{
+                                        "timeUnixNano": "1700000000000000000",
+                                        "attributes": [{"key": "route", "value": {"stringValue": "/pay"}}],
+                                        "count": "3",
+                                        "sum": 42.0,
+                                        "bucketCounts": ["1", "2", "0"],
+                                        "explicitBounds": [10.0, 100.0],
+                                        "exemplars": [{
+                                            "timeUnixNano": "1700000000000000000",
+                                            "asDouble": 20.0,
+                                            "traceId": "0af7651916cd43dd8448eb211c80319c",
+                                        }],
+                                    }],
+                                },
+                            },
+                        ],
+                    }],
+                }],
+            }))
+            .unwrap();
+            request.resource_metrics
+        };
+
+        let mut converter = MetricsConverter::default();
+        let now = Instant::now();
+        converter.convert(export(2), now);
+        let conversion = converter.convert(export(3), now);
+        assert_eq!(conversion.rejected_data_points, 1);
+        assert_eq!(conversion.errors.len(), 1);
+        let names: Vec<&str> = conversion
+            .metadata
+            .iter()
+            .map(|metadata| metadata.metric_family_name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            &["http_duration_milliseconds", "http_requests_total"]
+        );
+        assert_eq!(conversion.metadata[1].metric_type, MetricType::Counter);
+        assert_eq!(conversion.metadata[0].unit, "milliseconds");
+
+        // Delta sums accumulate across exports.
+        let requests = &conversion.series[0];
+        let requests_labels = labels(requests);
+        assert_eq!(requests_labels[SERIES_NAME_LABEL], "http_requests_total");
+        assert_eq!(requests_labels["job"], "checkout");
+        assert_eq!(requests_labels["instance"], "pod-1");
+        assert_eq!(requests_labels["host_cores"], "8");
+        assert_eq!(requests_labels["otel_scope_name"], "otel.sdk");
+        assert_eq!(requests.get_samples()[0].value, 5.0);
+        assert_eq!(requests.get_samples()[0].timestamp, 1_700_000_000_000);
+
+        let buckets: Vec<(&str, f64, usize)> = conversion.series[1..4]
+            .iter()
+            .map(|series| {
+                (
+                    labels(series)["le"],
+                    series.get_samples()[0].value,
+                    series.get_exemplars().len(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            buckets,
+            &[("10", 1.0, 0), ("100", 3.0, 1), ("+Inf", 3.0, 0)]
+        );
+        assert_eq!(
+            labels(&conversion.series[4])[SERIES_NAME_LABEL],
+            "http_duration_milliseconds_count"
+        );
+        assert_eq!(labels(&conversion.series[1])["route"], "/pay");
+        assert_eq!(
+            conversion.series[2].get_exemplars()[0].labels[0].value,
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+    }
+
+    #[test]
+    fn rollback_and_evict_totals() {
+        let export = |value: i64| {
+            let request: ExportMetricsServiceRequest = serde_json::from_value(json!({
+                "resourceMetrics": [{
+                    "scopeMetrics": [{
+                        "metrics": [{
+                            "name": "jobs",
+                            "sum": {
+                                "aggregationTemporality": 1,
+                                "isMonotonic": true,
+                                "dataPoints": [{"timeUnixNano": "1000000", "asInt": value.to_string()}],
+                            },
+                        }],
+                    }],
+                }],
+            }))
+            .unwrap();
+            request.resource_metrics
+        };
+        let value = |conversion: &Conversion| conversion.series[0].get_samples()[0].value;
+
+        let mut converter = MetricsConverter::default();
+        let now = Instant::now();
+        assert_eq!(value(&converter.convert(export(2), now)), 2.0);
+
+        // Concurrent exports see the deltas of each other before they are written.
+        let first = converter.convert(export(3), now);
+        let second = converter.convert(export(4), now);
+        assert_eq!(value(&first), 5.0);
+        assert_eq!(value(&second), 9.0);
+        // The deltas of an export which is not written are rolled back until it is retried.
+        converter.rollback(first.deltas);
+        assert_eq!(value(&converter.convert(export(3), now)), 9.0);
+        let later = converter.convert(export(1), now + TOTALS_RETENTION / 2);
+        assert_eq!(value(&later), 10.0);
+
+        // Totals without deltas for the retention are evicted.
+        converter.convert(Vec::new(), now + TOTALS_RETENTION);
+        assert_eq!(converter.totals.len(), 1);
+        converter.convert(Vec::new(), now + TOTALS_RETENTION * 2);
+        assert!(converter.totals.is_empty());
+        let evicted = converter.convert(export(1), now + TOTALS_RETENTION * 2);
+        assert_eq!(value(&evicted), 1.0);
+    }
+
+    #[test]
+    fn normalize_metric_names() {
+        let name = |name, unit, metric_type| metric_name(name, unit, metric_type).0;
+        assert_eq!(
+            name("http.server.request.duration", "s", MetricType::Histogram),
+            "http_server_request_duration_seconds"
+        );
+        assert_eq!(
+            name("http.server.requests", "{request}", MetricType::Counter),
+            "http_server_requests_total"
+        );
+        assert_eq!(
+            name("system.network.io", "By", MetricType::Counter),
+            "system_network_io_bytes_total"
+        );
+        assert_eq!(
+            name("requests.total", "", MetricType::Counter),
+            "requests_total"
+        );
+        assert_eq!(
+            name("cpu.utilization", "1", MetricType::Gauge),
+            "cpu_utilization_ratio"
+        );
+        assert_eq!(
+            name("throughput", "By/s", MetricType::Gauge),
+            "throughput_bytes_per_second"
+        );
+        assert_eq!(
+            name("memory_bytes", "By", MetricType::Gauge),
+            "memory_bytes"
+        );
+        assert_eq!(label_name("k8s.pod.name"), "k8s_pod_name");
+        assert_eq!(label_name("2xx"), "key_2xx");
+    }
+
+    #[test]
+    fn downscale_exponential_buckets() {
+        let buckets = Buckets {
+            offset: -3,
+            bucket_counts: vec![1, 2, 3, 4, 5],
+        };
+        // Buckets -3 | -2, -1 | 0, 1 of scale 1 are -2 | -1 | 0 of scale 0.
+        assert_eq!(downscale_buckets(&buckets, 1), (-2, vec![1.0, 5.0, 9.0]));
+        assert_eq!(
+            downscale_buckets(&buckets, 0),
+            (-3, vec![1.0, 2.0, 3.0, 4.0, 5.0])
+        );
+    }
+}
@@ -0,0 +1,219 @@
+mod metrics;
+pub mod types;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{
+        header::{CONTENT_ENCODING, CONTENT_TYPE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::post,
+    Router,
+};
+use prost::Message;
+use serde_json::json;
+use storage::{Storage, StorageError};
+use thiserror::Error;
+
+use self::metrics::MetricsConverter;
+use self::types::{ExportMetricsPartialSuccess, ExportMetricsServiceRequest, Status};
+use crate::http::{decode_content, retry_later, ContentError};
+
+const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// The encoding of an export request, which its response uses too.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Protobuf,
+    Json,
+}
+
+#[derive(Error, Debug)]
+pub enum OtlpError {
+    #[error("StorageError {0}")]
+    Storage(#[from] StorageError),
+    /// The request cannot be decoded.
+    #[error("BadRequest {0}")]
+    BadRequest(String),
+    #[error("UnsupportedMediaType {0}")]
+    UnsupportedMediaType(String),
+    #[error("PayloadTooLarge {0}")]
+    PayloadTooLarge(String),
+}
+
+impl From<ContentError> for OtlpError {
+    fn from(err: ContentError) -> Self {
+        match err {
+            ContentError::Invalid(err) => OtlpError::BadRequest(err),
+            ContentError::TooLarge(err) => OtlpError::PayloadTooLarge(err),
+        }
+    }
+}
+
+impl OtlpError {
+    /// Builds the `google.rpc.Status` response of the error. Clients retry
+    /// 429, 502, 503 and 504 responses, and drop the data of other ones.
+    fn into_response_as(self, encoding: Encoding) -> Response {
+        // The gRPC codes of the errors.
+        let (status_code, code) = match &self {
+            OtlpError::BadRequest(_) | OtlpError::Storage(StorageError::OutOfBounds(_)) => {
+                (StatusCode::BAD_REQUEST, 3)
+            }
+            OtlpError::UnsupportedMediaType(_) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, 3),
+            OtlpError::PayloadTooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, 3),
+            OtlpError::Storage(StorageError::Overloaded(_)) => (StatusCode::TOO_MANY_REQUESTS, 8),
+            OtlpError::Storage(StorageError::Unavailable(_)) => {
+                (StatusCode::SERVICE_UNAVAILABLE, 14)
+            }
+            OtlpError::Storage(_) => (StatusCode::INTERNAL_SERVER_ERROR, 13),
+        };
+        let status = Status {
+            code,
+            message: self.to_string(),
+        };
+        let body = match encoding {
+            Encoding::Protobuf => status.encode_to_vec(),
+            Encoding::Json => json!({"code": status.code, "message": status.message})
+                .to_string()
+                .into_bytes(),
+        };
+        retry_later((status_code, content_type(encoding), body).into_response())
+    }
+}
+
+impl IntoResponse for OtlpError {
+    fn into_response(self) -> Response {
+        self.into_response_as(Encoding::Protobuf)
+    }
+}
+
+fn content_type(encoding: Encoding) -> [(axum::http::HeaderName, HeaderValue); 1] {
+    let content_type = match encoding {
+        Encoding::Protobuf => PROTOBUF_CONTENT_TYPE,
+        Encoding::Json => JSON_CONTENT_TYPE,
+    };
+    [(CONTENT_TYPE, HeaderValue::from_static(content_type))]
+}
+
+#[derive(Debug, Clone)]
+struct OtlpState {
+    storage: Arc<Storage>,
+    /// Accumulates the delta metrics of all exports.
+    converter: Arc<Mutex<MetricsConverter>>,
+}
+
+async fn metrics_handler_service(
+    State(state): State<OtlpState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let media_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or_default().trim());
+    let encoding = match media_type {
+        None | Some(PROTOBUF_CONTENT_TYPE) => Encoding::Protobuf,
+        Some(JSON_CONTENT_TYPE) => Encoding::Json,
+        Some(media_type) => {
+            return OtlpError::UnsupportedMediaType(format!(
+                "unsupported content type `{}`",
+                media_type
+            ))
+            .into_response()
+        }
+    };
+    match export_metrics(&state, encoding, &headers, body).await {
+        Ok(partial_success) => {
+            let body = match encoding {
+                Encoding::Protobuf => {
+                    types::ExportMetricsServiceResponse { partial_success }.encode_to_vec()
+                }
+                Encoding::Json => {
+                    let response = match partial_success {
+                        Some(partial_success) => json!({"partialSuccess": {
+                            "rejectedDataPoints": partial_success.rejected_data_points.to_string(),
+                            "errorMessage": partial_success.error_message,
+                        }}),
+                        None => json!({}),
+                    };
+                    response.to_string().into_bytes()
+                }
+            };
+            (StatusCode::OK, content_type(encoding), body).into_response()
+        }
+        Err(err) => err.into_response_as(encoding),
+    }
+}
+
+/// Writes the metrics of an export, returning the data points that were rejected.
+async fn export_metrics(
+    state: &OtlpState,
+    encoding: Encoding,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<Option<ExportMetricsPartialSuccess>, OtlpError> {
+    let content_encoding = headers
+        .get(CONTENT_ENCODING)
+        .map(|value| value.to_str())
+        .transpose()
+        .map_err(|err| OtlpError::BadRequest(format!("invalid content encoding: {}", err)))?;
+    let body = decode_content(content_encoding, body.to_vec())?;
+    let request = match encoding {
+        Encoding::Protobuf => ExportMetricsServiceRequest::decode(body.as_slice())
+            .map_err(|err| OtlpError::BadRequest(format!("invalid protobuf body: {}", err)))?,
+        Encoding::Json => serde_json::from_slice(&body)
+            .map_err(|err| OtlpError::BadRequest(format!("invalid JSON body: {}", err)))?,
+    };
+
+    let conversion = state
+        .converter
+        .lock()
+        .unwrap()
+        .convert(request.resource_metrics, Instant::now());
+    println!(
+        "Received OTLP export: {} series, {} data points rejected",
+        conversion.series.len(),
+        conversion.rejected_data_points
+    );
+    let written = async {
+        if !conversion.metadata.is_empty() {
+            state.storage.write_metadata(conversion.metadata).await?;
+        }
+        if !conversion.series.is_empty() {
+            state.storage.write(conversion.series).await?;
+        }
+        Ok::<_, StorageError>(())
+    }
+    .await;
+    if let Err(err) = written {
+        state.converter.lock().unwrap().rollback(conversion.deltas);
+        return Err(err.into());
+    }
+    if conversion.rejected_data_points == 0 && conversion.errors.is_empty() {
+        return Ok(None);
+    }
+    let errors: Vec<String> = conversion.errors.into_iter().collect();
+    Ok(Some(ExportMetricsPartialSuccess {
+        rejected_data_points: conversion.rejected_data_points,
+        error_message: errors.join("; "),
+    }))
+}
+
+/// Serves the OTLP/HTTP metrics endpoint, with protobuf and JSON bodies.
+pub fn otlp_router(storage: Arc<Storage>) -> Router {
+    let state = OtlpState {
+        storage,
+        converter: Arc::new(Mutex::new(MetricsConverter::default())),
+    };
+    Router::new()
+        .route("/v1/metrics", post(metrics_handler_service))
+        .with_state(state)
+}
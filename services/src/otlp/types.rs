@@ -0,0 +1,121 @@
+use std::{fmt::Display, str::FromStr};
+
+use serde::{de::Error, Deserialize, Deserializer};
+
+/// The messages of OTLP, nested as their packages are.
+#[allow(clippy::all)]
+pub mod opentelemetry {
+    pub mod proto {
+        pub mod collector {
+            pub mod metrics {
+                pub mod v1 {
+                    include!(concat!(
+                        env!("OUT_DIR"),
+                        "/opentelemetry.proto.collector.metrics.v1.rs"
+                    ));
+                }
+            }
+        }
+        pub mod common {
+            pub mod v1 {
+                include!(concat!(
+                    env!("OUT_DIR"),
+                    "/opentelemetry.proto.common.v1.rs"
+                ));
+            }
+        }
+        pub mod metrics {
+            pub mod v1 {
+                include!(concat!(
+                    env!("OUT_DIR"),
+                    "/opentelemetry.proto.metrics.v1.rs"
+                ));
+            }
+        }
+        pub mod resource {
+            pub mod v1 {
+                include!(concat!(
+                    env!("OUT_DIR"),
+                    "/opentelemetry.proto.resource.v1.rs"
+                ));
+            }
+        }
+    }
+}
+
+pub use opentelemetry::proto::{
+    collector::metrics::v1::{
+        ExportMetricsPartialSuccess, ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+    },
+    common::v1::{any_value, AnyValue, KeyValue},
+    metrics::v1::{
+        exemplar, exponential_histogram_data_point::Buckets, metric, number_data_point,
+        AggregationTemporality, DataPointFlags, Exemplar, ExponentialHistogramDataPoint,
+        HistogramDataPoint, Metric, NumberDataPoint, SummaryDataPoint,
+    },
+};
+
+/// The status of a failed export, as `google.rpc.Status`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Status {
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+}
+
+/// A JSON integer or string holding one, as 64 bits integers are in OTLP/JSON.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonInt<T> {
+    Int(T),
+    String(String),
+}
+
+impl<T: FromStr> JsonInt<T>
+where
+    T::Err: Display,
+{
+    fn parse<E: Error>(self) -> Result<T, E> {
+        match self {
+            JsonInt::Int(value) => Ok(value),
+            JsonInt::String(value) => value.parse().map_err(E::custom),
+        }
+    }
+}
+
+pub(crate) fn json_int<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + Deserialize<'de>,
+    T::Err: Display,
+{
+    JsonInt::deserialize(deserializer)?.parse()
+}
+
+pub(crate) fn json_ints<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + Deserialize<'de>,
+    T::Err: Display,
+{
+    Vec::<JsonInt<T>>::deserialize(deserializer)?
+        .into_iter()
+        .map(JsonInt::parse)
+        .collect()
+}
+
+/// Decodes the hex trace and span ids of OTLP/JSON.
+pub(crate) fn json_hex<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let hex = String::deserialize(deserializer)?;
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(D::Error::custom(format!("invalid hex id `{}`", hex)));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).map_err(D::Error::custom))
+        .collect()
+}
@@ -0,0 +1,632 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{rejection::FormRejection, Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::get,
+    Form, Json, Router,
+};
+use fts::query::Query as NativeQuery;
+use promql_parser::{
+    parser::{self, Expr, VectorSelector},
+    util::parse_duration,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value as JsonValue};
+use storage::{Label, MetricType, Storage, StorageError};
+use thiserror::Error;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use super::engine::{
+    exemplars_to_json, expr_selectors, is_valid_label_name, selector_query, Engine, EngineError,
+    Value,
+};
+use crate::http::error_response;
+
+/// The default evaluation timeout, as in Prometheus.
+const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The maximum number of points per series of a range query, as in Prometheus.
+const MAX_RANGE_QUERY_POINTS: i64 = 11_000;
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+/// The errors of the Prometheus HTTP API, returned with
+/// the status code and `errorType` Prometheus uses.
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("{0}")]
+    BadData(String),
+    #[error("{0}")]
+    Engine(#[from] EngineError),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        let (status_code, error_type) = match &self {
+            ApiError::BadData(_) | ApiError::Engine(EngineError::BadData(_)) => {
+                (StatusCode::BAD_REQUEST, "bad_data")
+            }
+            ApiError::Engine(EngineError::Execution(_)) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "execution")
+            }
+            ApiError::Engine(EngineError::Storage(StorageError::Unavailable(_))) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+            }
+            ApiError::Engine(EngineError::Storage(_)) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal")
+            }
+            ApiError::Engine(EngineError::Timeout) => (StatusCode::SERVICE_UNAVAILABLE, "timeout"),
+        };
+        let body = json!({
+            "status": "error",
+            "errorType": error_type,
+            "error": self.to_string(),
+        });
+        error_response(status_code, body)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InstantQueryParams {
+    query: String,
+    time: Option<String>,
+    timeout: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RangeQueryParams {
+    query: String,
+    start: String,
+    end: String,
+    step: String,
+    timeout: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExemplarQueryParams {
+    query: String,
+    start: Option<String>,
+    end: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MetadataQueryParams {
+    metric: Option<String>,
+    limit: Option<String>,
+}
+
+/// The parameters of the label and series endpoints, read from a list
+/// of pairs as `match[]` may be repeated.
+#[derive(Debug, Default)]
+pub struct LabelQueryParams {
+    matches: Vec<String>,
+    start: Option<i64>,
+    end: Option<i64>,
+    limit: Option<usize>,
+}
+
+pub(crate) fn prometheus_api_router(storage: Arc<Storage>) -> Router {
+    // `Form` reads the query string of GET requests and the body of POST ones.
+    let query_router = Router::new()
+        .route(
+            "/api/v1/query",
+            get(instant_query_handler_service).post(instant_query_handler_service),
+        )
+        .route(
+            "/api/v1/query_range",
+            get(range_query_handler_service).post(range_query_handler_service),
+        )
+        .with_state(Engine::new(storage.clone()));
+
+    // These endpoints only read the label index, never the samples: the
+    // index keeps no time range, so `start` and `end` are best-effort.
+    let label_router = Router::new()
+        .route(
+            "/api/v1/labels",
+            get(labels_handler_service).post(labels_handler_service),
+        )
+        .route(
+            "/api/v1/label/:name/values",
+            get(label_values_handler_service),
+        )
+        .route(
+            "/api/v1/series",
+            get(series_handler_service).post(series_handler_service),
+        )
+        .with_state(storage.clone());
+
+    // These endpoints read what is stored apart from the samples.
+    let storage_router = Router::new()
+        .route(
+            "/api/v1/query_exemplars",
+            get(query_exemplars_handler_service).post(query_exemplars_handler_service),
+        )
+        .route("/api/v1/metadata", get(metadata_handler_service))
+        .with_state(storage);
+
+    query_router.merge(label_router).merge(storage_router)
+}
+
+async fn instant_query_handler_service(
+    State(engine): State<Engine>,
+    params: Result<Form<InstantQueryParams>, FormRejection>,
+) -> ApiResult<Json<JsonValue>> {
+    let Form(params) = params.map_err(|err| ApiError::BadData(err.body_text()))?;
+    let expr = parse_query(&params.query)?;
+    let time = match params.time {
+        Some(time) => parse_time("time", &time)?,
+        None => now_millis(),
+    };
+    let timeout = parse_timeout(params.timeout)?;
+
+    let value = engine
+        .with_timeout(timeout)
+        .instant_query(&expr, time)
+        .await?;
+    Ok(success_response(value))
+}
+
+async fn range_query_handler_service(
+    State(engine): State<Engine>,
+    params: Result<Form<RangeQueryParams>, FormRejection>,
+) -> ApiResult<Json<JsonValue>> {
+    let Form(params) = params.map_err(|err| ApiError::BadData(err.body_text()))?;
+    let expr = parse_query(&params.query)?;
+    let start = parse_time("start", &params.start)?;
+    let end = parse_time("end", &params.end)?;
+    if end < start {
+        return Err(ApiError::BadData(
+            "invalid parameter \"end\": end timestamp must not be before start time".to_string(),
+        ));
+    }
+    let step = parse_duration_millis("step", &params.step)?;
+    if step <= 0 {
+        return Err(ApiError::BadData(
+            "invalid parameter \"step\": zero or negative query resolution step widths are not accepted. Try a positive integer".to_string(),
+        ));
+    }
+    if (end - start) / step > MAX_RANGE_QUERY_POINTS {
+        return Err(ApiError::BadData(
+            "exceeded maximum resolution of 11,000 points per timeseries. Try decreasing the query resolution (?step=XX)".to_string(),
+        ));
+    }
+    let timeout = parse_timeout(params.timeout)?;
+
+    let value = engine
+        .with_timeout(timeout)
+        .range_query(&expr, start, end, step)
+        .await?;
+    Ok(success_response(value))
+}
+
+/// Returns the exemplars of the series selected by the query, whatever
+/// its functions and operators. Both bounds of the time range are optional.
+async fn query_exemplars_handler_service(
+    State(storage): State<Arc<Storage>>,
+    params: Result<Form<ExemplarQueryParams>, FormRejection>,
+) -> ApiResult<Json<JsonValue>> {
+    let Form(params) = params.map_err(|err| ApiError::BadData(err.body_text()))?;
+    let expr = parse_query(&params.query)?;
+    let start = match params.start {
+        Some(start) => parse_time("start", &start)?,
+        None => i64::MIN,
+    };
+    let end = match params.end {
+        Some(end) => parse_time("end", &end)?,
+        None => i64::MAX,
+    };
+    if end < start {
+        return Err(ApiError::BadData(
+            "invalid parameter \"end\": end timestamp must not be before start time".to_string(),
+        ));
+    }
+    let Some(query) = selectors_query(expr_selectors(&expr))? else {
+        return Ok(list_response(Vec::<JsonValue>::new(), None));
+    };
+
+    let mut series = storage
+        .exemplars(query, start, end)
+        .await
+        .map_err(EngineError::from)?;
+    series
+        .sort_by(|left, right| labels_key(left.get_labels()).cmp(&labels_key(right.get_labels())));
+    Ok(list_response(
+        series.iter().map(exemplars_to_json).collect(),
+        None,
+    ))
+}
+
+/// Returns the metadata of the metric families by name,
+/// `limit` is the maximum number of metric families.
+async fn metadata_handler_service(
+    State(storage): State<Arc<Storage>>,
+    params: Result<Form<MetadataQueryParams>, FormRejection>,
+) -> ApiResult<Json<JsonValue>> {
+    let Form(params) = params.map_err(|err| ApiError::BadData(err.body_text()))?;
+    let limit = match params.limit {
+        Some(limit) => parse_limit(&limit)?,
+        None => None,
+    };
+    let metric = params.metric.filter(|metric| !metric.is_empty());
+    let mut metadata = storage
+        .metadata(metric.as_deref())
+        .await
+        .map_err(EngineError::from)?;
+    if let Some(limit) = limit {
+        metadata.truncate(limit);
+    }
+
+    let families: Map<String, JsonValue> = metadata
+        .into_iter()
+        .map(|metadata| {
+            let metric_type = match metadata.metric_type {
+                MetricType::Unknown => "unknown",
+                MetricType::Counter => "counter",
+                MetricType::Gauge => "gauge",
+                MetricType::Histogram => "histogram",
+                MetricType::GaugeHistogram => "gaugehistogram",
+                MetricType::Summary => "summary",
+                MetricType::Info => "info",
+                MetricType::StateSet => "stateset",
+            };
+            let value = json!([{
+                "type": metric_type,
+                "help": metadata.help,
+                "unit": metadata.unit,
+            }]);
+            (metadata.metric_family_name, value)
+        })
+        .collect();
+    Ok(Json(json!({
+        "status": "success",
+        "data": families,
+    })))
+}
+
+async fn labels_handler_service(
+    State(storage): State<Arc<Storage>>,
+    params: Result<Form<Vec<(String, String)>>, FormRejection>,
+) -> ApiResult<Json<JsonValue>> {
+    let params = parse_label_query_params(params)?;
+    let query = matches_query(&params.matches)?;
+    let names = storage
+        .label_names(query)
+        .await
+        .map_err(EngineError::from)?;
+    Ok(list_response(names, params.limit))
+}
+
+async fn label_values_handler_service(
+    State(storage): State<Arc<Storage>>,
+    Path(name): Path<String>,
+    params: Result<Form<Vec<(String, String)>>, FormRejection>,
+) -> ApiResult<Json<JsonValue>> {
+    if !is_valid_label_name(&name) {
+        return Err(ApiError::BadData(format!("invalid label name: {:?}", name)));
+    }
+    let params = parse_label_query_params(params)?;
+    let query = matches_query(&params.matches)?;
+    let values = storage
+        .label_values(&name, query)
+        .await
+        .map_err(EngineError::from)?;
+    Ok(list_response(values, params.limit))
+}
+
+async fn series_handler_service(
+    State(storage): State<Arc<Storage>>,
+    params: Result<Form<Vec<(String, String)>>, FormRejection>,
+) -> ApiResult<Json<JsonValue>> {
+    let params = parse_label_query_params(params)?;
+    let Some(query) = matches_query(&params.matches)? else {
+        return Err(ApiError::BadData(
+            "no match[] parameter provided".to_string(),
+        ));
+    };
+    let mut series = storage.series(query).await.map_err(EngineError::from)?;
+    series.sort_by(|left, right| labels_key(left).cmp(&labels_key(right)));
+    let series = series
+        .into_iter()
+        .map(|labels| {
+            let labels: Map<String, JsonValue> = labels
+                .into_iter()
+                .map(|label| (label.name, JsonValue::String(label.value)))
+                .collect();
+            JsonValue::Object(labels)
+        })
+        .collect();
+    Ok(list_response(series, params.limit))
+}
+
+/// Reads the parameters of the label and series endpoints. The label index
+/// spans all the retained series and keeps no time range, so `start` and `end`
+/// are only validated: Prometheus also treats them as best-effort bounds.
+fn parse_label_query_params(
+    params: Result<Form<Vec<(String, String)>>, FormRejection>,
+) -> ApiResult<LabelQueryParams> {
+    let Form(pairs) = params.map_err(|err| ApiError::BadData(err.body_text()))?;
+    let mut params = LabelQueryParams::default();
+    for (name, value) in pairs {
+        match name.as_str() {
+            "match[]" => params.matches.push(value),
+            "start" => params.start = Some(parse_time("start", &value)?),
+            "end" => params.end = Some(parse_time("end", &value)?),
+            "limit" => params.limit = parse_limit(&value)?,
+            _ => {}
+        }
+    }
+    if let (Some(start), Some(end)) = (params.start, params.end) {
+        if end < start {
+            return Err(ApiError::BadData(
+                "invalid parameter \"end\": end timestamp must not be before start time"
+                    .to_string(),
+            ));
+        }
+    }
+    Ok(params)
+}
+
+/// Parses the `limit` parameter, a limit of 0 means no limit.
+fn parse_limit(value: &str) -> ApiResult<Option<usize>> {
+    let limit: usize = value.parse().map_err(|_| {
+        ApiError::BadData(format!(
+            "invalid parameter \"limit\": cannot parse \"{}\" to a valid limit",
+            value
+        ))
+    })?;
+    Ok(Some(limit).filter(|limit| *limit > 0))
+}
+
+/// Converts the `match[]` series selectors into a native query matching
+/// any of them, `None` when there are none.
+fn matches_query(matches: &[String]) -> ApiResult<Option<NativeQuery>> {
+    let mut selectors = vec![];
+    for selector in matches {
+        let Ok(Expr::VectorSelector(selector)) = parser::parse(selector) else {
+            return Err(ApiError::BadData(format!(
+                "invalid parameter \"match[]\": {:?} is not a series selector",
+                selector
+            )));
+        };
+        selectors.push(selector);
+    }
+    selectors_query(selectors.iter())
+}
+
+/// Converts series selectors into a native query matching any
+/// of them, `None` when there are none.
+fn selectors_query<'a>(
+    selectors: impl IntoIterator<Item = &'a VectorSelector>,
+) -> ApiResult<Option<NativeQuery>> {
+    let mut query: Option<NativeQuery> = None;
+    for selector in selectors {
+        let selector_query = selector_query(selector)?;
+        query = Some(match query {
+            Some(left) => NativeQuery::Or(Box::new(left), Box::new(selector_query)),
+            None => selector_query,
+        });
+    }
+    Ok(query)
+}
+
+fn labels_key(labels: &[Label]) -> Vec<(&str, &str)> {
+    let mut key: Vec<(&str, &str)> = labels
+        .iter()
+        .map(|label| (label.name.as_str(), label.value.as_str()))
+        .collect();
+    key.sort();
+    key
+}
+
+/// Truncates the results to `limit` with a warning, as Prometheus does.
+fn list_response<T: Into<JsonValue>>(mut items: Vec<T>, limit: Option<usize>) -> Json<JsonValue> {
+    let mut body = json!({ "status": "success" });
+    if let Some(limit) = limit {
+        if items.len() > limit {
+            items.truncate(limit);
+            body["warnings"] = json!(["results truncated due to limit"]);
+        }
+    }
+    body["data"] = JsonValue::Array(items.into_iter().map(Into::into).collect());
+    Json(body)
+}
+
+fn success_response(value: Value) -> Json<JsonValue> {
+    Json(json!({
+        "status": "success",
+        "data": value.to_json(),
+    }))
+}
+
+fn parse_query(query: &str) -> ApiResult<Expr> {
+    parser::parse(query)
+        .map_err(|err| ApiError::BadData(format!("invalid parameter \"query\": {}", err)))
+}
+
+/// Parses a unix timestamp in seconds or a RFC3339 date into ms.
+fn parse_time(name: &str, value: &str) -> ApiResult<i64> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        if seconds.is_finite() {
+            return Ok((seconds * 1000.0).round() as i64);
+        }
+    }
+    OffsetDateTime::parse(value, &Rfc3339)
+        .map(|date_time| (date_time.unix_timestamp_nanos() / 1_000_000) as i64)
+        .map_err(|_| {
+            ApiError::BadData(format!(
+                "invalid parameter \"{}\": cannot parse \"{}\" to a valid timestamp",
+                name, value
+            ))
+        })
+}
+
+/// Parses a number of seconds or a PromQL duration into ms.
+fn parse_duration_millis(name: &str, value: &str) -> ApiResult<i64> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        if seconds.is_finite() {
+            return Ok((seconds * 1000.0).round() as i64);
+        }
+    }
+    parse_duration(value)
+        .map(|duration| duration.as_millis() as i64)
+        .map_err(|_| {
+            ApiError::BadData(format!(
+                "invalid parameter \"{}\": cannot parse \"{}\" to a valid duration",
+                name, value
+            ))
+        })
+}
+
+fn parse_timeout(timeout: Option<String>) -> ApiResult<Duration> {
+    match timeout {
+        Some(timeout) => {
+            let millis = parse_duration_millis("timeout", &timeout)?;
+            Ok(Duration::from_millis(millis.max(0) as u64))
+        }
+        None => Ok(DEFAULT_QUERY_TIMEOUT),
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use storage::{
+        Sample, StorageFactory, StorageSettings, TimeSeries, WalFsyncPolicy, SERIES_NAME_LABEL,
+    };
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn parse_params() {
+        assert_eq!(parse_time("time", "1700000000").unwrap(), 1_700_000_000_000);
+        assert_eq!(
+            parse_time("time", "1700000000.123").unwrap(),
+            1_700_000_000_123
+        );
+        assert_eq!(
+            parse_time("time", "2023-11-14T22:13:20.5Z").unwrap(),
+            1_700_000_000_500
+        );
+        assert_eq!(
+            parse_time("time", "2023-11-14T23:13:20+01:00").unwrap(),
+            1_700_000_000_000
+        );
+        assert!(parse_time("time", "yesterday").is_err());
+
+        assert_eq!(parse_duration_millis("step", "15").unwrap(), 15_000);
+        assert_eq!(parse_duration_millis("step", "0.5").unwrap(), 500);
+        assert_eq!(parse_duration_millis("step", "1m30s").unwrap(), 90_000);
+        assert!(parse_duration_millis("step", "1x").is_err());
+    }
+
+    #[test]
+    fn parse_label_params() {
+        let pairs = |pairs: &[(&str, &str)]| {
+            Ok(Form(
+                pairs
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            ))
+        };
+        let params = parse_label_query_params(pairs(&[
+            ("match[]", "up"),
+            ("match[]", "{job=~\"api|db\"}"),
+            ("start", "10"),
+            ("limit", "5"),
+        ]))
+        .unwrap();
+        assert_eq!(params.matches, &["up", "{job=~\"api|db\"}"]);
+        assert_eq!(params.start, Some(10_000));
+        assert_eq!(params.limit, Some(5));
+        assert!(matches!(
+            matches_query(&params.matches),
+            Ok(Some(NativeQuery::Or(_, _)))
+        ));
+
+        assert_eq!(
+            parse_label_query_params(pairs(&[("limit", "0")]))
+                .unwrap()
+                .limit,
+            None
+        );
+        assert!(parse_label_query_params(pairs(&[("limit", "-1")])).is_err());
+        assert!(parse_label_query_params(pairs(&[("start", "20"), ("end", "10")])).is_err());
+        assert!(matches_query(&["sum(up)".to_string()]).is_err());
+        assert!(matches!(matches_query(&[]), Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn label_endpoints_with_time_range() {
+        let directory = TempDir::new("label_api").unwrap();
+        let settings = StorageSettings::Native {
+            path: directory.path().to_str().unwrap().to_string(),
+            block_duration: 120,
+            wal_fsync_policy: WalFsyncPolicy::Never,
+            max_exemplars: 0,
+        };
+        let storage = Arc::new(StorageFactory::open(&settings).unwrap());
+        let labels = vec![
+            Label { name: SERIES_NAME_LABEL.to_string(), value: "up".to_string() },
+            Label { name: "job".to_string(), value: "api".to_string() },
+        ];
+        let samples = vec![Sample { timestamp: 0, value: 1.0 }];
+        storage.write(vec![TimeSeries::new(labels, samples)]).await.unwrap();
+
+        // start and end are best-effort: the label index keeps no time range, so
+        // series without samples in the range are still listed.
+        let params = |pairs: &[(&str, &str)]| {
+            let mut pairs: Vec<(String, String)> = pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            pairs.push(("start".to_string(), "1000".to_string()));
+            pairs.push(("end".to_string(), "2000".to_string()));
+            Ok(Form(pairs))
+        };
+        let Json(names) = labels_handler_service(State(storage.clone()), params(&[]))
+            .await
+            .unwrap();
+        assert_eq!(names["data"], json!([SERIES_NAME_LABEL, "job"]));
+        let Json(values) = label_values_handler_service(
+            State(storage.clone()),
+            Path("job".to_string()),
+            params(&[("match[]", "up")]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(values["data"], json!(["api"]));
+        let Json(series) = series_handler_service(State(storage), params(&[("match[]", "up")]))
+            .await
+            .unwrap();
+        assert_eq!(series["data"], json!([{"__name__": "up", "job": "api"}]));
+    }
+
+    #[test]
+    fn exemplar_query_selectors() {
+        let expr =
+            parse_query("sum(rate(latency_bucket{job=\"api\"}[5m])) / on() group(up)").unwrap();
+        let selectors = expr_selectors(&expr);
+        assert_eq!(selectors.len(), 2);
+        assert_eq!(selectors[1].name.as_deref(), Some("up"));
+        assert!(matches!(
+            selectors_query(selectors),
+            Ok(Some(NativeQuery::Or(_, _)))
+        ));
+
+        let expr = parse_query("vector(1) + 2").unwrap();
+        assert!(matches!(selectors_query(expr_selectors(&expr)), Ok(None)));
+    }
+}
@@ -0,0 +1,188 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use promql_parser::{
+    label::METRIC_NAME,
+    parser::{
+        token::{
+            T_AVG, T_BOTTOMK, T_COUNT, T_COUNT_VALUES, T_GROUP, T_MAX, T_MIN, T_QUANTILE, T_STDDEV,
+            T_STDVAR, T_SUM, T_TOPK,
+        },
+        AggregateExpr, LabelModifier,
+    },
+};
+
+use super::{
+    value::{format_value, is_valid_label_name},
+    EngineError, EngineResult, Evaluator, Labels, Point, Value, VectorSample,
+};
+
+impl Evaluator {
+    pub(super) fn eval_aggregate(
+        &self,
+        aggregate: &AggregateExpr,
+        timestamp: i64,
+    ) -> EngineResult<Value> {
+        let op = aggregate.op.id();
+        let samples = self.eval_vector(&aggregate.expr, timestamp)?;
+        let modifier = aggregate.modifier.as_ref();
+
+        let mut count_values_label = None;
+        let mut param = f64::NAN;
+        if let Some(param_expr) = &aggregate.param {
+            if op == T_COUNT_VALUES {
+                let label = self.eval_string(param_expr, timestamp)?;
+                if !is_valid_label_name(&label) {
+                    return Err(EngineError::Execution(format!(
+                        "invalid label name {:?}",
+                        label
+                    )));
+                }
+                count_values_label = Some(label);
+            } else {
+                param = self.eval_scalar(param_expr, timestamp)?;
+            }
+        }
+
+        // Groups are kept in the order they first appear.
+        let mut groups: Vec<(Labels, Vec<VectorSample>)> = vec![];
+        let mut group_ids: HashMap<Labels, usize> = HashMap::new();
+        for sample in samples {
+            let mut labels = group_labels(&sample.labels, modifier);
+            if let Some(label) = &count_values_label {
+                labels.insert(label.clone(), format_value(sample.point.value));
+            }
+            let group_id = *group_ids.entry(labels.clone()).or_insert_with(|| {
+                groups.push((labels, vec![]));
+                groups.len() - 1
+            });
+            groups[group_id].1.push(sample);
+        }
+
+        let mut result = vec![];
+        for (labels, samples) in groups {
+            if matches!(op, T_TOPK | T_BOTTOMK) {
+                result.extend(select_k(samples, param, op == T_TOPK));
+                continue;
+            }
+
+            let values: Vec<f64> = samples.iter().map(|sample| sample.point.value).collect();
+            let value = match op {
+                T_SUM => values.iter().sum(),
+                T_AVG => values.iter().sum::<f64>() / values.len() as f64,
+                T_COUNT | T_COUNT_VALUES => values.len() as f64,
+                T_MIN => min(&values),
+                T_MAX => max(&values),
+                T_STDDEV => variance(&values).sqrt(),
+                T_STDVAR => variance(&values),
+                T_GROUP => 1.0,
+                T_QUANTILE => quantile(param, values),
+                _ => {
+                    return Err(EngineError::Execution(format!(
+                        "unsupported aggregation `{}`",
+                        aggregate.op
+                    )))
+                }
+            };
+            result.push(VectorSample::new(labels, Point::new(timestamp, value)));
+        }
+        Ok(Value::Vector(result))
+    }
+}
+
+/// The labels identifying the group of a series for `by` and `without`.
+fn group_labels(labels: &Labels, modifier: Option<&LabelModifier>) -> Labels {
+    match modifier {
+        Some(LabelModifier::Include(by)) => labels
+            .iter()
+            .filter(|(name, _)| by.labels.contains(name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+        Some(LabelModifier::Exclude(without)) => labels
+            .iter()
+            .filter(|(name, _)| *name != METRIC_NAME && !without.labels.contains(name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+        None => Labels::new(),
+    }
+}
+
+/// Selects the `k` largest or smallest samples, NaN values come last.
+fn select_k(mut samples: Vec<VectorSample>, k: f64, largest: bool) -> Vec<VectorSample> {
+    if k.is_nan() || k < 1.0 {
+        return vec![];
+    }
+    samples.sort_by(|left, right| {
+        let (left, right) = (left.point.value, right.point.value);
+        match (left.is_nan(), right.is_nan()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) if largest => right.total_cmp(&left),
+            (false, false) => left.total_cmp(&right),
+        }
+    });
+    samples.truncate(k.min(samples.len() as f64) as usize);
+    samples
+}
+
+/// NaN values are only the minimum of NaN values.
+pub(super) fn min(values: &[f64]) -> f64 {
+    values.iter().fold(f64::NAN, |min, value| {
+        if *value < min || min.is_nan() {
+            *value
+        } else {
+            min
+        }
+    })
+}
+
+/// NaN values are only the maximum of NaN values.
+pub(super) fn max(values: &[f64]) -> f64 {
+    values.iter().fold(f64::NAN, |max, value| {
+        if *value > max || max.is_nan() {
+            *value
+        } else {
+            max
+        }
+    })
+}
+
+/// The population variance, computed with Welford's online algorithm.
+pub(super) fn variance(values: &[f64]) -> f64 {
+    let mut count = 0.0;
+    let mut mean = 0.0;
+    let mut sum_squares = 0.0;
+    for value in values {
+        count += 1.0;
+        let delta = value - mean;
+        mean += delta / count;
+        sum_squares += delta * (value - mean);
+    }
+    sum_squares / count
+}
+
+/// The φ-quantile of the values, linearly interpolated between the closest ranks.
+pub(super) fn quantile(q: f64, mut values: Vec<f64>) -> f64 {
+    if values.is_empty() || q.is_nan() {
+        return f64::NAN;
+    }
+    if q < 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if q > 1.0 {
+        return f64::INFINITY;
+    }
+    // NaN values come first, as in Go.
+    values.sort_by(|left, right| match (left.is_nan(), right.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => left.total_cmp(right),
+    });
+
+    let rank = q * (values.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = (lower + 1).min(values.len() - 1);
+    let weight = rank - rank.floor();
+    values[lower] * (1.0 - weight) + values[upper] * weight
+}
@@ -0,0 +1,322 @@
+use std::collections::{HashMap, HashSet};
+
+use promql_parser::{
+    label::METRIC_NAME,
+    parser::{
+        token::{
+            TokenType, T_ADD, T_ATAN2, T_DIV, T_EQLC, T_GTE, T_GTR, T_LAND, T_LOR, T_LSS, T_LTE,
+            T_LUNLESS, T_MOD, T_MUL, T_NEQ, T_POW, T_SUB,
+        },
+        BinaryExpr, LabelModifier, VectorMatchCardinality,
+    },
+};
+
+use super::{
+    check_unique_labels, unexpected_value,
+    value::{drop_metric_name, labels_to_string},
+    EngineError, EngineResult, Evaluator, Labels, Point, Value, VectorSample,
+};
+
+impl Evaluator {
+    pub(super) fn eval_binary(&self, binary: &BinaryExpr, timestamp: i64) -> EngineResult<Value> {
+        let lhs = self.eval_floats(&binary.lhs, timestamp)?;
+        let rhs = self.eval_floats(&binary.rhs, timestamp)?;
+        let op = binary.op.id();
+        let return_bool = binary.return_bool();
+
+        match (lhs, rhs) {
+            (Value::Scalar(lhs), Value::Scalar(rhs)) => {
+                let (value, keep) = binary_op(op, lhs.value, rhs.value)?;
+                let value = if is_comparison(op) {
+                    bool_value(keep)
+                } else {
+                    value
+                };
+                Ok(Value::Scalar(Point::new(timestamp, value)))
+            }
+            (Value::Vector(lhs), Value::Scalar(rhs)) => {
+                vector_scalar_op(op, lhs, rhs.value, false, return_bool).map(Value::Vector)
+            }
+            (Value::Scalar(lhs), Value::Vector(rhs)) => {
+                vector_scalar_op(op, rhs, lhs.value, true, return_bool).map(Value::Vector)
+            }
+            (Value::Vector(lhs), Value::Vector(rhs)) => {
+                let modifier = binary.modifier.as_ref();
+                let matching = modifier.and_then(|modifier| modifier.matching.as_ref());
+                let samples = match op {
+                    T_LAND => and_op(lhs, rhs, matching),
+                    T_LOR => or_op(lhs, rhs, matching),
+                    T_LUNLESS => unless_op(lhs, rhs, matching),
+                    _ => {
+                        let card = modifier.map_or(VectorMatchCardinality::OneToOne, |modifier| {
+                            modifier.card.clone()
+                        });
+                        let samples = vector_op(op, lhs, rhs, &card, matching, return_bool)?;
+                        check_unique_labels(&samples)?;
+                        samples
+                    }
+                };
+                Ok(Value::Vector(samples))
+            }
+            (lhs, Value::Scalar(_)) | (lhs, Value::Vector(_)) => {
+                Err(unexpected_value("binary operand", &lhs))
+            }
+            (_, rhs) => Err(unexpected_value("binary operand", &rhs)),
+        }
+    }
+}
+
+fn is_comparison(op: u8) -> bool {
+    matches!(op, T_EQLC | T_NEQ | T_GTR | T_LSS | T_GTE | T_LTE)
+}
+
+/// Arithmetic operators change the meaning of a series.
+fn drops_metric_name(op: u8) -> bool {
+    matches!(op, T_ADD | T_SUB | T_MUL | T_DIV | T_MOD | T_POW | T_ATAN2)
+}
+
+fn bool_value(keep: bool) -> f64 {
+    if keep {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Returns the value of an operation and whether comparisons hold.
+fn binary_op(op: u8, lhs: f64, rhs: f64) -> EngineResult<(f64, bool)> {
+    let result = match op {
+        T_ADD => (lhs + rhs, true),
+        T_SUB => (lhs - rhs, true),
+        T_MUL => (lhs * rhs, true),
+        T_DIV => (lhs / rhs, true),
+        T_MOD => (lhs % rhs, true),
+        T_POW => (lhs.powf(rhs), true),
+        T_ATAN2 => (lhs.atan2(rhs), true),
+        T_EQLC => (lhs, lhs == rhs),
+        T_NEQ => (lhs, lhs != rhs),
+        T_GTR => (lhs, lhs > rhs),
+        T_LSS => (lhs, lhs < rhs),
+        T_GTE => (lhs, lhs >= rhs),
+        T_LTE => (lhs, lhs <= rhs),
+        _ => {
+            return Err(EngineError::Execution(format!(
+                "operator `{}` not allowed between these operands",
+                TokenType::new(op)
+            )))
+        }
+    };
+    Ok(result)
+}
+
+/// Applies an operation between each sample and a scalar, `swap` is set
+/// when the scalar is the left hand side.
+fn vector_scalar_op(
+    op: u8,
+    samples: Vec<VectorSample>,
+    scalar: f64,
+    swap: bool,
+    return_bool: bool,
+) -> EngineResult<Vec<VectorSample>> {
+    let mut result = Vec::with_capacity(samples.len());
+    for mut sample in samples {
+        let (lhs, rhs) = if swap {
+            (scalar, sample.point.value)
+        } else {
+            (sample.point.value, scalar)
+        };
+        let (mut value, keep) = binary_op(op, lhs, rhs)?;
+        // Comparisons keep the value of the vector whatever its side.
+        if is_comparison(op) && swap {
+            value = rhs;
+        }
+        if return_bool {
+            value = bool_value(keep);
+        } else if !keep {
+            continue;
+        }
+        if drops_metric_name(op) || return_bool {
+            drop_metric_name(&mut sample.labels);
+        }
+        sample.point.value = value;
+        result.push(sample);
+    }
+    check_unique_labels(&result)?;
+    Ok(result)
+}
+
+/// The labels two samples are matched on.
+fn signature(labels: &Labels, matching: Option<&LabelModifier>) -> Labels {
+    labels
+        .iter()
+        .filter(|(name, _)| match matching {
+            Some(LabelModifier::Include(on)) => on.labels.contains(name),
+            Some(LabelModifier::Exclude(ignoring)) => {
+                *name != METRIC_NAME && !ignoring.labels.contains(name)
+            }
+            None => *name != METRIC_NAME,
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+fn and_op(
+    lhs: Vec<VectorSample>,
+    rhs: Vec<VectorSample>,
+    matching: Option<&LabelModifier>,
+) -> Vec<VectorSample> {
+    let rhs_signatures: HashSet<Labels> = rhs
+        .iter()
+        .map(|sample| signature(&sample.labels, matching))
+        .collect();
+    lhs.into_iter()
+        .filter(|sample| rhs_signatures.contains(&signature(&sample.labels, matching)))
+        .collect()
+}
+
+fn or_op(
+    lhs: Vec<VectorSample>,
+    rhs: Vec<VectorSample>,
+    matching: Option<&LabelModifier>,
+) -> Vec<VectorSample> {
+    let lhs_signatures: HashSet<Labels> = lhs
+        .iter()
+        .map(|sample| signature(&sample.labels, matching))
+        .collect();
+    let mut result = lhs;
+    result.extend(
+        rhs.into_iter()
+            .filter(|sample| !lhs_signatures.contains(&signature(&sample.labels, matching))),
+    );
+    result
+}
+
+fn unless_op(
+    lhs: Vec<VectorSample>,
+    rhs: Vec<VectorSample>,
+    matching: Option<&LabelModifier>,
+) -> Vec<VectorSample> {
+    let rhs_signatures: HashSet<Labels> = rhs
+        .iter()
+        .map(|sample| signature(&sample.labels, matching))
+        .collect();
+    lhs.into_iter()
+        .filter(|sample| !rhs_signatures.contains(&signature(&sample.labels, matching)))
+        .collect()
+}
+
+/// Applies an arithmetic or comparison operation between the matching samples
+/// of two vectors, the "many" side of `group_left`/`group_right` may have
+/// several samples per match group.
+fn vector_op(
+    op: u8,
+    lhs: Vec<VectorSample>,
+    rhs: Vec<VectorSample>,
+    card: &VectorMatchCardinality,
+    matching: Option<&LabelModifier>,
+    return_bool: bool,
+) -> EngineResult<Vec<VectorSample>> {
+    // The "many" side is always handled as the left hand side.
+    let swap = matches!(card, VectorMatchCardinality::OneToMany(_));
+    let (many, one) = if swap { (rhs, lhs) } else { (lhs, rhs) };
+
+    let mut one_by_signature: HashMap<Labels, &VectorSample> = HashMap::new();
+    for sample in one.iter() {
+        let signature = signature(&sample.labels, matching);
+        if let Some(duplicate) = one_by_signature.insert(signature.clone(), sample) {
+            return Err(EngineError::Execution(format!(
+                "found duplicate series for the match group {} on the {} hand-side of the operation: [{}, {}];many-to-many matching not allowed: matching labels must be unique on one side",
+                labels_to_string(&signature),
+                if swap { "left" } else { "right" },
+                labels_to_string(&duplicate.labels),
+                labels_to_string(&sample.labels),
+            )));
+        }
+    }
+
+    let mut matched_signatures = HashSet::new();
+    let mut result_labels = HashSet::new();
+    let mut result = vec![];
+    for many_sample in many {
+        let signature = signature(&many_sample.labels, matching);
+        let Some(one_sample) = one_by_signature.get(&signature) else {
+            continue;
+        };
+        let (lhs_value, rhs_value) = if swap {
+            (one_sample.point.value, many_sample.point.value)
+        } else {
+            (many_sample.point.value, one_sample.point.value)
+        };
+        let (mut value, keep) = binary_op(op, lhs_value, rhs_value)?;
+        if return_bool {
+            value = bool_value(keep);
+        } else if !keep {
+            continue;
+        }
+
+        let labels = result_labels_of(
+            &many_sample.labels,
+            &one_sample.labels,
+            op,
+            return_bool,
+            card,
+            matching,
+        );
+        if let VectorMatchCardinality::OneToOne = card {
+            if !matched_signatures.insert(signature) {
+                return Err(EngineError::Execution(
+                    "multiple matches for labels: many-to-one matching must be explicit (group_left/group_right)".to_string(),
+                ));
+            }
+        } else if !result_labels.insert(labels.clone()) {
+            return Err(EngineError::Execution(
+                "multiple matches for labels: grouping labels must ensure unique matches"
+                    .to_string(),
+            ));
+        }
+        result.push(VectorSample::new(
+            labels,
+            Point::new(many_sample.point.timestamp, value),
+        ));
+    }
+    Ok(result)
+}
+
+/// The labels of the result of an operation: those of the "many" side,
+/// restricted to the matching labels for one-to-one matching, plus the
+/// labels of the "one" side listed by `group_left`/`group_right`.
+fn result_labels_of(
+    many: &Labels,
+    one: &Labels,
+    op: u8,
+    return_bool: bool,
+    card: &VectorMatchCardinality,
+    matching: Option<&LabelModifier>,
+) -> Labels {
+    let mut labels = many.clone();
+    if drops_metric_name(op) || return_bool {
+        drop_metric_name(&mut labels);
+    }
+    if let VectorMatchCardinality::OneToOne = card {
+        match matching {
+            Some(LabelModifier::Include(on)) => labels.retain(|name, _| on.labels.contains(name)),
+            Some(LabelModifier::Exclude(ignoring)) => {
+                labels.retain(|name, _| !ignoring.labels.contains(name))
+            }
+            None => {}
+        }
+    }
+    if let Some(include) = card.labels() {
+        for name in include.labels.iter() {
+            match one.get(name) {
+                Some(value) if !value.is_empty() => {
+                    labels.insert(name.clone(), value.clone());
+                }
+                _ => {
+                    labels.remove(name);
+                }
+            }
+        }
+    }
+    labels
+}
@@ -0,0 +1,663 @@
+use std::{cmp::Ordering, collections::HashMap, f64::consts::PI};
+
+use promql_parser::{
+    label::{MatchOp, METRIC_NAME},
+    parser::{Call, Expr},
+};
+use regex::Regex;
+use storage::HistogramSample as NativeHistogram;
+use time::OffsetDateTime;
+
+use super::{
+    aggregate::{max, min, quantile, variance},
+    check_unique_labels,
+    value::{drop_metric_name, is_valid_label_name},
+    EngineError, EngineResult, Evaluator, Labels, Point, RangeVector, Value, VectorSample,
+};
+
+/// The label of the upper bound of a histogram bucket.
+const BUCKET_LABEL: &str = "le";
+
+impl Evaluator {
+    pub(super) fn eval_call(&self, call: &Call, timestamp: i64) -> EngineResult<Value> {
+        let args: Vec<&Expr> = call.args.args.iter().map(|arg| arg.as_ref()).collect();
+        let samples = match call.func.name {
+            "time" => {
+                return Ok(Value::Scalar(Point::new(
+                    timestamp,
+                    timestamp as f64 / 1000.0,
+                )))
+            }
+            "pi" => return Ok(Value::Scalar(Point::new(timestamp, PI))),
+            "scalar" => {
+                let samples = self.eval_vector(args[0], timestamp)?;
+                let value = match samples.as_slice() {
+                    [sample] => sample.point.value,
+                    _ => f64::NAN,
+                };
+                return Ok(Value::Scalar(Point::new(timestamp, value)));
+            }
+            "vector" => vec![VectorSample::new(
+                Labels::new(),
+                Point::new(timestamp, self.eval_scalar(args[0], timestamp)?),
+            )],
+
+            "abs" => self.map_values(args[0], timestamp, f64::abs)?,
+            "ceil" => self.map_values(args[0], timestamp, f64::ceil)?,
+            "floor" => self.map_values(args[0], timestamp, f64::floor)?,
+            "exp" => self.map_values(args[0], timestamp, f64::exp)?,
+            "ln" => self.map_values(args[0], timestamp, f64::ln)?,
+            "log2" => self.map_values(args[0], timestamp, f64::log2)?,
+            "log10" => self.map_values(args[0], timestamp, f64::log10)?,
+            "sqrt" => self.map_values(args[0], timestamp, f64::sqrt)?,
+            "sgn" => self.map_values(args[0], timestamp, |value| {
+                if value > 0.0 {
+                    1.0
+                } else if value < 0.0 {
+                    -1.0
+                } else {
+                    value
+                }
+            })?,
+            "acos" => self.map_values(args[0], timestamp, f64::acos)?,
+            "acosh" => self.map_values(args[0], timestamp, f64::acosh)?,
+            "asin" => self.map_values(args[0], timestamp, f64::asin)?,
+            "asinh" => self.map_values(args[0], timestamp, f64::asinh)?,
+            "atan" => self.map_values(args[0], timestamp, f64::atan)?,
+            "atanh" => self.map_values(args[0], timestamp, f64::atanh)?,
+            "cos" => self.map_values(args[0], timestamp, f64::cos)?,
+            "cosh" => self.map_values(args[0], timestamp, f64::cosh)?,
+            "sin" => self.map_values(args[0], timestamp, f64::sin)?,
+            "sinh" => self.map_values(args[0], timestamp, f64::sinh)?,
+            "tan" => self.map_values(args[0], timestamp, f64::tan)?,
+            "tanh" => self.map_values(args[0], timestamp, f64::tanh)?,
+            "deg" => self.map_values(args[0], timestamp, f64::to_degrees)?,
+            "rad" => self.map_values(args[0], timestamp, f64::to_radians)?,
+            "round" => {
+                let to_nearest = match args.get(1) {
+                    Some(arg) => self.eval_scalar(arg, timestamp)?,
+                    None => 1.0,
+                };
+                // Dividing by the inverse is more accurate for e.g. 0.1.
+                let to_nearest_inverse = 1.0 / to_nearest;
+                self.map_values(args[0], timestamp, |value| {
+                    (value * to_nearest_inverse + 0.5).floor() / to_nearest_inverse
+                })?
+            }
+            "clamp" => {
+                let min = self.eval_scalar(args[1], timestamp)?;
+                let max = self.eval_scalar(args[2], timestamp)?;
+                if max < min {
+                    vec![]
+                } else {
+                    self.map_values(args[0], timestamp, |value| value.max(min).min(max))?
+                }
+            }
+            "clamp_min" => {
+                let min = self.eval_scalar(args[1], timestamp)?;
+                self.map_values(args[0], timestamp, |value| value.max(min))?
+            }
+            "clamp_max" => {
+                let max = self.eval_scalar(args[1], timestamp)?;
+                self.map_values(args[0], timestamp, |value| value.min(max))?
+            }
+            "timestamp" => {
+                let samples = match unwrap_parens(args[0]) {
+                    Expr::VectorSelector(selector) => {
+                        self.eval_vector_selector(selector, timestamp, true)
+                    }
+                    arg => self.eval_samples(arg, timestamp)?,
+                };
+                samples
+                    .into_iter()
+                    .map(|mut sample| {
+                        drop_metric_name(&mut sample.labels);
+                        let value = sample.point.timestamp as f64 / 1000.0;
+                        sample.point = Point::new(timestamp, value);
+                        sample.histogram = None;
+                        sample
+                    })
+                    .collect()
+            }
+
+            "day_of_month" => {
+                self.eval_date_function(&args, timestamp, |date| date.day() as f64)?
+            }
+            "day_of_week" => self.eval_date_function(&args, timestamp, |date| {
+                date.weekday().number_days_from_sunday() as f64
+            })?,
+            "day_of_year" => {
+                self.eval_date_function(&args, timestamp, |date| date.ordinal() as f64)?
+            }
+            "days_in_month" => self.eval_date_function(&args, timestamp, |date| {
+                date.month().length(date.year()) as f64
+            })?,
+            "hour" => self.eval_date_function(&args, timestamp, |date| date.hour() as f64)?,
+            "minute" => self.eval_date_function(&args, timestamp, |date| date.minute() as f64)?,
+            "month" => {
+                self.eval_date_function(&args, timestamp, |date| u8::from(date.month()) as f64)?
+            }
+            "year" => self.eval_date_function(&args, timestamp, |date| date.year() as f64)?,
+
+            "sort" | "sort_desc" => {
+                let mut samples = self.eval_vector(args[0], timestamp)?;
+                let descending = call.func.name == "sort_desc";
+                // NaN values come last in both orders.
+                samples.sort_by(|left, right| {
+                    let (left, right) = (left.point.value, right.point.value);
+                    match (left.is_nan(), right.is_nan()) {
+                        (true, true) => Ordering::Equal,
+                        (true, false) => Ordering::Greater,
+                        (false, true) => Ordering::Less,
+                        (false, false) if descending => right.total_cmp(&left),
+                        (false, false) => left.total_cmp(&right),
+                    }
+                });
+                return Ok(Value::Vector(samples));
+            }
+            "label_replace" => self.eval_label_replace(&args, timestamp)?,
+            "label_join" => self.eval_label_join(&args, timestamp)?,
+            "absent" => {
+                if self.eval_samples(args[0], timestamp)?.is_empty() {
+                    absent_sample(args[0], timestamp)
+                } else {
+                    vec![]
+                }
+            }
+            "histogram_count" => self.map_histograms(args[0], timestamp, |h| h.count)?,
+            "histogram_sum" => self.map_histograms(args[0], timestamp, |h| h.sum)?,
+            "histogram_avg" => self.map_histograms(args[0], timestamp, |h| h.sum / h.count)?,
+            "histogram_quantile" => {
+                let q = self.eval_scalar(args[0], timestamp)?;
+                let samples = self.eval_vector(args[1], timestamp)?;
+                histogram_quantile(q, samples, timestamp)
+            }
+
+            "rate" => self.eval_range_function(args[0], timestamp, false, |points, range| {
+                extrapolated_rate(points, range, true, true)
+            })?,
+            "increase" => {
+                self.eval_range_function(args[0], timestamp, false, |points, range| {
+                    extrapolated_rate(points, range, true, false)
+                })?
+            }
+            "delta" => self.eval_range_function(args[0], timestamp, false, |points, range| {
+                extrapolated_rate(points, range, false, false)
+            })?,
+            "irate" => self.eval_range_function(args[0], timestamp, false, |points, _| {
+                instant_value(points, true)
+            })?,
+            "idelta" => self.eval_range_function(args[0], timestamp, false, |points, _| {
+                instant_value(points, false)
+            })?,
+            "deriv" => self.eval_range_function(args[0], timestamp, false, |points, _| {
+                (points.len() >= 2).then(|| linear_regression(points, points[0].timestamp).0)
+            })?,
+            "predict_linear" => {
+                let duration = self.eval_scalar(args[1], timestamp)?;
+                self.eval_range_function(args[0], timestamp, false, |points, _| {
+                    (points.len() >= 2).then(|| {
+                        let (slope, intercept) = linear_regression(points, timestamp);
+                        slope * duration + intercept
+                    })
+                })?
+            }
+            "changes" => self.eval_range_function(args[0], timestamp, false, |points, _| {
+                let changes = points
+                    .windows(2)
+                    .filter(|pair| {
+                        let (previous, current) = (pair[0].value, pair[1].value);
+                        current != previous && !(current.is_nan() && previous.is_nan())
+                    })
+                    .count();
+                Some(changes as f64)
+            })?,
+            "resets" => self.eval_range_function(args[0], timestamp, false, |points, _| {
+                let resets = points
+                    .windows(2)
+                    .filter(|pair| pair[1].value < pair[0].value)
+                    .count();
+                Some(resets as f64)
+            })?,
+            "avg_over_time" => self.eval_over_time(args[0], timestamp, |values| {
+                values.iter().sum::<f64>() / values.len() as f64
+            })?,
+            "min_over_time" => self.eval_over_time(args[0], timestamp, min)?,
+            "max_over_time" => self.eval_over_time(args[0], timestamp, max)?,
+            "sum_over_time" => {
+                self.eval_over_time(args[0], timestamp, |values| values.iter().sum())?
+            }
+            "count_over_time" => {
+                self.eval_over_time(args[0], timestamp, |values| values.len() as f64)?
+            }
+            "stddev_over_time" => {
+                self.eval_over_time(args[0], timestamp, |values| variance(values).sqrt())?
+            }
+            "stdvar_over_time" => self.eval_over_time(args[0], timestamp, variance)?,
+            "present_over_time" => self.eval_over_time(args[0], timestamp, |_| 1.0)?,
+            "quantile_over_time" => {
+                let q = self.eval_scalar(args[0], timestamp)?;
+                self.eval_over_time(args[1], timestamp, |values| quantile(q, values.to_vec()))?
+            }
+            // The value of the series is unchanged, so is its name.
+            "last_over_time" => {
+                self.eval_range_function(args[0], timestamp, true, |points, _| {
+                    points.last().map(|point| point.value)
+                })?
+            }
+            "absent_over_time" => {
+                if self
+                    .eval_range_vector(args[0], timestamp)?
+                    .series
+                    .is_empty()
+                {
+                    absent_sample(args[0], timestamp)
+                } else {
+                    vec![]
+                }
+            }
+            name => {
+                return Err(EngineError::Execution(format!(
+                    "function `{}` is not supported",
+                    name
+                )))
+            }
+        };
+        check_unique_labels(&samples)?;
+        Ok(Value::Vector(samples))
+    }
+
+    /// Applies a function to the value of each sample.
+    fn map_values(
+        &self,
+        arg: &Expr,
+        timestamp: i64,
+        f: impl Fn(f64) -> f64,
+    ) -> EngineResult<Vec<VectorSample>> {
+        let mut samples = self.eval_vector(arg, timestamp)?;
+        for sample in samples.iter_mut() {
+            drop_metric_name(&mut sample.labels);
+            sample.point.value = f(sample.point.value);
+        }
+        Ok(samples)
+    }
+
+    /// Applies a function to each native histogram sample, float samples are dropped.
+    fn map_histograms(
+        &self,
+        arg: &Expr,
+        timestamp: i64,
+        f: impl Fn(&NativeHistogram) -> f64,
+    ) -> EngineResult<Vec<VectorSample>> {
+        let mut samples = self.eval_samples(arg, timestamp)?;
+        samples.retain(|sample| sample.histogram.is_some());
+        for sample in samples.iter_mut() {
+            drop_metric_name(&mut sample.labels);
+            if let Some(histogram) = sample.histogram.take() {
+                sample.point.value = f(&histogram);
+            }
+        }
+        Ok(samples)
+    }
+
+    /// Applies a function to the UTC date of each sample value,
+    /// the date of the evaluation time without argument.
+    fn eval_date_function(
+        &self,
+        args: &[&Expr],
+        timestamp: i64,
+        f: impl Fn(OffsetDateTime) -> f64,
+    ) -> EngineResult<Vec<VectorSample>> {
+        let mut samples = match args.first() {
+            Some(arg) => self.eval_vector(arg, timestamp)?,
+            None => vec![VectorSample::new(
+                Labels::new(),
+                Point::new(timestamp, timestamp as f64 / 1000.0),
+            )],
+        };
+        for sample in samples.iter_mut() {
+            drop_metric_name(&mut sample.labels);
+            let date = OffsetDateTime::from_unix_timestamp(sample.point.value as i64);
+            sample.point.value = match date {
+                Ok(date) if sample.point.value.is_finite() => f(date),
+                _ => f64::NAN,
+            };
+        }
+        Ok(samples)
+    }
+
+    /// Applies a function to the points of each series of a range vector.
+    fn eval_range_function(
+        &self,
+        arg: &Expr,
+        timestamp: i64,
+        keep_metric_name: bool,
+        f: impl Fn(&[Point], &RangeVector) -> Option<f64>,
+    ) -> EngineResult<Vec<VectorSample>> {
+        let range_vector = self.eval_range_vector(arg, timestamp)?;
+        let mut samples = vec![];
+        for series in range_vector.series.iter() {
+            let Some(value) = f(&series.points, &range_vector) else {
+                continue;
+            };
+            let mut labels = series.labels.clone();
+            if !keep_metric_name {
+                drop_metric_name(&mut labels);
+            }
+            samples.push(VectorSample::new(labels, Point::new(timestamp, value)));
+        }
+        Ok(samples)
+    }
+
+    /// Applies a function to the values of each series of a range vector.
+    fn eval_over_time(
+        &self,
+        arg: &Expr,
+        timestamp: i64,
+        f: impl Fn(&[f64]) -> f64,
+    ) -> EngineResult<Vec<VectorSample>> {
+        self.eval_range_function(arg, timestamp, false, |points, _| {
+            let values: Vec<f64> = points.iter().map(|point| point.value).collect();
+            Some(f(&values))
+        })
+    }
+
+    /// label_replace(v, dst, replacement, src, regex)
+    fn eval_label_replace(
+        &self,
+        args: &[&Expr],
+        timestamp: i64,
+    ) -> EngineResult<Vec<VectorSample>> {
+        let mut samples = self.eval_vector(args[0], timestamp)?;
+        let destination = self.eval_string(args[1], timestamp)?;
+        let replacement = self.eval_string(args[2], timestamp)?;
+        let source = self.eval_string(args[3], timestamp)?;
+        let regex = self.eval_string(args[4], timestamp)?;
+
+        let anchored_regex = Regex::new(&format!("^(?:{})$", regex)).map_err(|_| {
+            EngineError::Execution(format!(
+                "invalid regular expression in label_replace(): {}",
+                regex
+            ))
+        })?;
+        if !is_valid_label_name(&destination) {
+            return Err(EngineError::Execution(format!(
+                "invalid destination label name in label_replace(): {}",
+                destination
+            )));
+        }
+
+        for sample in samples.iter_mut() {
+            let source_value = sample.labels.get(&source).map_or("", String::as_str);
+            let Some(captures) = anchored_regex.captures(source_value) else {
+                continue;
+            };
+            let mut value = String::new();
+            captures.expand(&replacement, &mut value);
+            set_label(&mut sample.labels, &destination, value);
+        }
+        Ok(samples)
+    }
+
+    /// label_join(v, dst, separator, src_1, src_2, ...)
+    fn eval_label_join(&self, args: &[&Expr], timestamp: i64) -> EngineResult<Vec<VectorSample>> {
+        let mut samples = self.eval_vector(args[0], timestamp)?;
+        let destination = self.eval_string(args[1], timestamp)?;
+        let separator = self.eval_string(args[2], timestamp)?;
+        let sources = args[3..]
+            .iter()
+            .map(|arg| self.eval_string(arg, timestamp))
+            .collect::<EngineResult<Vec<String>>>()?;
+        if !is_valid_label_name(&destination) {
+            return Err(EngineError::Execution(format!(
+                "invalid destination label name in label_join(): {}",
+                destination
+            )));
+        }
+
+        for sample in samples.iter_mut() {
+            let values: Vec<&str> = sources
+                .iter()
+                .map(|source| sample.labels.get(source).map_or("", String::as_str))
+                .collect();
+            let value = values.join(&separator);
+            set_label(&mut sample.labels, &destination, value);
+        }
+        Ok(samples)
+    }
+}
+
+/// Empty labels are the same as missing ones.
+fn set_label(labels: &mut Labels, name: &str, value: String) {
+    if value.is_empty() {
+        labels.remove(name);
+    } else {
+        labels.insert(name.to_string(), value);
+    }
+}
+
+fn unwrap_parens(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Paren(paren) => unwrap_parens(&paren.expr),
+        expr => expr,
+    }
+}
+
+/// The sample of `absent`, labelled with the equality matchers of the selector.
+fn absent_sample(arg: &Expr, timestamp: i64) -> Vec<VectorSample> {
+    let selector = match unwrap_parens(arg) {
+        Expr::VectorSelector(selector) => Some(selector),
+        Expr::MatrixSelector(selector) => Some(&selector.vs),
+        _ => None,
+    };
+
+    let mut labels = Labels::new();
+    if let Some(selector) = selector {
+        // Labels matched more than once are ambiguous.
+        let mut seen = HashMap::new();
+        for matcher in selector.matchers.matchers.iter() {
+            if matcher.name == METRIC_NAME {
+                continue;
+            }
+            let count = seen.entry(matcher.name.clone()).or_insert(0);
+            *count += 1;
+            if matches!(matcher.op, MatchOp::Equal) && *count == 1 {
+                labels.insert(matcher.name.clone(), matcher.value.clone());
+            } else {
+                labels.remove(&matcher.name);
+            }
+        }
+    }
+    vec![VectorSample::new(labels, Point::new(timestamp, 1.0))]
+}
+
+/// Computes the increase of a series over a range, extrapolated to the range
+/// boundaries when the first and last samples are close enough to them, as in
+/// Prometheus. Counter resets are compensated for counters.
+fn extrapolated_rate(
+    points: &[Point],
+    range_vector: &RangeVector,
+    is_counter: bool,
+    is_rate: bool,
+) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+    let first = points[0];
+    let last = points[points.len() - 1];
+
+    let mut result = last.value - first.value;
+    if is_counter {
+        for pair in points.windows(2) {
+            if pair[1].value < pair[0].value {
+                result += pair[0].value;
+            }
+        }
+    }
+
+    let range_start = range_vector.end - range_vector.range;
+    let mut duration_to_start = (first.timestamp - range_start) as f64 / 1000.0;
+    let mut duration_to_end = (range_vector.end - last.timestamp) as f64 / 1000.0;
+    let sampled_interval = (last.timestamp - first.timestamp) as f64 / 1000.0;
+    let average_interval = sampled_interval / (points.len() - 1) as f64;
+
+    // Samples far from a boundary mean the series starts or ends within the range.
+    let extrapolation_threshold = average_interval * 1.1;
+    if duration_to_start >= extrapolation_threshold {
+        duration_to_start = average_interval / 2.0;
+    }
+    // Counters can't be extrapolated below zero.
+    if is_counter && result > 0.0 && first.value >= 0.0 {
+        let duration_to_zero = sampled_interval * (first.value / result);
+        if duration_to_zero < duration_to_start {
+            duration_to_start = duration_to_zero;
+        }
+    }
+    if duration_to_end >= extrapolation_threshold {
+        duration_to_end = average_interval / 2.0;
+    }
+
+    let extrapolated_interval = sampled_interval + duration_to_start + duration_to_end;
+    let mut factor = extrapolated_interval / sampled_interval;
+    if is_rate {
+        factor /= range_vector.range as f64 / 1000.0;
+    }
+    Some(result * factor)
+}
+
+/// Computes `irate` or `idelta` from the last two samples.
+fn instant_value(points: &[Point], is_rate: bool) -> Option<f64> {
+    let [.., previous, last] = points else {
+        return None;
+    };
+    let result = if is_rate && last.value < previous.value {
+        // Counter reset.
+        last.value
+    } else {
+        last.value - previous.value
+    };
+    if !is_rate {
+        return Some(result);
+    }
+    let interval = last.timestamp - previous.timestamp;
+    (interval != 0).then(|| result / (interval as f64 / 1000.0))
+}
+
+/// Returns the slope (per second) and the intercept at `intercept_time`
+/// of the least squares line of the points.
+fn linear_regression(points: &[Point], intercept_time: i64) -> (f64, f64) {
+    let initial_value = points[0].value;
+    if points.iter().all(|point| point.value == initial_value) {
+        if initial_value.is_infinite() {
+            return (f64::NAN, f64::NAN);
+        }
+        return (0.0, initial_value);
+    }
+
+    let count = points.len() as f64;
+    let (mut sum_x, mut sum_y, mut sum_xy, mut sum_x2) = (0.0, 0.0, 0.0, 0.0);
+    for point in points {
+        let x = (point.timestamp - intercept_time) as f64 / 1000.0;
+        sum_x += x;
+        sum_y += point.value;
+        sum_xy += x * point.value;
+        sum_x2 += x * x;
+    }
+    let covariance = sum_xy - sum_x * sum_y / count;
+    let variance = sum_x2 - sum_x * sum_x / count;
+    let slope = covariance / variance;
+    let intercept = sum_y / count - slope * sum_x / count;
+    (slope, intercept)
+}
+
+/// Computes the φ-quantile of the classic histogram buckets of each series,
+/// buckets are identified by their `le` label.
+fn histogram_quantile(q: f64, samples: Vec<VectorSample>, timestamp: i64) -> Vec<VectorSample> {
+    let mut histograms: Vec<(Labels, Vec<(f64, f64)>)> = vec![];
+    let mut histogram_ids: HashMap<Labels, usize> = HashMap::new();
+    for sample in samples {
+        let Some(upper_bound) = sample
+            .labels
+            .get(BUCKET_LABEL)
+            .and_then(|bound| bound.parse::<f64>().ok())
+        else {
+            continue;
+        };
+        let mut labels = sample.labels;
+        labels.remove(BUCKET_LABEL);
+        drop_metric_name(&mut labels);
+        let histogram_id = *histogram_ids.entry(labels.clone()).or_insert_with(|| {
+            histograms.push((labels, vec![]));
+            histograms.len() - 1
+        });
+        histograms[histogram_id]
+            .1
+            .push((upper_bound, sample.point.value));
+    }
+
+    histograms
+        .into_iter()
+        .map(|(labels, buckets)| {
+            VectorSample::new(labels, Point::new(timestamp, bucket_quantile(q, buckets)))
+        })
+        .collect()
+}
+
+/// The quantile of cumulative `(upper bound, count)` buckets, assuming
+/// a linear distribution of the observations within each bucket.
+fn bucket_quantile(q: f64, mut buckets: Vec<(f64, f64)>) -> f64 {
+    if q.is_nan() {
+        return f64::NAN;
+    }
+    if q < 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if q > 1.0 {
+        return f64::INFINITY;
+    }
+    buckets.sort_by(|left, right| left.0.total_cmp(&right.0));
+    if buckets
+        .last()
+        .is_none_or(|(upper_bound, _)| *upper_bound != f64::INFINITY)
+    {
+        return f64::NAN;
+    }
+
+    // Merge the buckets of the same bound, then make counts monotonic
+    // as they may be scraped at slightly different times.
+    let mut merged: Vec<(f64, f64)> = vec![];
+    for (upper_bound, count) in buckets {
+        match merged.last_mut() {
+            Some(last) if last.0 == upper_bound => last.1 += count,
+            _ => merged.push((upper_bound, count)),
+        }
+    }
+    for index in 1..merged.len() {
+        merged[index].1 = merged[index].1.max(merged[index - 1].1);
+    }
+    let buckets = merged;
+    if buckets.len() < 2 {
+        return f64::NAN;
+    }
+    let observations = buckets[buckets.len() - 1].1;
+    if observations == 0.0 {
+        return f64::NAN;
+    }
+
+    let mut rank = q * observations;
+    let bucket = buckets[..buckets.len() - 1].partition_point(|(_, count)| *count < rank);
+    if bucket == buckets.len() - 1 {
+        return buckets[buckets.len() - 2].0;
+    }
+    if bucket == 0 && buckets[0].0 <= 0.0 {
+        return buckets[0].0;
+    }
+
+    let (mut bucket_start, bucket_end, mut count) = (0.0, buckets[bucket].0, buckets[bucket].1);
+    if bucket > 0 {
+        bucket_start = buckets[bucket - 1].0;
+        count -= buckets[bucket - 1].1;
+        rank -= buckets[bucket - 1].1;
+    }
+    bucket_start + (bucket_end - bucket_start) * (rank / count)
+}
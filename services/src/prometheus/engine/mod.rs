@@ -0,0 +1,627 @@
+mod aggregate;
+mod binary;
+mod functions;
+#[cfg(test)]
+mod promqltest;
+mod value;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use fts::query::Query as NativeQuery;
+use promql_parser::{
+    label::{MatchOp, METRIC_NAME},
+    parser::{value::ValueType, AtModifier, Expr, Offset, VectorSelector},
+};
+use storage::Storage;
+use thiserror::Error;
+
+use super::remote::types::{
+    convert_prom_query_to_native_query, label_matcher::Type, LabelMatcher,
+    Query as PromProtoBuffQuery,
+};
+
+use self::value::{drop_metric_name, is_stale_nan};
+pub(crate) use self::value::{exemplars_to_json, is_valid_label_name, STALE_NAN};
+pub use self::value::{HistogramPoint, Labels, Point, Series, Value, VectorSample};
+
+/// How far back an instant vector selector looks for the latest sample.
+const DEFAULT_LOOKBACK_DELTA: i64 = 5 * 60 * 1000;
+
+/// The step of subqueries without one, the default Prometheus evaluation interval.
+const DEFAULT_SUBQUERY_STEP: i64 = 60 * 1000;
+
+pub type EngineResult<T> = Result<T, EngineError>;
+
+#[derive(Error, Debug)]
+pub enum EngineError {
+    #[error("{0}")]
+    Storage(#[from] storage::StorageError),
+    /// The query can't be evaluated as requested.
+    #[error("{0}")]
+    BadData(String),
+    /// The evaluation of the query failed.
+    #[error("{0}")]
+    Execution(String),
+    /// The evaluation didn't finish before the deadline of the query.
+    #[error("query timed out in expression evaluation")]
+    Timeout,
+}
+
+/// Evaluates PromQL expressions over the storage.
+#[derive(Debug, Clone)]
+pub struct Engine {
+    storage: Arc<Storage>,
+    lookback_delta: i64,
+    timeout: Option<Duration>,
+}
+
+impl Engine {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        Self {
+            storage,
+            lookback_delta: DEFAULT_LOOKBACK_DELTA,
+            timeout: None,
+        }
+    }
+
+    /// Stops the evaluation of each query after `timeout`. Evaluation steps
+    /// don't await, so the deadline is checked between them.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Evaluates an expression at `time` (in ms).
+    pub async fn instant_query(&self, expr: &Expr, time: i64) -> EngineResult<Value> {
+        let evaluator = Evaluator::load(self, expr, time, time).await?;
+        evaluator.eval(expr, time)
+    }
+
+    /// Evaluates an expression at each step from `start` to `end` (in ms),
+    /// the results are merged into a matrix.
+    pub async fn range_query(
+        &self,
+        expr: &Expr,
+        start: i64,
+        end: i64,
+        step: i64,
+    ) -> EngineResult<Value> {
+        let value_type = expr.value_type();
+        if !matches!(value_type, ValueType::Scalar | ValueType::Vector) {
+            return Err(EngineError::BadData(format!(
+                "invalid expression type \"{}\" for range query, must be Scalar or instant Vector",
+                value_type
+            )));
+        }
+
+        let evaluator = Evaluator::load(self, expr, start, end).await?;
+        let mut matrix: BTreeMap<Labels, Series> = BTreeMap::new();
+        let mut timestamp = start;
+        while timestamp <= end {
+            evaluator.check_deadline()?;
+            match evaluator.eval(expr, timestamp)? {
+                Value::Scalar(point) => {
+                    push_sample(&mut matrix, VectorSample::new(Labels::new(), point))
+                }
+                Value::Vector(samples) => {
+                    for sample in samples {
+                        push_sample(&mut matrix, sample);
+                    }
+                }
+                value => {
+                    return Err(EngineError::Execution(format!(
+                        "unexpected {} result of range query",
+                        value.result_type()
+                    )))
+                }
+            }
+            timestamp += step;
+        }
+        Ok(Value::Matrix(matrix.into_values().collect()))
+    }
+}
+
+/// Evaluates an expression once the series of its selectors are loaded.
+#[derive(Debug)]
+struct Evaluator {
+    start: i64,
+    end: i64,
+    lookback_delta: i64,
+    deadline: Option<Instant>,
+    /// The series of each selector over the time range the query needs.
+    selected: HashMap<String, Vec<Series>>,
+}
+
+impl Evaluator {
+    async fn load(engine: &Engine, expr: &Expr, start: i64, end: i64) -> EngineResult<Self> {
+        let deadline = engine.timeout.map(|timeout| Instant::now() + timeout);
+        let load = Self::load_selected(engine, expr, start, end, deadline);
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), load)
+                .await
+                .map_err(|_| EngineError::Timeout)?,
+            None => load.await,
+        }
+    }
+
+    async fn load_selected(
+        engine: &Engine,
+        expr: &Expr,
+        start: i64,
+        end: i64,
+        deadline: Option<Instant>,
+    ) -> EngineResult<Self> {
+        let mut evaluator = Self {
+            start,
+            end,
+            lookback_delta: engine.lookback_delta,
+            deadline,
+            selected: HashMap::new(),
+        };
+
+        let mut time_ranges = HashMap::new();
+        evaluator.collect_selectors(expr, (start, end), &mut time_ranges);
+        for (key, (selector, (min_time, max_time))) in time_ranges {
+            let query = selector_query(&selector)?;
+            let native_series = engine.storage.read(query, min_time, max_time + 1).await?;
+            let series = native_series
+                .into_iter()
+                .map(|series| {
+                    let (labels, samples, histograms) = series.into_parts();
+                    Series {
+                        labels: labels
+                            .into_iter()
+                            .map(|label| (label.name, label.value))
+                            .collect(),
+                        points: samples
+                            .into_iter()
+                            .map(|sample| Point::new(sample.timestamp, sample.value))
+                            .collect(),
+                        histograms: histograms
+                            .into_iter()
+                            .map(|histogram| HistogramPoint {
+                                timestamp: histogram.timestamp,
+                                histogram: Arc::new(histogram),
+                            })
+                            .collect(),
+                    }
+                })
+                .collect();
+            evaluator.selected.insert(key, series);
+        }
+        Ok(evaluator)
+    }
+
+    fn check_deadline(&self) -> EngineResult<()> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(EngineError::Timeout),
+            _ => Ok(()),
+        }
+    }
+
+    /// Collects the time range to load for each selector,
+    /// `time_range` is the range the expression is evaluated over.
+    fn collect_selectors(
+        &self,
+        expr: &Expr,
+        time_range: (i64, i64),
+        time_ranges: &mut HashMap<String, (VectorSelector, (i64, i64))>,
+    ) {
+        let mut add_selector = |selector: &VectorSelector, range: i64| {
+            let (min_time, max_time) =
+                self.shift_time_range(time_range, selector.at.as_ref(), selector.offset.as_ref());
+            let (_, selector_range) = time_ranges
+                .entry(selector_key(selector))
+                .or_insert_with(|| (selector.clone(), (min_time - range, max_time)));
+            selector_range.0 = selector_range.0.min(min_time - range);
+            selector_range.1 = selector_range.1.max(max_time);
+        };
+
+        match expr {
+            Expr::VectorSelector(selector) => add_selector(selector, self.lookback_delta),
+            Expr::MatrixSelector(selector) => {
+                add_selector(&selector.vs, selector.range.as_millis() as i64)
+            }
+            Expr::Subquery(subquery) => {
+                let (min_time, max_time) = self.shift_time_range(
+                    time_range,
+                    subquery.at.as_ref(),
+                    subquery.offset.as_ref(),
+                );
+                let range = subquery.range.as_millis() as i64;
+                self.collect_selectors(&subquery.expr, (min_time - range, max_time), time_ranges);
+            }
+            Expr::Aggregate(aggregate) => {
+                self.collect_selectors(&aggregate.expr, time_range, time_ranges);
+                if let Some(param) = &aggregate.param {
+                    self.collect_selectors(param, time_range, time_ranges);
+                }
+            }
+            Expr::Unary(unary) => self.collect_selectors(&unary.expr, time_range, time_ranges),
+            Expr::Binary(binary) => {
+                self.collect_selectors(&binary.lhs, time_range, time_ranges);
+                self.collect_selectors(&binary.rhs, time_range, time_ranges);
+            }
+            Expr::Paren(paren) => self.collect_selectors(&paren.expr, time_range, time_ranges),
+            Expr::Call(call) => {
+                for arg in call.args.args.iter() {
+                    self.collect_selectors(arg, time_range, time_ranges);
+                }
+            }
+            Expr::NumberLiteral(_) | Expr::StringLiteral(_) | Expr::Extension(_) => {}
+        }
+    }
+
+    fn eval(&self, expr: &Expr, timestamp: i64) -> EngineResult<Value> {
+        match expr {
+            Expr::NumberLiteral(number) => Ok(Value::Scalar(Point::new(timestamp, number.val))),
+            Expr::StringLiteral(string) => Ok(Value::String(timestamp, string.val.clone())),
+            Expr::Paren(paren) => self.eval(&paren.expr, timestamp),
+            Expr::Unary(unary) => match self.eval_floats(&unary.expr, timestamp)? {
+                Value::Scalar(point) => Ok(Value::Scalar(Point::new(timestamp, -point.value))),
+                Value::Vector(mut samples) => {
+                    for sample in samples.iter_mut() {
+                        drop_metric_name(&mut sample.labels);
+                        sample.point.value = -sample.point.value;
+                    }
+                    check_unique_labels(&samples)?;
+                    Ok(Value::Vector(samples))
+                }
+                value => Err(unexpected_value("unary expression", &value)),
+            },
+            Expr::Binary(binary) => self.eval_binary(binary, timestamp),
+            Expr::Aggregate(aggregate) => self.eval_aggregate(aggregate, timestamp),
+            Expr::Call(call) => self.eval_call(call, timestamp),
+            Expr::VectorSelector(selector) => Ok(Value::Vector(
+                self.eval_vector_selector(selector, timestamp, false),
+            )),
+            Expr::MatrixSelector(_) | Expr::Subquery(_) => Ok(Value::Matrix(
+                self.eval_range_vector(expr, timestamp)?.series,
+            )),
+            Expr::Extension(_) => Err(EngineError::Execution(format!(
+                "unsupported expression `{}`",
+                expr
+            ))),
+        }
+    }
+
+    /// Evaluates an expression, native histogram samples are dropped from vectors.
+    /// Only selectors and the `histogram_*` functions handle native histograms.
+    fn eval_floats(&self, expr: &Expr, timestamp: i64) -> EngineResult<Value> {
+        match self.eval(expr, timestamp)? {
+            Value::Vector(mut samples) => {
+                samples.retain(|sample| sample.histogram.is_none());
+                Ok(Value::Vector(samples))
+            }
+            value => Ok(value),
+        }
+    }
+
+    /// Evaluates an instant vector of float samples.
+    fn eval_vector(&self, expr: &Expr, timestamp: i64) -> EngineResult<Vec<VectorSample>> {
+        match self.eval_floats(expr, timestamp)? {
+            Value::Vector(samples) => Ok(samples),
+            value => Err(unexpected_value("instant vector", &value)),
+        }
+    }
+
+    /// Evaluates an instant vector of float and native histogram samples.
+    fn eval_samples(&self, expr: &Expr, timestamp: i64) -> EngineResult<Vec<VectorSample>> {
+        match self.eval(expr, timestamp)? {
+            Value::Vector(samples) => Ok(samples),
+            value => Err(unexpected_value("instant vector", &value)),
+        }
+    }
+
+    fn eval_scalar(&self, expr: &Expr, timestamp: i64) -> EngineResult<f64> {
+        match self.eval(expr, timestamp)? {
+            Value::Scalar(point) => Ok(point.value),
+            value => Err(unexpected_value("scalar", &value)),
+        }
+    }
+
+    fn eval_string(&self, expr: &Expr, timestamp: i64) -> EngineResult<String> {
+        match self.eval(expr, timestamp)? {
+            Value::String(_, string) => Ok(string),
+            value => Err(unexpected_value("string", &value)),
+        }
+    }
+
+    /// Evaluates a range vector, i.e. a matrix selector or a subquery.
+    fn eval_range_vector(&self, expr: &Expr, timestamp: i64) -> EngineResult<RangeVector> {
+        match expr {
+            Expr::Paren(paren) => self.eval_range_vector(&paren.expr, timestamp),
+            Expr::MatrixSelector(selector) => {
+                let range = selector.range.as_millis() as i64;
+                Ok(RangeVector {
+                    series: self.eval_range_selector(&selector.vs, range, timestamp),
+                    end: self.selector_time(&selector.vs, timestamp),
+                    range,
+                })
+            }
+            Expr::Subquery(subquery) => {
+                let (end, _) = self.shift_time_range(
+                    (timestamp, timestamp),
+                    subquery.at.as_ref(),
+                    subquery.offset.as_ref(),
+                );
+                let range = subquery.range.as_millis() as i64;
+                let step = subquery
+                    .step
+                    .map_or(DEFAULT_SUBQUERY_STEP, |step| step.as_millis() as i64);
+
+                // Steps are aligned on multiples of the step within `(end - range, end]`.
+                let start = end - range;
+                let mut step_timestamp = start.div_euclid(step) * step;
+                if step_timestamp <= start {
+                    step_timestamp += step;
+                }
+                let mut matrix: BTreeMap<Labels, Series> = BTreeMap::new();
+                while step_timestamp <= end {
+                    self.check_deadline()?;
+                    match self.eval(&subquery.expr, step_timestamp)? {
+                        Value::Scalar(point) => {
+                            push_sample(&mut matrix, VectorSample::new(Labels::new(), point))
+                        }
+                        Value::Vector(samples) => {
+                            for sample in samples {
+                                push_sample(&mut matrix, sample);
+                            }
+                        }
+                        value => return Err(unexpected_value("subquery", &value)),
+                    }
+                    step_timestamp += step;
+                }
+                let series = matrix.into_values().collect();
+                Ok(RangeVector { series, end, range })
+            }
+            expr => Err(EngineError::Execution(format!(
+                "expected range vector, got `{}`",
+                expr
+            ))),
+        }
+    }
+
+    /// Selects the latest sample or native histogram of each series within
+    /// the lookback delta, unless it is a staleness marker. Samples are at
+    /// `timestamp` unless `keep_timestamps` is set.
+    fn eval_vector_selector(
+        &self,
+        selector: &VectorSelector,
+        timestamp: i64,
+        keep_timestamps: bool,
+    ) -> Vec<VectorSample> {
+        let selector_time = self.selector_time(selector, timestamp);
+        let mut samples = vec![];
+        for series in self.selected_series(selector) {
+            let end = series
+                .points
+                .partition_point(|point| point.timestamp <= selector_time);
+            let point = end.checked_sub(1).map(|last| series.points[last]);
+            let end = series
+                .histograms
+                .partition_point(|point| point.timestamp <= selector_time);
+            let histogram_point = end.checked_sub(1).map(|last| &series.histograms[last]);
+
+            // A staleness marker also ends the preceding histograms.
+            let (point, histogram) = match (point, histogram_point) {
+                (point, Some(histogram_point))
+                    if point.is_none_or(|point| point.timestamp < histogram_point.timestamp) =>
+                {
+                    (
+                        Point::new(histogram_point.timestamp, 0.0),
+                        Some(histogram_point.histogram.clone()),
+                    )
+                }
+                (Some(point), _) if !is_stale_nan(point.value) => (point, None),
+                _ => continue,
+            };
+            if point.timestamp > selector_time - self.lookback_delta {
+                let sample_timestamp = if keep_timestamps {
+                    point.timestamp
+                } else {
+                    timestamp
+                };
+                samples.push(VectorSample {
+                    labels: series.labels.clone(),
+                    point: Point::new(sample_timestamp, point.value),
+                    histogram,
+                });
+            }
+        }
+        samples
+    }
+
+    /// Selects the samples and native histograms of each series within `range`,
+    /// staleness markers excluded.
+    fn eval_range_selector(
+        &self,
+        selector: &VectorSelector,
+        range: i64,
+        timestamp: i64,
+    ) -> Vec<Series> {
+        let selector_time = self.selector_time(selector, timestamp);
+        let mut matrix = vec![];
+        for series in self.selected_series(selector) {
+            let start = series
+                .points
+                .partition_point(|point| point.timestamp <= selector_time - range);
+            let end = series
+                .points
+                .partition_point(|point| point.timestamp <= selector_time);
+            let points: Vec<Point> = series.points[start..end]
+                .iter()
+                .filter(|point| !is_stale_nan(point.value))
+                .copied()
+                .collect();
+            let start = series
+                .histograms
+                .partition_point(|point| point.timestamp <= selector_time - range);
+            let end = series
+                .histograms
+                .partition_point(|point| point.timestamp <= selector_time);
+            let histograms = series.histograms[start..end].to_vec();
+            if !points.is_empty() || !histograms.is_empty() {
+                matrix.push(Series {
+                    labels: series.labels.clone(),
+                    points,
+                    histograms,
+                });
+            }
+        }
+        matrix
+    }
+
+    fn selected_series(&self, selector: &VectorSelector) -> &[Series] {
+        self.selected
+            .get(&selector_key(selector))
+            .map_or(&[], |series| series.as_slice())
+    }
+
+    /// Returns the time a selector reads at, given its `@` and `offset` modifiers.
+    fn selector_time(&self, selector: &VectorSelector, timestamp: i64) -> i64 {
+        let (selector_time, _) = self.shift_time_range(
+            (timestamp, timestamp),
+            selector.at.as_ref(),
+            selector.offset.as_ref(),
+        );
+        selector_time
+    }
+
+    fn shift_time_range(
+        &self,
+        (min_time, max_time): (i64, i64),
+        at: Option<&AtModifier>,
+        offset: Option<&Offset>,
+    ) -> (i64, i64) {
+        let (min_time, max_time) = match at {
+            Some(AtModifier::Start) => (self.start, self.start),
+            Some(AtModifier::End) => (self.end, self.end),
+            Some(AtModifier::At(time)) => (system_time_millis(time), system_time_millis(time)),
+            None => (min_time, max_time),
+        };
+        let offset = match offset {
+            Some(Offset::Pos(duration)) => duration.as_millis() as i64,
+            Some(Offset::Neg(duration)) => -(duration.as_millis() as i64),
+            None => 0,
+        };
+        (min_time - offset, max_time - offset)
+    }
+}
+
+/// The series of a range vector along with the time range they were selected over.
+#[derive(Debug)]
+struct RangeVector {
+    series: Vec<Series>,
+    /// The end of the range, `@` and `offset` applied.
+    end: i64,
+    range: i64,
+}
+
+/// Appends a sample to the series of its labels.
+fn push_sample(matrix: &mut BTreeMap<Labels, Series>, sample: VectorSample) {
+    matrix
+        .entry(sample.labels.clone())
+        .or_insert_with(|| Series::new(sample.labels.clone()))
+        .push(sample);
+}
+
+fn unexpected_value(expected: &str, value: &Value) -> EngineError {
+    EngineError::Execution(format!(
+        "expected {}, got {}",
+        expected,
+        value.result_type()
+    ))
+}
+
+/// Functions and operators dropping the metric name may make series collide.
+fn check_unique_labels(samples: &[VectorSample]) -> EngineResult<()> {
+    let mut labels = HashSet::with_capacity(samples.len());
+    if samples.iter().all(|sample| labels.insert(&sample.labels)) {
+        Ok(())
+    } else {
+        Err(EngineError::Execution(
+            "vector cannot contain metrics with the same labelset".to_string(),
+        ))
+    }
+}
+
+fn system_time_millis(time: &SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_millis() as i64,
+        Err(err) => -(err.duration().as_millis() as i64),
+    }
+}
+
+/// Identifies the series a selector selects, whatever its modifiers.
+fn selector_key(selector: &VectorSelector) -> String {
+    VectorSelector::new(selector.name.clone(), selector.matchers.clone()).to_string()
+}
+
+/// Returns the vector selectors of an expression, those of range selectors included.
+pub(crate) fn expr_selectors(expr: &Expr) -> Vec<&VectorSelector> {
+    fn collect<'a>(expr: &'a Expr, selectors: &mut Vec<&'a VectorSelector>) {
+        match expr {
+            Expr::VectorSelector(selector) => selectors.push(selector),
+            Expr::MatrixSelector(selector) => selectors.push(&selector.vs),
+            Expr::Subquery(subquery) => collect(&subquery.expr, selectors),
+            Expr::Aggregate(aggregate) => {
+                collect(&aggregate.expr, selectors);
+                if let Some(param) = &aggregate.param {
+                    collect(param, selectors);
+                }
+            }
+            Expr::Unary(unary) => collect(&unary.expr, selectors),
+            Expr::Binary(binary) => {
+                collect(&binary.lhs, selectors);
+                collect(&binary.rhs, selectors);
+            }
+            Expr::Paren(paren) => collect(&paren.expr, selectors),
+            Expr::Call(call) => {
+                for arg in call.args.args.iter() {
+                    collect(arg, selectors);
+                }
+            }
+            Expr::NumberLiteral(_) | Expr::StringLiteral(_) | Expr::Extension(_) => {}
+        }
+    }
+
+    let mut selectors = vec![];
+    collect(expr, &mut selectors);
+    selectors
+}
+
+/// Converts the metric name and label matchers of a selector into a native fts query.
+pub(crate) fn selector_query(selector: &VectorSelector) -> EngineResult<NativeQuery> {
+    let mut matchers = vec![];
+    if let Some(name) = &selector.name {
+        matchers.push(LabelMatcher {
+            r#type: Type::Eq as i32,
+            name: METRIC_NAME.to_string(),
+            value: name.clone(),
+        });
+    }
+    for matcher in selector.matchers.matchers.iter() {
+        let r#type = match matcher.op {
+            MatchOp::Equal => Type::Eq,
+            MatchOp::NotEqual => Type::Neq,
+            MatchOp::Re(_) => Type::Re,
+            MatchOp::NotRe(_) => Type::Nre,
+        };
+        matchers.push(LabelMatcher {
+            r#type: r#type as i32,
+            name: matcher.name.clone(),
+            value: matcher.value.clone(),
+        });
+    }
+    let prom_query = PromProtoBuffQuery {
+        matchers,
+        ..Default::default()
+    };
+    convert_prom_query_to_native_query(prom_query)
+        .map_err(|err| EngineError::BadData(err.to_string()))
+}
@@ -0,0 +1,510 @@
+//! Runs the golden tests of `testdata`, written in a subset of the
+//! Prometheus `promqltest` language:
+//!
+//! ```text
+//! load <step>
+//!     <series> <values>
+//!
+//! eval[_ordered|_fail] instant at <time> <expr>
+//!     <series> <value> | <scalar value>
+//!
+//! eval[_fail] range from <start> to <end> step <step> <expr>
+//!     <series> <values>
+//!
+//! clear
+//! ```
+//!
+//! Values are numbers, `NaN`, `Inf`, `stale`, `_` for a missing sample,
+//! or `a+bxn` for the n + 1 values a, a + b, ..., a + n * b.
+
+use std::{fs, path::Path, sync::Arc, time::Duration};
+
+use promql_parser::{
+    label::{MatchOp, METRIC_NAME},
+    parser::{self, Expr},
+    util::parse_duration,
+};
+use serde_json::json;
+use storage::{
+    BucketSpan, CounterResetHint, HistogramSample, Label, Sample, Storage, StorageFactory,
+    StorageSettings, TimeSeries, WalFsyncPolicy,
+};
+use tempdir::TempDir;
+
+use super::{
+    value::{labels_to_string, STALE_NAN},
+    Engine, EngineError, Labels, Point, Value, VectorSample,
+};
+
+/// A test database, dropped on `clear`.
+struct TestStorage {
+    storage: Arc<Storage>,
+    _directory: TempDir,
+}
+
+impl TestStorage {
+    fn new() -> Self {
+        let directory = TempDir::new("promqltest").unwrap();
+        let settings = StorageSettings::Native {
+            path: directory.path().to_str().unwrap().to_string(),
+            block_duration: 120,
+            wal_fsync_policy: WalFsyncPolicy::Never,
+            max_exemplars: 0,
+        };
+        Self {
+            storage: Arc::new(StorageFactory::open(&settings).unwrap()),
+            _directory: directory,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum EvalKind {
+    Instant { time: i64 },
+    Range { start: i64, end: i64, step: i64 },
+}
+
+#[derive(Debug)]
+struct Eval {
+    line: usize,
+    query: String,
+    kind: EvalKind,
+    ordered: bool,
+    fail: bool,
+    expected: Vec<(Option<Labels>, Vec<Option<f64>>)>,
+}
+
+#[tokio::test]
+async fn golden_tests() {
+    let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/prometheus/engine/testdata");
+    let mut file_names: Vec<_> = fs::read_dir(testdata)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    file_names.sort();
+
+    let mut failures = vec![];
+    for file_name in file_names {
+        let content = fs::read_to_string(&file_name).unwrap();
+        let file_name = file_name.file_name().unwrap().to_string_lossy().to_string();
+        for failure in run_test_file(&content).await {
+            failures.push(format!("{}:{}", file_name, failure));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+/// Native histograms can't be written in the test files yet.
+#[tokio::test]
+async fn evaluation_timeout() {
+    let test_storage = TestStorage::new();
+    let series = parse_load_line("requests{job=\"api\"} 0+1x120", 30_000);
+    test_storage.storage.write(vec![series]).await.unwrap();
+    let engine = Engine::new(test_storage.storage.clone());
+
+    // Without selectors nothing is awaited, only the steps check the deadline.
+    let expr = parser::parse("vector(1)").unwrap();
+    let result = engine
+        .clone()
+        .with_timeout(Duration::ZERO)
+        .range_query(&expr, 0, 3_600_000, 1_000)
+        .await;
+    assert!(matches!(result, Err(EngineError::Timeout)));
+
+    // Millions of subquery steps, stopped long before they are all evaluated.
+    let expr = parser::parse("max_over_time(rate(requests[5m])[1h:1s])").unwrap();
+    let result = engine
+        .clone()
+        .with_timeout(Duration::from_millis(10))
+        .range_query(&expr, 0, 3_600_000, 1_000)
+        .await;
+    assert!(matches!(result, Err(EngineError::Timeout)));
+
+    let result = engine
+        .with_timeout(Duration::from_secs(60))
+        .instant_query(&expr, 3_600_000)
+        .await;
+    assert!(result.is_ok());
+}
+
+#[tokio::test]
+async fn native_histograms() {
+    let test_storage = TestStorage::new();
+    let histogram = |timestamp: i64, count: f64| HistogramSample {
+        timestamp,
+        counter_reset_hint: CounterResetHint::Unknown,
+        schema: 0,
+        zero_threshold: 0.001,
+        zero_count: 1.0,
+        count,
+        sum: count * 1.5,
+        positive_spans: vec![BucketSpan {
+            offset: 0,
+            length: 2,
+        }],
+        positive_counts: vec![1.0, count - 6.0],
+        negative_spans: vec![BucketSpan {
+            offset: 1,
+            length: 1,
+        }],
+        negative_counts: vec![4.0],
+    };
+    // The series switches from float samples to histograms at 60s.
+    let mut series = parse_load_line("latency{job=\"api\"} 1 2", 30_000);
+    series.extend_histograms(vec![histogram(60_000, 8.0), histogram(90_000, 10.0)]);
+    test_storage.storage.write(vec![series]).await.unwrap();
+    let engine = Engine::new(test_storage.storage.clone());
+    let query = |query: &str, time: i64| {
+        let engine = engine.clone();
+        let expr = parser::parse(query).unwrap();
+        async move { engine.instant_query(&expr, time).await.unwrap() }
+    };
+
+    assert_eq!(
+        query("latency", 90_000).await.to_json()["result"][0]["histogram"],
+        json!([
+            90,
+            {
+                "count": "10",
+                "sum": "15",
+                "buckets": [
+                    [1, "-2", "-1", "4"],
+                    [3, "-0.001", "0.001", "1"],
+                    [0, "0.5", "1", "1"],
+                    [0, "1", "2", "4"]
+                ]
+            }
+        ])
+    );
+    assert_eq!(
+        query("latency", 45_000).await.to_json()["result"][0]["value"],
+        json!([45, "2"])
+    );
+    assert_eq!(
+        query("histogram_count(latency)", 75_000).await,
+        Value::Vector(vec![VectorSample::new(
+            [("job".to_string(), "api".to_string())].into(),
+            Point::new(75_000, 8.0)
+        )])
+    );
+    // Float operators and functions ignore histograms.
+    assert_eq!(query("latency * 2", 90_000).await, Value::Vector(vec![]));
+    assert_eq!(
+        query("absent(latency)", 90_000).await,
+        Value::Vector(vec![])
+    );
+
+    let Value::Matrix(series) = query("latency[90s]", 90_000).await else {
+        panic!("expected a matrix");
+    };
+    assert_eq!(series[0].points, &[Point::new(30_000, 2.0)]);
+    assert_eq!(series[0].histograms.len(), 2);
+    test_storage.storage.shutdown().await.unwrap();
+}
+
+async fn run_test_file(content: &str) -> Vec<String> {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut failures = vec![];
+    let mut test_storage = TestStorage::new();
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index].trim();
+        index += 1;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // Commands are followed by indented lines.
+        let mut block = vec![];
+        while index < lines.len() && lines[index].starts_with(char::is_whitespace) {
+            let block_line = lines[index].trim();
+            if !block_line.is_empty() && !block_line.starts_with('#') {
+                block.push(block_line);
+            }
+            index += 1;
+        }
+
+        if line == "clear" {
+            test_storage.storage.shutdown().await.unwrap();
+            test_storage = TestStorage::new();
+        } else if let Some(step) = line.strip_prefix("load ") {
+            let step = parse_time(step);
+            let series = block
+                .iter()
+                .map(|line| parse_load_line(line, step))
+                .collect();
+            test_storage.storage.write(series).await.unwrap();
+        } else if line.starts_with("eval") {
+            let eval = parse_eval(line, index - block.len(), &block);
+            if let Err(err) = run_eval(&test_storage, &eval).await {
+                failures.push(format!("{}: `{}`: {}", eval.line, eval.query, err));
+            }
+        } else {
+            panic!("unknown command `{}`", line);
+        }
+    }
+    test_storage.storage.shutdown().await.unwrap();
+    failures
+}
+
+async fn run_eval(test_storage: &TestStorage, eval: &Eval) -> Result<(), String> {
+    let engine = Engine::new(test_storage.storage.clone());
+    let expr = parser::parse(&eval.query);
+    let result = match (&expr, &eval.kind) {
+        (Err(err), _) => Err(err.clone()),
+        (Ok(expr), EvalKind::Instant { time }) => engine
+            .instant_query(expr, *time)
+            .await
+            .map_err(|err| err.to_string()),
+        (Ok(expr), EvalKind::Range { start, end, step }) => engine
+            .range_query(expr, *start, *end, *step)
+            .await
+            .map_err(|err| err.to_string()),
+    };
+
+    let value = match (result, eval.fail) {
+        (Ok(value), true) => return Err(format!("expected an error, got {:?}", value)),
+        (Err(_), true) => return Ok(()),
+        (Err(err), false) => return Err(format!("unexpected error: {}", err)),
+        (Ok(value), false) => value,
+    };
+
+    let actual: Vec<(Option<Labels>, Vec<Option<f64>>)> = match (&value, &eval.kind) {
+        (Value::Scalar(point), _) => vec![(None, vec![Some(point.value)])],
+        (Value::Vector(samples), _) => samples
+            .iter()
+            .map(|sample| (Some(sample.labels.clone()), vec![Some(sample.point.value)]))
+            .collect(),
+        (Value::Matrix(series), EvalKind::Range { start, end, step }) => series
+            .iter()
+            .map(|series| {
+                // Align the points on the steps, missing ones are `None`.
+                let mut values = vec![None; ((end - start) / step + 1) as usize];
+                for point in series.points.iter() {
+                    values[((point.timestamp - start) / step) as usize] = Some(point.value);
+                }
+                (Some(series.labels.clone()), values)
+            })
+            .collect(),
+        (Value::Matrix(series), EvalKind::Instant { .. }) => series
+            .iter()
+            .map(|series| {
+                let values = series.points.iter().map(|point| Some(point.value));
+                (Some(series.labels.clone()), values.collect())
+            })
+            .collect(),
+        (value, _) => return Err(format!("unexpected {} result", value.result_type())),
+    };
+    compare_results(&eval.expected, actual, eval.ordered)
+}
+
+fn compare_results(
+    expected: &[(Option<Labels>, Vec<Option<f64>>)],
+    mut actual: Vec<(Option<Labels>, Vec<Option<f64>>)>,
+    ordered: bool,
+) -> Result<(), String> {
+    let mut expected = expected.to_vec();
+    if !ordered {
+        expected.sort_by(|left, right| left.0.cmp(&right.0));
+        actual.sort_by(|left, right| left.0.cmp(&right.0));
+    }
+
+    let matches = expected.len() == actual.len()
+        && expected.iter().zip(actual.iter()).all(
+            |((expected_labels, expected_values), (actual_labels, actual_values))| {
+                expected_labels == actual_labels
+                    && expected_values.len() == actual_values.len()
+                    && expected_values
+                        .iter()
+                        .zip(actual_values.iter())
+                        .all(|(expected, actual)| match (expected, actual) {
+                            (Some(expected), Some(actual)) => almost_equal(*expected, *actual),
+                            (None, None) => true,
+                            _ => false,
+                        })
+            },
+        );
+    if matches {
+        return Ok(());
+    }
+    Err(format!(
+        "expected [{}], got [{}]",
+        format_results(&expected),
+        format_results(&actual)
+    ))
+}
+
+fn format_results(results: &[(Option<Labels>, Vec<Option<f64>>)]) -> String {
+    results
+        .iter()
+        .map(|(labels, values)| {
+            let labels = labels.as_ref().map_or(String::new(), labels_to_string);
+            let values: Vec<String> = values
+                .iter()
+                .map(|value| value.map_or("_".to_string(), |value| value.to_string()))
+                .collect();
+            format!("{} {}", labels, values.join(" "))
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn almost_equal(expected: f64, actual: f64) -> bool {
+    if expected.is_nan() || actual.is_nan() {
+        return expected.is_nan() && actual.is_nan();
+    }
+    if expected == actual {
+        return true;
+    }
+    let difference = (expected - actual).abs();
+    difference <= 1e-6 * expected.abs().max(actual.abs()) || difference < 1e-12
+}
+
+fn parse_eval(line: &str, line_number: usize, block: &[&str]) -> Eval {
+    let (command, rest) = line.split_once(' ').unwrap();
+    let (ordered, fail) = match command {
+        "eval" => (false, false),
+        "eval_ordered" => (true, false),
+        "eval_fail" => (false, true),
+        _ => panic!("unknown command `{}`", command),
+    };
+
+    let (kind, query) = if let Some(rest) = rest.strip_prefix("instant at ") {
+        let (time, query) = rest.split_once(' ').unwrap();
+        let time = parse_time(time);
+        (EvalKind::Instant { time }, query)
+    } else if let Some(rest) = rest.strip_prefix("range from ") {
+        let tokens: Vec<&str> = rest.splitn(6, ' ').collect();
+        assert!(
+            tokens.len() == 6 && tokens[1] == "to" && tokens[3] == "step",
+            "invalid range eval `{}`",
+            line
+        );
+        let kind = EvalKind::Range {
+            start: parse_time(tokens[0]),
+            end: parse_time(tokens[2]),
+            step: parse_time(tokens[4]),
+        };
+        (kind, tokens[5])
+    } else {
+        panic!("invalid eval `{}`", line);
+    };
+
+    let expected = block
+        .iter()
+        .map(|line| {
+            // Scalar results have no labels.
+            if !line.contains(['{', ' ']) {
+                return (None, parse_values(line));
+            }
+            let (labels, values) = split_series(line);
+            (Some(parse_labels(labels)), parse_values(values))
+        })
+        .collect();
+
+    Eval {
+        line: line_number,
+        query: query.to_string(),
+        kind,
+        ordered,
+        fail,
+        expected,
+    }
+}
+
+fn parse_load_line(line: &str, step: i64) -> TimeSeries {
+    let (labels, values) = split_series(line);
+    let labels = parse_labels(labels)
+        .into_iter()
+        .map(|(name, value)| Label { name, value })
+        .collect();
+    let samples = parse_values(values)
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, value)| {
+            value.map(|value| Sample {
+                timestamp: index as i64 * step,
+                value,
+            })
+        })
+        .collect();
+    TimeSeries::new(labels, samples)
+}
+
+/// Splits `name{labels} values` after the series description.
+fn split_series(line: &str) -> (&str, &str) {
+    let end = match line.find('}') {
+        Some(end) => end + 1,
+        None => line.find(' ').unwrap_or(line.len()),
+    };
+    (line[..end].trim(), line[end..].trim())
+}
+
+/// Parses a series description with the PromQL parser.
+fn parse_labels(series: &str) -> Labels {
+    if series == "{}" {
+        return Labels::new();
+    }
+    let Ok(Expr::VectorSelector(selector)) = parser::parse(series) else {
+        panic!("invalid series `{}`", series);
+    };
+    let mut labels = Labels::new();
+    if let Some(name) = selector.name {
+        labels.insert(METRIC_NAME.to_string(), name);
+    }
+    for matcher in selector.matchers.matchers {
+        assert!(
+            matches!(matcher.op, MatchOp::Equal),
+            "invalid series `{}`",
+            series
+        );
+        labels.insert(matcher.name, matcher.value);
+    }
+    labels
+}
+
+fn parse_values(values: &str) -> Vec<Option<f64>> {
+    let mut result = vec![];
+    for token in values.split_whitespace() {
+        let Some((base, count)) = token.rsplit_once('x') else {
+            result.push(parse_value(token));
+            continue;
+        };
+        let count: usize = count.parse().unwrap();
+        if base == "_" {
+            result.extend(std::iter::repeat_n(None, count));
+            continue;
+        }
+        // The sign of the increment is after the first character.
+        let (start, increment) = match base[1..].find(['+', '-']) {
+            Some(position) => (&base[..=position], &base[position + 1..]),
+            None => (base, "0"),
+        };
+        let start = parse_value(start).unwrap();
+        let increment: f64 = increment.parse().unwrap();
+        result.extend((0..=count).map(|index| Some(start + index as f64 * increment)));
+    }
+    result
+}
+
+fn parse_value(value: &str) -> Option<f64> {
+    match value {
+        "_" => None,
+        "stale" => Some(f64::from_bits(STALE_NAN)),
+        value => Some(
+            value
+                .parse()
+                .unwrap_or_else(|_| panic!("invalid value `{}`", value)),
+        ),
+    }
+}
+
+/// Parses `0` or a PromQL duration since the epoch into ms.
+fn parse_time(time: &str) -> i64 {
+    if time == "0" {
+        return 0;
+    }
+    parse_duration(time)
+        .unwrap_or_else(|err| panic!("invalid time `{}`: {}", time, err))
+        .as_millis() as i64
+}
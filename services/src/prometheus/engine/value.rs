@@ -0,0 +1,286 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use promql_parser::label::METRIC_NAME;
+use serde_json::{json, Value as JsonValue};
+use storage::{BucketSpan, HistogramSample as NativeHistogram, Label, TimeSeries as NativeSeries};
+
+/// The labels of a series, sorted by name.
+pub type Labels = BTreeMap<String, String>;
+
+/// The value Prometheus writes when a series disappears, see
+/// https://prometheus.io/docs/prometheus/latest/querying/basics/#staleness
+pub(crate) const STALE_NAN: u64 = 0x7ff0000000000002;
+
+pub(crate) fn is_stale_nan(value: f64) -> bool {
+    value.to_bits() == STALE_NAN
+}
+
+/// Functions and arithmetic operators change the meaning of a series.
+pub(crate) fn drop_metric_name(labels: &mut Labels) {
+    labels.remove(METRIC_NAME);
+}
+
+pub(crate) fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|char| char.is_ascii_alphanumeric() || char == '_')
+}
+
+/// Formats labels as in Prometheus error messages, e.g. `{job="api"}`.
+pub(crate) fn labels_to_string(labels: &Labels) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}={:?}", name, value))
+        .collect();
+    format!("{{{}}}", labels.join(", "))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub timestamp: i64,
+    pub value: f64,
+}
+
+impl Point {
+    pub fn new(timestamp: i64, value: f64) -> Self {
+        Self { timestamp, value }
+    }
+}
+
+/// A native histogram sample of a series.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramPoint {
+    pub timestamp: i64,
+    pub histogram: Arc<NativeHistogram>,
+}
+
+/// A sample of an instant vector, either a float or a native histogram,
+/// the value of the point of a histogram sample is unused.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorSample {
+    pub labels: Labels,
+    pub point: Point,
+    pub histogram: Option<Arc<NativeHistogram>>,
+}
+
+impl VectorSample {
+    pub fn new(labels: Labels, point: Point) -> Self {
+        Self {
+            labels,
+            point,
+            histogram: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub labels: Labels,
+    pub points: Vec<Point>,
+    pub histograms: Vec<HistogramPoint>,
+}
+
+impl Series {
+    pub fn new(labels: Labels) -> Self {
+        Self {
+            labels,
+            points: vec![],
+            histograms: vec![],
+        }
+    }
+
+    /// Appends a sample of an instant vector, e.g. when merging the steps of a range query.
+    pub(crate) fn push(&mut self, sample: VectorSample) {
+        match sample.histogram {
+            Some(histogram) => self.histograms.push(HistogramPoint {
+                timestamp: sample.point.timestamp,
+                histogram,
+            }),
+            None => self.points.push(sample.point),
+        }
+    }
+}
+
+/// The result of a PromQL expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(Point),
+    String(i64, String),
+    Vector(Vec<VectorSample>),
+    Matrix(Vec<Series>),
+}
+
+impl Value {
+    pub fn result_type(&self) -> &'static str {
+        match self {
+            Value::Scalar(_) => "scalar",
+            Value::String(_, _) => "string",
+            Value::Vector(_) => "vector",
+            Value::Matrix(_) => "matrix",
+        }
+    }
+
+    /// Returns the `data` of a Prometheus HTTP API query response.
+    pub fn to_json(&self) -> JsonValue {
+        let result = match self {
+            Value::Scalar(point) => point_to_json(point),
+            Value::String(timestamp, value) => json!([timestamp_to_json(*timestamp), value]),
+            Value::Vector(samples) => samples
+                .iter()
+                .map(|sample| match &sample.histogram {
+                    Some(histogram) => json!({
+                        "metric": sample.labels,
+                        "histogram": histogram_to_json(sample.point.timestamp, histogram),
+                    }),
+                    None => json!({
+                        "metric": sample.labels,
+                        "value": point_to_json(&sample.point),
+                    }),
+                })
+                .collect(),
+            // Series only have the float or histogram values they hold, as in Prometheus.
+            Value::Matrix(series) => series
+                .iter()
+                .map(|series| {
+                    let mut json = json!({ "metric": series.labels });
+                    if !series.points.is_empty() || series.histograms.is_empty() {
+                        json["values"] = series.points.iter().map(point_to_json).collect();
+                    }
+                    if !series.histograms.is_empty() {
+                        json["histograms"] = series
+                            .histograms
+                            .iter()
+                            .map(|point| histogram_to_json(point.timestamp, &point.histogram))
+                            .collect();
+                    }
+                    json
+                })
+                .collect(),
+        };
+        json!({
+            "resultType": self.result_type(),
+            "result": result,
+        })
+    }
+}
+
+/// Timestamps are in seconds and values are strings, as in Prometheus.
+fn point_to_json(point: &Point) -> JsonValue {
+    json!([
+        timestamp_to_json(point.timestamp),
+        format_value(point.value)
+    ])
+}
+
+/// Formats a native histogram as in Prometheus: its count, sum and the
+/// `[boundary_rule, lower, upper, count]` of each populated bucket, from the
+/// lowest to the highest. Boundary rules are 0 for `(lower, upper]`,
+/// 1 for `[lower, upper)` and 3 for `[lower, upper]`.
+fn histogram_to_json(timestamp: i64, histogram: &NativeHistogram) -> JsonValue {
+    let mut buckets = vec![];
+    let negative_buckets: Vec<(i32, f64)> = bucket_indexes(&histogram.negative_spans)
+        .zip(histogram.negative_counts.iter().copied())
+        .collect();
+    for (index, count) in negative_buckets.into_iter().rev() {
+        let lower = -bucket_upper_bound(histogram.schema, index);
+        let upper = -bucket_upper_bound(histogram.schema, index - 1);
+        buckets.push((1, lower, upper, count));
+    }
+    buckets.push((
+        3,
+        -histogram.zero_threshold,
+        histogram.zero_threshold,
+        histogram.zero_count,
+    ));
+    for (index, count) in
+        bucket_indexes(&histogram.positive_spans).zip(histogram.positive_counts.iter().copied())
+    {
+        let lower = bucket_upper_bound(histogram.schema, index - 1);
+        let upper = bucket_upper_bound(histogram.schema, index);
+        buckets.push((0, lower, upper, count));
+    }
+
+    let buckets: Vec<JsonValue> = buckets
+        .into_iter()
+        .filter(|(_, _, _, count)| *count != 0.0)
+        .map(|(boundary_rule, lower, upper, count)| {
+            json!([
+                boundary_rule,
+                format_value(lower),
+                format_value(upper),
+                format_value(count)
+            ])
+        })
+        .collect();
+    let mut value = json!({
+        "count": format_value(histogram.count),
+        "sum": format_value(histogram.sum),
+    });
+    if !buckets.is_empty() {
+        value["buckets"] = JsonValue::Array(buckets);
+    }
+    json!([timestamp_to_json(timestamp), value])
+}
+
+/// Formats a series and its exemplars as the exemplar query API of Prometheus does.
+pub(crate) fn exemplars_to_json(series: &NativeSeries) -> JsonValue {
+    let to_labels = |labels: &[Label]| -> Labels {
+        labels
+            .iter()
+            .map(|label| (label.name.clone(), label.value.clone()))
+            .collect()
+    };
+    let exemplars: Vec<JsonValue> = series
+        .get_exemplars()
+        .iter()
+        .map(|exemplar| {
+            json!({
+                "labels": to_labels(&exemplar.labels),
+                "value": format_value(exemplar.value),
+                "timestamp": timestamp_to_json(exemplar.timestamp),
+            })
+        })
+        .collect();
+    json!({
+        "seriesLabels": to_labels(series.get_labels()),
+        "exemplars": exemplars,
+    })
+}
+
+/// Returns the index of each bucket of the spans of a native histogram.
+pub(crate) fn bucket_indexes(spans: &[BucketSpan]) -> impl Iterator<Item = i32> + '_ {
+    let mut index = 0;
+    spans.iter().flat_map(move |span| {
+        index += span.offset;
+        let start = index;
+        index += span.length as i32;
+        start..index
+    })
+}
+
+/// The upper bound of a bucket of a native histogram: `2^(2^-schema * index)`.
+pub(crate) fn bucket_upper_bound(schema: i32, index: i32) -> f64 {
+    (index as f64 * 2f64.powi(-schema)).exp2()
+}
+
+fn timestamp_to_json(timestamp: i64) -> JsonValue {
+    if timestamp % 1000 == 0 {
+        json!(timestamp / 1000)
+    } else {
+        json!(timestamp as f64 / 1000.0)
+    }
+}
+
+pub(crate) fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
@@ -1,3 +1,5 @@
+pub mod api;
+pub mod engine;
 pub mod remote;
 pub mod promql;
 
@@ -6,15 +8,26 @@ use std::sync::Arc;
 use axum::Router;
 use storage::Storage;
 
-use self::{promql::prometheus_query_language_router, remote::prometheus_remote_router};
+use self::{
+    api::prometheus_api_router, promql::prometheus_query_language_router,
+    remote::prometheus_remote_router,
+};
 
-pub fn prometheus_router(storage: Arc<Storage>, can_read: bool, can_write: bool) -> Router {
+/// `read_metadata` attaches the metadata of the metric families to remote read results.
+pub fn prometheus_router(
+    storage: Arc<Storage>,
+    can_read: bool,
+    can_write: bool,
+    read_metadata: bool,
+) -> Router {
     Router::new()
+        .merge(prometheus_api_router(storage.clone()))
         .merge(prometheus_query_language_router(storage.clone()))
         .merge(prometheus_remote_router(
             storage,
             can_read,
             can_write,
+            read_metadata,
         ))
 }
     
\ No newline at end of file
@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use axum::{extract::{Query, State}, http::StatusCode, routing::get, Json, Router};
+use axum::{extract::{Query, State}, routing::get, Json, Router};
 use promql_parser::{label::{MatchOp, Matcher}, parser};
 use serde_json::{json, Value};
 use storage::Storage;
@@ -24,7 +24,7 @@ impl PromReadQuery {
                     MatchOp::Equal => LabelMatcher{r#type: Type::Eq as i32, name: m.name, value: m.value},
                     MatchOp::NotEqual => LabelMatcher{r#type: Type::Neq as i32, name: m.name, value: m.value},
                     MatchOp::Re(_) => LabelMatcher{r#type: Type::Re as i32, name: m.name, value: m.value},
-                    MatchOp::NotRe(_) => LabelMatcher{r#type: Type::Re as i32, name: m.name, value: m.value},
+                    MatchOp::NotRe(_) => LabelMatcher{r#type: Type::Nre as i32, name: m.name, value: m.value},
                 }
             }).collect();
         PromProtoBuffQuery{
@@ -41,9 +41,8 @@ pub(crate) fn prometheus_query_language_router(storage: Arc<Storage>) -> Router
     let router = Router::new()
         .route("/prometheus/query", get(promql_handler_service));
 
-    let ctx = PrometheusStorage::new(storage);
-    let router = router.with_state(ctx);
-    router
+    let ctx = PrometheusStorage::new(storage, false);
+    router.with_state(ctx)
 }
 
 
@@ -52,7 +51,7 @@ pub async fn promql_handler_service(
     prom_query: Query<PromReadQuery>,
 ) -> PrometheusResult<Json<Value>> {
     let query_ast = parser::parse(&prom_query.qs)
-        .map_err(PrometheusRemoteStorageError::Other)?;
+        .map_err(PrometheusRemoteStorageError::BadRequest)?;
     let matchers = match query_ast {
         parser::Expr::VectorSelector(selector) => selector.matchers.matchers,
         parser::Expr::MatrixSelector(selector) => selector.vs.matchers.matchers,
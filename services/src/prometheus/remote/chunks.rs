@@ -0,0 +1,366 @@
+use prost::Message;
+use storage::Sample as NativeSample;
+
+use super::types::{chunk::Encoding, Chunk};
+
+/// Maximum number of samples of a chunk, as in Prometheus.
+const MAX_CHUNK_SAMPLES: usize = 120;
+
+/// A Prometheus XOR chunk encoder, see `tsdb/chunkenc/xor.go`.
+///
+/// Chunk Format: bit stream, most significant bits first
+/// ┌─────────────────────┬──────────────────┬──────────────┬────────────────────┬───────────────────┬─────┐
+/// │ num_samples (2 B)   │ timestamp varint │ value (64 b) │ delta uvarint, xor │ dod, xor          │ ... │
+/// └─────────────────────┴──────────────────┴──────────────┴────────────────────┴───────────────────┴─────┘
+///
+/// Delta-of-deltas are prefixed by `0`, `10`, `110`, `1110` or `1111` for 0, 14, 17, 20 or 64 bits.
+/// Values are XORed with the previous one: `0` if equal, `10` reusing the previous leading and
+/// trailing zeros, `11` followed by 5 bits of leading zeros and 6 bits of significant bits.
+#[derive(Debug)]
+struct XorChunk {
+    data: Vec<u8>,
+    /// Number of bits used in the last byte of `data`.
+    bit_count: u8,
+    num_samples: u16,
+    min_time: i64,
+    max_time: i64,
+    last_delta: i64,
+    last_value: u64,
+    leading_zeros: u8,
+    trailing_zeros: u8,
+}
+
+impl XorChunk {
+    fn new() -> Self {
+        Self {
+            data: vec![0, 0],
+            bit_count: 8,
+            num_samples: 0,
+            min_time: 0,
+            max_time: 0,
+            last_delta: 0,
+            last_value: 0,
+            leading_zeros: 0xff,
+            trailing_zeros: 0,
+        }
+    }
+
+    /// Appends a sample, timestamps must be increasing.
+    fn append(&mut self, timestamp: i64, value: f64) {
+        let value = value.to_bits();
+        match self.num_samples {
+            0 => {
+                self.min_time = timestamp;
+                self.write_varint(timestamp);
+                self.write_bits(value, 64);
+            }
+            1 => {
+                let delta = timestamp - self.max_time;
+                self.write_uvarint(delta as u64);
+                self.write_value(value);
+                self.last_delta = delta;
+            }
+            _ => {
+                let delta = timestamp - self.max_time;
+                let dod = delta - self.last_delta;
+                match dod {
+                    0 => self.write_bits(0, 1),
+                    dod if fits_in_bits(dod, 14) => {
+                        self.write_bits(0b10, 2);
+                        self.write_bits(dod as u64, 14);
+                    }
+                    dod if fits_in_bits(dod, 17) => {
+                        self.write_bits(0b110, 3);
+                        self.write_bits(dod as u64, 17);
+                    }
+                    dod if fits_in_bits(dod, 20) => {
+                        self.write_bits(0b1110, 4);
+                        self.write_bits(dod as u64, 20);
+                    }
+                    dod => {
+                        self.write_bits(0b1111, 4);
+                        self.write_bits(dod as u64, 64);
+                    }
+                }
+                self.write_value(value);
+                self.last_delta = delta;
+            }
+        }
+        self.max_time = timestamp;
+        self.last_value = value;
+        self.num_samples += 1;
+        self.data[..2].copy_from_slice(&self.num_samples.to_be_bytes());
+    }
+
+    fn into_chunk(self) -> Chunk {
+        Chunk {
+            min_time_ms: self.min_time,
+            max_time_ms: self.max_time,
+            r#type: Encoding::Xor as i32,
+            data: self.data,
+        }
+    }
+
+    fn write_value(&mut self, value: u64) {
+        let xor = value ^ self.last_value;
+        if xor == 0 {
+            self.write_bits(0, 1);
+            return;
+        }
+        self.write_bits(1, 1);
+
+        // The leading zeros are written on 5 bits.
+        let leading_zeros = (xor.leading_zeros() as u8).min(31);
+        let trailing_zeros = xor.trailing_zeros() as u8;
+        if self.leading_zeros != 0xff
+            && leading_zeros >= self.leading_zeros
+            && trailing_zeros >= self.trailing_zeros
+        {
+            let significant_bits = 64 - self.leading_zeros - self.trailing_zeros;
+            self.write_bits(0, 1);
+            self.write_bits(xor >> self.trailing_zeros, significant_bits);
+            return;
+        }
+
+        self.leading_zeros = leading_zeros;
+        self.trailing_zeros = trailing_zeros;
+        // 64 significant bits overflow to 0 on 6 bits, which readers expect.
+        let significant_bits = 64 - leading_zeros - trailing_zeros;
+        self.write_bits(1, 1);
+        self.write_bits(leading_zeros as u64, 5);
+        self.write_bits(significant_bits as u64, 6);
+        self.write_bits(xor >> trailing_zeros, significant_bits);
+    }
+
+    fn write_varint(&mut self, value: i64) {
+        self.write_uvarint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn write_uvarint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.write_bits((value as u8 | 0x80) as u64, 8);
+            value >>= 7;
+        }
+        self.write_bits(value, 8);
+    }
+
+    /// Writes the `num_bits` lowest bits of `value`, most significant first.
+    fn write_bits(&mut self, value: u64, num_bits: u8) {
+        let mut remaining = num_bits;
+        while remaining > 0 {
+            if self.bit_count == 8 {
+                self.data.push(0);
+                self.bit_count = 0;
+            }
+            let free = 8 - self.bit_count;
+            let count = free.min(remaining);
+            let bits = (value >> (remaining - count)) & ((1u64 << count) - 1);
+            let last = self.data.len() - 1;
+            self.data[last] |= (bits as u8) << (free - count);
+            self.bit_count += count;
+            remaining -= count;
+        }
+    }
+}
+
+/// Whether a delta-of-delta is written on `num_bits` bits, as `bitRange` in Prometheus.
+fn fits_in_bits(value: i64, num_bits: u8) -> bool {
+    -((1 << (num_bits - 1)) - 1) <= value && value <= 1 << (num_bits - 1)
+}
+
+/// Encodes samples into XOR chunks of at most 120 samples. The encoding needs
+/// strictly increasing timestamps: unsorted samples are sorted and only the
+/// first of the samples sharing a timestamp is kept.
+pub(crate) fn encode_xor_chunks(samples: &[NativeSample]) -> Vec<Chunk> {
+    let mut sorted_samples;
+    let samples = if samples.is_sorted_by(|a, b| a.timestamp < b.timestamp) {
+        samples
+    } else {
+        sorted_samples = samples.to_vec();
+        sorted_samples.sort_by_key(|sample| sample.timestamp);
+        sorted_samples.dedup_by_key(|sample| sample.timestamp);
+        &sorted_samples
+    };
+    samples
+        .chunks(MAX_CHUNK_SAMPLES)
+        .map(|samples| {
+            let mut chunk = XorChunk::new();
+            for sample in samples {
+                chunk.append(sample.timestamp, sample.value);
+            }
+            chunk.into_chunk()
+        })
+        .collect()
+}
+
+/// Encodes a message of a streamed response: its uvarint length,
+/// its big endian CRC32C checksum and the message itself.
+pub(crate) fn encode_frame<T: Message>(message: &T) -> Vec<u8> {
+    let data = message.encode_to_vec();
+    let mut frame = Vec::with_capacity(data.len() + 14);
+    prost::encoding::encode_varint(data.len() as u64, &mut frame);
+    frame.extend_from_slice(&crc32c::crc32c(&data).to_be_bytes());
+    frame.extend_from_slice(&data);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prometheus::remote::types::ChunkedReadResponse;
+
+    /// Reads a bit stream, most significant bits first.
+    struct BitReader<'a> {
+        data: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn read_bits(&mut self, num_bits: u8) -> u64 {
+            let mut value = 0;
+            for _ in 0..num_bits {
+                let bit = (self.data[self.position / 8] >> (7 - self.position % 8)) & 1;
+                value = (value << 1) | bit as u64;
+                self.position += 1;
+            }
+            value
+        }
+
+        fn read_uvarint(&mut self) -> u64 {
+            let mut value = 0;
+            let mut shift = 0;
+            loop {
+                let byte = self.read_bits(8);
+                value |= (byte & 0x7f) << shift;
+                if byte < 0x80 {
+                    return value;
+                }
+                shift += 7;
+            }
+        }
+
+        /// Reads a two's complement value of `num_bits` bits.
+        fn read_signed(&mut self, num_bits: u8) -> i64 {
+            let value = self.read_bits(num_bits);
+            ((value << (64 - num_bits)) as i64) >> (64 - num_bits)
+        }
+    }
+
+    /// Decodes a XOR chunk the way `xorIterator` does in Prometheus.
+    fn decode(data: &[u8]) -> Vec<(i64, f64)> {
+        let num_samples = u16::from_be_bytes([data[0], data[1]]);
+        let mut reader = BitReader { data, position: 16 };
+        let mut samples = vec![];
+        let (mut timestamp, mut delta, mut value) = (0i64, 0i64, 0u64);
+        let (mut leading_zeros, mut significant_bits) = (0u8, 0u8);
+        for index in 0..num_samples {
+            match index {
+                0 => {
+                    let zigzag = reader.read_uvarint();
+                    timestamp = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+                    value = reader.read_bits(64);
+                    samples.push((timestamp, f64::from_bits(value)));
+                    continue;
+                }
+                1 => delta = reader.read_uvarint() as i64,
+                _ => {
+                    let dod = if reader.read_bits(1) == 0 {
+                        0
+                    } else if reader.read_bits(1) == 0 {
+                        reader.read_signed(14)
+                    } else if reader.read_bits(1) == 0 {
+                        reader.read_signed(17)
+                    } else if reader.read_bits(1) == 0 {
+                        reader.read_signed(20)
+                    } else {
+                        reader.read_bits(64) as i64
+                    };
+                    delta += dod;
+                }
+            }
+            timestamp += delta;
+            if reader.read_bits(1) == 1 {
+                if reader.read_bits(1) == 1 {
+                    leading_zeros = reader.read_bits(5) as u8;
+                    significant_bits = reader.read_bits(6) as u8;
+                    if significant_bits == 0 {
+                        significant_bits = 64;
+                    }
+                }
+                let trailing_zeros = 64 - leading_zeros - significant_bits;
+                value ^= reader.read_bits(significant_bits) << trailing_zeros;
+            }
+            samples.push((timestamp, f64::from_bits(value)));
+        }
+        samples
+    }
+
+    #[test]
+    fn encode_decode_xor_chunks() {
+        let mut samples = vec![];
+        let mut timestamp = -1_000;
+        for index in 0..300i64 {
+            // Regular, jittered and large gaps exercise every delta-of-delta bucket.
+            timestamp += match index % 7 {
+                0 => 15_000,
+                1 => 15_000 + index,
+                2 => 100_000,
+                3 => 600_000,
+                4 => 1 << 40,
+                _ => 15_000,
+            };
+            let value = match index % 5 {
+                0 => index as f64,
+                1 => -0.5 * index as f64,
+                2 => f64::NAN,
+                3 => f64::MAX,
+                _ => 1.0,
+            };
+            samples.push(NativeSample { timestamp, value });
+        }
+
+        let chunks = encode_xor_chunks(&samples);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].min_time_ms, samples[0].timestamp);
+        assert_eq!(chunks[0].max_time_ms, samples[119].timestamp);
+        let decoded: Vec<(i64, u64)> = chunks
+            .iter()
+            .flat_map(|chunk| decode(&chunk.data))
+            .map(|(timestamp, value)| (timestamp, value.to_bits()))
+            .collect();
+        let expected: Vec<(i64, u64)> = samples
+            .iter()
+            .map(|sample| (sample.timestamp, sample.value.to_bits()))
+            .collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn encode_unsorted_samples() {
+        let samples: Vec<NativeSample> = [(3_000, 3.0), (1_000, 1.0), (3_000, 3.0), (2_000, 2.0)]
+            .into_iter()
+            .map(|(timestamp, value)| NativeSample { timestamp, value })
+            .collect();
+        let chunks = encode_xor_chunks(&samples);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!((chunks[0].min_time_ms, chunks[0].max_time_ms), (1_000, 3_000));
+        assert_eq!(
+            decode(&chunks[0].data),
+            &[(1_000, 1.0), (2_000, 2.0), (3_000, 3.0)]
+        );
+    }
+
+    #[test]
+    fn encode_frames() {
+        let message = ChunkedReadResponse {
+            chunked_series: vec![],
+            query_index: 300,
+            metadata: vec![],
+        };
+        let data = message.encode_to_vec();
+        let frame = encode_frame(&message);
+        assert_eq!(frame[0] as usize, data.len());
+        assert_eq!(frame[1..5], crc32c::crc32c(&data).to_be_bytes());
+        assert_eq!(frame[5..], data);
+    }
+}
@@ -0,0 +1,219 @@
+use storage::{
+    BucketSpan as NativeBucketSpan, CounterResetHint, HistogramSample as NativeHistogram,
+};
+
+use super::types::{
+    histogram::{self, ResetHint},
+    write_v2, BucketSpan, Histogram, PrometheusRemoteStorageError, PrometheusResult,
+};
+
+/// The schema of native histograms with custom bucket boundaries.
+const CUSTOM_BUCKETS_SCHEMA: i32 = -53;
+
+/// Converts a remote write histogram into a native histogram. Integer
+/// histograms are delta encoded, their bucket counts are made absolute.
+pub(crate) fn histogram_from_proto(histogram: Histogram) -> NativeHistogram {
+    let counter_reset_hint = match histogram.reset_hint() {
+        ResetHint::Unknown => CounterResetHint::Unknown,
+        ResetHint::Yes => CounterResetHint::Reset,
+        ResetHint::No => CounterResetHint::NotReset,
+        ResetHint::Gauge => CounterResetHint::Gauge,
+    };
+    let count = match histogram.count {
+        Some(histogram::Count::CountInt(count)) => count as f64,
+        Some(histogram::Count::CountFloat(count)) => count,
+        None => 0.0,
+    };
+    let zero_count = match histogram.zero_count {
+        Some(histogram::ZeroCount::ZeroCountInt(count)) => count as f64,
+        Some(histogram::ZeroCount::ZeroCountFloat(count)) => count,
+        None => 0.0,
+    };
+    let is_integer = matches!(histogram.count, Some(histogram::Count::CountInt(_)));
+    let bucket_counts = |deltas: Vec<i64>, counts: Vec<f64>| {
+        if is_integer {
+            absolute_counts(&deltas)
+        } else {
+            counts
+        }
+    };
+    NativeHistogram {
+        timestamp: histogram.timestamp,
+        counter_reset_hint,
+        schema: histogram.schema,
+        zero_threshold: histogram.zero_threshold,
+        zero_count,
+        count,
+        sum: histogram.sum,
+        positive_spans: spans_from_proto(histogram.positive_spans),
+        positive_counts: bucket_counts(histogram.positive_deltas, histogram.positive_counts),
+        negative_spans: spans_from_proto(histogram.negative_spans),
+        negative_counts: bucket_counts(histogram.negative_deltas, histogram.negative_counts),
+    }
+}
+
+/// Converts a remote write 2.0 histogram, histograms with custom buckets are rejected.
+pub(crate) fn histogram_from_proto_v2(
+    histogram: write_v2::Histogram,
+) -> PrometheusResult<NativeHistogram> {
+    if histogram.schema == CUSTOM_BUCKETS_SCHEMA {
+        return Err(PrometheusRemoteStorageError::BadRequest(
+            "native histograms with custom buckets are not supported".to_string(),
+        ));
+    }
+    let reset_hint = match histogram.reset_hint() {
+        write_v2::histogram::ResetHint::Unspecified => ResetHint::Unknown,
+        write_v2::histogram::ResetHint::Yes => ResetHint::Yes,
+        write_v2::histogram::ResetHint::No => ResetHint::No,
+        write_v2::histogram::ResetHint::Gauge => ResetHint::Gauge,
+    };
+    let spans = |spans: Vec<write_v2::BucketSpan>| {
+        spans
+            .into_iter()
+            .map(|span| BucketSpan {
+                offset: span.offset,
+                length: span.length,
+            })
+            .collect()
+    };
+    let histogram = Histogram {
+        count: histogram.count.map(|count| match count {
+            write_v2::histogram::Count::CountInt(count) => histogram::Count::CountInt(count),
+            write_v2::histogram::Count::CountFloat(count) => histogram::Count::CountFloat(count),
+        }),
+        sum: histogram.sum,
+        schema: histogram.schema,
+        zero_threshold: histogram.zero_threshold,
+        zero_count: histogram.zero_count.map(|count| match count {
+            write_v2::histogram::ZeroCount::ZeroCountInt(count) => {
+                histogram::ZeroCount::ZeroCountInt(count)
+            }
+            write_v2::histogram::ZeroCount::ZeroCountFloat(count) => {
+                histogram::ZeroCount::ZeroCountFloat(count)
+            }
+        }),
+        negative_spans: spans(histogram.negative_spans),
+        negative_deltas: histogram.negative_deltas,
+        negative_counts: histogram.negative_counts,
+        positive_spans: spans(histogram.positive_spans),
+        positive_deltas: histogram.positive_deltas,
+        positive_counts: histogram.positive_counts,
+        reset_hint: reset_hint as i32,
+        timestamp: histogram.timestamp,
+    };
+    Ok(histogram_from_proto(histogram))
+}
+
+/// Converts a native histogram into a remote read float histogram.
+pub(crate) fn histogram_to_proto(histogram: &NativeHistogram) -> Histogram {
+    let reset_hint = match histogram.counter_reset_hint {
+        CounterResetHint::Unknown => ResetHint::Unknown,
+        CounterResetHint::Reset => ResetHint::Yes,
+        CounterResetHint::NotReset => ResetHint::No,
+        CounterResetHint::Gauge => ResetHint::Gauge,
+    };
+    let spans = |spans: &[NativeBucketSpan]| {
+        spans
+            .iter()
+            .map(|span| BucketSpan {
+                offset: span.offset,
+                length: span.length,
+            })
+            .collect()
+    };
+    Histogram {
+        count: Some(histogram::Count::CountFloat(histogram.count)),
+        sum: histogram.sum,
+        schema: histogram.schema,
+        zero_threshold: histogram.zero_threshold,
+        zero_count: Some(histogram::ZeroCount::ZeroCountFloat(histogram.zero_count)),
+        negative_spans: spans(&histogram.negative_spans),
+        negative_deltas: vec![],
+        negative_counts: histogram.negative_counts.clone(),
+        positive_spans: spans(&histogram.positive_spans),
+        positive_deltas: vec![],
+        positive_counts: histogram.positive_counts.clone(),
+        reset_hint: reset_hint as i32,
+        timestamp: histogram.timestamp,
+    }
+}
+
+fn spans_from_proto(spans: Vec<BucketSpan>) -> Vec<NativeBucketSpan> {
+    spans
+        .into_iter()
+        .map(|span| NativeBucketSpan {
+            offset: span.offset,
+            length: span.length,
+        })
+        .collect()
+}
+
+/// Each delta is relative to the previous bucket, or to zero for the first one.
+fn absolute_counts(deltas: &[i64]) -> Vec<f64> {
+    deltas
+        .iter()
+        .scan(0i64, |count, delta| {
+            *count += delta;
+            Some(*count as f64)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_histograms() {
+        let integer_histogram = Histogram {
+            count: Some(histogram::Count::CountInt(9)),
+            sum: 12.5,
+            schema: 1,
+            zero_threshold: 0.001,
+            zero_count: Some(histogram::ZeroCount::ZeroCountInt(1)),
+            positive_spans: vec![
+                BucketSpan {
+                    offset: -1,
+                    length: 2,
+                },
+                BucketSpan {
+                    offset: 3,
+                    length: 1,
+                },
+            ],
+            positive_deltas: vec![2, 1, -3],
+            negative_spans: vec![BucketSpan {
+                offset: 0,
+                length: 1,
+            }],
+            negative_deltas: vec![3],
+            reset_hint: ResetHint::No as i32,
+            timestamp: 1_000,
+            ..Default::default()
+        };
+        let native = histogram_from_proto(integer_histogram);
+        assert_eq!((native.count, native.zero_count), (9.0, 1.0));
+        assert_eq!(native.positive_counts, &[2.0, 3.0, 0.0]);
+        assert_eq!(native.negative_counts, &[3.0]);
+        assert_eq!(
+            native.positive_spans[1],
+            NativeBucketSpan {
+                offset: 3,
+                length: 1
+            }
+        );
+        assert_eq!(native.counter_reset_hint, CounterResetHint::NotReset);
+
+        // Read back as a float histogram, converting it again is lossless.
+        let float_histogram = histogram_to_proto(&native);
+        assert_eq!(float_histogram.positive_counts, &[2.0, 3.0, 0.0]);
+        assert!(float_histogram.positive_deltas.is_empty());
+        assert_eq!(histogram_from_proto(float_histogram), native);
+
+        let custom_buckets = write_v2::Histogram {
+            schema: CUSTOM_BUCKETS_SCHEMA,
+            ..Default::default()
+        };
+        assert!(histogram_from_proto_v2(custom_buckets).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+
+use storage::{MetricMetadata as NativeMetadata, MetricType as NativeMetricType};
+
+use super::types::{
+    metric_metadata::MetricType, write_v2, MetricMetadata, PrometheusRemoteStorageError,
+    PrometheusResult,
+};
+
+/// The suffixes of the series of a metric family, e.g. `_bucket` for a classic histogram.
+const FAMILY_SUFFIXES: [&str; 8] = [
+    "_bucket", "_count", "_sum", "_total", "_created", "_gcount", "_gsum", "_info",
+];
+
+/// Converts the metadata of a remote write request.
+pub(crate) fn metadata_from_proto(metadata: MetricMetadata) -> NativeMetadata {
+    let metric_type = match metadata.r#type() {
+        MetricType::Unknown => NativeMetricType::Unknown,
+        MetricType::Counter => NativeMetricType::Counter,
+        MetricType::Gauge => NativeMetricType::Gauge,
+        MetricType::Histogram => NativeMetricType::Histogram,
+        MetricType::Gaugehistogram => NativeMetricType::GaugeHistogram,
+        MetricType::Summary => NativeMetricType::Summary,
+        MetricType::Info => NativeMetricType::Info,
+        MetricType::Stateset => NativeMetricType::StateSet,
+    };
+    NativeMetadata {
+        metric_family_name: metadata.metric_family_name,
+        metric_type,
+        help: metadata.help,
+        unit: metadata.unit,
+    }
+}
+
+/// Converts the metadata of a remote write 2.0 series named `name`,
+/// `None` if the series has no type, help or unit.
+pub(crate) fn metadata_from_proto_v2(
+    name: &str,
+    metadata: &write_v2::Metadata,
+    symbols: &[String],
+) -> PrometheusResult<Option<NativeMetadata>> {
+    let symbol = |symbol_ref: u32| {
+        symbols.get(symbol_ref as usize).cloned().ok_or_else(|| {
+            PrometheusRemoteStorageError::BadRequest(format!(
+                "metadata reference `{}` out of the `{}` symbols",
+                symbol_ref,
+                symbols.len()
+            ))
+        })
+    };
+    let metric_type = match metadata.r#type() {
+        write_v2::metadata::MetricType::Unspecified => MetricType::Unknown,
+        write_v2::metadata::MetricType::Counter => MetricType::Counter,
+        write_v2::metadata::MetricType::Gauge => MetricType::Gauge,
+        write_v2::metadata::MetricType::Histogram => MetricType::Histogram,
+        write_v2::metadata::MetricType::Gaugehistogram => MetricType::Gaugehistogram,
+        write_v2::metadata::MetricType::Summary => MetricType::Summary,
+        write_v2::metadata::MetricType::Info => MetricType::Info,
+        write_v2::metadata::MetricType::Stateset => MetricType::Stateset,
+    };
+    let metadata = MetricMetadata {
+        r#type: metric_type as i32,
+        metric_family_name: name.to_string(),
+        help: symbol(metadata.help_ref)?,
+        unit: symbol(metadata.unit_ref)?,
+    };
+    if metric_type == MetricType::Unknown && metadata.help.is_empty() && metadata.unit.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(metadata_from_proto(metadata)))
+}
+
+/// Converts metadata into the metadata of a remote read result.
+pub(crate) fn metadata_to_proto(metadata: &NativeMetadata) -> MetricMetadata {
+    let metric_type = match metadata.metric_type {
+        NativeMetricType::Unknown => MetricType::Unknown,
+        NativeMetricType::Counter => MetricType::Counter,
+        NativeMetricType::Gauge => MetricType::Gauge,
+        NativeMetricType::Histogram => MetricType::Histogram,
+        NativeMetricType::GaugeHistogram => MetricType::Gaugehistogram,
+        NativeMetricType::Summary => MetricType::Summary,
+        NativeMetricType::Info => MetricType::Info,
+        NativeMetricType::StateSet => MetricType::Stateset,
+    };
+    MetricMetadata {
+        r#type: metric_type as i32,
+        metric_family_name: metadata.metric_family_name.clone(),
+        help: metadata.help.clone(),
+        unit: metadata.unit.clone(),
+    }
+}
+
+/// Returns the metadata of the metric families of the series named `series_names`, sorted
+/// by name. A series belongs to the family of its name, or else of its name without a
+/// family suffix.
+pub(crate) fn series_metadata<'a>(
+    series_names: impl IntoIterator<Item = &'a str>,
+    metadata: Vec<NativeMetadata>,
+) -> Vec<MetricMetadata> {
+    let families: BTreeMap<&str, &NativeMetadata> = metadata
+        .iter()
+        .map(|metadata| (metadata.metric_family_name.as_str(), metadata))
+        .collect();
+    let mut series_families = BTreeMap::new();
+    for name in series_names {
+        let family = families.get(name).or_else(|| {
+            FAMILY_SUFFIXES
+                .iter()
+                .filter_map(|suffix| name.strip_suffix(suffix))
+                .find_map(|family_name| families.get(family_name))
+        });
+        if let Some(family) = family {
+            series_families.insert(family.metric_family_name.as_str(), *family);
+        }
+    }
+    series_families
+        .into_values()
+        .map(metadata_to_proto)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use storage::{Label, Sample, TimeSeries as NativeSeries};
+
+    use super::*;
+
+    fn series(name: &str) -> NativeSeries {
+        NativeSeries::new(
+            vec![Label {
+                name: storage::SERIES_NAME_LABEL.to_string(),
+                value: name.to_string(),
+            }],
+            vec![Sample {
+                timestamp: 0,
+                value: 1.0,
+            }],
+        )
+    }
+
+    #[test]
+    fn match_series_metadata() {
+        let metadata = |name: &str, r#type: MetricType| {
+            metadata_from_proto(MetricMetadata {
+                r#type: r#type as i32,
+                metric_family_name: name.to_string(),
+                help: format!("The {}.", name),
+                unit: "".to_string(),
+            })
+        };
+        let families = vec![
+            metadata("http_requests_total", MetricType::Counter),
+            metadata("http_duration_seconds", MetricType::Histogram),
+            metadata("up", MetricType::Gauge),
+        ];
+        let series = [
+            series("http_requests_total"),
+            series("http_duration_seconds_bucket"),
+            series("http_duration_seconds_count"),
+            series("unknown_total"),
+        ];
+        let matched = series_metadata(series.iter().map(NativeSeries::get_name), families);
+        let names: Vec<&str> = matched
+            .iter()
+            .map(|metadata| metadata.metric_family_name.as_str())
+            .collect();
+        assert_eq!(names, &["http_duration_seconds", "http_requests_total"]);
+        assert_eq!(matched[0].r#type(), MetricType::Histogram);
+
+        let symbols = ["".to_string(), "Requests.".to_string()];
+        let v2_metadata = |r#type, help_ref| write_v2::Metadata {
+            r#type: r#type as i32,
+            help_ref,
+            unit_ref: 0,
+        };
+        let converted = metadata_from_proto_v2(
+            "requests_total",
+            &v2_metadata(write_v2::metadata::MetricType::Counter, 1),
+            &symbols,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(converted.metric_type, NativeMetricType::Counter);
+        assert_eq!(converted.help, "Requests.");
+        assert!(metadata_from_proto_v2(
+            "up",
+            &v2_metadata(write_v2::metadata::MetricType::Unspecified, 0),
+            &symbols
+        )
+        .unwrap()
+        .is_none());
+        assert!(metadata_from_proto_v2(
+            "up",
+            &v2_metadata(write_v2::metadata::MetricType::Gauge, 2),
+            &symbols
+        )
+        .is_err());
+    }
+}
@@ -1,29 +1,53 @@
+mod chunks;
+mod histograms;
+mod metadata;
 pub mod types;
 mod utils;
 
 use std::sync::Arc;
 
 use axum::{
-    body::Bytes,
+    body::{Body, Bytes},
     // routing::{get, post},
     // http::StatusCode,
     // Json,
     extract::State,
     http::{
         header::{CONTENT_ENCODING, CONTENT_TYPE},
-        HeaderMap, HeaderValue,
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
     },
+    response::{IntoResponse, Response},
     routing::{get, post},
     Router,
 };
 
+use futures::StreamExt;
 use prost::Message;
 use storage::Storage;
 
 use self::types::{
-    PrometheusRemoteStorageError, PrometheusResult, PrometheusStorage, ReadRequest, WriteRequest,
+    read_request::ResponseType, write_v2, PrometheusRemoteStorageError, PrometheusResult,
+    PrometheusStorage, ReadRequest, WriteRequest,
 };
 
+/// The size of the messages of streamed responses, as in Prometheus.
+const MAX_BYTES_IN_FRAME: usize = 1024 * 1024;
+
+const REMOTE_WRITE_VERSION_HEADER: &str = "x-prometheus-remote-write-version";
+const SAMPLES_WRITTEN_HEADER: &str = "x-prometheus-remote-write-samples-written";
+const HISTOGRAMS_WRITTEN_HEADER: &str = "x-prometheus-remote-write-histograms-written";
+const EXEMPLARS_WRITTEN_HEADER: &str = "x-prometheus-remote-write-exemplars-written";
+
+/// The protobuf messages of the remote write protocol versions.
+const WRITE_V1_PROTO: &str = "prometheus.WriteRequest";
+const WRITE_V2_PROTO: &str = "io.prometheus.write.v2.Request";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RemoteWriteVersion {
+    V1,
+    V2,
+}
+
 use super::promql::promql_handler_service;
 
 fn decode_request<T: Message + Default>(compressed_bytes: &[u8]) -> PrometheusResult<T> {
@@ -33,11 +57,48 @@ fn decode_request<T: Message + Default>(compressed_bytes: &[u8]) -> PrometheusRe
     })
 }
 
+/// Returns the first response type accepted by the client that is supported,
+/// the client only accepts `SAMPLES` if it does not say.
+fn negotiate_response_type(read_request: &ReadRequest) -> PrometheusResult<ResponseType> {
+    if read_request.accepted_response_types.is_empty() {
+        return Ok(ResponseType::Samples);
+    }
+    read_request
+        .accepted_response_types
+        .iter()
+        .find_map(|response_type| ResponseType::try_from(*response_type).ok())
+        .ok_or_else(|| {
+            PrometheusRemoteStorageError::BadRequest(format!(
+                "server does not support any of the requested response types: {:?}",
+                read_request.accepted_response_types
+            ))
+        })
+}
+
 async fn read_handler_service(
     State(storage): State<PrometheusStorage>,
     body: Bytes,
-) -> PrometheusResult<(HeaderMap, Vec<u8>)> {
+) -> PrometheusResult<Response> {
     let read_request = decode_request::<ReadRequest>(&body)?;
+    // Native histograms are not encoded as chunks, requests reading some are
+    // answered with samples, which Prometheus tells apart by content type.
+    if negotiate_response_type(&read_request)? == ResponseType::StreamedXorChunks
+        && !storage.has_histograms(&read_request).await?
+    {
+        // Each message is framed by its varint size and CRC32C checksum.
+        let frames = storage
+            .read_chunked(read_request, MAX_BYTES_IN_FRAME)
+            .map(|response| response.map(|response| chunks::encode_frame(&response)));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static(
+                "application/x-streamed-protobuf; proto=prometheus.ChunkedReadResponse",
+            ),
+        );
+        return Ok((headers, Body::from_stream(frames)).into_response());
+    }
+
     let read_response = storage.read(read_request).await?;
     let response_body = utils::encode_snappy(read_response.encode_to_vec().as_slice())?;
     let mut headers = HeaderMap::new();
@@ -46,18 +107,82 @@ async fn read_handler_service(
         HeaderValue::from_static("application/x-protobuf"),
     );
     headers.insert(CONTENT_ENCODING, HeaderValue::from_static("snappy"));
-    Ok((headers, response_body))
+    Ok((headers, response_body).into_response())
+}
+
+/// Returns the remote write version of a request from the `proto` parameter of
+/// its content type, or else from its version header. Requests without
+/// either are remote write 1.0 ones.
+fn negotiate_write_version(headers: &HeaderMap) -> PrometheusResult<RemoteWriteVersion> {
+    let unsupported = |value: &str| {
+        PrometheusRemoteStorageError::UnsupportedMediaType(format!(
+            "unsupported remote write content: {}",
+            value
+        ))
+    };
+    if let Some(content_encoding) = headers.get(CONTENT_ENCODING) {
+        let content_encoding = content_encoding.to_str().unwrap_or_default();
+        if content_encoding != "snappy" {
+            return Err(unsupported(content_encoding));
+        }
+    }
+
+    let Some(content_type) = headers.get(CONTENT_TYPE) else {
+        return Ok(RemoteWriteVersion::V1);
+    };
+    let content_type = content_type.to_str().unwrap_or_default();
+    let mut parameters = content_type.split(';').map(str::trim);
+    if parameters.next() != Some("application/x-protobuf") {
+        return Err(unsupported(content_type));
+    }
+    match parameters.find_map(|parameter| parameter.strip_prefix("proto=")) {
+        Some(WRITE_V1_PROTO) => Ok(RemoteWriteVersion::V1),
+        Some(WRITE_V2_PROTO) => Ok(RemoteWriteVersion::V2),
+        Some(_) => Err(unsupported(content_type)),
+        None => {
+            let version = headers
+                .get(REMOTE_WRITE_VERSION_HEADER)
+                .and_then(|version| version.to_str().ok())
+                .unwrap_or_default();
+            if version.starts_with("2.") {
+                Ok(RemoteWriteVersion::V2)
+            } else {
+                Ok(RemoteWriteVersion::V1)
+            }
+        }
+    }
 }
 
 async fn write_handler_service(
     State(storage): State<PrometheusStorage>,
+    headers: HeaderMap,
     body: Bytes,
-) -> PrometheusResult<()> {
-    let write_request = decode_request::<WriteRequest>(&body)?;
-    storage.write(write_request).await
+) -> PrometheusResult<Response> {
+    if negotiate_write_version(&headers)? == RemoteWriteVersion::V1 {
+        let write_request = decode_request::<WriteRequest>(&body)?;
+        storage.write(write_request).await?;
+        return Ok(().into_response());
+    }
+
+    let write_request = decode_request::<write_v2::Request>(&body)?;
+    let written = storage.write_v2(write_request).await?;
+    let mut headers = HeaderMap::new();
+    for (name, count) in [
+        (SAMPLES_WRITTEN_HEADER, written.samples),
+        (HISTOGRAMS_WRITTEN_HEADER, written.histograms),
+        (EXEMPLARS_WRITTEN_HEADER, written.exemplars),
+    ] {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(count));
+    }
+    Ok((StatusCode::NO_CONTENT, headers).into_response())
 }
 
-pub(crate) fn prometheus_remote_router(storage: Arc<Storage>, can_read: bool, can_write: bool) -> Router {
+pub(crate) fn prometheus_remote_router(
+    storage: Arc<Storage>,
+    can_read: bool,
+    can_write: bool,
+    read_metadata: bool,
+) -> Router {
     let router = Router::new()
         .route("/prometheus", get(promql_handler_service));
 
@@ -73,7 +198,145 @@ pub(crate) fn prometheus_remote_router(storage: Arc<Storage>, can_read: bool, ca
         router
     };
 
-    let ctx = PrometheusStorage::new(storage);
-    let router = router.with_state(ctx);
-    router
+    let ctx = PrometheusStorage::new(storage, read_metadata);
+    router.with_state(ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use storage::{
+        BucketSpan, CounterResetHint, HistogramSample, Label, Sample, StorageFactory,
+        StorageSettings, TimeSeries, WalFsyncPolicy,
+    };
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::prometheus::remote::types::{label_matcher, LabelMatcher, Query, ReadResponse};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn negotiate_remote_write_version() {
+        let version = |pairs| negotiate_write_version(&headers(pairs)).ok();
+        assert_eq!(version(&[]), Some(RemoteWriteVersion::V1));
+        assert_eq!(
+            version(&[("content-type", "application/x-protobuf")]),
+            Some(RemoteWriteVersion::V1)
+        );
+        assert_eq!(
+            version(&[(
+                "content-type",
+                "application/x-protobuf;proto=io.prometheus.write.v2.Request"
+            )]),
+            Some(RemoteWriteVersion::V2)
+        );
+        assert_eq!(
+            version(&[
+                ("content-type", "application/x-protobuf; proto=prometheus.WriteRequest"),
+                ("x-prometheus-remote-write-version", "2.0.0"),
+            ]),
+            Some(RemoteWriteVersion::V1)
+        );
+        assert_eq!(
+            version(&[
+                ("content-type", "application/x-protobuf"),
+                ("x-prometheus-remote-write-version", "2.0.0"),
+            ]),
+            Some(RemoteWriteVersion::V2)
+        );
+        assert!(matches!(
+            negotiate_write_version(&headers(&[(
+                "content-type",
+                "application/x-protobuf;proto=io.prometheus.write.v3.Request"
+            )])),
+            Err(PrometheusRemoteStorageError::UnsupportedMediaType(_))
+        ));
+        assert_eq!(version(&[("content-type", "application/json")]), None);
+        assert_eq!(version(&[("content-encoding", "gzip")]), None);
+    }
+
+    #[tokio::test]
+    async fn streamed_read_with_histograms() {
+        let directory = TempDir::new("remote_read").unwrap();
+        let settings = StorageSettings::Native {
+            path: directory.path().to_str().unwrap().to_string(),
+            block_duration: 120,
+            wal_fsync_policy: WalFsyncPolicy::Never,
+            max_exemplars: 0,
+        };
+        let storage = Arc::new(StorageFactory::open(&settings).unwrap());
+        let series = |name: &str| {
+            let labels = vec![Label {
+                name: storage::SERIES_NAME_LABEL.to_string(),
+                value: name.to_string(),
+            }];
+            TimeSeries::new(labels, vec![Sample { timestamp: 1_000, value: 1.0 }])
+        };
+        let mut latency = series("latency");
+        latency.extend_histograms(vec![HistogramSample {
+            timestamp: 2_000,
+            counter_reset_hint: CounterResetHint::Unknown,
+            schema: 0,
+            zero_threshold: 0.001,
+            zero_count: 0.0,
+            count: 3.0,
+            sum: 4.5,
+            positive_spans: vec![BucketSpan { offset: 1, length: 1 }],
+            positive_counts: vec![3.0],
+            negative_spans: vec![],
+            negative_counts: vec![],
+        }]);
+        storage.write(vec![series("up"), latency]).await.unwrap();
+
+        let read = |name: &str| {
+            let read_request = ReadRequest {
+                queries: vec![Query {
+                    start_timestamp_ms: 0,
+                    end_timestamp_ms: 10_000,
+                    matchers: vec![LabelMatcher {
+                        r#type: label_matcher::Type::Eq as i32,
+                        name: storage::SERIES_NAME_LABEL.to_string(),
+                        value: name.to_string(),
+                    }],
+                    hints: None,
+                }],
+                accepted_response_types: vec![ResponseType::StreamedXorChunks as i32],
+            };
+            let body = utils::encode_snappy(&read_request.encode_to_vec()).unwrap();
+            let storage = PrometheusStorage::new(storage.clone(), false);
+            async move {
+                let response = read_handler_service(State(storage), Bytes::from(body))
+                    .await
+                    .unwrap();
+                let content_type = response.headers()[CONTENT_TYPE].to_str().unwrap().to_string();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (content_type, body)
+            }
+        };
+
+        let (content_type, _) = read("up").await;
+        assert!(content_type.starts_with("application/x-streamed-protobuf"));
+
+        // Histograms are not dropped, they are read with samples.
+        let (content_type, body) = read("latency").await;
+        assert_eq!(content_type, "application/x-protobuf");
+        let response =
+            ReadResponse::decode(utils::decode_snappy(&body).unwrap().as_slice()).unwrap();
+        let timeseries = &response.results[0].timeseries[0];
+        assert_eq!(timeseries.samples.len(), 1);
+        assert_eq!(timeseries.histograms.len(), 1);
+        assert_eq!(timeseries.histograms[0].timestamp, 2_000);
+    }
 }
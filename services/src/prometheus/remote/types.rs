@@ -1,14 +1,40 @@
 use axum::{http::StatusCode, response::IntoResponse};
-use std::{fmt::Display, sync::Arc};
-use storage::{Label as NativeLabel, Sample as NativeSample, Storage, TimeSeries as NativeSeries};
+use futures::{stream, Stream, StreamExt};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    sync::Arc,
+};
+use serde_json::json;
+use storage::{
+    Exemplar as NativeExemplar, Label as NativeLabel, ReadHints as NativeReadHints,
+    Sample as NativeSample, Storage, StorageError, TimeSeries as NativeSeries, SERIES_NAME_LABEL,
+};
 use fts::query::Query as NativeQuery;
+use regex::Regex;
+use prost::Message;
 use thiserror::Error;
 
+use super::{
+    chunks::encode_xor_chunks,
+    histograms::{histogram_from_proto, histogram_from_proto_v2, histogram_to_proto},
+    metadata::{metadata_from_proto, metadata_from_proto_v2, series_metadata},
+};
+use crate::http::error_response;
+
+/// Number of series of a streamed read query whose samples are read at once.
+const SERIES_READ_BATCH_SIZE: usize = 64;
+
 mod prompb {
     include!(concat!(env!("OUT_DIR"), "/prometheus.rs"));
 }
 pub use prompb::*;
 
+/// The messages of the remote write 2.0 protocol.
+pub mod write_v2 {
+    include!(concat!(env!("OUT_DIR"), "/io.prometheus.write.v2.rs"));
+}
+
 pub type PrometheusResult<T> = Result<T, PrometheusRemoteStorageError>;
 
 #[derive(Error, Debug)]
@@ -16,6 +42,11 @@ pub enum PrometheusRemoteStorageError {
     Storage(#[from] storage::StorageError),
     Snappy(#[from] snap::Error),
     ProtocolBuffer(#[from] prost::DecodeError),
+    /// The request is invalid and must not be retried.
+    BadRequest(String),
+    UnsupportedMediaType(String),
+    /// The decompressed request is too large.
+    PayloadTooLarge(String),
     Other(String),
 }
 
@@ -25,42 +56,95 @@ impl Display for PrometheusRemoteStorageError {
             Self::Storage(err) => f.write_fmt(format_args!("StorageError {}", err)),
             Self::Snappy(err) => f.write_fmt(format_args!("SnappyError {}", err)),
             Self::ProtocolBuffer(err) => f.write_fmt(format_args!("ProtocolBufferError {}", err)),
+            Self::BadRequest(err) => f.write_fmt(format_args!("BadRequestError {}", err)),
+            Self::UnsupportedMediaType(err) => {
+                f.write_fmt(format_args!("UnsupportedMediaTypeError {}", err))
+            }
+            Self::PayloadTooLarge(err) => f.write_fmt(format_args!("PayloadTooLargeError {}", err)),
             Self::Other(err) => f.write_fmt(format_args!("OtherError {}", err)),
         }
     }
 }
 
 impl IntoResponse for PrometheusRemoteStorageError {
+    /// Prometheus retries 5xx and 429 responses, and drops the samples of other ones.
     fn into_response(self) -> axum::response::Response {
-        let error_message = match self {
-            PrometheusRemoteStorageError::Storage(err) => format!("Internal server error: {}", err),
-            PrometheusRemoteStorageError::Snappy(err) => format!("Internal server error: {}", err),
-            PrometheusRemoteStorageError::ProtocolBuffer(err) => {
-                format!("Internal server error: {}", err)
-            },
-            PrometheusRemoteStorageError::Other(err) => format!("Internal server error: {}", err),
+        let (status_code, error_type) = match &self {
+            PrometheusRemoteStorageError::Snappy(_)
+            | PrometheusRemoteStorageError::ProtocolBuffer(_)
+            | PrometheusRemoteStorageError::BadRequest(_)
+            | PrometheusRemoteStorageError::Storage(StorageError::OutOfBounds(_)) => {
+                (StatusCode::BAD_REQUEST, "bad_data")
+            }
+            PrometheusRemoteStorageError::UnsupportedMediaType(_) => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, "bad_data")
+            }
+            PrometheusRemoteStorageError::PayloadTooLarge(_) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "bad_data")
+            }
+            PrometheusRemoteStorageError::Storage(StorageError::Overloaded(_)) => {
+                (StatusCode::TOO_MANY_REQUESTS, "overloaded")
+            }
+            PrometheusRemoteStorageError::Storage(StorageError::Unavailable(_)) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+            }
+            PrometheusRemoteStorageError::Storage(_) | PrometheusRemoteStorageError::Other(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal")
+            }
         };
-        (StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response()
+        let body = json!({
+            "status": "error",
+            "errorType": error_type,
+            "error": self.to_string(),
+        });
+        error_response(status_code, body)
     }
 }
 
+/// The number of samples, native histograms and exemplars written
+/// from a remote write 2.0 request.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct WrittenCounts {
+    pub samples: usize,
+    pub histograms: usize,
+    pub exemplars: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct PrometheusStorage {
     storage: Arc<Storage>,
+    /// Whether read results carry the metadata of their metric families.
+    read_metadata: bool,
 }
 
 impl PrometheusStorage {
-    pub fn new(storage: Arc<Storage>) -> Self {
-        PrometheusStorage { storage }
+    pub fn new(storage: Arc<Storage>, read_metadata: bool) -> Self {
+        PrometheusStorage {
+            storage,
+            read_metadata,
+        }
     }
 
-    /// Write samples to remote storage.
+    /// Write samples, native histograms, exemplars and metadata to remote storage.
     pub async fn write(&self, request: WriteRequest) -> Result<(), PrometheusRemoteStorageError> {
         println!( "Received WriteRequest: {} records", request.timeseries.len());
+        // Prometheus sends the metadata apart from the series.
+        if !request.metadata.is_empty() {
+            let metadata = request.metadata.into_iter().map(metadata_from_proto).collect();
+            self.storage.write_metadata(metadata).await?;
+        }
+        if request.timeseries.is_empty() {
+            return Ok(());
+        }
         let native_series = request
             .timeseries
             .into_iter()
             .map(|series| {
+                if !series.labels.iter().any(|label| label.name == SERIES_NAME_LABEL) {
+                    return Err(PrometheusRemoteStorageError::BadRequest(
+                        "series without a metric name".to_string(),
+                    ));
+                }
                 let labels = series
                     .labels
                     .into_iter()
@@ -79,13 +163,110 @@ impl PrometheusStorage {
                     })
                     .collect();
 
-                NativeSeries::new(labels, samples)
+                let mut native_series = NativeSeries::new(labels, samples);
+                native_series.extend_histograms(
+                    series.histograms.into_iter().map(histogram_from_proto).collect(),
+                );
+                native_series.extend_exemplars(
+                    series
+                        .exemplars
+                        .into_iter()
+                        .map(|exemplar| NativeExemplar {
+                            labels: exemplar
+                                .labels
+                                .into_iter()
+                                .map(|label| NativeLabel {
+                                    name: label.name,
+                                    value: label.value,
+                                })
+                                .collect(),
+                            value: exemplar.value,
+                            timestamp: exemplar.timestamp,
+                        })
+                        .collect(),
+                );
+                Ok(native_series)
             })
-            .collect();
+            .collect::<Result<Vec<_>, PrometheusRemoteStorageError>>()?;
         self.storage.write(native_series).await?;
         Ok(())
     }
 
+    /// Write the samples, native histograms, exemplars and metadata of a remote
+    /// write 2.0 request to remote storage. Created timestamps are not stored,
+    /// the returned counts only include what was written.
+    pub async fn write_v2(
+        &self,
+        request: write_v2::Request,
+    ) -> Result<WrittenCounts, PrometheusRemoteStorageError> {
+        println!("Received v2 WriteRequest: {} records", request.timeseries.len());
+        let mut written = WrittenCounts::default();
+        let mut native_series = Vec::with_capacity(request.timeseries.len());
+        let mut metadata = BTreeMap::new();
+        for series in request.timeseries {
+            let has_data = !series.samples.is_empty()
+                || !series.histograms.is_empty()
+                || !series.exemplars.is_empty();
+            if !has_data && series.metadata.is_none() {
+                continue;
+            }
+            let labels = resolve_label_refs(&request.symbols, &series.labels_refs)?;
+            if let Some(series_metadata) = &series.metadata {
+                let name = labels
+                    .iter()
+                    .find(|label| label.name == SERIES_NAME_LABEL)
+                    .map(|label| label.value.clone())
+                    .unwrap_or_default();
+                if let Some(series_metadata) =
+                    metadata_from_proto_v2(&name, series_metadata, &request.symbols)?
+                {
+                    metadata.insert(name, series_metadata);
+                }
+            }
+            if !has_data {
+                continue;
+            }
+            let samples: Vec<NativeSample> = series
+                .samples
+                .into_iter()
+                .map(|sample| NativeSample {
+                    timestamp: sample.timestamp,
+                    value: sample.value,
+                })
+                .collect();
+            let histograms = series
+                .histograms
+                .into_iter()
+                .map(histogram_from_proto_v2)
+                .collect::<Result<Vec<_>, PrometheusRemoteStorageError>>()?;
+            let exemplars = series
+                .exemplars
+                .into_iter()
+                .map(|exemplar| {
+                    Ok(NativeExemplar {
+                        labels: resolve_label_refs(&request.symbols, &exemplar.labels_refs)?,
+                        value: exemplar.value,
+                        timestamp: exemplar.timestamp,
+                    })
+                })
+                .collect::<Result<Vec<_>, PrometheusRemoteStorageError>>()?;
+            written.samples += samples.len();
+            written.histograms += histograms.len();
+            written.exemplars += exemplars.len();
+            let mut series = NativeSeries::new(labels, samples);
+            series.extend_histograms(histograms);
+            series.extend_exemplars(exemplars);
+            native_series.push(series);
+        }
+        if !metadata.is_empty() {
+            self.storage
+                .write_metadata(metadata.into_values().collect())
+                .await?;
+        }
+        self.storage.write(native_series).await?;
+        Ok(written)
+    }
+
     /// Serves HTTP read request from remote storage.
     ///
     /// [ReadRequest](crate::types::ReadRequest) may contain multiple [queries](crate::types::Query).
@@ -107,14 +288,76 @@ impl PrometheusStorage {
         Ok(ReadResponse { results })
     }
 
+    /// Streams the series of each query as XOR chunks, in [ChunkedReadResponse]
+    /// messages of about `max_frame_bytes`. Queries are read one after the other
+    /// and the samples of a query are read by batches of series as they are sent.
+    /// XOR chunks only hold float samples, see `has_histograms` for the requests
+    /// whose native histograms are returned by a sampled response instead.
+    pub fn read_chunked(
+        &self,
+        request: ReadRequest,
+        max_frame_bytes: usize,
+    ) -> impl Stream<Item = Result<ChunkedReadResponse, PrometheusRemoteStorageError>> {
+        println!("Received chunked ReadRequest: {:?} queries", request.queries.len());
+        let storage = self.clone();
+        stream::iter(request.queries.into_iter().enumerate())
+            .then(move |(query_index, prom_query)| {
+                let storage = storage.clone();
+                async move {
+                    ChunkedSeriesReader::new(storage, query_index as i64, prom_query, max_frame_bytes)
+                        .await
+                }
+            })
+            .flat_map(|result| match result {
+                Ok(reader) => reader.into_stream().left_stream(),
+                Err(err) => stream::once(async { Err(err) }).right_stream(),
+            })
+    }
+
+    /// Returns whether the series of any query of a read request have native
+    /// histograms, which cannot be streamed as XOR chunks.
+    pub async fn has_histograms(
+        &self,
+        request: &ReadRequest,
+    ) -> Result<bool, PrometheusRemoteStorageError> {
+        for prom_query in request.queries.iter() {
+            let start_timestamp = prom_query.start_timestamp_ms;
+            let end_timestamp = prom_query.end_timestamp_ms;
+            let query = convert_prom_query_to_native_query(prom_query.clone())?;
+            let series_ids: Vec<u64> = self
+                .storage
+                .indexed_series(query)
+                .await?
+                .into_iter()
+                .map(|(series_id, _)| series_id)
+                .collect();
+            if self
+                .storage
+                .has_histograms(&series_ids, start_timestamp, end_timestamp)
+                .await?
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
     pub async fn read_prom_query(
         &self,
         prom_query: Query,
     ) -> Result<Vec<NativeSeries>, PrometheusRemoteStorageError> {
         let start_timestamp = prom_query.start_timestamp_ms;
         let end_timestamp = prom_query.end_timestamp_ms;
+        let hints = prom_query.hints.clone();
         let query = convert_prom_query_to_native_query(prom_query)?;
-        let native_series = self.storage.read(query, start_timestamp, end_timestamp).await?;
+        let native_series = match hints {
+            Some(hints) => {
+                self.storage
+                    .read_with_hints(query, start_timestamp, end_timestamp, &convert_read_hints(hints))
+                    .await?
+            }
+            None => self.storage.read(query, start_timestamp, end_timestamp).await?,
+        };
         Ok(native_series)
     }
 
@@ -123,40 +366,521 @@ impl PrometheusStorage {
         &self,
         prom_query: Query,
     ) -> Result<QueryResult, PrometheusRemoteStorageError> {
-        let start_timestamp = prom_query.start_timestamp_ms;
-        let end_timestamp = prom_query.end_timestamp_ms;
-        let query = convert_prom_query_to_native_query(prom_query)?;
-
-        let native_series = self.storage.read(query, start_timestamp, end_timestamp).await?;
+        let native_series = self.read_prom_query(prom_query).await?;
+        let metadata = self
+            .query_metadata(native_series.iter().map(NativeSeries::get_name))
+            .await?;
         let mut timeseries = Vec::with_capacity(native_series.len());
         for series in native_series {
-            let(native_labels, native_samples) = series.into_raw();
+            let(native_labels, native_samples, native_histograms) = series.into_parts();
             let labels = native_labels.into_iter()
                 .map(|l| Label{name: l.name, value: l.value})
                 .collect();
             let samples = native_samples.into_iter()
                 .map(|s| Sample{value: s.value, timestamp: s.timestamp})
                 .collect();
+            let histograms = native_histograms.iter()
+                .map(histogram_to_proto)
+                .collect();
             timeseries.push(TimeSeries{
                 labels,
                 samples,
+                histograms,
                 ..Default::default()
             });
         }
 
-        Ok(QueryResult { timeseries })
+        Ok(QueryResult {
+            timeseries,
+            metadata,
+        })
+    }
+
+    /// Returns the metadata of the metric families of the series read by a query,
+    /// none unless read results carry metadata.
+    async fn query_metadata<'a>(
+        &self,
+        series_names: impl ExactSizeIterator<Item = &'a str>,
+    ) -> Result<Vec<MetricMetadata>, PrometheusRemoteStorageError> {
+        if !self.read_metadata || series_names.len() == 0 {
+            return Ok(vec![]);
+        }
+        let metadata = self.storage.metadata(None).await?;
+        Ok(series_metadata(series_names, metadata))
+    }
+}
+
+/// Resolves the `(name, value)` references of a remote write 2.0 series
+/// into labels, the series must have a metric name.
+fn resolve_label_refs(
+    symbols: &[String],
+    labels_refs: &[u32],
+) -> Result<Vec<NativeLabel>, PrometheusRemoteStorageError> {
+    if !labels_refs.len().is_multiple_of(2) {
+        return Err(PrometheusRemoteStorageError::BadRequest(format!(
+            "odd number of label references `{}`",
+            labels_refs.len()
+        )));
+    }
+    let symbol = |symbol_ref: u32| {
+        symbols.get(symbol_ref as usize).ok_or_else(|| {
+            PrometheusRemoteStorageError::BadRequest(format!(
+                "label reference `{}` out of the `{}` symbols",
+                symbol_ref,
+                symbols.len()
+            ))
+        })
+    };
+    let labels = labels_refs
+        .chunks(2)
+        .map(|refs| {
+            Ok(NativeLabel {
+                name: symbol(refs[0])?.clone(),
+                value: symbol(refs[1])?.clone(),
+            })
+        })
+        .collect::<Result<Vec<_>, PrometheusRemoteStorageError>>()?;
+    if !labels.iter().any(|label| label.name == SERIES_NAME_LABEL) {
+        return Err(PrometheusRemoteStorageError::BadRequest(
+            "series without a metric name".to_string(),
+        ));
+    }
+    Ok(labels)
+}
+
+/// Reads the series of a streamed query. The series matching the query are listed
+/// from the label index and sorted by labels, their samples are then read by
+/// batches of `SERIES_READ_BATCH_SIZE` series as the frames are sent.
+struct ChunkedSeriesReader {
+    storage: PrometheusStorage,
+    series: std::vec::IntoIter<(u64, Vec<Label>)>,
+    start_timestamp: i64,
+    end_timestamp: i64,
+    hints: Option<NativeReadHints>,
+    frames: ChunkedFrames,
+}
+
+impl ChunkedSeriesReader {
+    async fn new(
+        storage: PrometheusStorage,
+        query_index: i64,
+        prom_query: Query,
+        max_frame_bytes: usize,
+    ) -> Result<Self, PrometheusRemoteStorageError> {
+        let start_timestamp = prom_query.start_timestamp_ms;
+        let end_timestamp = prom_query.end_timestamp_ms;
+        let hints = prom_query.hints.clone().map(convert_read_hints);
+        let query = convert_prom_query_to_native_query(prom_query)?;
+        let indexed_series = storage.storage.indexed_series(query).await?;
+        let metadata = storage
+            .query_metadata(indexed_series.iter().map(|(_, labels)| series_name(labels)))
+            .await?;
+        Ok(Self {
+            storage,
+            series: sort_series_labels(indexed_series).into_iter(),
+            start_timestamp,
+            end_timestamp,
+            hints,
+            frames: ChunkedFrames::new(query_index, metadata, max_frame_bytes),
+        })
+    }
+
+    /// Reads the next batch of series into the frames it completes,
+    /// `None` once every series is sent.
+    async fn next_frames(
+        &mut self,
+    ) -> Result<Option<Vec<ChunkedReadResponse>>, PrometheusRemoteStorageError> {
+        loop {
+            let batch: Vec<(u64, Vec<Label>)> =
+                self.series.by_ref().take(SERIES_READ_BATCH_SIZE).collect();
+            if batch.is_empty() {
+                return Ok(self.frames.finish().map(|frame| vec![frame]));
+            }
+
+            let series_ids = batch.iter().map(|(series_id, _)| *series_id).collect();
+            let mut samples: HashMap<u64, Vec<NativeSample>> = self
+                .storage
+                .storage
+                .read_series(series_ids, self.start_timestamp, self.end_timestamp, self.hints.as_ref())
+                .await?
+                .into_iter()
+                .map(|series| (series.get_id(), series.into_raw().1))
+                .collect();
+            let frames: Vec<ChunkedReadResponse> = batch
+                .into_iter()
+                .filter_map(|(series_id, labels)| {
+                    let samples = samples.remove(&series_id).unwrap_or_default();
+                    self.frames.push(ChunkedSeries {
+                        labels,
+                        chunks: encode_xor_chunks(&samples),
+                    })
+                })
+                .collect();
+            if !frames.is_empty() {
+                return Ok(Some(frames));
+            }
+        }
+    }
+
+    fn into_stream(
+        self,
+    ) -> impl Stream<Item = Result<ChunkedReadResponse, PrometheusRemoteStorageError>> {
+        stream::unfold(Some(self), |reader| async move {
+            let mut reader = reader?;
+            match reader.next_frames().await {
+                Ok(Some(frames)) => Some((Ok(frames), Some(reader))),
+                Ok(None) => None,
+                // The stream ends after an error.
+                Err(err) => Some((Err(err), None)),
+            }
+        })
+        .flat_map(|result| match result {
+            Ok(frames) => stream::iter(frames.into_iter().map(Ok)).left_stream(),
+            Err(err) => stream::once(async { Err(err) }).right_stream(),
+        })
     }
 }
 
-fn convert_prom_query_to_native_query(_prom_query: Query) -> Result<NativeQuery, PrometheusRemoteStorageError>{
-    //TODO: convert prom_query to native query
-    // for matcher in prom_query.matchers {
-    //     match matcher.r#type() {
-    //         label_matcher::Type::Eq => todo!(),
-    //         label_matcher::Type::Neq => todo!(),
-    //         label_matcher::Type::Re => todo!(),
-    //         label_matcher::Type::Nre => todo!(),
-    //     }
-    // }
-    Ok(NativeQuery::All)
+/// Returns the value of the `__name__` label.
+fn series_name(labels: &[NativeLabel]) -> &str {
+    labels
+        .iter()
+        .find(|label| label.name == SERIES_NAME_LABEL)
+        .map_or("", |label| label.value.as_str())
+}
+
+/// Sorts the labels of each series by name, and the series by labels.
+fn sort_series_labels(series: Vec<(u64, Vec<NativeLabel>)>) -> Vec<(u64, Vec<Label>)> {
+    let mut series: Vec<(u64, Vec<Label>)> = series
+        .into_iter()
+        .map(|(series_id, native_labels)| {
+            let mut labels: Vec<Label> = native_labels
+                .into_iter()
+                .map(|l| Label { name: l.name, value: l.value })
+                .collect();
+            labels.sort_by(|left, right| left.name.cmp(&right.name));
+            (series_id, labels)
+        })
+        .collect();
+    series.sort_by(|(_, left), (_, right)| {
+        let left = left.iter().map(|l| (&l.name, &l.value));
+        let right = right.iter().map(|l| (&l.name, &l.value));
+        left.cmp(right)
+    });
+    series
+}
+
+/// Groups the series of a query into responses of about `max_frame_bytes`.
+/// A series is never split across responses, the metadata of the query is
+/// sent in the first one.
+struct ChunkedFrames {
+    query_index: i64,
+    metadata: Vec<MetricMetadata>,
+    max_frame_bytes: usize,
+    chunked_series: Vec<ChunkedSeries>,
+    frame_bytes: usize,
+}
+
+impl ChunkedFrames {
+    fn new(query_index: i64, metadata: Vec<MetricMetadata>, max_frame_bytes: usize) -> Self {
+        Self {
+            query_index,
+            metadata,
+            max_frame_bytes,
+            chunked_series: vec![],
+            frame_bytes: 0,
+        }
+    }
+
+    /// Adds a series to the current response, returns the response once full.
+    fn push(&mut self, series: ChunkedSeries) -> Option<ChunkedReadResponse> {
+        self.frame_bytes += series.encoded_len();
+        self.chunked_series.push(series);
+        (self.frame_bytes >= self.max_frame_bytes).then(|| self.take_frame())
+    }
+
+    /// Returns the last response, if it holds any series.
+    fn finish(&mut self) -> Option<ChunkedReadResponse> {
+        (!self.chunked_series.is_empty()).then(|| self.take_frame())
+    }
+
+    fn take_frame(&mut self) -> ChunkedReadResponse {
+        self.frame_bytes = 0;
+        ChunkedReadResponse {
+            chunked_series: std::mem::take(&mut self.chunked_series),
+            query_index: self.query_index,
+            metadata: std::mem::take(&mut self.metadata),
+        }
+    }
+}
+
+/// Converts the hints of a remote read query into the hints of a storage read.
+fn convert_read_hints(hints: ReadHints) -> NativeReadHints {
+    NativeReadHints {
+        step_ms: hints.step_ms,
+        func: hints.func,
+        start_ms: hints.start_ms,
+        end_ms: hints.end_ms,
+        grouping: hints.grouping,
+        by: hints.by,
+        range_ms: hints.range_ms,
+    }
+}
+
+/// Converts the label matchers of a remote read query into a native fts query.
+/// All matchers are combined with `And`; a query without matchers selects
+/// every series. Labels are indexed as fields, so each matcher is scoped
+/// to the field of its label.
+///
+/// As in Prometheus, a matcher that matches the empty string also selects
+/// the series that do not carry the label at all.
+pub(crate) fn convert_prom_query_to_native_query(
+    prom_query: Query,
+) -> Result<NativeQuery, PrometheusRemoteStorageError> {
+    let mut query: Option<NativeQuery> = None;
+    for matcher in prom_query.matchers {
+        let matcher_query = convert_label_matcher(&matcher)?;
+        query = Some(match query {
+            Some(left) => NativeQuery::And(Box::new(left), Box::new(matcher_query)),
+            None => matcher_query,
+        });
+    }
+    Ok(query.unwrap_or(NativeQuery::All))
+}
+
+fn convert_label_matcher(
+    matcher: &LabelMatcher,
+) -> Result<NativeQuery, PrometheusRemoteStorageError> {
+    let name = &matcher.name;
+    let value = &matcher.value;
+    let matcher_type = label_matcher::Type::try_from(matcher.r#type).map_err(|_| {
+        PrometheusRemoteStorageError::BadRequest(format!("unknown matcher type `{}`", matcher.r#type))
+    })?;
+
+    let query = match matcher_type {
+        label_matcher::Type::Eq if value.is_empty() => label_absent(name),
+        label_matcher::Type::Eq => label_query(name, NativeQuery::Equal(value.clone())),
+        label_matcher::Type::Neq if value.is_empty() => label_present(name),
+        label_matcher::Type::Neq => NativeQuery::Not(Box::new(label_query(
+            name,
+            NativeQuery::Equal(value.clone()),
+        ))),
+        // Prometheus regexes are fully anchored, as the fts regex automaton
+        // must consume the whole value.
+        label_matcher::Type::Re => {
+            let regex_query = label_query(name, NativeQuery::Regex(value.clone()));
+            if regex_matches_empty(value)? {
+                NativeQuery::Or(Box::new(regex_query), Box::new(label_absent(name)))
+            } else {
+                regex_query
+            }
+        }
+        label_matcher::Type::Nre => {
+            let regex_query = label_query(name, NativeQuery::Regex(value.clone()));
+            if regex_matches_empty(value)? {
+                NativeQuery::AndNot(Box::new(label_present(name)), Box::new(regex_query))
+            } else {
+                NativeQuery::Not(Box::new(regex_query))
+            }
+        }
+    };
+    Ok(query)
+}
+
+fn label_query(name: &str, query: NativeQuery) -> NativeQuery {
+    NativeQuery::Field(name.to_string(), Box::new(query))
+}
+
+/// Matches series having the label `name` whatever its value.
+fn label_present(name: &str) -> NativeQuery {
+    NativeQuery::Exists(name.to_string())
+}
+
+/// Matches series that do not have the label `name`.
+fn label_absent(name: &str) -> NativeQuery {
+    NativeQuery::Not(Box::new(label_present(name)))
+}
+
+fn regex_matches_empty(pattern: &str) -> Result<bool, PrometheusRemoteStorageError> {
+    Regex::new(&format!("^(?:{})$", pattern))
+        .map(|re| re.is_match(""))
+        .map_err(|err| {
+            PrometheusRemoteStorageError::BadRequest(format!("invalid regex `{}`: {}", pattern, err))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(r#type: label_matcher::Type, name: &str, value: &str) -> LabelMatcher {
+        LabelMatcher {
+            r#type: r#type as i32,
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn query(matchers: Vec<LabelMatcher>) -> Query {
+        Query {
+            matchers,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn convert_label_matchers() {
+        use label_matcher::Type;
+
+        let native_query = convert_prom_query_to_native_query(query(vec![])).unwrap();
+        assert_eq!(native_query, NativeQuery::All);
+
+        let native_query = convert_prom_query_to_native_query(query(vec![
+            matcher(Type::Eq, "__name__", "up"),
+            matcher(Type::Neq, "job", "api"),
+            matcher(Type::Re, "env", "prod|dev"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            native_query,
+            NativeQuery::And(
+                Box::new(NativeQuery::And(
+                    Box::new(label_query("__name__", NativeQuery::Equal("up".to_string()))),
+                    Box::new(NativeQuery::Not(Box::new(label_query(
+                        "job",
+                        NativeQuery::Equal("api".to_string())
+                    )))),
+                )),
+                Box::new(label_query("env", NativeQuery::Regex("prod|dev".to_string()))),
+            )
+        );
+
+        // Empty value matchers select on the presence of the label.
+        let native_query = convert_label_matcher(&matcher(Type::Eq, "job", "")).unwrap();
+        let job_absent = NativeQuery::Not(Box::new(NativeQuery::Exists("job".to_string())));
+        assert_eq!(native_query, job_absent);
+        let native_query = convert_label_matcher(&matcher(Type::Neq, "job", "")).unwrap();
+        assert_eq!(native_query, NativeQuery::Exists("job".to_string()));
+        let native_query = convert_label_matcher(&matcher(Type::Re, "job", "api|")).unwrap();
+        assert_eq!(
+            native_query,
+            NativeQuery::Or(
+                Box::new(label_query("job", NativeQuery::Regex("api|".to_string()))),
+                Box::new(job_absent),
+            )
+        );
+        let native_query = convert_label_matcher(&matcher(Type::Nre, "job", ".*")).unwrap();
+        assert_eq!(
+            native_query,
+            NativeQuery::AndNot(
+                Box::new(NativeQuery::Exists("job".to_string())),
+                Box::new(label_query("job", NativeQuery::Regex(".*".to_string()))),
+            )
+        );
+
+        assert!(convert_label_matcher(&matcher(Type::Re, "job", "(")).is_err());
+    }
+
+    #[test]
+    fn chunked_frames() {
+        let series = |job: &str, num_samples: i64| {
+            let labels = vec![
+                NativeLabel { name: "job".to_string(), value: job.to_string() },
+                NativeLabel { name: "__name__".to_string(), value: "up".to_string() },
+            ];
+            let samples = (0..num_samples)
+                .map(|i| NativeSample { timestamp: i * 1000, value: 1.0 })
+                .collect();
+            NativeSeries::new(labels, samples)
+        };
+        let frames = |query_index, series: Vec<NativeSeries>, metadata, max_frame_bytes| {
+            let mut samples: HashMap<u64, Vec<NativeSample>> = HashMap::new();
+            let mut indexed_series = vec![];
+            for series in series {
+                let series_id = series.get_id();
+                let (labels, series_samples) = series.into_raw();
+                samples.insert(series_id, series_samples);
+                indexed_series.push((series_id, labels));
+            }
+            let mut chunked_frames = ChunkedFrames::new(query_index, metadata, max_frame_bytes);
+            let mut frames: Vec<ChunkedReadResponse> = sort_series_labels(indexed_series)
+                .into_iter()
+                .filter_map(|(series_id, labels)| {
+                    chunked_frames.push(ChunkedSeries {
+                        labels,
+                        chunks: encode_xor_chunks(&samples[&series_id]),
+                    })
+                })
+                .collect();
+            frames.extend(chunked_frames.finish());
+            frames
+        };
+
+        let metadata = MetricMetadata {
+            metric_family_name: "up".to_string(),
+            ..Default::default()
+        };
+        let frames_1 = frames(2, vec![series("db", 1), series("api", 250)], vec![metadata], 1);
+        assert_eq!(frames_1.len(), 2);
+        // The metadata of the query is only sent once.
+        assert_eq!(frames_1[0].metadata.len(), 1);
+        assert!(frames_1[1].metadata.is_empty());
+        // Series and their labels are sorted.
+        let api = &frames_1[0].chunked_series[0];
+        assert_eq!(frames_1[0].query_index, 2);
+        assert_eq!(api.labels[0].name, "__name__");
+        assert_eq!(api.labels[1].value, "api");
+        assert_eq!(api.chunks.len(), 3);
+        assert_eq!(api.chunks[2].min_time_ms, 240_000);
+        assert_eq!(api.chunks[2].max_time_ms, 249_000);
+        assert_eq!(frames_1[1].chunked_series[0].labels[1].value, "db");
+
+        let frames_2 = frames(0, vec![series("db", 1), series("api", 250)], vec![], 1024 * 1024);
+        assert_eq!(frames_2.len(), 1);
+        assert_eq!(frames_2[0].chunked_series.len(), 2);
+        assert!(frames(0, vec![], vec![], 1).is_empty());
+    }
+
+    #[test]
+    fn resolve_v2_label_refs() {
+        let symbols: Vec<String> = ["", "__name__", "up", "job", "api"]
+            .iter()
+            .map(|symbol| symbol.to_string())
+            .collect();
+        let labels = resolve_label_refs(&symbols, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(labels.len(), 2);
+        assert_eq!((labels[1].name.as_str(), labels[1].value.as_str()), ("job", "api"));
+        assert!(resolve_label_refs(&symbols, &[1, 2, 3]).is_err());
+        assert!(resolve_label_refs(&symbols, &[1, 2, 3, 5]).is_err());
+        assert!(resolve_label_refs(&symbols, &[3, 4]).is_err());
+    }
+
+    #[test]
+    fn error_status_codes() {
+        let status = |err: PrometheusRemoteStorageError| {
+            let response = err.into_response();
+            let retry_after = response.headers().contains_key("retry-after");
+            (response.status(), retry_after)
+        };
+        let bad_request = (StatusCode::BAD_REQUEST, false);
+        assert_eq!(status(PrometheusRemoteStorageError::BadRequest("".to_string())), bad_request);
+        assert_eq!(status(snap::Error::Empty.into()), bad_request);
+        assert_eq!(status(StorageError::OutOfBounds("".to_string()).into()), bad_request);
+        assert_eq!(
+            status(PrometheusRemoteStorageError::PayloadTooLarge("".to_string())),
+            (StatusCode::PAYLOAD_TOO_LARGE, false)
+        );
+        assert_eq!(
+            status(StorageError::Overloaded("".to_string()).into()),
+            (StatusCode::TOO_MANY_REQUESTS, true)
+        );
+        assert_eq!(
+            status(StorageError::Unavailable("".to_string()).into()),
+            (StatusCode::SERVICE_UNAVAILABLE, true)
+        );
+        assert_eq!(
+            status(StorageError::Other("".to_string()).into()),
+            (StatusCode::INTERNAL_SERVER_ERROR, false)
+        );
+    }
 }
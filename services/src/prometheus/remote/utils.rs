@@ -1,6 +1,16 @@
 use super::types::{PrometheusRemoteStorageError, PrometheusResult};
+use crate::http::MAX_DECODED_BODY_BYTES;
 
+/// Decompresses a snappy body, rejecting bodies whose decompressed size,
+/// read from their header, is larger than `MAX_DECODED_BODY_BYTES`.
 pub(crate) fn decode_snappy(raw: &[u8]) -> PrometheusResult<Vec<u8>> {
+    let decompressed_len = snap::raw::decompress_len(raw)?;
+    if decompressed_len as u64 > MAX_DECODED_BODY_BYTES {
+        return Err(PrometheusRemoteStorageError::PayloadTooLarge(format!(
+            "decompressed body of {} bytes larger than {} bytes",
+            decompressed_len, MAX_DECODED_BODY_BYTES
+        )));
+    }
     let mut decoder = snap::raw::Decoder::new();
     decoder
         .decompress_vec(raw)
@@ -1,20 +1,29 @@
-use std::{path::Path, time::{Duration, Instant}};
+use std::{
+    path::Path,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use clickhouse::{Client, Row};
 use derivative::Derivative;
-use fts::{query::Query, Config, Document, Index, IndexReader, IndexWriter};
+use fts::{query::Query, Index, IndexReader, IndexWriter};
 use hashbrown::HashMap;
 use serde::{Deserialize, Serialize};
 use tokio::{
-    sync::mpsc::{self, Sender},
+    sync::{
+        mpsc::{self, Sender},
+        oneshot,
+    },
     task::JoinHandle,
 };
 
 use crate::{
-    error::{StorageError, StorageResult}, Label, Sample, TimeSeries
+    error::{StorageError, StorageResult}, open_label_index, spool::Spool, wal::Wal, BucketSpan,
+    CounterResetHint, Exemplar, HistogramSample, Label, MetricMetadata, MetricType, ReadHints,
+    Sample, SpoolSettings, TimeSeries, WalSettings
 };
 
-const DDL_SQL: &'static str = r#"
+const DDL_SQL: &str = r#"
 CREATE TABLE IF NOT EXISTS samples (
     series_id UInt64, 
     timestamp Int64 Codec(DoubleDelta, LZ4), 
@@ -25,17 +34,115 @@ PARTITION BY toStartOfWeek(toDateTime64(timestamp, 3))
 ORDER BY (series_id, timestamp);
 "#;
 
-const SELECT_SQL: &'static str = r#"
+/// Native histograms are sparse, only the populated buckets are stored: the spans
+/// as parallel offset and length arrays along with the absolute count of each bucket.
+const HISTOGRAMS_DDL_SQL: &str = r#"
+CREATE TABLE IF NOT EXISTS histograms (
+    series_id UInt64,
+    timestamp Int64 Codec(DoubleDelta, LZ4),
+    counter_reset_hint UInt8,
+    schema Int32,
+    zero_threshold Float64,
+    zero_count Float64,
+    count Float64,
+    sum Float64,
+    positive_span_offsets Array(Int32),
+    positive_span_lengths Array(UInt32),
+    positive_counts Array(Float64),
+    negative_span_offsets Array(Int32),
+    negative_span_lengths Array(UInt32),
+    negative_counts Array(Float64)
+)
+ENGINE = MergeTree
+PARTITION BY toStartOfWeek(toDateTime64(timestamp, 3))
+ORDER BY (series_id, timestamp);
+"#;
+
+/// Exemplars are only read by series and time range, their labels
+/// are stored as parallel name and value arrays.
+const EXEMPLARS_DDL_SQL: &str = r#"
+CREATE TABLE IF NOT EXISTS exemplars (
+    series_id UInt64,
+    timestamp Int64 Codec(DoubleDelta, LZ4),
+    value Float64,
+    label_names Array(String),
+    label_values Array(String)
+)
+ENGINE = MergeTree
+PARTITION BY toStartOfWeek(toDateTime64(timestamp, 3))
+ORDER BY (series_id, timestamp);
+"#;
+
+/// Rows of a metric family are merged in the background, the latest update wins.
+const METADATA_DDL_SQL: &str = r#"
+CREATE TABLE IF NOT EXISTS metadata (
+    metric_family_name String,
+    metric_type UInt8,
+    help String,
+    unit String,
+    updated_at Int64
+)
+ENGINE = ReplacingMergeTree(updated_at)
+ORDER BY metric_family_name;
+"#;
+
+const EXEMPLARS_TTL_SQL: &str = r#"
+ALTER TABLE exemplars MODIFY TTL toDateTime(intDiv(timestamp, 1000)) + INTERVAL ? DAY;
+"#;
+
+const SELECT_SQL: &str = r#"
 SELECT * FROM samples 
-WHERE series_id IN (?) AND timestamp >= ? AND timestamp < ?"#; 
+WHERE series_id IN (?) AND timestamp >= ? AND timestamp < ?
+ORDER BY series_id, timestamp"#; 
+
+/// The bits of the NaN Prometheus writes to mark a series as stale.
+const STALE_NAN_BITS: u64 = 0x7ff0000000000002;
+
+const HISTOGRAMS_SELECT_SQL: &str = r#"
+SELECT ?fields FROM histograms
+WHERE series_id IN (?) AND timestamp >= ? AND timestamp < ?
+ORDER BY series_id, timestamp"#;
+
+const EXEMPLARS_SELECT_SQL: &str = r#"
+SELECT ?fields FROM exemplars
+WHERE series_id IN (?) AND timestamp >= ? AND timestamp <= ?
+ORDER BY series_id, timestamp"#;
 
-const DELETE_SQL: &'static str = r#"
+const HISTOGRAM_SERIES_SELECT_SQL: &str = r#"
+SELECT count() FROM (
+    SELECT series_id FROM histograms
+    WHERE series_id IN (?) AND timestamp >= ? AND timestamp < ?
+    LIMIT 1
+)"#;
+
+const METADATA_SELECT_SQL: &str = r#"
+SELECT ?fields FROM metadata FINAL
+WHERE ? = '' OR metric_family_name = ?
+ORDER BY metric_family_name"#;
+
+const DELETE_SQL: &str = r#"
 ALTER TABLE samples DELETE 
     WHERE toStartOfWeek(toDateTime64(timestamp, 3)) < toStartOfWeek(toDateTime64(?, 3)))
     AND timestamp >= ? AND timestamp < ?;
 "#;
 
-const SAMPLES_TABLE_NAME: &'static str = "samples";
+const HISTOGRAMS_DELETE_SQL: &str = r#"
+ALTER TABLE histograms DELETE WHERE timestamp < ?;
+"#;
+
+const EXEMPLARS_DELETE_SQL: &str = r#"
+ALTER TABLE exemplars DELETE WHERE timestamp < ?;
+"#;
+
+const SAMPLES_TABLE_NAME: &str = "samples";
+const HISTOGRAMS_TABLE_NAME: &str = "histograms";
+const EXEMPLARS_TABLE_NAME: &str = "exemplars";
+const METADATA_TABLE_NAME: &str = "metadata";
+
+const INSERT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const INSERT_MAX_BACKOFF: Duration = Duration::from_secs(8);
+/// How often an empty spool is checked for new batches.
+const SPOOL_POLL_INTERVAL: Duration = Duration::from_secs(2);
 
 #[derive(Clone)]
 pub struct ClickHouseClient {
@@ -53,15 +160,19 @@ impl ClickHouseClient {
         }
     }
 
-    pub async fn migrate(&self) -> StorageResult<()> {
-        self.client
-            .query(DDL_SQL)
-            .execute()
-            .await
-            .map_err(StorageError::from)
+    /// Creates the tables, exemplars expire after `exemplar_retention` days if set.
+    pub async fn migrate(&self, exemplar_retention: Option<u64>) -> StorageResult<()> {
+        self.client.query(DDL_SQL).execute().await?;
+        self.client.query(HISTOGRAMS_DDL_SQL).execute().await?;
+        self.client.query(EXEMPLARS_DDL_SQL).execute().await?;
+        self.client.query(METADATA_DDL_SQL).execute().await?;
+        if let Some(days) = exemplar_retention {
+            self.client.query(EXEMPLARS_TTL_SQL).bind(days).execute().await?;
+        }
+        Ok(())
     }
 
-    pub async fn insert(&self, time_series: Vec<TimeSeries>) -> StorageResult<()> {
+    pub async fn insert(&self, time_series: &[TimeSeries]) -> StorageResult<()> {
         let mut batch = self.client.insert(SAMPLES_TABLE_NAME)?;
         for series in time_series {
             let series_id = series.get_id();
@@ -77,6 +188,35 @@ impl ClickHouseClient {
             }
         }
         batch.end().await?;
+        self.insert_histograms(time_series).await?;
+        self.insert_exemplars(time_series).await
+    }
+
+    async fn insert_histograms(&self, time_series: &[TimeSeries]) -> StorageResult<()> {
+        if time_series.iter().all(|series| series.get_histograms().is_empty()) {
+            return Ok(());
+        }
+        let mut batch = self.client.insert(HISTOGRAMS_TABLE_NAME)?;
+        for series in time_series {
+            for histogram in series.get_histograms() {
+                batch.write(&HistogramRow::new(series.get_id(), histogram)).await?;
+            }
+        }
+        batch.end().await?;
+        Ok(())
+    }
+
+    async fn insert_exemplars(&self, time_series: &[TimeSeries]) -> StorageResult<()> {
+        if time_series.iter().all(|series| series.get_exemplars().is_empty()) {
+            return Ok(());
+        }
+        let mut batch = self.client.insert(EXEMPLARS_TABLE_NAME)?;
+        for series in time_series {
+            for exemplar in series.get_exemplars() {
+                batch.write(&ExemplarRow::new(series.get_id(), exemplar)).await?;
+            }
+        }
+        batch.end().await?;
         Ok(())
     }
 
@@ -100,6 +240,115 @@ impl ClickHouseClient {
         Ok(samples)
     }
 
+    /// Selects whether any of the series of `series_ids` has histograms in the range.
+    pub async fn select_has_histograms(
+        &self,
+        series_ids: &[u64],
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> StorageResult<bool> {
+        let count = self
+            .client
+            .query(HISTOGRAM_SERIES_SELECT_SQL)
+            .bind(series_ids)
+            .bind(start_timestamp)
+            .bind(end_timestamp)
+            .fetch_one::<u64>()
+            .await?;
+        Ok(count > 0)
+    }
+
+    /// Selects a sample by step and series, the `aggregation` of the samples of
+    /// the step, see `StepAggregation::select_sql`.
+    pub async fn select_steps(
+        &self,
+        series_ids: Vec<u64>,
+        start_timestamp: i64,
+        end_timestamp: i64,
+        aggregation: &StepAggregation,
+    ) -> StorageResult<Vec<SampleRow>> {
+        let mut cursor = self
+            .client
+            .query(&aggregation.select_sql())
+            .bind(series_ids)
+            .bind(start_timestamp)
+            .bind(end_timestamp)
+            .fetch::<SampleRow>()?;
+        let mut samples = vec![];
+        while let Some(sample) = cursor.next().await? {
+            samples.push(sample);
+        }
+        Ok(samples)
+    }
+
+    pub async fn select_histograms(
+        &self,
+        series_ids: Vec<u64>,
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> StorageResult<Vec<HistogramRow>> {
+        let mut cursor = self
+            .client
+            .query(HISTOGRAMS_SELECT_SQL)
+            .bind(series_ids)
+            .bind(start_timestamp)
+            .bind(end_timestamp)
+            .fetch::<HistogramRow>()?;
+        let mut histograms = vec![];
+        while let Some(histogram) = cursor.next().await? {
+            histograms.push(histogram);
+        }
+        Ok(histograms)
+    }
+
+    pub async fn select_exemplars(
+        &self,
+        series_ids: Vec<u64>,
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> StorageResult<Vec<ExemplarRow>> {
+        let mut cursor = self
+            .client
+            .query(EXEMPLARS_SELECT_SQL)
+            .bind(series_ids)
+            .bind(start_timestamp)
+            .bind(end_timestamp)
+            .fetch::<ExemplarRow>()?;
+        let mut exemplars = vec![];
+        while let Some(exemplar) = cursor.next().await? {
+            exemplars.push(exemplar);
+        }
+        Ok(exemplars)
+    }
+
+    pub async fn insert_metadata(&self, metadata: &[MetricMetadata]) -> StorageResult<()> {
+        let updated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let mut batch = self.client.insert(METADATA_TABLE_NAME)?;
+        for metadata in metadata {
+            batch.write(&MetadataRow::new(metadata, updated_at)).await?;
+        }
+        batch.end().await?;
+        Ok(())
+    }
+
+    pub async fn select_metadata(&self, metric: Option<&str>) -> StorageResult<Vec<MetadataRow>> {
+        let metric = metric.unwrap_or_default();
+        let mut cursor = self
+            .client
+            .query(METADATA_SELECT_SQL)
+            .bind(metric)
+            .bind(metric)
+            .fetch::<MetadataRow>()?;
+        let mut rows = vec![];
+        while let Some(row) = cursor.next().await? {
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+
     /// Remove samples older than specified timestamp
     /// Internally, this will discard all CH parts older than the
     /// the specified timestamp converted into part naming scheme (weekly).
@@ -109,10 +358,100 @@ impl ClickHouseClient {
             .bind(timestamp)
             .execute()
             .await?;
+        self.client
+            .query(HISTOGRAMS_DELETE_SQL)
+            .bind(timestamp)
+            .execute()
+            .await?;
+        self.client
+            .query(EXEMPLARS_DELETE_SQL)
+            .bind(timestamp)
+            .execute()
+            .await?;
         Ok(())
     }
 }
 
+/// A function of the samples of a step ClickHouse can compute.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepFunction {
+    Min,
+    Max,
+    Avg,
+    Sum,
+    Last,
+}
+
+/// The samples of each series grouped by the windows of the evaluations of a range
+/// query, `(start_ms + k * step_ms, start_ms + (k + 1) * step_ms]` up to `end_ms`,
+/// and reduced to one by `function`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepAggregation {
+    function: StepFunction,
+    step_ms: i64,
+    start_ms: i64,
+    end_ms: i64,
+}
+
+impl StepAggregation {
+    /// Returns the aggregation the evaluation described by `hints` can use in place
+    /// of the raw samples, `None` if it needs them, e.g. for `rate` or instant queries.
+    ///
+    /// The selection of `<min|max|avg|sum|last>_over_time` over a range of one step
+    /// starts a range before the first evaluation, its windows are then the steps
+    /// from the start and each window holds its reduced step alone: the function of
+    /// that single sample is the function of the step. The windows of other ranges
+    /// overlap or leave samples out.
+    ///
+    /// `count_over_time` is not pushed down: the reader, e.g. Prometheus over remote
+    /// read, counts the samples it gets, and would count 1 by window.
+    pub fn from_hints(hints: &ReadHints) -> Option<Self> {
+        if hints.step_ms <= 0 || hints.range_ms != hints.step_ms || hints.end_ms <= hints.start_ms {
+            return None;
+        }
+        let function = match hints.func.as_str() {
+            "min_over_time" => StepFunction::Min,
+            "max_over_time" => StepFunction::Max,
+            "avg_over_time" => StepFunction::Avg,
+            "sum_over_time" => StepFunction::Sum,
+            "last_over_time" => StepFunction::Last,
+            _ => return None,
+        };
+        Some(Self {
+            function,
+            step_ms: hints.step_ms,
+            start_ms: hints.start_ms,
+            end_ms: hints.end_ms,
+        })
+    }
+
+    /// Returns the query of the steps of the series and time range to bind. Each
+    /// step is reduced to one of its samples, so that it stays within its window.
+    /// Stale markers are left out, as Prometheus does with range vectors.
+    fn select_sql(&self) -> String {
+        let (timestamp, value) = match self.function {
+            StepFunction::Min => ("argMin(timestamp, value)", "min(value)"),
+            StepFunction::Max => ("argMax(timestamp, value)", "max(value)"),
+            StepFunction::Avg => ("max(timestamp)", "avg(value)"),
+            StepFunction::Sum => ("max(timestamp)", "sum(value)"),
+            StepFunction::Last => ("max(timestamp)", "argMax(value, timestamp)"),
+        };
+        format!(
+            r#"
+SELECT series_id, {timestamp}, toFloat64({value}) FROM samples
+WHERE series_id IN (?) AND timestamp >= ? AND timestamp < ?
+    AND timestamp > {start} AND timestamp <= {end}
+    AND reinterpretAsUInt64(value) != {stale}
+GROUP BY series_id, intDiv(timestamp - {start} - 1, {step}) AS step_index
+ORDER BY series_id, step_index"#,
+            start = self.start_ms,
+            end = self.end_ms,
+            step = self.step_ms,
+            stale = STALE_NAN_BITS,
+        )
+    }
+}
+
 #[derive(Debug, Clone, Row, Serialize, Deserialize)]
 pub struct SampleRow {
     series_id: u64,
@@ -120,14 +459,170 @@ pub struct SampleRow {
     value: f64,
 }
 
-#[derive(Derivative)]
-#[derivative(Debug)]
-pub struct ClickHouseStorageInner {
-    memory: HashMap<u64, TimeSeries>,
-    #[derivative(Debug = "ignore")]
-    client: ClickHouseClient,
-    // indexer:
-    sample_size: u64,
+#[derive(Debug, Clone, Row, Serialize, Deserialize)]
+pub struct HistogramRow {
+    series_id: u64,
+    timestamp: i64,
+    counter_reset_hint: u8,
+    schema: i32,
+    zero_threshold: f64,
+    zero_count: f64,
+    count: f64,
+    sum: f64,
+    positive_span_offsets: Vec<i32>,
+    positive_span_lengths: Vec<u32>,
+    positive_counts: Vec<f64>,
+    negative_span_offsets: Vec<i32>,
+    negative_span_lengths: Vec<u32>,
+    negative_counts: Vec<f64>,
+}
+
+impl HistogramRow {
+    fn new(series_id: u64, histogram: &HistogramSample) -> Self {
+        let counter_reset_hint = match histogram.counter_reset_hint {
+            CounterResetHint::Unknown => 0,
+            CounterResetHint::Reset => 1,
+            CounterResetHint::NotReset => 2,
+            CounterResetHint::Gauge => 3,
+        };
+        Self {
+            series_id,
+            timestamp: histogram.timestamp,
+            counter_reset_hint,
+            schema: histogram.schema,
+            zero_threshold: histogram.zero_threshold,
+            zero_count: histogram.zero_count,
+            count: histogram.count,
+            sum: histogram.sum,
+            positive_span_offsets: histogram.positive_spans.iter().map(|s| s.offset).collect(),
+            positive_span_lengths: histogram.positive_spans.iter().map(|s| s.length).collect(),
+            positive_counts: histogram.positive_counts.clone(),
+            negative_span_offsets: histogram.negative_spans.iter().map(|s| s.offset).collect(),
+            negative_span_lengths: histogram.negative_spans.iter().map(|s| s.length).collect(),
+            negative_counts: histogram.negative_counts.clone(),
+        }
+    }
+
+    fn into_histogram(self) -> HistogramSample {
+        let counter_reset_hint = match self.counter_reset_hint {
+            1 => CounterResetHint::Reset,
+            2 => CounterResetHint::NotReset,
+            3 => CounterResetHint::Gauge,
+            _ => CounterResetHint::Unknown,
+        };
+        let spans = |offsets: Vec<i32>, lengths: Vec<u32>| {
+            offsets
+                .into_iter()
+                .zip(lengths)
+                .map(|(offset, length)| BucketSpan { offset, length })
+                .collect()
+        };
+        HistogramSample {
+            timestamp: self.timestamp,
+            counter_reset_hint,
+            schema: self.schema,
+            zero_threshold: self.zero_threshold,
+            zero_count: self.zero_count,
+            count: self.count,
+            sum: self.sum,
+            positive_spans: spans(self.positive_span_offsets, self.positive_span_lengths),
+            positive_counts: self.positive_counts,
+            negative_spans: spans(self.negative_span_offsets, self.negative_span_lengths),
+            negative_counts: self.negative_counts,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Row, Serialize, Deserialize)]
+pub struct ExemplarRow {
+    series_id: u64,
+    timestamp: i64,
+    value: f64,
+    label_names: Vec<String>,
+    label_values: Vec<String>,
+}
+
+impl ExemplarRow {
+    fn new(series_id: u64, exemplar: &Exemplar) -> Self {
+        Self {
+            series_id,
+            timestamp: exemplar.timestamp,
+            value: exemplar.value,
+            label_names: exemplar.labels.iter().map(|l| l.name.clone()).collect(),
+            label_values: exemplar.labels.iter().map(|l| l.value.clone()).collect(),
+        }
+    }
+
+    fn into_exemplar(self) -> Exemplar {
+        Exemplar {
+            labels: self
+                .label_names
+                .into_iter()
+                .zip(self.label_values)
+                .map(|(name, value)| Label { name, value })
+                .collect(),
+            value: self.value,
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Row, Serialize, Deserialize)]
+pub struct MetadataRow {
+    metric_family_name: String,
+    metric_type: u8,
+    help: String,
+    unit: String,
+    updated_at: i64,
+}
+
+impl MetadataRow {
+    fn new(metadata: &MetricMetadata, updated_at: i64) -> Self {
+        let metric_type = match metadata.metric_type {
+            MetricType::Unknown => 0,
+            MetricType::Counter => 1,
+            MetricType::Gauge => 2,
+            MetricType::Histogram => 3,
+            MetricType::GaugeHistogram => 4,
+            MetricType::Summary => 5,
+            MetricType::Info => 6,
+            MetricType::StateSet => 7,
+        };
+        Self {
+            metric_family_name: metadata.metric_family_name.clone(),
+            metric_type,
+            help: metadata.help.clone(),
+            unit: metadata.unit.clone(),
+            updated_at,
+        }
+    }
+
+    fn into_metadata(self) -> MetricMetadata {
+        let metric_type = match self.metric_type {
+            1 => MetricType::Counter,
+            2 => MetricType::Gauge,
+            3 => MetricType::Histogram,
+            4 => MetricType::GaugeHistogram,
+            5 => MetricType::Summary,
+            6 => MetricType::Info,
+            7 => MetricType::StateSet,
+            _ => MetricType::Unknown,
+        };
+        MetricMetadata {
+            metric_family_name: self.metric_family_name,
+            metric_type,
+            help: self.help,
+            unit: self.unit,
+        }
+    }
+}
+
+/// Operations handled by the ingestion task.
+enum StorageOp {
+    /// Series to write along with the sender of the write acknowledgement.
+    Write(Vec<TimeSeries>, oneshot::Sender<StorageResult<()>>),
+    /// Stops accepting writes, drains the queued ones & commits the buffer.
+    Shutdown,
 }
 
 #[derive(Derivative)]
@@ -135,15 +630,39 @@ pub struct ClickHouseStorageInner {
 pub struct ClickHouseStorage {
     #[derivative(Debug = "ignore")]
     client: ClickHouseClient,
-    sender: Sender<Vec<TimeSeries>>,
+    sender: Sender<StorageOp>,
+    /// The index is closed on shutdown.
     #[derivative(Debug = "ignore")]
-    index: Index,
-    handle: JoinHandle<StorageResult<()>>,
+    index: Mutex<Option<Index>>,
+    handle: Mutex<Option<JoinHandle<StorageResult<()>>>>,
+    /// Inserts the spooled batches, stopped on shutdown.
+    spool_handle: Mutex<Option<JoinHandle<()>>>,
+    /// Set while the spool of failed inserts is over its limit.
+    spool_over_limit: Arc<AtomicBool>,
+    /// The metadata last inserted for each metric family, unchanged metadata is not inserted again.
+    metadata: tokio::sync::Mutex<HashMap<String, MetricMetadata>>,
+}
+
+/// When the in-memory buffer gets committed.
+#[derive(Debug, Clone, Copy)]
+struct CommitPolicy {
     memory_budget: u64, // Max allowed memory consumption by in-memory buffer before commit.
     sample_budget: u64, // Max allowed number of sample in buffer before commit.
+    flush_interval: Duration, // Max age of the in-memory buffer before commit.
+}
+
+/// Series accepted since the last commit.
+#[derive(Default)]
+struct SeriesBuffer {
+    series: HashMap<u64, TimeSeries>,
+    memory_usage: u64,
+    sample_count: u64,
+    /// When the first series was buffered.
+    created_at: Option<Instant>,
 }
 
 impl ClickHouseStorage {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         url: &str,
         db: &str,
@@ -152,39 +671,81 @@ impl ClickHouseStorage {
         index_path: &str,
         memory_budget: u64,
         sample_budget: u64,
+        flush_interval: Duration,
+        wal_settings: &WalSettings,
+        spool_settings: &SpoolSettings,
+        exemplar_retention: Option<u64>,
     ) -> StorageResult<Self> {
         let client = ClickHouseClient::new(url, db, username, password);
         let click_house_client = client.clone();
 
-        let index = Index::open(Config::new(&Path::new(index_path)))?;
+        let index = open_label_index(Path::new(index_path))?;
         let index_writer = index.writer();
 
+        let (mut wal, wal_records) = Wal::open(
+            Path::new(&wal_settings.path),
+            wal_settings.fsync_policy,
+            wal_settings.segment_size * 1024 * 1024, // convert to MB
+        )?;
+        let spool = Spool::open(
+            Path::new(&spool_settings.path),
+            spool_settings.max_size * 1024 * 1024, // convert to MB
+        )?;
+        let spool_over_limit = spool.over_limit();
+        let spool = Arc::new(Mutex::new(spool));
+        let commit_policy = CommitPolicy {
+            memory_budget,
+            sample_budget,
+            flush_interval,
+        };
+
+        let spool_handle = tokio::spawn(replay_spool(client.clone(), spool.clone()));
+
         let (sender, mut receiver) = mpsc::channel(50);
         let task = tokio::spawn(async move {
-            let mut memory_usage = 0u64;
-            let mut sample_count = 0u64;
-            let mut memory_buffer: HashMap<u64, TimeSeries> = HashMap::new();
+            let mut buffer = SeriesBuffer::default();
             let mut interval = tokio::time::interval(Duration::from_secs(2));
-            let result = click_house_client.migrate().await;
+            let result = click_house_client.migrate(exemplar_retention).await;
             if let Err(err) = result {
                 println!("Clickhouse migration error {:?}", err);
             };
-            
+
+            // Samples acknowledged before a crash or restart.
+            if !wal_records.is_empty() {
+                println!("Replaying `{}` write-ahead log records.", wal_records.len());
+            }
+            for time_series in wal_records {
+                handle_received_series(&index_writer, &mut buffer, time_series);
+            }
+
             loop {
                 tokio::select! {
                     _ = interval.tick() => {
                         handle_commit_ticker(
                             &index_writer,
                             &click_house_client,
-                            &mut memory_buffer,
-                            &mut memory_usage,
-                            &mut sample_count,
-                            memory_budget, sample_budget
+                            &mut wal,
+                            &spool,
+                            &mut buffer,
+                            commit_policy,
                         ).await?;
                     }
                     message = receiver.recv() => {
                         match message {
-                            Some(time_series) => handle_received_series(&index_writer, &mut memory_buffer, &mut memory_usage, &mut sample_count, time_series),
+                            Some(StorageOp::Write(time_series, reply_sender)) => {
+                                handle_write(&index_writer, &mut wal, &mut buffer, time_series, reply_sender);
+                            },
+                            Some(StorageOp::Shutdown) => {
+                                // Writes already queued are accepted, later ones are rejected.
+                                receiver.close();
+                                while let Some(message) = receiver.recv().await {
+                                    if let StorageOp::Write(time_series, reply_sender) = message {
+                                        handle_write(&index_writer, &mut wal, &mut buffer, time_series, reply_sender);
+                                    }
+                                }
+                                commit_buffer(&index_writer, &click_house_client, &mut wal, &spool, &mut buffer).await;
+                                break;
+                            },
                             _ => break  // sender has been dropped
                         }
                     }
@@ -196,10 +757,11 @@ impl ClickHouseStorage {
         Ok(Self {
             client,
             sender,
-            index,
-            handle: task,
-            memory_budget,
-            sample_budget,
+            index: Mutex::new(Some(index)),
+            handle: Mutex::new(Some(task)),
+            spool_handle: Mutex::new(Some(spool_handle)),
+            spool_over_limit,
+            metadata: tokio::sync::Mutex::new(HashMap::new()),
         })
     }
 
@@ -207,10 +769,43 @@ impl ClickHouseStorage {
         // put in a fts index,
         // buffer until full or commit time elapsed
         // commit it by storing inside clickhouse
+        if self.spool_over_limit.load(Ordering::Relaxed) {
+            return Err(StorageError::Unavailable(
+                "too many samples waiting for ClickHouse".to_string(),
+            ));
+        }
+        let (reply_sender, reply_receiver) = oneshot::channel();
         self.sender
-            .send(series)
+            .send(StorageOp::Write(series, reply_sender))
             .await
-            .map_err(|_| StorageError::Other("tokio send error".to_string()))
+            .map_err(|_| StorageError::Unavailable("storage is shut down".to_string()))?;
+        reply_receiver
+            .await
+            .map_err(|_| StorageError::Other("tokio receive error".to_string()))?
+    }
+
+    /// Commits the accepted samples and closes the index.
+    /// Writes received after this call are rejected.
+    pub async fn shutdown(&self) -> StorageResult<()> {
+        let Some(handle) = self.handle.lock().unwrap().take() else {
+            return Ok(());
+        };
+        // The task is gone if it failed, the index is still closed.
+        let _ = self.sender.send(StorageOp::Shutdown).await;
+        let result = handle
+            .await
+            .map_err(|err| StorageError::Other(format!("tokio join error {}", err)))
+            .and_then(|result| result);
+        // Batches left in the spool are replayed on restart.
+        if let Some(spool_handle) = self.spool_handle.lock().unwrap().take() {
+            spool_handle.abort();
+        }
+
+        if let Some(index) = self.index.lock().unwrap().take() {
+            index.close(true)?;
+        }
+        println!("ClickTSDB storage shut down!");
+        result
     }
 
     pub async fn read(
@@ -218,25 +813,72 @@ impl ClickHouseStorage {
         query: Query,
         start_timestamp: i64,
         end_timestamp: i64,
+    ) -> StorageResult<Vec<TimeSeries>> {
+        self.read_with_hints(query, start_timestamp, end_timestamp, None).await
+    }
+
+    /// Reads the series matching `query`, with a sample by step when `hints`
+    /// describe an evaluation ClickHouse can aggregate the samples for.
+    pub async fn read_with_hints(
+        &self,
+        query: Query,
+        start_timestamp: i64,
+        end_timestamp: i64,
+        hints: Option<&ReadHints>,
+    ) -> StorageResult<Vec<TimeSeries>> {
+        let series_ids = self.index_reader()?.query(query)?;
+        self.read_series(series_ids, start_timestamp, end_timestamp, hints).await
+    }
+
+    /// Reads the samples of the series of `series_ids`, see `read_with_hints`.
+    pub async fn read_series(
+        &self,
+        series_ids: Vec<u64>,
+        start_timestamp: i64,
+        end_timestamp: i64,
+        hints: Option<&ReadHints>,
     ) -> StorageResult<Vec<TimeSeries>> {
         let now = Instant::now();
-        let index_reader = self.index.reader();
-        let series_ids = index_reader.query(query)?;
+        let index_reader = self.index_reader()?;
         //TODO: improve series grouping (maybe do it in clickhouse)
         let mut timeseries_map: HashMap<u64, TimeSeries> = self.fetch_docs(&index_reader, &series_ids)?;
-        let rows = self.client
-            .clone()
-            .select(series_ids, start_timestamp, end_timestamp)
-            .await?;
+        let aggregation = hints.and_then(StepAggregation::from_hints);
+        let rows = match &aggregation {
+            Some(aggregation) => {
+                println!("Pushing `{:?}` down to ClickHouse.", aggregation);
+                self.client
+                    .clone()
+                    .select_steps(series_ids.clone(), start_timestamp, end_timestamp, aggregation)
+                    .await?
+            }
+            None => {
+                self.client
+                    .clone()
+                    .select(series_ids.clone(), start_timestamp, end_timestamp)
+                    .await?
+            }
+        };
         let num_rows = rows.len();
         for row in rows {
             if let Some(entry) = timeseries_map.get_mut(&row.series_id) {
                 entry.push(Sample{timestamp: row.timestamp, value: row.value});
             }
         }
+        let histogram_rows = self.client
+            .select_histograms(series_ids, start_timestamp, end_timestamp)
+            .await?;
+        for row in histogram_rows {
+            if let Some(entry) = timeseries_map.get_mut(&row.series_id) {
+                entry.push_histogram(row.into_histogram());
+            }
+        }
 
-        let timeseries = timeseries_map.into_iter()
-            .map(|(_, v)| v)
+        // Replayed log records & retried inserts can store a sample twice.
+        let timeseries = timeseries_map.into_values()
+            .map(|mut series| {
+                series.sort_and_dedup();
+                series
+            })
             .collect();
 
         let elapsed = now.elapsed();
@@ -244,10 +886,82 @@ impl ClickHouseStorage {
         Ok(timeseries)
     }
 
+    /// Returns the series matching `query` with their exemplars within
+    /// `[start_timestamp, end_timestamp]`, series without exemplars are left out.
+    pub async fn exemplars(
+        &self,
+        query: Query,
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> StorageResult<Vec<TimeSeries>> {
+        let index_reader = self.index_reader()?;
+        let series_ids = index_reader.query(query)?;
+        let mut timeseries_map: HashMap<u64, TimeSeries> = self.fetch_docs(&index_reader, &series_ids)?;
+        let rows = self.client
+            .select_exemplars(series_ids, start_timestamp, end_timestamp)
+            .await?;
+        for row in rows {
+            if let Some(entry) = timeseries_map.get_mut(&row.series_id) {
+                entry.push_exemplar(row.into_exemplar());
+            }
+        }
+        Ok(timeseries_map
+            .into_values()
+            .filter(|series| !series.get_exemplars().is_empty())
+            .collect())
+    }
+
+    /// Inserts the metadata of the metric families that changed since their last insert.
+    pub async fn write_metadata(&self, metadata: Vec<MetricMetadata>) -> StorageResult<()> {
+        let mut inserted = self.metadata.lock().await;
+        let changed: Vec<MetricMetadata> = metadata
+            .into_iter()
+            .filter(|metadata| inserted.get(&metadata.metric_family_name) != Some(metadata))
+            .collect();
+        if changed.is_empty() {
+            return Ok(());
+        }
+        self.client.insert_metadata(&changed).await?;
+        for metadata in changed {
+            inserted.insert(metadata.metric_family_name.clone(), metadata);
+        }
+        Ok(())
+    }
+
+    pub async fn metadata(&self, metric: Option<&str>) -> StorageResult<Vec<MetricMetadata>> {
+        let rows = self.client.select_metadata(metric).await?;
+        Ok(rows.into_iter().map(MetadataRow::into_metadata).collect())
+    }
+
+    /// Returns the ids of the series with samples within `[start_timestamp, end_timestamp]`.
+    /// Like reads, it only sees the samples already inserted into ClickHouse.
+    pub async fn has_histograms(
+        &self,
+        series_ids: &[u64],
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> StorageResult<bool> {
+        if series_ids.is_empty() {
+            return Ok(false);
+        }
+        self.client
+            .select_has_histograms(series_ids, start_timestamp, end_timestamp)
+            .await
+    }
+
     pub async fn truncate(&self, timestamp: i64) -> StorageResult<()> {
         self.client.clone().truncate(timestamp).await
     }
 
+    pub(crate) fn index_reader(&self) -> StorageResult<IndexReader> {
+        self.index
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|index| index.reader())
+            .ok_or_else(|| StorageError::Unavailable("storage is shut down".to_string()))
+    }
+
     fn fetch_docs(&self,index_reader: &IndexReader, series_ids: &[u64]) -> StorageResult<HashMap<u64, TimeSeries>> {
         let mut map = HashMap::with_capacity(series_ids.len());
         for id in series_ids {
@@ -263,62 +977,339 @@ impl ClickHouseStorage {
 async fn handle_commit_ticker(
     index_writer: &IndexWriter,
     click_house_client: &ClickHouseClient,
-    memory_buffer: &mut HashMap<u64, TimeSeries>,
-    memory_usage: &mut u64,
-    sample_count: &mut u64,
-    memory_budget: u64,
-    sample_budget: u64,
+    wal: &mut Wal,
+    spool: &Mutex<Spool>,
+    buffer: &mut SeriesBuffer,
+    commit_policy: CommitPolicy,
 ) -> StorageResult<()> {
-    if *memory_usage >= memory_budget || *sample_count >= sample_budget {
-        // commit
-        let committing_buffer =
-            std::mem::replace(&mut *memory_buffer, HashMap::<u64, TimeSeries>::new());
-        let series = committing_buffer
-            .into_iter()
-            .map(|(_, v)| v)
-            .collect::<Vec<_>>();
-        let result = click_house_client.insert(series).await;
-        if let Err(err) = result {
-            println!("ClickHouse insertion error {:?}", err);
+    if let Err(err) = wal.sync() {
+        println!("Wal sync error {:?}", err);
+    }
+
+    let buffer_expired = buffer
+        .created_at
+        .is_some_and(|created_at| created_at.elapsed() >= commit_policy.flush_interval);
+    if buffer.memory_usage >= commit_policy.memory_budget
+        || buffer.sample_count >= commit_policy.sample_budget
+        || buffer_expired
+    {
+        commit_buffer(index_writer, click_house_client, wal, spool, buffer).await;
+    }
+    Ok(())
+}
+
+async fn commit_buffer(
+    index_writer: &IndexWriter,
+    click_house_client: &ClickHouseClient,
+    wal: &mut Wal,
+    spool: &Mutex<Spool>,
+    buffer: &mut SeriesBuffer,
+) {
+    if buffer.series.is_empty() {
+        return;
+    }
+
+    let committing_buffer = std::mem::take(buffer);
+    let series = committing_buffer
+        .series
+        .into_values()
+        .collect::<Vec<_>>();
+
+    let mut committed = true;
+    // While batches wait in the spool ClickHouse is likely down, the batch
+    // is spooled right away rather than holding up the writes behind it.
+    let spool_pending = !spool.lock().unwrap().is_empty();
+    let inserted = if spool_pending {
+        false
+    } else {
+        match click_house_client.insert(&series).await {
+            Ok(()) => true,
+            Err(err) => {
+                println!("ClickHouse insertion error {:?}, spooling `{}` series.", err, series.len());
+                false
+            }
+        }
+    };
+    if !inserted {
+        // Samples that can't be spooled are left in the log.
+        if let Err(err) = spool.lock().unwrap().push(&series) {
+            println!("Spool error {:?}", err);
+            committed = false;
+        }
+    }
+
+    // The commit waits for the new segment to be written to disk.
+    let moved_index_writer = index_writer.clone();
+    let result = tokio::task::spawn_blocking(move || moved_index_writer.commit(true))
+        .await
+        .map_err(|err| StorageError::Other(format!("tokio join error {}", err)))
+        .and_then(|result| result.map_err(StorageError::from));
+    if let Err(err) = result {
+        println!("Fts index commit error {:?}", err);
+        committed = false;
+    }
+
+    // Uncommitted samples are kept in the log to be replayed on restart.
+    if committed {
+        if let Err(err) = wal.truncate() {
+            println!("Wal truncation error {:?}", err);
+        }
+    }
+
+    println!("ClickTSDB buffer committed!")
+}
+
+/// Inserts the spooled batches, oldest first, retrying failed inserts
+/// with an exponential backoff. It runs apart from the storage task so
+/// that writes are not held up while ClickHouse is down.
+async fn replay_spool(click_house_client: ClickHouseClient, spool: Arc<Mutex<Spool>>) {
+    let mut backoff = INSERT_INITIAL_BACKOFF;
+    loop {
+        let batch = {
+            let spool = spool.lock().unwrap();
+            if spool.is_empty() {
+                Ok(None)
+            } else {
+                spool.oldest()
+            }
+        };
+        let (file_name, series) = match batch {
+            Ok(Some(batch)) => batch,
+            Ok(None) => {
+                tokio::time::sleep(SPOOL_POLL_INTERVAL).await;
+                continue;
+            }
+            Err(err) => {
+                println!("Spool read error {:?}", err);
+                tokio::time::sleep(SPOOL_POLL_INTERVAL).await;
+                continue;
+            }
         };
 
-        if let Err(err) = index_writer.commit(true) {
-            println!("Fts index commit error {:?}", err);
+        if let Err(err) = click_house_client.insert(&series).await {
+            println!("ClickHouse spool replay error {:?}, retrying in `{:?}`.", err, backoff);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(INSERT_MAX_BACKOFF);
+            continue;
+        }
+        backoff = INSERT_INITIAL_BACKOFF;
+        let result = spool.lock().unwrap().remove(&file_name);
+        if let Err(err) = result {
+            println!("Spool error {:?}", err);
+            tokio::time::sleep(SPOOL_POLL_INTERVAL).await;
+            continue;
         }
-        *memory_usage = 0;
-        *sample_count = 0;
+        println!("Replayed `{}` spooled series.", series.len());
+    }
+}
 
-        println!("ClickTSDB buffer committed!")
+fn handle_write(
+    index_writer: &IndexWriter,
+    wal: &mut Wal,
+    buffer: &mut SeriesBuffer,
+    time_series: Vec<TimeSeries>,
+    reply_sender: oneshot::Sender<StorageResult<()>>,
+) {
+    // Only acknowledge the write once it is logged.
+    let result = wal.append(&time_series);
+    if result.is_ok() {
+        handle_received_series(index_writer, buffer, time_series);
     }
-    Ok(())
+    let _ = reply_sender.send(result);
 }
 
 fn handle_received_series(
     index_writer: &IndexWriter,
-    memory_buffer: &mut HashMap<u64, TimeSeries>,
-    memory_usage: &mut u64,
-    sample_count: &mut u64,
+    buffer: &mut SeriesBuffer,
     time_series: Vec<TimeSeries>,
 ) {
+    if buffer.created_at.is_none() && !time_series.is_empty() {
+        buffer.created_at = Some(Instant::now());
+    }
+
     for series in time_series {
-        *memory_usage += series.get_size_bytes();
-        *sample_count += series.get_samples().len() as u64;
+        buffer.memory_usage += series.get_size_bytes();
+        buffer.sample_count += series.num_samples() as u64;
 
-        if let Some(entry) = memory_buffer.get_mut(&series.get_id()) {
-            let (_, samples) = series.into_raw();
+        if let Some(entry) = buffer.series.get_mut(&series.get_id()) {
+            let mut series = series;
+            entry.extend_exemplars(series.take_exemplars());
+            let (_, samples, histograms) = series.into_parts();
             entry.extend(samples);
+            entry.extend_histograms(histograms);
         } else {
-            let labels = series.get_labels().iter().cloned().collect::<Vec<_>>();
-            let series_id = series.get_id();
-            let doc_content = serde_json::to_string(&labels).unwrap();
+            index_writer.insert_doc(series.index_document());
+            buffer.series.insert(series.get_id(), series);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use tempdir::TempDir;
 
-            let terms = labels
+    use super::*;
+    use crate::WalFsyncPolicy;
+
+    #[tokio::test]
+    async fn reject_writes_while_spool_over_limit() -> StorageResult<()> {
+        let directory = TempDir::new("clickhouse")?;
+        let path = |name: &str| directory.path().join(name).to_str().unwrap().to_string();
+        let storage = ClickHouseStorage::new(
+            "http://127.0.0.1:1",
+            "default",
+            "",
+            "",
+            &path("index"),
+            1024 * 1024,
+            1_000,
+            Duration::from_secs(60),
+            &WalSettings {
+                path: path("wal"),
+                fsync_policy: WalFsyncPolicy::Never,
+                segment_size: 1,
+            },
+            &SpoolSettings {
+                path: path("spool"),
+                max_size: 1,
+            },
+            None,
+        )?;
+        storage.spool_over_limit.store(true, Ordering::Relaxed);
+        // Unavailable is answered with 503 and a Retry-After header.
+        let result = storage.write(vec![]).await;
+        assert!(matches!(result, Err(StorageError::Unavailable(_))));
+        storage.shutdown().await
+    }
+
+    fn hints(func: &str, step_ms: i64, range_ms: i64) -> ReadHints {
+        ReadHints {
+            step_ms,
+            func: func.to_string(),
+            start_ms: 0,
+            end_ms: 600_000,
+            grouping: vec![],
+            by: false,
+            range_ms,
+        }
+    }
+
+    #[test]
+    fn step_aggregation_from_hints() {
+        let function = |hints: ReadHints| StepAggregation::from_hints(&hints).map(|aggregation| aggregation.function);
+        assert_eq!(function(hints("max_over_time", 60_000, 60_000)), Some(StepFunction::Max));
+        assert_eq!(function(hints("avg_over_time", 60_000, 60_000)), Some(StepFunction::Avg));
+        assert_eq!(function(hints("sum_over_time", 60_000, 60_000)), Some(StepFunction::Sum));
+        assert_eq!(function(hints("last_over_time", 60_000, 60_000)), Some(StepFunction::Last));
+        // functions which cannot be applied to reduced steps
+        assert_eq!(function(hints("count_over_time", 60_000, 60_000)), None);
+        assert_eq!(function(hints("rate", 60_000, 60_000)), None);
+        // ranges of another length than a step, and instant selectors
+        assert_eq!(function(hints("max_over_time", 60_000, 300_000)), None);
+        assert_eq!(function(hints("sum", 60_000, 0)), None);
+        // instant queries
+        assert_eq!(function(hints("max_over_time", 0, 0)), None);
+
+        let sql = StepAggregation::from_hints(&hints("min_over_time", 60_000, 60_000))
+            .unwrap()
+            .select_sql();
+        assert!(sql.contains("argMin(timestamp, value), toFloat64(min(value))"));
+        assert!(sql.contains("timestamp > 0 AND timestamp <= 600000"));
+        assert!(sql.contains("GROUP BY series_id, intDiv(timestamp - 0 - 1, 60000) AS step_index"));
+        assert!(sql.contains(&STALE_NAN_BITS.to_string()));
+        let sql = |func: &str| {
+            StepAggregation::from_hints(&hints(func, 60_000, 60_000))
+                .unwrap()
+                .select_sql()
+        };
+        assert!(sql("max_over_time").contains("argMax(timestamp, value), toFloat64(max(value))"));
+        assert!(sql("avg_over_time").contains("max(timestamp), toFloat64(avg(value))"));
+        assert!(sql("sum_over_time").contains("max(timestamp), toFloat64(sum(value))"));
+        assert!(sql("last_over_time").contains("max(timestamp), toFloat64(argMax(value, timestamp))"));
+    }
+
+    /// Evaluates `<function>_over_time` as Prometheus does over the windows
+    /// `(t - range, t]` of the evaluations at `t = start + range + k * step`.
+    fn evaluate(function: StepFunction, hints: &ReadHints, samples: &[(i64, f64)]) -> Vec<(i64, f64)> {
+        let mut results = vec![];
+        let mut t = hints.start_ms + hints.range_ms;
+        while t <= hints.end_ms {
+            let window: Vec<(i64, f64)> = samples
                 .iter()
-                .map(|l| format!("{}:{}", l.name, l.value))
-                .collect::<Vec<_>>();
-            index_writer.insert_doc(Document::new(series_id, doc_content.as_str(), &terms));
+                .filter(|(timestamp, _)| t - hints.range_ms < *timestamp && *timestamp <= t)
+                .cloned()
+                .collect();
+            let value = match function {
+                StepFunction::Min => window.iter().map(|(_, value)| *value).reduce(f64::min),
+                StepFunction::Max => window.iter().map(|(_, value)| *value).reduce(f64::max),
+                StepFunction::Avg => (!window.is_empty()).then(|| {
+                    window.iter().map(|(_, value)| *value).sum::<f64>() / window.len() as f64
+                }),
+                StepFunction::Sum => window.iter().map(|(_, value)| *value).reduce(|a, b| a + b),
+                StepFunction::Last => window.last().map(|(_, value)| *value),
+            };
+            results.extend(value.map(|value| (t, value)));
+            t += hints.step_ms;
+        }
+        results
+    }
+
+    /// Reduces the steps of the samples as `StepAggregation::select_sql` does.
+    fn reduce_steps(aggregation: &StepAggregation, samples: &[(i64, f64)]) -> Vec<(i64, f64)> {
+        let mut steps: BTreeMap<i64, Vec<(i64, f64)>> = BTreeMap::new();
+        for (timestamp, value) in samples {
+            if aggregation.start_ms < *timestamp && *timestamp <= aggregation.end_ms {
+                let step_index = (timestamp - aggregation.start_ms - 1) / aggregation.step_ms;
+                steps.entry(step_index).or_default().push((*timestamp, *value));
+            }
+        }
+        steps
+            .into_values()
+            .map(|step| {
+                let by_value = |a: &&(i64, f64), b: &&(i64, f64)| a.1.total_cmp(&b.1);
+                match aggregation.function {
+                    StepFunction::Min => *step.iter().min_by(by_value).unwrap(),
+                    StepFunction::Max => *step.iter().max_by(by_value).unwrap(),
+                    StepFunction::Avg | StepFunction::Sum => {
+                        let timestamp = step.iter().map(|(timestamp, _)| *timestamp).max().unwrap();
+                        let sum: f64 = step.iter().map(|(_, value)| *value).sum();
+                        let value = if aggregation.function == StepFunction::Avg {
+                            sum / step.len() as f64
+                        } else {
+                            sum
+                        };
+                        (timestamp, value)
+                    }
+                    StepFunction::Last => *step.iter().max_by_key(|(timestamp, _)| *timestamp).unwrap(),
+                }
+            })
+            .collect()
+    }
 
-            memory_buffer.insert(series_id, series);
+    #[test]
+    fn step_aggregation_matches_raw_samples() {
+        // Irregular samples, some on the evaluation timestamps, and a gap.
+        let samples: Vec<(i64, f64)> = (0..40)
+            .filter(|i| !(20..25).contains(i))
+            .map(|i| (i * 17_000 - 5_000, ((i * 7) % 11) as f64))
+            .chain([(120_000, -1.0), (180_000, 20.0)])
+            .collect::<BTreeMap<i64, f64>>()
+            .into_iter()
+            .collect();
+        for func in ["min_over_time", "max_over_time", "avg_over_time", "sum_over_time", "last_over_time"] {
+            let mut read_hints = hints(func, 60_000, 60_000);
+            read_hints.start_ms = 30_000;
+            let aggregation = StepAggregation::from_hints(&read_hints).unwrap();
+            let steps = reduce_steps(&aggregation, &samples);
+            assert!(steps.len() < samples.len());
+            assert_eq!(
+                evaluate(aggregation.function, &read_hints, &steps),
+                evaluate(aggregation.function, &read_hints, &samples),
+                "{}",
+                func
+            );
         }
     }
 }
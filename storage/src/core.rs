@@ -1,11 +1,12 @@
-use std::mem::size_of;
+use std::{mem::size_of, path::Path};
 
 use fasthash::xx;
+use fts::{Config, Document, FtsError, Index};
 use serde::{Deserialize, Serialize};
 
-pub const SERIES_NAME_LABEL: &'static str = "__name__";
+pub const SERIES_NAME_LABEL: &str = "__name__";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Label {
     pub name: String,
     pub value: String,
@@ -17,12 +18,115 @@ pub struct Sample {
     pub value: f64,
 }
 
+/// A span of consecutive buckets of a native histogram, `offset` is the
+/// gap to the previous span or the index of the first bucket.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BucketSpan {
+    pub offset: i32,
+    pub length: u32,
+}
+
+/// Whether a native histogram follows a counter reset, as hinted by the client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum CounterResetHint {
+    #[default]
+    Unknown,
+    Reset,
+    NotReset,
+    Gauge,
+}
+
+/// A sample of a native (sparse) histogram, as in Prometheus. Bucket `i`
+/// of schema `s` spans `(2^(2^-s * (i - 1)), 2^(2^-s * i)]`, mirrored for
+/// negative observations. Counts are absolute, integer histograms included.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistogramSample {
+    pub timestamp: i64, // timestamp is in ms format
+    pub counter_reset_hint: CounterResetHint,
+    pub schema: i32,
+    pub zero_threshold: f64,
+    pub zero_count: f64,
+    pub count: f64,
+    pub sum: f64,
+    pub positive_spans: Vec<BucketSpan>,
+    pub positive_counts: Vec<f64>,
+    pub negative_spans: Vec<BucketSpan>,
+    pub negative_counts: Vec<f64>,
+}
+
+impl HistogramSample {
+    fn size_bytes(&self) -> usize {
+        size_of::<Self>()
+            + (self.positive_spans.len() + self.negative_spans.len()) * size_of::<BucketSpan>()
+            + (self.positive_counts.len() + self.negative_counts.len()) * size_of::<f64>()
+    }
+}
+
+/// An exemplar of a series, e.g. the trace of a request observed by the series.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exemplar {
+    pub labels: Vec<Label>,
+    pub value: f64,
+    pub timestamp: i64, // timestamp is in ms format
+}
+
+impl Exemplar {
+    fn size_bytes(&self) -> usize {
+        size_of::<Self>()
+            + self
+                .labels
+                .iter()
+                .map(|l| l.name.len() + l.value.len())
+                .sum::<usize>()
+    }
+}
+
+/// How the samples of a read are used, as hinted by a Prometheus remote read query:
+/// the function or aggregation around the selector, evaluated every `step_ms`
+/// up to `end_ms` over `range_ms` for range selectors. Times are in ms.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReadHints {
+    pub step_ms: i64,
+    pub func: String,
+    pub start_ms: i64,
+    pub end_ms: i64,
+    pub grouping: Vec<String>,
+    pub by: bool,
+    pub range_ms: i64,
+}
+
+/// The type of a metric family, as in Prometheus.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum MetricType {
+    #[default]
+    Unknown,
+    Counter,
+    Gauge,
+    Histogram,
+    GaugeHistogram,
+    Summary,
+    Info,
+    StateSet,
+}
+
+/// The metadata of a metric family, e.g. of the `_bucket`,
+/// `_sum` and `_count` series of a classic histogram.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetricMetadata {
+    pub metric_family_name: String,
+    pub metric_type: MetricType,
+    pub help: String,
+    pub unit: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TimeSeries {
     id: u64,
     name: String,
     labels: Vec<Label>,
     samples: Vec<Sample>,
+    histograms: Vec<HistogramSample>,
+    exemplars: Vec<Exemplar>,
     #[serde(skip)]
     size_bytes: u64,
 }
@@ -34,6 +138,8 @@ impl Default for TimeSeries {
             name: "".to_string(),
             labels: vec![],
             samples: vec![],
+            histograms: vec![],
+            exemplars: vec![],
             size_bytes: 0,
         }
     }
@@ -45,7 +151,7 @@ impl TimeSeries {
             let mut mem_size = 0;
             mem_size += labels
                 .iter()
-                .map(|l| l.name.as_bytes().len() + l.value.as_bytes().len())
+                .map(|l| l.name.len() + l.value.len())
                 .sum::<usize>();
             mem_size += size_of::<Sample>() + samples.len();
             mem_size as u64
@@ -56,6 +162,8 @@ impl TimeSeries {
             name: info.name,
             labels: info.labels,
             samples,
+            histograms: vec![],
+            exemplars: vec![],
             size_bytes,
         }
     }
@@ -67,7 +175,48 @@ impl TimeSeries {
 
     pub fn extend(&mut self, samples: Vec<Sample>) {
         self.size_bytes += (size_of::<Sample>() * samples.len()) as u64;
-        self.samples.extend(samples.into_iter());
+        self.samples.extend(samples);
+    }
+
+    pub fn push_histogram(&mut self, histogram: HistogramSample) {
+        self.size_bytes += histogram.size_bytes() as u64;
+        self.histograms.push(histogram);
+    }
+
+    pub fn extend_histograms(&mut self, histograms: Vec<HistogramSample>) {
+        self.size_bytes += histograms.iter().map(HistogramSample::size_bytes).sum::<usize>() as u64;
+        self.histograms.extend(histograms);
+    }
+
+    pub fn push_exemplar(&mut self, exemplar: Exemplar) {
+        self.size_bytes += exemplar.size_bytes() as u64;
+        self.exemplars.push(exemplar);
+    }
+
+    pub fn extend_exemplars(&mut self, exemplars: Vec<Exemplar>) {
+        self.size_bytes += exemplars.iter().map(Exemplar::size_bytes).sum::<usize>() as u64;
+        self.exemplars.extend(exemplars);
+    }
+
+    /// Sorts the samples and histograms by timestamp, only the first
+    /// of the samples sharing a timestamp is kept.
+    pub fn sort_and_dedup(&mut self) {
+        if !self.samples.is_sorted_by(|a, b| a.timestamp < b.timestamp) {
+            self.samples.sort_by_key(|sample| sample.timestamp);
+            self.samples.dedup_by_key(|sample| sample.timestamp);
+        }
+        if !self.histograms.is_sorted_by(|a, b| a.timestamp < b.timestamp) {
+            self.histograms.sort_by_key(|histogram| histogram.timestamp);
+            self.histograms.dedup_by_key(|histogram| histogram.timestamp);
+        }
+    }
+
+    /// Removes the exemplars of the series, they are stored apart from the samples.
+    pub fn take_exemplars(&mut self) -> Vec<Exemplar> {
+        let exemplars = std::mem::take(&mut self.exemplars);
+        let exemplars_size = exemplars.iter().map(Exemplar::size_bytes).sum::<usize>() as u64;
+        self.size_bytes = self.size_bytes.saturating_sub(exemplars_size);
+        exemplars
     }
 
     pub fn get_id(&self) -> u64 {
@@ -86,6 +235,19 @@ impl TimeSeries {
         &self.samples
     }
 
+    pub fn get_histograms(&self) -> &[HistogramSample] {
+        &self.histograms
+    }
+
+    pub fn get_exemplars(&self) -> &[Exemplar] {
+        &self.exemplars
+    }
+
+    /// Returns the number of float samples and histograms of the series.
+    pub fn num_samples(&self) -> usize {
+        self.samples.len() + self.histograms.len()
+    }
+
     pub fn get_size_bytes(&self) -> u64 {
         self.size_bytes
     }
@@ -102,6 +264,38 @@ impl TimeSeries {
     pub fn into_raw(self) -> (Vec<Label>, Vec<Sample>) {
         (self.labels, self.samples)
     }
+
+    /// Like `into_raw`, along with the native histograms of the series.
+    pub fn into_parts(self) -> (Vec<Label>, Vec<Sample>, Vec<HistogramSample>) {
+        (self.labels, self.samples, self.histograms)
+    }
+
+    /// Returns the fts document indexing the labels of the series,
+    /// the labels are stored as its content.
+    pub(crate) fn index_document(&self) -> Document {
+        labels_document(self.id, &self.labels)
+    }
+}
+
+fn labels_document(id: u64, labels: &[Label]) -> Document {
+    let doc_content = serde_json::to_string(labels).unwrap();
+    let fields = labels
+        .iter()
+        .map(|l| (l.name.as_str(), l.value.as_str()))
+        .collect::<Vec<_>>();
+    Document::with_fields(id, doc_content.as_str(), &fields)
+}
+
+/// Opens the label index in `directory`. An index written before labels
+/// were indexed as fields is migrated first, from the labels of its documents.
+pub(crate) fn open_label_index(directory: &Path) -> Result<Index, FtsError> {
+    let config = Config::new(directory);
+    Index::migrate(&config, |id, content| {
+        let labels: Vec<Label> =
+            serde_json::from_slice(&content).map_err(|err| FtsError::Other(err.to_string()))?;
+        Ok(labels_document(id, &labels))
+    })?;
+    Index::open(config)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -118,10 +312,9 @@ impl TimeSeriesInfo {
             .iter()
             .find(|l| l.name == SERIES_NAME_LABEL)
             .map(|l| l.value.clone())
-            .expect(&format!(
-                "time series should have a `{}` label.",
-                SERIES_NAME_LABEL
-            ));
+            .unwrap_or_else(|| {
+                panic!("time series should have a `{}` label.", SERIES_NAME_LABEL)
+            });
 
         // sort the labels, append __name__ & hash
         let mut label_set: Vec<String> = labels
@@ -1,3 +1,5 @@
+use std::io;
+
 use thiserror::Error;
 
 pub type StorageResult<T> = std::result::Result<T, StorageError>;
@@ -8,6 +10,22 @@ pub enum StorageError {
     ClickHouse(#[from] clickhouse::error::Error),
     #[error("Fts error")]
     Fts(#[from] fts::FtsError),
-    #[error("Other error")]
+    #[error("IO error")]
+    Io(#[from] io::Error),
+    #[error("Serde error")]
+    Serde(#[from] bincode::Error),
+    #[error("Json error")]
+    Json(#[from] serde_json::Error),
+    /// Writes cannot be accepted for now, e.g. while the spool of failed inserts
+    /// is over its limit or once shut down, they should be retried later.
+    #[error("Storage unavailable: {0}")]
+    Unavailable(String),
+    /// Writes are coming in faster than they are accepted, they should be retried later.
+    #[error("Storage overloaded: {0}")]
+    Overloaded(String),
+    /// Samples too old to be written, the other samples are written.
+    #[error("Out of bounds: {0}")]
+    OutOfBounds(String),
+    #[error("Other error: {0}")]
     Other(String),
 }
@@ -3,17 +3,23 @@ mod core;
 mod error;
 mod native;
 mod settings;
+mod spool;
+mod wal;
+
+use std::{collections::BTreeSet, time::Duration};
 
 pub use core::*;
 
-use fts::query::Query;
-pub use settings::StorageSettings;
+use fts::{query::Query, IndexReader};
+pub use settings::{SpoolSettings, StorageSettings, WalSettings};
+pub use wal::WalFsyncPolicy;
 
 use clickhouse::ClickHouseStorage;
 pub use error::{StorageResult, StorageError};
 use native::NativeStorage;
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Storage {
     Native(NativeStorage),
     ClickHouse(ClickHouseStorage),
@@ -41,12 +47,187 @@ impl Storage {
         }
     }
 
+    /// Like `read`, `hints` describe how the samples are used. Series may then
+    /// have a single sample by step, the hinted function applied to the samples
+    /// of the step, when the storage can compute it, e.g. ClickHouse.
+    pub async fn read_with_hints(
+        &self,
+        query: Query,
+        start_timestamp: i64,
+        end_timestamp: i64,
+        hints: &ReadHints,
+    ) -> StorageResult<Vec<TimeSeries>> {
+        match self {
+            Storage::Native(storage) => storage.read(query, start_timestamp, end_timestamp).await,
+            Storage::ClickHouse(storage) => {
+                storage
+                    .read_with_hints(query, start_timestamp, end_timestamp, Some(hints))
+                    .await
+            }
+        }
+    }
+
+    /// Reads the series of `series_ids`, e.g. the series matching a query listed
+    /// by `indexed_series`, to read them in batches. `hints` are used as in
+    /// `read_with_hints`.
+    pub async fn read_series(
+        &self,
+        series_ids: Vec<u64>,
+        start_timestamp: i64,
+        end_timestamp: i64,
+        hints: Option<&ReadHints>,
+    ) -> StorageResult<Vec<TimeSeries>> {
+        match self {
+            Storage::Native(storage) => {
+                storage.read_series(series_ids, start_timestamp, end_timestamp).await
+            }
+            Storage::ClickHouse(storage) => {
+                storage
+                    .read_series(series_ids, start_timestamp, end_timestamp, hints)
+                    .await
+            }
+        }
+    }
+
+    /// Returns whether any of the series of `series_ids` has native histograms
+    /// within `[start_timestamp, end_timestamp)`, possibly `true` when a native
+    /// block overlapping the range holds histograms of the series.
+    pub async fn has_histograms(
+        &self,
+        series_ids: &[u64],
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> StorageResult<bool> {
+        match self {
+            Storage::Native(storage) => {
+                Ok(storage.has_histograms(series_ids, start_timestamp, end_timestamp))
+            }
+            Storage::ClickHouse(storage) => {
+                storage
+                    .has_histograms(series_ids, start_timestamp, end_timestamp)
+                    .await
+            }
+        }
+    }
+
+    /// Returns the series matching `query` along with their exemplars within
+    /// `[start_timestamp, end_timestamp]`, series without exemplars are left out.
+    pub async fn exemplars(
+        &self,
+        query: Query,
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> StorageResult<Vec<TimeSeries>> {
+        match self {
+            Storage::Native(storage) => {
+                storage.exemplars(query, start_timestamp, end_timestamp).await
+            }
+            Storage::ClickHouse(storage) => {
+                storage.exemplars(query, start_timestamp, end_timestamp).await
+            }
+        }
+    }
+
+    /// Keeps the latest metadata of each metric family.
+    pub async fn write_metadata(&self, metadata: Vec<MetricMetadata>) -> StorageResult<()> {
+        match self {
+            Storage::Native(storage) => storage.write_metadata(metadata).await,
+            Storage::ClickHouse(storage) => storage.write_metadata(metadata).await,
+        }
+    }
+
+    /// Returns the metadata of the metric family `metric`, or of all of them, sorted by name.
+    pub async fn metadata(&self, metric: Option<&str>) -> StorageResult<Vec<MetricMetadata>> {
+        match self {
+            Storage::Native(storage) => storage.metadata(metric).await,
+            Storage::ClickHouse(storage) => storage.metadata(metric).await,
+        }
+    }
+
+    /// Returns the sorted label names of the series matching `query`, or of all
+    /// the series. Only the label index is read, it keeps no time range.
+    pub async fn label_names(&self, query: Option<Query>) -> StorageResult<Vec<String>> {
+        let index_reader = self.index_reader()?;
+        let Some(query) = query else {
+            return Ok(index_reader.fields()?);
+        };
+        let mut names = BTreeSet::new();
+        for (_, labels) in fetch_labels(&index_reader, query)? {
+            names.extend(labels.into_iter().map(|label| label.name));
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    /// Returns the sorted values of the label `name` in the series matching
+    /// `query`, or in all the series. Only the label index is read.
+    pub async fn label_values(
+        &self,
+        name: &str,
+        query: Option<Query>,
+    ) -> StorageResult<Vec<String>> {
+        let index_reader = self.index_reader()?;
+        let Some(query) = query else {
+            return Ok(index_reader.field_values(name, Query::All)?);
+        };
+        let query = Query::And(Box::new(query), Box::new(Query::Exists(name.to_string())));
+        let mut values = BTreeSet::new();
+        for (_, labels) in fetch_labels(&index_reader, query)? {
+            values.extend(
+                labels
+                    .into_iter()
+                    .filter(|label| label.name == name)
+                    .map(|label| label.value),
+            );
+        }
+        Ok(values.into_iter().collect())
+    }
+
+    /// Returns the labels of the series matching `query`, without their samples.
+    /// Only the label index is read.
+    pub async fn series(&self, query: Query) -> StorageResult<Vec<Vec<Label>>> {
+        let index_reader = self.index_reader()?;
+        let series = fetch_labels(&index_reader, query)?;
+        Ok(series.into_iter().map(|(_, labels)| labels).collect())
+    }
+
+    /// Returns the ids and labels of the series matching `query`.
+    /// Only the label index is read.
+    pub async fn indexed_series(&self, query: Query) -> StorageResult<Vec<(u64, Vec<Label>)>> {
+        let index_reader = self.index_reader()?;
+        fetch_labels(&index_reader, query)
+    }
+
     pub async fn truncate(&self, timestamp: i64) -> StorageResult<()> {
         match self {
             Storage::Native(storage) => storage.truncate(timestamp).await,
             Storage::ClickHouse(storage) => storage.truncate(timestamp).await,
         }
     }
+
+    /// Flushes the accepted samples and releases the storage resources.
+    pub async fn shutdown(&self) -> StorageResult<()> {
+        match self {
+            Storage::Native(storage) => storage.shutdown().await,
+            Storage::ClickHouse(storage) => storage.shutdown().await,
+        }
+    }
+
+    fn index_reader(&self) -> StorageResult<IndexReader> {
+        match self {
+            Storage::Native(storage) => storage.index_reader(),
+            Storage::ClickHouse(storage) => storage.index_reader(),
+        }
+    }
+}
+
+/// Fetches the ids and labels of the series matching `query` from their index document.
+fn fetch_labels(index_reader: &IndexReader, query: Query) -> StorageResult<Vec<(u64, Vec<Label>)>> {
+    let mut series_labels = vec![];
+    for series_id in index_reader.query(query)? {
+        let doc_data = index_reader.fetch_doc(series_id)?;
+        series_labels.push((series_id, serde_json::from_slice(&doc_data)?));
+    }
+    Ok(series_labels)
 }
 
 #[derive(Debug)]
@@ -55,7 +236,20 @@ pub struct StorageFactory;
 impl StorageFactory {
     pub fn open(settings: &StorageSettings) -> StorageResult<Storage> {
         match settings {
-            StorageSettings::Native(path) => Ok(Storage::Native(NativeStorage::new(path))),
+            StorageSettings::Native {
+                path,
+                block_duration,
+                wal_fsync_policy,
+                max_exemplars,
+            } => {
+                let store = NativeStorage::new(
+                    path,
+                    *block_duration as i64 * 60 * 1000, // convert to ms
+                    *wal_fsync_policy,
+                    *max_exemplars,
+                )?;
+                Ok(Storage::Native(store))
+            },
             StorageSettings::ClickHouse {
                 url,
                 db,
@@ -64,6 +258,10 @@ impl StorageFactory {
                 index_path, 
                 memory_budget,
                 sample_budget,
+                flush_interval,
+                wal,
+                spool,
+                exemplar_retention,
             } => {
                 let store = ClickHouseStorage::new(
                     url,
@@ -73,9 +271,92 @@ impl StorageFactory {
                     index_path,
                     *memory_budget * 1024 * 1024, // convert to MB
                     *sample_budget,
+                    Duration::from_secs(*flush_interval),
+                    wal,
+                    spool,
+                    *exemplar_retention,
                 )?;
                 Ok(Storage::ClickHouse(store))
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn series(labels: &[(&str, &str)]) -> TimeSeries {
+        let labels = labels
+            .iter()
+            .map(|(name, value)| Label {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect();
+        TimeSeries::new(labels, vec![Sample { timestamp: 0, value: 1.0 }])
+    }
+
+    #[test]
+    fn sort_and_dedup_samples() {
+        let sample = |timestamp, value| Sample { timestamp, value };
+        let mut time_series = series(&[(SERIES_NAME_LABEL, "up")]);
+        time_series.extend(vec![sample(3, 3.0), sample(1, 1.0), sample(3, 3.0), sample(2, 2.0)]);
+        time_series.sort_and_dedup();
+        let timestamps: Vec<i64> = time_series
+            .get_samples()
+            .iter()
+            .map(|sample| sample.timestamp)
+            .collect();
+        assert_eq!(timestamps, &[0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn label_names_values_and_series() -> StorageResult<()> {
+        let tmp_dir = TempDir::new("storage").unwrap();
+        let storage = StorageFactory::open(&StorageSettings::Native {
+            path: tmp_dir.path().to_str().unwrap().to_string(),
+            block_duration: 120,
+            wal_fsync_policy: WalFsyncPolicy::Never,
+            max_exemplars: 0,
+        })?;
+        storage
+            .write(vec![
+                series(&[(SERIES_NAME_LABEL, "up"), ("job", "api"), ("env", "prod")]),
+                series(&[(SERIES_NAME_LABEL, "up"), ("job", "db")]),
+                series(&[(SERIES_NAME_LABEL, "errors"), ("job", "api"), ("code", "500")]),
+            ])
+            .await?;
+
+        let job = |value: &str| {
+            Query::Field("job".to_string(), Box::new(Query::Equal(value.to_string())))
+        };
+        assert_eq!(
+            storage.label_names(None).await?,
+            &[SERIES_NAME_LABEL, "code", "env", "job"]
+        );
+        assert_eq!(
+            storage.label_names(Some(job("db"))).await?,
+            &[SERIES_NAME_LABEL, "job"]
+        );
+        assert_eq!(storage.label_values("job", None).await?, &["api", "db"]);
+        assert_eq!(
+            storage
+                .label_values(SERIES_NAME_LABEL, Some(job("api")))
+                .await?,
+            &["errors", "up"]
+        );
+        assert!(storage
+            .label_values("env", Some(job("db")))
+            .await?
+            .is_empty());
+
+        let series = storage.series(job("db")).await?;
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].len(), 2);
+        storage.shutdown().await?;
+        Ok(())
+    }
+}
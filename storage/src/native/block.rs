@@ -0,0 +1,255 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::StorageResult, HistogramSample, Sample};
+
+use super::chunk::Chunk;
+
+const BLOCK_META_FILE_NAME: &str = "meta.json";
+const BLOCK_INDEX_FILE_NAME: &str = "index";
+const BLOCK_CHUNKS_FILE_NAME: &str = "chunks";
+const BLOCK_HISTOGRAMS_INDEX_FILE_NAME: &str = "histograms.index";
+const BLOCK_HISTOGRAMS_FILE_NAME: &str = "histograms";
+const BLOCK_DIRECTORY_PREFIX: &str = "block-";
+const WRITING_BLOCK_SUFFIX: &str = ".tmp";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct BlockMeta {
+    /// Start of the block time range (inclusive).
+    pub min_time: i64,
+    /// End of the block time range (exclusive).
+    pub max_time: i64,
+    pub num_series: usize,
+    pub num_samples: usize,
+    #[serde(default)]
+    pub num_histograms: usize,
+}
+
+/// An immutable block of the samples of a time range, persisted
+/// under its own directory. Blocks are written once when the head
+/// is compacted and dropped as a whole by the retention.
+///
+/// - meta.json: the time range & stats of the block
+/// - index: the sorted (series_id, offset, length) of every chunk
+/// - chunks: the gorilla encoded chunks
+/// - histograms.index: the sorted (series_id, offset, length) of the histograms
+///   of every series, only written when the block has native histograms
+/// - histograms: the serialized native histograms of each series
+///
+#[derive(Debug)]
+pub(crate) struct Block {
+    directory: PathBuf,
+    meta: BlockMeta,
+    index: Vec<(u64, u64, u32)>,
+    chunks: Mmap,
+    histograms_index: Vec<(u64, u64, u32)>,
+    histograms: Option<Mmap>,
+}
+
+impl Block {
+    /// Writes the chunks and histograms of each series as a new block.
+    pub fn write(
+        blocks_directory: &Path,
+        min_time: i64,
+        max_time: i64,
+        series_chunks: &BTreeMap<u64, Vec<Chunk>>,
+        series_histograms: &BTreeMap<u64, Vec<HistogramSample>>,
+    ) -> StorageResult<Self> {
+        let directory = blocks_directory.join(format!("{}{}", BLOCK_DIRECTORY_PREFIX, min_time));
+        let tmp_directory = directory.with_extension(&WRITING_BLOCK_SUFFIX[1..]);
+        if tmp_directory.exists() {
+            fs::remove_dir_all(&tmp_directory)?;
+        }
+        fs::create_dir_all(&tmp_directory)?;
+
+        let mut index = vec![];
+        let mut offset = 0u64;
+        let mut num_samples = 0;
+        let chunks_file = File::create(tmp_directory.join(BLOCK_CHUNKS_FILE_NAME))?;
+        let mut chunks_writer = BufWriter::new(&chunks_file);
+        for (series_id, chunks) in series_chunks {
+            for chunk in chunks {
+                let bytes = chunk.bytes();
+                chunks_writer.write_all(&bytes)?;
+                index.push((*series_id, offset, bytes.len() as u32));
+                offset += bytes.len() as u64;
+                num_samples += chunk.num_samples();
+            }
+        }
+        chunks_writer.flush()?;
+        drop(chunks_writer);
+        chunks_file.sync_all()?;
+
+        let mut num_histograms = 0;
+        if !series_histograms.is_empty() {
+            let mut histograms_index = vec![];
+            let mut offset = 0u64;
+            let histograms_file = File::create(tmp_directory.join(BLOCK_HISTOGRAMS_FILE_NAME))?;
+            let mut histograms_writer = BufWriter::new(&histograms_file);
+            for (series_id, histograms) in series_histograms {
+                let bytes = bincode::serialize(histograms)?;
+                histograms_writer.write_all(&bytes)?;
+                histograms_index.push((*series_id, offset, bytes.len() as u32));
+                offset += bytes.len() as u64;
+                num_histograms += histograms.len();
+            }
+            histograms_writer.flush()?;
+            drop(histograms_writer);
+            histograms_file.sync_all()?;
+            fs::write(
+                tmp_directory.join(BLOCK_HISTOGRAMS_INDEX_FILE_NAME),
+                bincode::serialize(&histograms_index)?,
+            )?;
+        }
+
+        let num_series = series_chunks
+            .keys()
+            .chain(series_histograms.keys())
+            .collect::<BTreeSet<_>>()
+            .len();
+        let meta = BlockMeta {
+            min_time,
+            max_time,
+            num_series,
+            num_samples,
+            num_histograms,
+        };
+        fs::write(
+            tmp_directory.join(BLOCK_INDEX_FILE_NAME),
+            bincode::serialize(&index)?,
+        )?;
+        fs::write(
+            tmp_directory.join(BLOCK_META_FILE_NAME),
+            serde_json::to_vec(&meta)?,
+        )?;
+
+        // The block only becomes visible once complete.
+        fs::rename(&tmp_directory, &directory)?;
+        File::open(blocks_directory)?.sync_all()?;
+        Self::open(&directory)
+    }
+
+    pub fn open(directory: &Path) -> StorageResult<Self> {
+        let meta: BlockMeta =
+            serde_json::from_slice(&fs::read(directory.join(BLOCK_META_FILE_NAME))?)?;
+        let index = bincode::deserialize(&fs::read(directory.join(BLOCK_INDEX_FILE_NAME))?)?;
+        let chunks_file = File::open(directory.join(BLOCK_CHUNKS_FILE_NAME))?;
+        let chunks = unsafe { Mmap::map(&chunks_file)? };
+
+        // Blocks without native histograms have no histogram files.
+        let histograms_index_path = directory.join(BLOCK_HISTOGRAMS_INDEX_FILE_NAME);
+        let (histograms_index, histograms) = if histograms_index_path.exists() {
+            let histograms_file = File::open(directory.join(BLOCK_HISTOGRAMS_FILE_NAME))?;
+            (
+                bincode::deserialize(&fs::read(histograms_index_path)?)?,
+                Some(unsafe { Mmap::map(&histograms_file)? }),
+            )
+        } else {
+            (vec![], None)
+        };
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            meta,
+            index,
+            chunks,
+            histograms_index,
+            histograms,
+        })
+    }
+
+    /// Opens all the blocks of the directory sorted by time,
+    /// unfinished blocks are removed.
+    pub fn open_all(blocks_directory: &Path) -> StorageResult<Vec<Self>> {
+        fs::create_dir_all(blocks_directory)?;
+        let mut blocks = vec![];
+        for entry in fs::read_dir(blocks_directory)? {
+            let path = entry?.path();
+            let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if file_name.ends_with(WRITING_BLOCK_SUFFIX) {
+                fs::remove_dir_all(&path)?;
+            } else if file_name.starts_with(BLOCK_DIRECTORY_PREFIX) {
+                blocks.push(Self::open(&path)?);
+            }
+        }
+        blocks.sort_by_key(|block| block.meta.min_time);
+        Ok(blocks)
+    }
+
+    pub fn meta(&self) -> &BlockMeta {
+        &self.meta
+    }
+
+    pub fn overlaps(&self, start_timestamp: i64, end_timestamp: i64) -> bool {
+        self.meta.min_time < end_timestamp && start_timestamp < self.meta.max_time
+    }
+
+    /// Returns whether a series has histograms in the block.
+    pub fn has_histograms(&self, series_id: u64) -> bool {
+        self.histograms_index
+            .binary_search_by_key(&series_id, |(id, _, _)| *id)
+            .is_ok()
+    }
+
+    /// Appends the samples of a series within `[start_timestamp, end_timestamp)`.
+    pub fn samples(
+        &self,
+        series_id: u64,
+        start_timestamp: i64,
+        end_timestamp: i64,
+        samples: &mut Vec<Sample>,
+    ) {
+        let first = self.index.partition_point(|(id, _, _)| *id < series_id);
+        for (_, offset, len) in self.index[first..]
+            .iter()
+            .take_while(|(id, _, _)| *id == series_id)
+        {
+            let bytes = &self.chunks[*offset as usize..*offset as usize + *len as usize];
+            samples.extend(Chunk::decode(bytes).filter(|sample| {
+                start_timestamp <= sample.timestamp && sample.timestamp < end_timestamp
+            }));
+        }
+    }
+
+    /// Appends the histograms of a series within `[start_timestamp, end_timestamp)`.
+    pub fn histograms(
+        &self,
+        series_id: u64,
+        start_timestamp: i64,
+        end_timestamp: i64,
+        histograms: &mut Vec<HistogramSample>,
+    ) -> StorageResult<()> {
+        let Some(data) = self.histograms.as_ref() else {
+            return Ok(());
+        };
+        let Ok(position) = self
+            .histograms_index
+            .binary_search_by_key(&series_id, |(id, _, _)| *id)
+        else {
+            return Ok(());
+        };
+        let (_, offset, len) = self.histograms_index[position];
+        let bytes = &data[offset as usize..offset as usize + len as usize];
+        let series_histograms: Vec<HistogramSample> = bincode::deserialize(bytes)?;
+        histograms.extend(
+            series_histograms
+                .into_iter()
+                .filter(|h| start_timestamp <= h.timestamp && h.timestamp < end_timestamp),
+        );
+        Ok(())
+    }
+
+    /// Removes the block files, readers still holding the block keep its mmap alive.
+    pub fn remove(&self) -> StorageResult<()> {
+        fs::remove_dir_all(&self.directory)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,364 @@
+use crate::Sample;
+
+/// Maximum number of samples of a head chunk before a new one is cut.
+pub(crate) const MAX_CHUNK_SAMPLES: usize = 120;
+
+/// Delta-of-delta timestamp buckets: (control bits, control bits length, value bits length).
+const TIMESTAMP_BUCKETS: [(u64, u8, u8); 3] = [(0b10, 2, 14), (0b110, 3, 17), (0b1110, 4, 20)];
+
+/// A Gorilla compressed chunk of samples, see
+/// "Gorilla: A Fast, Scalable, In-Memory Time Series Database".
+/// Timestamps are delta-of-delta encoded and values are XORed with
+/// the previous one, so that regular series take a few bits per sample.
+///
+/// Chunk Format: bit stream, most significant bits first
+/// ┌─────────────┬─────────────┬─────────┬────────────────┬──────────────┬─────┐
+/// │ num_samples │ timestamp 0 │ value 0 │ delta, value 1 │ dod, value 2 │ ... │
+/// └─────────────┴─────────────┴─────────┴────────────────┴──────────────┴─────┘
+///
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Chunk {
+    writer: BitWriter,
+    num_samples: u16,
+    max_time: i64,
+    last_delta: i64,
+    last_value: u64,
+    leading_zeros: u8,
+    trailing_zeros: u8,
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        let mut chunk = Self::default();
+        chunk.writer.write_bits(0, 16);
+        chunk
+    }
+
+    /// Decodes the samples of an encoded chunk.
+    pub fn decode(bytes: &[u8]) -> ChunkIterator<'_> {
+        ChunkIterator::new(bytes)
+    }
+
+    pub fn num_samples(&self) -> usize {
+        self.num_samples as usize
+    }
+
+    pub fn max_time(&self) -> i64 {
+        self.max_time
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.num_samples() >= MAX_CHUNK_SAMPLES
+    }
+
+    /// Appends a sample, timestamps must be increasing.
+    pub fn append(&mut self, timestamp: i64, value: f64) {
+        let value = value.to_bits();
+        match self.num_samples {
+            0 => {
+                self.writer.write_bits(timestamp as u64, 64);
+                self.writer.write_bits(value, 64);
+            }
+            1 => {
+                let delta = timestamp - self.max_time;
+                self.writer.write_bits(delta as u64, 64);
+                self.write_value(value);
+                self.last_delta = delta;
+            }
+            _ => {
+                let delta = timestamp - self.max_time;
+                self.write_delta_of_delta(delta - self.last_delta);
+                self.write_value(value);
+                self.last_delta = delta;
+            }
+        }
+        self.max_time = timestamp;
+        self.last_value = value;
+        self.num_samples += 1;
+    }
+
+    /// Returns the encoded chunk.
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = self.writer.bytes.clone();
+        bytes[..2].copy_from_slice(&self.num_samples.to_be_bytes());
+        bytes
+    }
+
+    pub fn iter(&self) -> ChunkIterator<'_> {
+        ChunkIterator {
+            reader: BitReader::new(&self.writer.bytes),
+            num_samples: self.num_samples,
+            ..ChunkIterator::default()
+        }
+        .skip_header()
+    }
+
+    fn write_delta_of_delta(&mut self, dod: i64) {
+        if dod == 0 {
+            self.writer.write_bit(false);
+            return;
+        }
+        for (control, control_len, value_len) in TIMESTAMP_BUCKETS {
+            if fits_in_bits(dod, value_len) {
+                self.writer.write_bits(control, control_len);
+                self.writer.write_bits(dod as u64, value_len);
+                return;
+            }
+        }
+        self.writer.write_bits(0b1111, 4);
+        self.writer.write_bits(dod as u64, 64);
+    }
+
+    fn write_value(&mut self, value: u64) {
+        let xor = value ^ self.last_value;
+        if xor == 0 {
+            self.writer.write_bit(false);
+            return;
+        }
+        self.writer.write_bit(true);
+
+        let leading_zeros = (xor.leading_zeros() as u8).min(31);
+        let trailing_zeros = xor.trailing_zeros() as u8;
+        // Reuse the previous meaningful bits window when the value fits in it.
+        if self.num_samples > 1
+            && leading_zeros >= self.leading_zeros
+            && trailing_zeros >= self.trailing_zeros
+        {
+            self.writer.write_bit(false);
+            let significant_bits = 64 - self.leading_zeros - self.trailing_zeros;
+            self.writer
+                .write_bits(xor >> self.trailing_zeros, significant_bits);
+            return;
+        }
+
+        let significant_bits = 64 - leading_zeros - trailing_zeros;
+        self.writer.write_bit(true);
+        self.writer.write_bits(leading_zeros as u64, 5);
+        // 64 significant bits are stored as 0, the 6 bits can't hold it.
+        self.writer.write_bits(significant_bits as u64 & 0x3f, 6);
+        self.writer
+            .write_bits(xor >> trailing_zeros, significant_bits);
+        self.leading_zeros = leading_zeros;
+        self.trailing_zeros = trailing_zeros;
+    }
+}
+
+fn fits_in_bits(value: i64, num_bits: u8) -> bool {
+    let bound = 1i64 << (num_bits - 1);
+    -bound <= value && value < bound
+}
+
+/// Decodes the samples of a chunk.
+#[derive(Debug, Default)]
+pub(crate) struct ChunkIterator<'a> {
+    reader: BitReader<'a>,
+    num_samples: u16,
+    position: u16,
+    timestamp: i64,
+    delta: i64,
+    value: u64,
+    leading_zeros: u8,
+    trailing_zeros: u8,
+}
+
+impl<'a> ChunkIterator<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        let num_samples = u16::from_be_bytes([bytes[0], bytes[1]]);
+        Self {
+            reader: BitReader::new(bytes),
+            num_samples,
+            ..Self::default()
+        }
+        .skip_header()
+    }
+
+    fn skip_header(mut self) -> Self {
+        self.reader.read_bits(16);
+        self
+    }
+
+    fn read_delta_of_delta(&mut self) -> i64 {
+        if !self.reader.read_bit() {
+            return 0;
+        }
+        // Control bits are a run of ones ended by a zero.
+        for (_, _, value_len) in TIMESTAMP_BUCKETS {
+            if !self.reader.read_bit() {
+                return sign_extend(self.reader.read_bits(value_len), value_len);
+            }
+        }
+        self.reader.read_bits(64) as i64
+    }
+
+    fn read_value(&mut self) -> u64 {
+        if !self.reader.read_bit() {
+            return self.value;
+        }
+        if self.reader.read_bit() {
+            self.leading_zeros = self.reader.read_bits(5) as u8;
+            let significant_bits = match self.reader.read_bits(6) as u8 {
+                0 => 64,
+                significant_bits => significant_bits,
+            };
+            self.trailing_zeros = 64 - self.leading_zeros - significant_bits;
+        }
+        let significant_bits = 64 - self.leading_zeros - self.trailing_zeros;
+        let xor = self.reader.read_bits(significant_bits) << self.trailing_zeros;
+        self.value ^ xor
+    }
+}
+
+impl<'a> Iterator for ChunkIterator<'a> {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.num_samples {
+            return None;
+        }
+        match self.position {
+            0 => {
+                self.timestamp = self.reader.read_bits(64) as i64;
+                self.value = self.reader.read_bits(64);
+            }
+            1 => {
+                self.delta = self.reader.read_bits(64) as i64;
+                self.timestamp += self.delta;
+                self.value = self.read_value();
+            }
+            _ => {
+                self.delta += self.read_delta_of_delta();
+                self.timestamp += self.delta;
+                self.value = self.read_value();
+            }
+        }
+        self.position += 1;
+        Some(Sample {
+            timestamp: self.timestamp,
+            value: f64::from_bits(self.value),
+        })
+    }
+}
+
+fn sign_extend(value: u64, num_bits: u8) -> i64 {
+    let shift = 64 - num_bits;
+    ((value << shift) as i64) >> shift
+}
+
+#[derive(Debug, Clone, Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Number of bits used in the last byte, 8 when it is full.
+    last_byte_bits: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        self.write_bits(bit as u64, 1);
+    }
+
+    /// Writes the `num_bits` lowest bits of `value`, most significant first.
+    fn write_bits(&mut self, value: u64, num_bits: u8) {
+        let mut remaining = num_bits;
+        while remaining > 0 {
+            if self.bytes.is_empty() || self.last_byte_bits == 8 {
+                self.bytes.push(0);
+                self.last_byte_bits = 0;
+            }
+            let free_bits = 8 - self.last_byte_bits;
+            let num_written = free_bits.min(remaining);
+            let bits = (value >> (remaining - num_written)) & ((1u64 << num_written) - 1);
+            let last_byte = self.bytes.last_mut().unwrap();
+            *last_byte |= (bits as u8) << (free_bits - num_written);
+            self.last_byte_bits += num_written;
+            remaining -= num_written;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> bool {
+        self.read_bits(1) == 1
+    }
+
+    fn read_bits(&mut self, num_bits: u8) -> u64 {
+        let mut value = 0u64;
+        let mut remaining = num_bits;
+        while remaining > 0 {
+            let byte = self.bytes[self.position / 8];
+            let offset = (self.position % 8) as u8;
+            let available = 8 - offset;
+            let num_read = available.min(remaining);
+            let bits = (byte >> (available - num_read)) & ((1u16 << num_read) - 1) as u8;
+            value = (value << num_read) | bits as u64;
+            self.position += num_read as usize;
+            remaining -= num_read;
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(samples: &[(i64, f64)]) {
+        let mut chunk = Chunk::new();
+        for (timestamp, value) in samples {
+            chunk.append(*timestamp, *value);
+        }
+        let bytes = chunk.bytes();
+        for decoded in [
+            chunk.iter().collect::<Vec<_>>(),
+            Chunk::decode(&bytes).collect(),
+        ] {
+            assert_eq!(decoded.len(), samples.len());
+            for (sample, (timestamp, value)) in decoded.iter().zip(samples) {
+                assert_eq!(sample.timestamp, *timestamp);
+                assert_eq!(sample.value.to_bits(), value.to_bits());
+            }
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        round_trip(&[]);
+        round_trip(&[(1_700_000_000_000, 1.5)]);
+
+        // Regular scrapes with a counter compress well.
+        let samples: Vec<(i64, f64)> = (0..MAX_CHUNK_SAMPLES as i64)
+            .map(|i| (1_700_000_000_000 + i * 15_000, (i * 3) as f64))
+            .collect();
+        round_trip(&samples);
+        let mut chunk = Chunk::new();
+        for (timestamp, value) in samples.iter() {
+            chunk.append(*timestamp, *value);
+        }
+        assert!(chunk.bytes().len() < samples.len() * 4);
+
+        // Jittered timestamps, large gaps and special values.
+        round_trip(&[
+            (-5, 0.0),
+            (10, -0.0),
+            (11, f64::NAN),
+            (1_000, f64::INFINITY),
+            (1_001, f64::MIN_POSITIVE),
+            (70_000, 1e300),
+            (70_001, 1e300),
+            (5_000_000_000, -1.25),
+            (5_000_000_017, 42.0),
+            (5_000_008_000, 42.000001),
+            (i64::MAX / 2, f64::from_bits(1)),
+            (i64::MAX / 2 + 1, f64::from_bits(u64::MAX >> 1)),
+        ]);
+    }
+}
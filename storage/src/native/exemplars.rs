@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+
+use hashbrown::{HashMap, HashSet};
+
+use crate::Exemplar;
+
+/// A bounded buffer of the most recent exemplars of all the series, like the
+/// circular exemplar storage of Prometheus: once full, each appended exemplar
+/// evicts the oldest one.
+#[derive(Debug, Default)]
+pub(crate) struct ExemplarStorage {
+    exemplars: VecDeque<(u64, Exemplar)>,
+    max_exemplars: usize,
+    /// The timestamp of the last exemplar appended to each series.
+    max_times: HashMap<u64, i64>,
+}
+
+impl ExemplarStorage {
+    /// Creates a storage of at most `max_exemplars`, none are kept if zero.
+    pub fn new(max_exemplars: usize) -> Self {
+        Self {
+            max_exemplars,
+            ..Self::default()
+        }
+    }
+
+    /// Appends the exemplars of a series, exemplars not newer
+    /// than the last one of the series are dropped.
+    pub fn append(&mut self, series_id: u64, exemplars: Vec<Exemplar>) {
+        if self.max_exemplars == 0 || exemplars.is_empty() {
+            return;
+        }
+        let max_time = self.max_times.entry(series_id).or_insert(i64::MIN);
+        for exemplar in exemplars {
+            if exemplar.timestamp <= *max_time {
+                continue;
+            }
+            *max_time = exemplar.timestamp;
+            if self.exemplars.len() == self.max_exemplars {
+                self.exemplars.pop_front();
+            }
+            self.exemplars.push_back((series_id, exemplar));
+        }
+    }
+
+    /// Returns the exemplars of `series_ids` within `[start_timestamp, end_timestamp]`
+    /// by series, oldest first.
+    pub fn select(
+        &self,
+        series_ids: &HashSet<u64>,
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> HashMap<u64, Vec<Exemplar>> {
+        let mut selected: HashMap<u64, Vec<Exemplar>> = HashMap::new();
+        for (series_id, exemplar) in self.exemplars.iter() {
+            if series_ids.contains(series_id)
+                && start_timestamp <= exemplar.timestamp
+                && exemplar.timestamp <= end_timestamp
+            {
+                selected
+                    .entry(*series_id)
+                    .or_default()
+                    .push(exemplar.clone());
+            }
+        }
+        selected
+    }
+
+    /// Returns all the exemplars by series, oldest first.
+    pub fn by_series(&self) -> HashMap<u64, Vec<Exemplar>> {
+        let mut by_series: HashMap<u64, Vec<Exemplar>> = HashMap::new();
+        for (series_id, exemplar) in self.exemplars.iter() {
+            by_series
+                .entry(*series_id)
+                .or_default()
+                .push(exemplar.clone());
+        }
+        by_series
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Label;
+
+    fn exemplar(timestamp: i64, trace_id: &str) -> Exemplar {
+        Exemplar {
+            labels: vec![Label {
+                name: "trace_id".to_string(),
+                value: trace_id.to_string(),
+            }],
+            value: 0.5,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn append_evict_and_select() {
+        let mut storage = ExemplarStorage::new(3);
+        storage.append(1, vec![exemplar(10, "a"), exemplar(20, "b")]);
+        // Out of order exemplars are dropped, the oldest one is evicted.
+        storage.append(1, vec![exemplar(20, "c")]);
+        storage.append(2, vec![exemplar(15, "d"), exemplar(30, "e")]);
+
+        let series_ids = HashSet::from([1, 2]);
+        let selected = storage.select(&series_ids, 0, 100);
+        assert_eq!(selected[&1], &[exemplar(20, "b")]);
+        assert_eq!(selected[&2], &[exemplar(15, "d"), exemplar(30, "e")]);
+        let selected = storage.select(&HashSet::from([2]), 16, 30);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[&2], &[exemplar(30, "e")]);
+
+        let mut disabled = ExemplarStorage::new(0);
+        disabled.append(1, vec![exemplar(10, "a")]);
+        assert!(disabled.by_series().is_empty());
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::BTreeMap;
+
+use hashbrown::{HashMap, HashSet};
+
+use crate::{Exemplar, HistogramSample, Label, Sample, TimeSeries};
+
+use super::{chunk::Chunk, exemplars::ExemplarStorage};
+
+/// The in-memory block receiving the most recent samples,
+/// each series has a list of chunks, only the last one is appended to.
+/// Native histograms are kept uncompressed next to the chunks, exemplars
+/// in a bounded buffer: they are never persisted in blocks.
+#[derive(Debug, Default)]
+pub(crate) struct Head {
+    series: HashMap<u64, HeadSeries>,
+    exemplars: ExemplarStorage,
+    /// Samples before this time are already persisted in blocks.
+    min_valid_time: i64,
+    min_time: Option<i64>,
+    max_time: i64,
+}
+
+#[derive(Debug)]
+struct HeadSeries {
+    labels: Vec<Label>,
+    chunks: Vec<Chunk>,
+    histograms: Vec<HistogramSample>,
+}
+
+impl HeadSeries {
+    fn max_time(&self) -> Option<i64> {
+        self.chunks
+            .last()
+            .filter(|chunk| chunk.num_samples() > 0)
+            .map(|chunk| chunk.max_time())
+    }
+
+    fn append(&mut self, sample: &Sample) {
+        if self.chunks.last().is_none_or(|chunk| chunk.is_full()) {
+            self.chunks.push(Chunk::new());
+        }
+        self.chunks
+            .last_mut()
+            .unwrap()
+            .append(sample.timestamp, sample.value);
+    }
+
+    fn samples(&self) -> impl Iterator<Item = Sample> + '_ {
+        self.chunks.iter().flat_map(|chunk| chunk.iter())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.chunks.is_empty() && self.histograms.is_empty()
+    }
+}
+
+impl Head {
+    pub fn new(min_valid_time: i64, max_exemplars: usize) -> Self {
+        Self {
+            min_valid_time,
+            exemplars: ExemplarStorage::new(max_exemplars),
+            ..Self::default()
+        }
+    }
+
+    pub fn min_time(&self) -> Option<i64> {
+        self.min_time
+    }
+
+    pub fn max_time(&self) -> i64 {
+        self.max_time
+    }
+
+    /// Samples older than this fall in an already persisted block.
+    pub fn min_valid_time(&self) -> i64 {
+        self.min_valid_time
+    }
+
+    /// Appends the samples and histograms of a series, returns the number
+    /// of samples dropped because they are out of order and because they
+    /// fall in an already persisted block.
+    pub fn append(&mut self, mut series: TimeSeries) -> (usize, usize) {
+        let series_id = series.get_id();
+        self.exemplars.append(series_id, series.take_exemplars());
+        let (labels, samples, histograms) = series.into_parts();
+        let head_series = self.series.entry(series_id).or_insert_with(|| HeadSeries {
+            labels,
+            chunks: vec![],
+            histograms: vec![],
+        });
+
+        let mut num_out_of_order = 0;
+        let mut num_out_of_bounds = 0;
+        let mut max_time = head_series.max_time();
+        for sample in samples.iter() {
+            if sample.timestamp < self.min_valid_time {
+                num_out_of_bounds += 1;
+                continue;
+            }
+            if max_time.is_some_and(|max_time| sample.timestamp <= max_time) {
+                num_out_of_order += 1;
+                continue;
+            }
+            head_series.append(sample);
+            max_time = Some(sample.timestamp);
+            extend_time_range(&mut self.min_time, &mut self.max_time, sample.timestamp);
+        }
+
+        // Histograms are ordered independently of the float samples.
+        let mut max_time = head_series.histograms.last().map(|h| h.timestamp);
+        for histogram in histograms {
+            if histogram.timestamp < self.min_valid_time {
+                num_out_of_bounds += 1;
+                continue;
+            }
+            if max_time.is_some_and(|max_time| histogram.timestamp <= max_time) {
+                num_out_of_order += 1;
+                continue;
+            }
+            max_time = Some(histogram.timestamp);
+            extend_time_range(&mut self.min_time, &mut self.max_time, histogram.timestamp);
+            head_series.histograms.push(histogram);
+        }
+        (num_out_of_order, num_out_of_bounds)
+    }
+
+    /// Returns whether a series has histograms within `[start_timestamp, end_timestamp)`.
+    pub fn has_histograms(&self, series_id: u64, start_timestamp: i64, end_timestamp: i64) -> bool {
+        self.series.get(&series_id).is_some_and(|head_series| {
+            head_series
+                .histograms
+                .iter()
+                .any(|h| start_timestamp <= h.timestamp && h.timestamp < end_timestamp)
+        })
+    }
+
+    /// Appends the samples of a series within `[start_timestamp, end_timestamp)`.
+    pub fn samples(
+        &self,
+        series_id: u64,
+        start_timestamp: i64,
+        end_timestamp: i64,
+        samples: &mut Vec<Sample>,
+    ) {
+        if let Some(head_series) = self.series.get(&series_id) {
+            samples.extend(head_series.samples().filter(|sample| {
+                start_timestamp <= sample.timestamp && sample.timestamp < end_timestamp
+            }));
+        }
+    }
+
+    /// Appends the histograms of a series within `[start_timestamp, end_timestamp)`.
+    pub fn histograms(
+        &self,
+        series_id: u64,
+        start_timestamp: i64,
+        end_timestamp: i64,
+        histograms: &mut Vec<HistogramSample>,
+    ) {
+        if let Some(head_series) = self.series.get(&series_id) {
+            histograms.extend(
+                head_series
+                    .histograms
+                    .iter()
+                    .filter(|h| start_timestamp <= h.timestamp && h.timestamp < end_timestamp)
+                    .cloned(),
+            );
+        }
+    }
+
+    /// Returns the exemplars of `series_ids` within `[start_timestamp, end_timestamp]`.
+    pub fn exemplars(
+        &self,
+        series_ids: &HashSet<u64>,
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> HashMap<u64, Vec<Exemplar>> {
+        self.exemplars
+            .select(series_ids, start_timestamp, end_timestamp)
+    }
+
+    /// Removes the samples before `end_timestamp` and returns them as chunks
+    /// by series, along with the histograms by series, to be persisted in a block.
+    pub fn cut(
+        &mut self,
+        end_timestamp: i64,
+    ) -> (
+        BTreeMap<u64, Vec<Chunk>>,
+        BTreeMap<u64, Vec<HistogramSample>>,
+    ) {
+        let mut cut_chunks = BTreeMap::new();
+        let mut cut_histograms = BTreeMap::new();
+        let mut min_time = None;
+        for (series_id, head_series) in self.series.iter_mut() {
+            let samples: Vec<Sample> = head_series.samples().collect();
+            let split = samples.partition_point(|sample| sample.timestamp < end_timestamp);
+
+            let mut chunks = vec![];
+            for cut_samples in samples[..split].chunks(super::chunk::MAX_CHUNK_SAMPLES) {
+                let mut chunk = Chunk::new();
+                for sample in cut_samples {
+                    chunk.append(sample.timestamp, sample.value);
+                }
+                chunks.push(chunk);
+            }
+            if !chunks.is_empty() {
+                cut_chunks.insert(*series_id, chunks);
+            }
+
+            head_series.chunks.clear();
+            for sample in samples[split..].iter() {
+                head_series.append(sample);
+            }
+            if let Some(sample) = samples.get(split) {
+                min_time =
+                    Some(min_time.map_or(sample.timestamp, |t: i64| t.min(sample.timestamp)));
+            }
+
+            let split = head_series
+                .histograms
+                .partition_point(|h| h.timestamp < end_timestamp);
+            if split > 0 {
+                cut_histograms.insert(*series_id, head_series.histograms.drain(..split).collect());
+            }
+            if let Some(histogram) = head_series.histograms.first() {
+                min_time =
+                    Some(min_time.map_or(histogram.timestamp, |t: i64| t.min(histogram.timestamp)));
+            }
+        }
+
+        // Series without recent samples are still known by the index.
+        self.series.retain(|_, head_series| !head_series.is_empty());
+        self.min_valid_time = end_timestamp;
+        self.min_time = min_time;
+        (cut_chunks, cut_histograms)
+    }
+
+    /// Returns the samples of the head as series, e.g. to checkpoint them.
+    /// Exemplars of series without samples in the head are left out.
+    pub fn time_series(&self) -> Vec<TimeSeries> {
+        let mut exemplars = self.exemplars.by_series();
+        self.series
+            .iter()
+            .map(|(series_id, head_series)| {
+                let mut series =
+                    TimeSeries::new(head_series.labels.clone(), head_series.samples().collect());
+                series.extend_histograms(head_series.histograms.clone());
+                series.extend_exemplars(exemplars.remove(series_id).unwrap_or_default());
+                series
+            })
+            .collect()
+    }
+}
+
+fn extend_time_range(min_time: &mut Option<i64>, max_time: &mut i64, timestamp: i64) {
+    *min_time = Some(min_time.map_or(timestamp, |min_time| min_time.min(timestamp)));
+    *max_time = (*max_time).max(timestamp);
+}
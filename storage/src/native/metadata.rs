@@ -0,0 +1,57 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{error::StorageResult, MetricMetadata};
+
+/// The latest metadata of each metric family, saved
+/// to a JSON file whenever one of them changes.
+#[derive(Debug)]
+pub(crate) struct MetadataStore {
+    path: PathBuf,
+    families: BTreeMap<String, MetricMetadata>,
+}
+
+impl MetadataStore {
+    /// Opens the store saved at `path`, empty if the file does not exist.
+    pub fn open(path: &Path) -> StorageResult<Self> {
+        let families = match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            families,
+        })
+    }
+
+    /// Replaces the metadata of the given metric families, the
+    /// file is written again, then renamed, if any of them changed.
+    pub fn update(&mut self, metadata: Vec<MetricMetadata>) -> StorageResult<()> {
+        let mut changed = false;
+        for metadata in metadata {
+            if self.families.get(&metadata.metric_family_name) != Some(&metadata) {
+                self.families
+                    .insert(metadata.metric_family_name.clone(), metadata);
+                changed = true;
+            }
+        }
+        if changed {
+            let tmp_path = self.path.with_extension("tmp");
+            fs::write(&tmp_path, serde_json::to_vec(&self.families)?)?;
+            fs::rename(&tmp_path, &self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the metadata of the metric family `metric`, or of all of them, sorted by name.
+    pub fn get(&self, metric: Option<&str>) -> Vec<MetricMetadata> {
+        match metric {
+            Some(metric) => self.families.get(metric).cloned().into_iter().collect(),
+            None => self.families.values().cloned().collect(),
+        }
+    }
+}
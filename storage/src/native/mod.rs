@@ -0,0 +1,625 @@
+mod block;
+mod chunk;
+mod exemplars;
+mod head;
+mod metadata;
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+
+use derivative::Derivative;
+use fts::{query::Query, Config, Index, IndexReader};
+use hashbrown::{HashMap, HashSet};
+
+use crate::{
+    error::{StorageError, StorageResult},
+    wal::{Wal, WalFsyncPolicy},
+    Exemplar, Label, MetricMetadata, TimeSeries,
+};
+
+use self::{block::Block, head::Head, metadata::MetadataStore};
+
+const INDEX_DIRECTORY: &str = "index";
+const WAL_DIRECTORY: &str = "wal";
+const BLOCKS_DIRECTORY: &str = "blocks";
+const METADATA_FILE: &str = "metadata.json";
+const WAL_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A Prometheus/VictoriaMetrics storage
+/// engine like implementation.
+///
+/// Samples are appended to an in-memory head block of gorilla compressed
+/// chunks, protected by a write-ahead log. Once the head spans more than
+/// one and a half block duration, its oldest block duration worth of samples
+/// is persisted as an immutable block. Series labels are in a fts index.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct NativeStorage {
+    path: PathBuf,
+    block_duration: i64,
+    #[derivative(Debug = "ignore")]
+    index: Mutex<Option<Index>>,
+    head: RwLock<HeadState>,
+    blocks: RwLock<Vec<Arc<Block>>>,
+    metadata: Mutex<MetadataStore>,
+}
+
+#[derive(Debug)]
+struct HeadState {
+    head: Head,
+    wal: Wal,
+    /// Series already inserted in the index.
+    indexed_series: HashSet<u64>,
+}
+
+impl NativeStorage {
+    /// Opens the storage under `path`, `block_duration` is in ms.
+    /// At most `max_exemplars` of the most recent exemplars are kept.
+    pub fn new(
+        path: &str,
+        block_duration: i64,
+        fsync_policy: WalFsyncPolicy,
+        max_exemplars: usize,
+    ) -> StorageResult<Self> {
+        let path = PathBuf::from(path);
+        fs::create_dir_all(&path)?;
+        let index = Index::open(Config::new(&path.join(INDEX_DIRECTORY)))?;
+        let indexed_series = index.reader().query(Query::All)?.into_iter().collect();
+
+        let blocks = Block::open_all(&path.join(BLOCKS_DIRECTORY))?;
+        let min_valid_time = blocks
+            .last()
+            .map_or(i64::MIN, |block| block.meta().max_time);
+
+        let (wal, wal_records) =
+            Wal::open(&path.join(WAL_DIRECTORY), fsync_policy, WAL_SEGMENT_SIZE)?;
+        let mut head_state = HeadState {
+            head: Head::new(min_valid_time, max_exemplars),
+            wal,
+            indexed_series,
+        };
+
+        // Samples acknowledged before a crash or restart.
+        let index_writer = index.writer();
+        for time_series in wal_records {
+            for series in time_series {
+                if head_state.indexed_series.insert(series.get_id()) {
+                    index_writer.insert_doc(series.index_document());
+                }
+                head_state.head.append(series);
+            }
+        }
+        index_writer.commit(true)?;
+
+        let metadata = MetadataStore::open(&path.join(METADATA_FILE))?;
+        let storage = Self {
+            path,
+            block_duration,
+            index: Mutex::new(Some(index)),
+            head: RwLock::new(head_state),
+            blocks: RwLock::new(blocks.into_iter().map(Arc::new).collect()),
+            metadata: Mutex::new(metadata),
+        };
+        storage.compact_head(&mut storage.head.write().unwrap())?;
+        Ok(storage)
+    }
+
+    pub async fn write(&self, series: Vec<TimeSeries>) -> StorageResult<()> {
+        let index_writer = self.index_reader_or_writer(|index| index.writer())?;
+        let mut has_new_series = false;
+        let mut num_out_of_bounds = 0;
+        let min_valid_time;
+        {
+            let mut head_state = self.head.write().unwrap();
+            head_state.wal.append(&series)?;
+
+            let mut num_out_of_order = 0;
+            for time_series in series {
+                if head_state.indexed_series.insert(time_series.get_id()) {
+                    index_writer.insert_doc(time_series.index_document());
+                    has_new_series = true;
+                }
+                let (out_of_order, out_of_bounds) = head_state.head.append(time_series);
+                num_out_of_order += out_of_order;
+                num_out_of_bounds += out_of_bounds;
+            }
+            if num_out_of_order > 0 {
+                println!("Dropped `{}` out of order samples.", num_out_of_order);
+            }
+            min_valid_time = head_state.head.min_valid_time();
+            self.compact_head(&mut head_state)?;
+        }
+
+        // New series are searchable once the index is committed.
+        if has_new_series {
+            tokio::task::spawn_blocking(move || index_writer.commit(true))
+                .await
+                .map_err(|err| StorageError::Other(format!("tokio join error {}", err)))??;
+        }
+        if num_out_of_bounds > 0 {
+            return Err(StorageError::OutOfBounds(format!(
+                "`{}` samples older than `{}`, the start of the head block",
+                num_out_of_bounds, min_valid_time
+            )));
+        }
+        Ok(())
+    }
+
+    pub async fn read(
+        &self,
+        query: Query,
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> StorageResult<Vec<TimeSeries>> {
+        let series_ids = self.index_reader()?.query(query)?;
+        self.read_series(series_ids, start_timestamp, end_timestamp).await
+    }
+
+    /// Reads the samples of the series of `series_ids` within `[start_timestamp, end_timestamp)`.
+    pub async fn read_series(
+        &self,
+        series_ids: Vec<u64>,
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> StorageResult<Vec<TimeSeries>> {
+        let now = Instant::now();
+        let index_reader = self.index_reader()?;
+
+        let blocks: Vec<Arc<Block>> = self
+            .blocks
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|block| block.overlaps(start_timestamp, end_timestamp))
+            .cloned()
+            .collect();
+        let head_state = self.head.read().unwrap();
+
+        let mut num_samples = 0;
+        let mut num_histograms = 0;
+        let mut timeseries = Vec::with_capacity(series_ids.len());
+        for series_id in series_ids {
+            let doc_data = index_reader.fetch_doc(series_id)?;
+            let labels: Vec<Label> = serde_json::from_slice(&doc_data)?;
+
+            // Blocks are sorted by time and precede the head.
+            let mut samples = vec![];
+            let mut histograms = vec![];
+            for block in blocks.iter() {
+                block.samples(series_id, start_timestamp, end_timestamp, &mut samples);
+                block.histograms(series_id, start_timestamp, end_timestamp, &mut histograms)?;
+            }
+            head_state
+                .head
+                .samples(series_id, start_timestamp, end_timestamp, &mut samples);
+            head_state
+                .head
+                .histograms(series_id, start_timestamp, end_timestamp, &mut histograms);
+            num_samples += samples.len();
+            num_histograms += histograms.len();
+            let mut series = TimeSeries::new(labels, samples);
+            series.extend_histograms(histograms);
+            timeseries.push(series);
+        }
+
+        let elapsed = now.elapsed();
+        println!(
+            "Selected `{}` samples and `{}` histograms in `{:.2?}`.",
+            num_samples, num_histograms, elapsed
+        );
+        Ok(timeseries)
+    }
+
+    /// Returns whether any of the series of `series_ids` has histograms within
+    /// `[start_timestamp, end_timestamp)`. Blocks are not decoded, a series of a
+    /// block overlapping the range is taken as having histograms in the range.
+    pub fn has_histograms(
+        &self,
+        series_ids: &[u64],
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> bool {
+        let blocks = self.blocks.read().unwrap();
+        let blocks: Vec<&Arc<Block>> = blocks
+            .iter()
+            .filter(|block| block.meta().num_histograms > 0)
+            .filter(|block| block.overlaps(start_timestamp, end_timestamp))
+            .collect();
+        let head_state = self.head.read().unwrap();
+        series_ids.iter().any(|series_id| {
+            blocks.iter().any(|block| block.has_histograms(*series_id))
+                || head_state
+                    .head
+                    .has_histograms(*series_id, start_timestamp, end_timestamp)
+        })
+    }
+
+    /// Returns the series matching `query` with their exemplars within
+    /// `[start_timestamp, end_timestamp]`, series without exemplars are left out.
+    pub async fn exemplars(
+        &self,
+        query: Query,
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> StorageResult<Vec<TimeSeries>> {
+        let index_reader = self.index_reader()?;
+        let series_ids: HashSet<u64> = index_reader.query(query)?.into_iter().collect();
+        let series_exemplars: HashMap<u64, Vec<Exemplar>> = self
+            .head
+            .read()
+            .unwrap()
+            .head
+            .exemplars(&series_ids, start_timestamp, end_timestamp);
+
+        let mut timeseries = Vec::with_capacity(series_exemplars.len());
+        for (series_id, exemplars) in series_exemplars {
+            let doc_data = index_reader.fetch_doc(series_id)?;
+            let mut series = TimeSeries::new(serde_json::from_slice(&doc_data)?, vec![]);
+            series.extend_exemplars(exemplars);
+            timeseries.push(series);
+        }
+        Ok(timeseries)
+    }
+
+    /// Keeps the latest metadata of each metric family.
+    pub async fn write_metadata(&self, metadata: Vec<MetricMetadata>) -> StorageResult<()> {
+        self.metadata.lock().unwrap().update(metadata)
+    }
+
+    /// Returns the metadata of the metric family `metric`, or of all of them, sorted by name.
+    pub async fn metadata(&self, metric: Option<&str>) -> StorageResult<Vec<MetricMetadata>> {
+        Ok(self.metadata.lock().unwrap().get(metric))
+    }
+
+    /// Drops the blocks whose samples are all older than `timestamp`.
+    pub async fn truncate(&self, timestamp: i64) -> StorageResult<()> {
+        let mut blocks = self.blocks.write().unwrap();
+        let num_expired = blocks.partition_point(|block| block.meta().max_time <= timestamp);
+        for block in blocks.drain(..num_expired) {
+            block.remove()?;
+        }
+        Ok(())
+    }
+
+    /// Commits and closes the index, the head is kept in the write-ahead log.
+    pub async fn shutdown(&self) -> StorageResult<()> {
+        let mut head_state = self.head.write().unwrap();
+        head_state.wal.sync()?;
+        if let Some(index) = self.index.lock().unwrap().take() {
+            index.close(true)?;
+        }
+        Ok(())
+    }
+
+    /// Persists the oldest block duration of the head while it spans
+    /// more than one and a half block duration.
+    fn compact_head(&self, head_state: &mut HeadState) -> StorageResult<()> {
+        while let Some(min_time) = head_state.head.min_time() {
+            let block_start = min_time.div_euclid(self.block_duration) * self.block_duration;
+            let block_end = block_start + self.block_duration;
+            if head_state.head.max_time() < block_end + self.block_duration / 2 {
+                break;
+            }
+
+            let (series_chunks, series_histograms) = head_state.head.cut(block_end);
+            let block = Block::write(
+                &self.path.join(BLOCKS_DIRECTORY),
+                block_start,
+                block_end,
+                &series_chunks,
+                &series_histograms,
+            )?;
+            println!(
+                "Persisted block of `{}` samples and `{}` histograms from `{}` to `{}`.",
+                block.meta().num_samples,
+                block.meta().num_histograms,
+                block_start,
+                block_end
+            );
+            self.blocks.write().unwrap().push(Arc::new(block));
+
+            // Only the samples left in the head are needed on restart.
+            let head_series = head_state.head.time_series();
+            head_state.wal.checkpoint(&head_series)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn index_reader(&self) -> StorageResult<IndexReader> {
+        self.index_reader_or_writer(|index| index.reader())
+    }
+
+    fn index_reader_or_writer<T>(&self, f: impl FnOnce(&Index) -> T) -> StorageResult<T> {
+        self.index
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(f)
+            .ok_or_else(|| StorageError::Unavailable("storage is shut down".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        BucketSpan, CounterResetHint, HistogramSample, MetricType, Sample, SERIES_NAME_LABEL,
+    };
+
+    const BLOCK_DURATION: i64 = 1_000;
+    const MAX_EXEMPLARS: usize = 100;
+
+    fn series(name: &str, job: &str, samples: &[(i64, f64)]) -> TimeSeries {
+        TimeSeries::new(
+            vec![
+                Label {
+                    name: SERIES_NAME_LABEL.to_string(),
+                    value: name.to_string(),
+                },
+                Label {
+                    name: "job".to_string(),
+                    value: job.to_string(),
+                },
+            ],
+            samples
+                .iter()
+                .map(|(timestamp, value)| Sample {
+                    timestamp: *timestamp,
+                    value: *value,
+                })
+                .collect(),
+        )
+    }
+
+    fn job_query(job: &str) -> Query {
+        Query::Field("job".to_string(), Box::new(Query::Equal(job.to_string())))
+    }
+
+    async fn read_samples(
+        storage: &NativeStorage,
+        query: Query,
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> Vec<(i64, f64)> {
+        let series = storage
+            .read(query, start_timestamp, end_timestamp)
+            .await
+            .unwrap();
+        series
+            .iter()
+            .flat_map(|series| series.get_samples())
+            .map(|sample| (sample.timestamp, sample.value))
+            .collect()
+    }
+
+    async fn read_histograms(
+        storage: &NativeStorage,
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> Vec<HistogramSample> {
+        let series = storage
+            .read(Query::All, start_timestamp, end_timestamp)
+            .await
+            .unwrap();
+        series
+            .iter()
+            .flat_map(|series| series.get_histograms().to_vec())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn write_read_and_truncate() -> StorageResult<()> {
+        let tmp_dir = TempDir::new("native").unwrap();
+        let path = tmp_dir.path().to_str().unwrap();
+        let storage =
+            NativeStorage::new(path, BLOCK_DURATION, WalFsyncPolicy::Never, MAX_EXEMPLARS)?;
+
+        let api_samples: Vec<(i64, f64)> = (0..300).map(|i| (i * 10, i as f64)).collect();
+        storage
+            .write(vec![
+                series("up", "api", &api_samples),
+                series("up", "db", &[(5, 1.0), (1_005, 0.0)]),
+            ])
+            .await?;
+        // Out of order samples are dropped, samples of persisted blocks are rejected.
+        storage
+            .write(vec![series("up", "api", &[(2_500, -1.0)])])
+            .await?;
+        assert!(matches!(
+            storage
+                .write(vec![series("up", "api", &[(100, -1.0)])])
+                .await,
+            Err(StorageError::OutOfBounds(_))
+        ));
+
+        // Blocks are persisted while the head spans more than 1.5 block.
+        assert_eq!(storage.blocks.read().unwrap().len(), 2);
+        assert_eq!(
+            read_samples(&storage, job_query("api"), 0, 3_000).await,
+            api_samples
+        );
+        assert_eq!(
+            read_samples(&storage, job_query("api"), 995, 1_020).await,
+            &[(1_000, 100.0), (1_010, 101.0)]
+        );
+        assert_eq!(
+            read_samples(&storage, job_query("db"), 0, 3_000).await,
+            &[(5, 1.0), (1_005, 0.0)]
+        );
+        storage.shutdown().await?;
+        drop(storage);
+
+        // Blocks and the head survive restarts.
+        let storage =
+            NativeStorage::new(path, BLOCK_DURATION, WalFsyncPolicy::Never, MAX_EXEMPLARS)?;
+        assert_eq!(
+            read_samples(&storage, Query::All, 0, 3_000).await.len(),
+            api_samples.len() + 2
+        );
+
+        storage.truncate(1_500).await?;
+        assert_eq!(storage.blocks.read().unwrap().len(), 1);
+        assert_eq!(
+            read_samples(&storage, job_query("api"), 0, 3_000).await,
+            &api_samples[100..]
+        );
+        assert_eq!(fs_entries(&tmp_dir.path().join(BLOCKS_DIRECTORY)), 1);
+        storage.shutdown().await?;
+        Ok(())
+    }
+
+    fn histogram(timestamp: i64, count: f64) -> HistogramSample {
+        HistogramSample {
+            timestamp,
+            counter_reset_hint: CounterResetHint::Unknown,
+            schema: 0,
+            zero_threshold: 0.001,
+            zero_count: 1.0,
+            count,
+            sum: count * 2.0,
+            positive_spans: vec![BucketSpan {
+                offset: 1,
+                length: 2,
+            }],
+            positive_counts: vec![count - 2.0, 1.0],
+            negative_spans: vec![],
+            negative_counts: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn write_read_histograms() -> StorageResult<()> {
+        let tmp_dir = TempDir::new("native").unwrap();
+        let path = tmp_dir.path().to_str().unwrap();
+        let storage =
+            NativeStorage::new(path, BLOCK_DURATION, WalFsyncPolicy::Never, MAX_EXEMPLARS)?;
+
+        let mut histograms_series = series("latency", "api", &[]);
+        let histograms: Vec<HistogramSample> = (0..30)
+            .map(|i| histogram(i * 100, 3.0 + i as f64))
+            .collect();
+        histograms_series.extend_histograms(histograms.clone());
+        storage.write(vec![histograms_series]).await?;
+
+        // Histograms older than the head are persisted in blocks.
+        assert_eq!(storage.blocks.read().unwrap().len(), 2);
+        assert_eq!(storage.blocks.read().unwrap()[0].meta().num_histograms, 10);
+        assert_eq!(read_histograms(&storage, 0, 3_000).await, histograms);
+        assert_eq!(
+            read_histograms(&storage, 950, 1_150).await,
+            &histograms[10..12]
+        );
+        storage.shutdown().await?;
+        drop(storage);
+
+        let storage =
+            NativeStorage::new(path, BLOCK_DURATION, WalFsyncPolicy::Never, MAX_EXEMPLARS)?;
+        assert_eq!(read_histograms(&storage, 0, 3_000).await, histograms);
+        storage.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_query_exemplars() -> StorageResult<()> {
+        let tmp_dir = TempDir::new("native").unwrap();
+        let path = tmp_dir.path().to_str().unwrap();
+        let storage =
+            NativeStorage::new(path, BLOCK_DURATION, WalFsyncPolicy::Never, MAX_EXEMPLARS)?;
+
+        let exemplar = |timestamp: i64| Exemplar {
+            labels: vec![Label {
+                name: "trace_id".to_string(),
+                value: format!("trace-{}", timestamp),
+            }],
+            value: 0.25,
+            timestamp,
+        };
+        let mut api_series = series("latency", "api", &[(10, 1.0), (20, 2.0)]);
+        api_series.extend_exemplars(vec![exemplar(10), exemplar(20)]);
+        storage
+            .write(vec![api_series, series("latency", "db", &[(10, 1.0)])])
+            .await?;
+
+        // Series without exemplars are left out, bounds are inclusive.
+        let selected = storage.exemplars(Query::All, 0, 100).await?;
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].get_exemplars(), &[exemplar(10), exemplar(20)]);
+        let selected = storage.exemplars(job_query("api"), 20, 20).await?;
+        assert_eq!(selected[0].get_exemplars(), &[exemplar(20)]);
+        assert!(storage.exemplars(job_query("db"), 0, 100).await?.is_empty());
+        storage.shutdown().await?;
+        drop(storage);
+
+        // Exemplars are replayed from the write-ahead log.
+        let storage =
+            NativeStorage::new(path, BLOCK_DURATION, WalFsyncPolicy::Never, MAX_EXEMPLARS)?;
+        let selected = storage.exemplars(job_query("api"), 0, 100).await?;
+        assert_eq!(selected[0].get_exemplars().len(), 2);
+        storage.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_read_metadata() -> StorageResult<()> {
+        let tmp_dir = TempDir::new("native").unwrap();
+        let path = tmp_dir.path().to_str().unwrap();
+        let storage =
+            NativeStorage::new(path, BLOCK_DURATION, WalFsyncPolicy::Never, MAX_EXEMPLARS)?;
+
+        let metadata = |name: &str, metric_type: MetricType, help: &str| MetricMetadata {
+            metric_family_name: name.to_string(),
+            metric_type,
+            help: help.to_string(),
+            unit: "".to_string(),
+        };
+        storage
+            .write_metadata(vec![
+                metadata("up", MetricType::Gauge, "Whether the target is up."),
+                metadata("requests", MetricType::Counter, "Requests."),
+            ])
+            .await?;
+        // The latest metadata of a family replaces the previous one.
+        storage
+            .write_metadata(vec![metadata(
+                "requests",
+                MetricType::Counter,
+                "Served requests.",
+            )])
+            .await?;
+        assert_eq!(
+            storage.metadata(Some("requests")).await?,
+            &[metadata(
+                "requests",
+                MetricType::Counter,
+                "Served requests."
+            )]
+        );
+        assert!(storage.metadata(Some("errors")).await?.is_empty());
+        storage.shutdown().await?;
+        drop(storage);
+
+        let storage =
+            NativeStorage::new(path, BLOCK_DURATION, WalFsyncPolicy::Never, MAX_EXEMPLARS)?;
+        let names: Vec<String> = storage
+            .metadata(None)
+            .await?
+            .into_iter()
+            .map(|metadata| metadata.metric_family_name)
+            .collect();
+        assert_eq!(names, &["requests", "up"]);
+        storage.shutdown().await?;
+        Ok(())
+    }
+
+    fn fs_entries(path: &Path) -> usize {
+        std::fs::read_dir(path).unwrap().count()
+    }
+}
@@ -1,10 +1,28 @@
 use serde::Deserialize;
 
+use crate::WalFsyncPolicy;
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
 pub enum StorageSettings {
-    Native(String),
+    Native {
+        /// The directory of the index, write-ahead log and blocks.
+        path: String,
+
+        /// The time range (in minutes) of a persisted block.
+        #[serde(default = "default_block_duration")]
+        block_duration: u64,
+
+        /// When appended samples are flushed to disk.
+        #[serde(default)]
+        wal_fsync_policy: WalFsyncPolicy,
+
+        /// The maximum number of exemplars kept in memory, the
+        /// oldest ones are evicted first. Zero disables exemplars.
+        #[serde(default = "default_max_exemplars")]
+        max_exemplars: usize,
+    },
     ClickHouse {
         /// The ClickHouse server connection url.
         url: String,
@@ -28,5 +46,77 @@ pub enum StorageSettings {
         /// The maximum number of sample allowed for the in-memory
         /// buffer before committing
         sample_budget: u64,
+
+        /// The maximum age (in seconds) of the in-memory
+        /// buffer before committing.
+        #[serde(default = "default_flush_interval")]
+        flush_interval: u64,
+
+        /// The write-ahead log of the in-memory buffer.
+        #[serde(default)]
+        wal: WalSettings,
+
+        /// The spool of the samples ClickHouse failed to insert.
+        #[serde(default)]
+        spool: SpoolSettings,
+
+        /// The retention (in days) of the exemplars,
+        /// they are kept forever if not set.
+        #[serde(default)]
+        exemplar_retention: Option<u64>,
     },
 }
+
+fn default_block_duration() -> u64 {
+    120
+}
+
+fn default_max_exemplars() -> usize {
+    100_000
+}
+
+fn default_flush_interval() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WalSettings {
+    /// The write-ahead log directory.
+    pub path: String,
+
+    /// When appended samples are flushed to disk.
+    pub fsync_policy: WalFsyncPolicy,
+
+    /// The size of a log segment file (in MB).
+    pub segment_size: u64,
+}
+
+impl Default for WalSettings {
+    fn default() -> Self {
+        Self {
+            path: "./wal-data".to_string(),
+            fsync_policy: WalFsyncPolicy::Always,
+            segment_size: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SpoolSettings {
+    /// The spool directory.
+    pub path: String,
+
+    /// The spool size (in MB) above which writes are rejected.
+    pub max_size: u64,
+}
+
+impl Default for SpoolSettings {
+    fn default() -> Self {
+        Self {
+            path: "./spool-data".to_string(),
+            max_size: 1024,
+        }
+    }
+}
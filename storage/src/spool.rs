@@ -0,0 +1,170 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{error::StorageResult, TimeSeries};
+
+const SPOOL_FILE_EXTENSION: &str = "spool";
+
+/// A local directory of batches that could not be inserted into ClickHouse.
+/// Each batch is a file named after an increasing id holding a
+/// serialized `Vec<TimeSeries>`, batches are replayed oldest first.
+#[derive(Debug)]
+pub(crate) struct Spool {
+    directory: PathBuf,
+    max_size: u64,
+    size: u64,
+    next_id: u64,
+    /// Shared with the writers to push back while the spool is over its limit.
+    over_limit: Arc<AtomicBool>,
+}
+
+impl Spool {
+    pub fn open(directory: &Path, max_size: u64) -> StorageResult<Self> {
+        fs::create_dir_all(directory)?;
+
+        let mut size = 0;
+        let mut next_id = 0;
+        for (batch_id, file_name) in list_batches(directory)? {
+            size += fs::metadata(file_name)?.len();
+            next_id = batch_id + 1;
+        }
+
+        let spool = Self {
+            directory: directory.to_path_buf(),
+            max_size,
+            size,
+            next_id,
+            over_limit: Arc::new(AtomicBool::new(false)),
+        };
+        spool.update_over_limit();
+        Ok(spool)
+    }
+
+    pub fn over_limit(&self) -> Arc<AtomicBool> {
+        self.over_limit.clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Durably stores a batch.
+    pub fn push(&mut self, time_series: &[TimeSeries]) -> StorageResult<()> {
+        let file_name = batch_file_name(&self.directory, self.next_id);
+        let tmp_file_name = file_name.with_extension("tmp");
+        let bytes = bincode::serialize(time_series)?;
+        let mut file = File::create(&tmp_file_name)?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+        fs::rename(tmp_file_name, file_name)?;
+        File::open(&self.directory)?.sync_all()?;
+
+        self.next_id += 1;
+        self.size += bytes.len() as u64;
+        self.update_over_limit();
+        Ok(())
+    }
+
+    /// Returns the oldest batch along with the file storing it.
+    pub fn oldest(&self) -> StorageResult<Option<(PathBuf, Vec<TimeSeries>)>> {
+        let Some((_, file_name)) = list_batches(&self.directory)?.into_iter().next() else {
+            return Ok(None);
+        };
+        let time_series = bincode::deserialize(&fs::read(&file_name)?)?;
+        Ok(Some((file_name, time_series)))
+    }
+
+    /// Removes a replayed batch.
+    pub fn remove(&mut self, file_name: &Path) -> StorageResult<()> {
+        let file_size = fs::metadata(file_name)?.len();
+        fs::remove_file(file_name)?;
+        self.size = self.size.saturating_sub(file_size);
+        self.update_over_limit();
+        Ok(())
+    }
+
+    fn update_over_limit(&self) {
+        self.over_limit
+            .store(self.size > self.max_size, Ordering::Relaxed);
+    }
+}
+
+fn batch_file_name(directory: &Path, batch_id: u64) -> PathBuf {
+    directory.join(format!("{:020}.{}", batch_id, SPOOL_FILE_EXTENSION))
+}
+
+/// Returns the batches of the directory, oldest first.
+fn list_batches(directory: &Path) -> StorageResult<Vec<(u64, PathBuf)>> {
+    let mut batches = vec![];
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(SPOOL_FILE_EXTENSION) {
+            continue;
+        }
+        if let Some(batch_id) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            batches.push((batch_id, path));
+        }
+    }
+    batches.sort_unstable();
+    Ok(batches)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{Label, Sample};
+
+    fn series(name: &str) -> TimeSeries {
+        TimeSeries::new(
+            vec![Label {
+                name: "__name__".to_string(),
+                value: name.to_string(),
+            }],
+            vec![Sample {
+                timestamp: 1,
+                value: 1.0,
+            }],
+        )
+    }
+
+    #[test]
+    fn push_and_replay() -> StorageResult<()> {
+        let tmp_dir = TempDir::new("spool").unwrap();
+        let mut spool = Spool::open(tmp_dir.path(), 10)?;
+        let over_limit = spool.over_limit();
+        assert!(spool.is_empty());
+
+        spool.push(&[series("a")])?;
+        spool.push(&[series("b")])?;
+        assert!(over_limit.load(Ordering::Relaxed));
+
+        // Batches survive restarts.
+        let mut spool = Spool::open(tmp_dir.path(), 10)?;
+        let over_limit = spool.over_limit();
+        assert!(over_limit.load(Ordering::Relaxed));
+        let (file_name, batch) = spool.oldest()?.unwrap();
+        assert_eq!(batch[0].get_name(), "a");
+        spool.remove(&file_name)?;
+
+        let (file_name, batch) = spool.oldest()?.unwrap();
+        assert_eq!(batch[0].get_name(), "b");
+        spool.remove(&file_name)?;
+        assert!(spool.oldest()?.is_none());
+        assert!(spool.is_empty());
+        assert!(!over_limit.load(Ordering::Relaxed));
+        Ok(())
+    }
+}
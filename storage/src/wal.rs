@@ -0,0 +1,302 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{error::StorageResult, TimeSeries};
+
+const WAL_SEGMENT_EXTENSION: &str = "wal";
+const RECORD_HEADER_NUM_BYTE: usize = 8;
+
+/// When appended records are flushed to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WalFsyncPolicy {
+    /// Fsync every record before acknowledging the write.
+    #[default]
+    Always,
+    /// Fsync on the storage commit ticker, a crash can lose
+    /// the records of the last interval.
+    Interval,
+    /// Leave flushing to the OS.
+    Never,
+}
+
+/// A segmented write-ahead log of the series accepted by the storage
+/// and not yet committed. Segments are named after an increasing id,
+/// a new one is started when the current one exceeds the segment size.
+///
+/// Record Format: a serialized `Vec<TimeSeries>`
+/// ┌─────────────┬───────────────┬─────────┐
+/// │ payload len │ payload crc32 │ payload │
+/// └─────────────┴───────────────┴─────────┘
+///
+#[derive(Debug)]
+pub(crate) struct Wal {
+    directory: PathBuf,
+    fsync_policy: WalFsyncPolicy,
+    segment_size: u64,
+    segment_id: u64,
+    segment_file: File,
+    segment_len: u64,
+    synced: bool,
+}
+
+impl Wal {
+    /// Opens the log and returns the records left by a previous run,
+    /// appending starts in a new segment.
+    pub fn open(
+        directory: &Path,
+        fsync_policy: WalFsyncPolicy,
+        segment_size: u64,
+    ) -> StorageResult<(Self, Vec<Vec<TimeSeries>>)> {
+        fs::create_dir_all(directory)?;
+
+        let segment_ids = list_segments(directory)?;
+        let mut records = vec![];
+        for segment_id in segment_ids.iter() {
+            read_segment(&segment_file_name(directory, *segment_id), &mut records)?;
+        }
+
+        let segment_id = segment_ids.last().map_or(0, |id| id + 1);
+        let wal = Self {
+            directory: directory.to_path_buf(),
+            fsync_policy,
+            segment_size,
+            segment_id,
+            segment_file: create_segment(directory, segment_id)?,
+            segment_len: 0,
+            synced: true,
+        };
+        Ok((wal, records))
+    }
+
+    /// Appends a record, it is on disk when this returns
+    /// if the fsync policy is `Always`.
+    pub fn append(&mut self, time_series: &[TimeSeries]) -> StorageResult<()> {
+        if self.segment_len >= self.segment_size {
+            self.rotate()?;
+        }
+
+        let payload = bincode::serialize(time_series)?;
+        let mut record = Vec::with_capacity(RECORD_HEADER_NUM_BYTE + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        self.segment_file.write_all(&record)?;
+        self.segment_len += record.len() as u64;
+        self.synced = false;
+
+        if self.fsync_policy == WalFsyncPolicy::Always {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the appended records to disk.
+    pub fn sync(&mut self) -> StorageResult<()> {
+        if !self.synced && self.fsync_policy != WalFsyncPolicy::Never {
+            self.segment_file.sync_data()?;
+        }
+        self.synced = true;
+        Ok(())
+    }
+
+    /// Removes all the records, to be called once they are committed.
+    pub fn truncate(&mut self) -> StorageResult<()> {
+        self.rotate()?;
+        self.remove_previous_segments()
+    }
+
+    /// Replaces all the records by a single one, e.g. with the
+    /// uncommitted samples once the others are committed.
+    pub fn checkpoint(&mut self, time_series: &[TimeSeries]) -> StorageResult<()> {
+        self.rotate()?;
+        self.append(time_series)?;
+        self.segment_file.sync_data()?;
+        self.synced = true;
+        self.remove_previous_segments()
+    }
+
+    fn remove_previous_segments(&mut self) -> StorageResult<()> {
+        for segment_id in list_segments(&self.directory)? {
+            if segment_id < self.segment_id {
+                fs::remove_file(segment_file_name(&self.directory, segment_id))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> StorageResult<()> {
+        self.sync()?;
+        self.segment_id += 1;
+        self.segment_file = create_segment(&self.directory, self.segment_id)?;
+        self.segment_len = 0;
+        Ok(())
+    }
+}
+
+fn segment_file_name(directory: &Path, segment_id: u64) -> PathBuf {
+    directory.join(format!("{:020}.{}", segment_id, WAL_SEGMENT_EXTENSION))
+}
+
+fn create_segment(directory: &Path, segment_id: u64) -> StorageResult<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_file_name(directory, segment_id))?;
+    // Make the new segment entry durable.
+    File::open(directory)?.sync_all()?;
+    Ok(file)
+}
+
+/// Returns the sorted ids of the segments in the directory.
+fn list_segments(directory: &Path) -> StorageResult<Vec<u64>> {
+    let mut segment_ids = vec![];
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(WAL_SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(segment_id) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            segment_ids.push(segment_id);
+        }
+    }
+    segment_ids.sort_unstable();
+    Ok(segment_ids)
+}
+
+/// Reads the records of a segment. A torn or corrupted record,
+/// e.g. from a crash in the middle of an append, ends the segment.
+fn read_segment(file_name: &Path, records: &mut Vec<Vec<TimeSeries>>) -> StorageResult<()> {
+    let mut data = vec![];
+    File::open(file_name)?.read_to_end(&mut data)?;
+
+    let mut offset = 0;
+    while offset + RECORD_HEADER_NUM_BYTE <= data.len() {
+        let payload_len = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap());
+        let payload_start = offset + RECORD_HEADER_NUM_BYTE;
+        let Some(payload) = data.get(payload_start..payload_start + payload_len) else {
+            break;
+        };
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+
+        let time_series: Vec<TimeSeries> = bincode::deserialize(payload)?;
+        // The series size is not serialized, rebuilding computes it again.
+        records.push(
+            time_series
+                .into_iter()
+                .map(|mut series| {
+                    let exemplars = series.take_exemplars();
+                    let (labels, samples, histograms) = series.into_parts();
+                    let mut series = TimeSeries::new(labels, samples);
+                    series.extend_histograms(histograms);
+                    series.extend_exemplars(exemplars);
+                    series
+                })
+                .collect(),
+        );
+        offset = payload_start + payload_len;
+    }
+
+    if offset < data.len() {
+        println!(
+            "Wal segment `{}` has a corrupted record at offset {}, ignoring {} bytes.",
+            file_name.display(),
+            offset,
+            data.len() - offset
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{Label, Sample};
+
+    fn series(name: &str, timestamp: i64) -> TimeSeries {
+        TimeSeries::new(
+            vec![Label {
+                name: "__name__".to_string(),
+                value: name.to_string(),
+            }],
+            vec![Sample {
+                timestamp,
+                value: 1.0,
+            }],
+        )
+    }
+
+    fn replayed_names(records: &[Vec<TimeSeries>]) -> Vec<Vec<String>> {
+        records
+            .iter()
+            .map(|record| {
+                record
+                    .iter()
+                    .map(|series| series.get_name().to_string())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replay_and_truncate() -> StorageResult<()> {
+        let tmp_dir = TempDir::new("wal").unwrap();
+        let (mut wal, records) = Wal::open(tmp_dir.path(), WalFsyncPolicy::Always, 64)?;
+        assert!(records.is_empty());
+
+        wal.append(&[series("a", 1), series("b", 1)])?;
+        wal.append(&[series("c", 2)])?;
+        wal.append(&[series("d", 3)])?;
+        assert!(list_segments(tmp_dir.path())?.len() > 1);
+        drop(wal);
+
+        let (mut wal, records) = Wal::open(tmp_dir.path(), WalFsyncPolicy::Always, 64)?;
+        assert_eq!(
+            replayed_names(&records),
+            vec![vec!["a", "b"], vec!["c"], vec!["d"]]
+        );
+        assert!(records[0][0].get_size_bytes() > 0);
+
+        wal.append(&[series("e", 4)])?;
+        wal.truncate()?;
+        drop(wal);
+        let (_, records) = Wal::open(tmp_dir.path(), WalFsyncPolicy::Always, 64)?;
+        assert!(records.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_torn_record() -> StorageResult<()> {
+        let tmp_dir = TempDir::new("wal").unwrap();
+        let (mut wal, _) = Wal::open(tmp_dir.path(), WalFsyncPolicy::Never, 1024)?;
+        wal.append(&[series("a", 1)])?;
+        wal.append(&[series("b", 2)])?;
+        let segment_len = wal.segment_len;
+        drop(wal);
+
+        let file = OpenOptions::new()
+            .write(true)
+            .open(segment_file_name(tmp_dir.path(), 0))?;
+        file.set_len(segment_len - 1)?;
+
+        let (_, records) = Wal::open(tmp_dir.path(), WalFsyncPolicy::Never, 1024)?;
+        assert_eq!(replayed_names(&records), vec![vec!["a"]]);
+        Ok(())
+    }
+}